@@ -0,0 +1,41 @@
+use prost::Message;
+use std::{
+    env, fs,
+    path::PathBuf,
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Generates the gRPC service from `proto/`, and the encoded descriptors
+/// that reflection serves. The `.proto` files are parsed with protox, so
+/// building needs no `protoc`. Also records the commit being built as
+/// `GIT_COMMIT` and the time as `BUILD_TIMESTAMP`, from `SOURCE_DATE_EPOCH`
+/// when set so reproducible builds stay reproducible.
+fn main() {
+    println!("cargo:rerun-if-changed=proto");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+    let commit = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|commit| commit.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=GIT_COMMIT={}", commit);
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    let timestamp = env::var("SOURCE_DATE_EPOCH").ok().unwrap_or_else(|| {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        now.as_secs().to_string()
+    });
+    println!("cargo:rustc-env=BUILD_TIMESTAMP={}", timestamp);
+    let descriptors = protox::compile(["power_usage.proto"], ["proto"]).expect("invalid proto/power_usage.proto");
+    let out_dir = PathBuf::from(env::var("OUT_DIR").expect("OUT_DIR is set by cargo"));
+    fs::write(out_dir.join("power_usage_descriptor.bin"), descriptors.encode_to_vec())
+        .expect("failed to write the file descriptor set");
+    tonic_build::configure()
+        .build_client(false)
+        .compile_fds(descriptors)
+        .expect("failed to generate gRPC code");
+}
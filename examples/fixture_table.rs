@@ -0,0 +1,57 @@
+//! Prints the daily usage of the golden fixtures as a table, without a
+//! server or a Prometheus:
+//!
+//!     cargo run --example fixture_table [FIXTURE_DIR]
+
+use chrono::NaiveDate;
+use power_usage::{Config, Settings, UsageCalculator};
+use std::{env, path::PathBuf, process::ExitCode};
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> ExitCode {
+    let dir = env::args().nth(1).map_or_else(
+        || PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/golden/fixtures"),
+        PathBuf::from,
+    );
+    let settings = Settings {
+        backend: "fixture".to_string(),
+        fixture_dir: Some(dir),
+        ..Settings::default()
+    };
+    let config = match Config::from_settings(&settings) {
+        Ok(config) => config,
+        Err(errors) => {
+            eprintln!("{}", errors.join("\n"));
+            return ExitCode::FAILURE;
+        }
+    };
+    let calculator = match UsageCalculator::new(config) {
+        Ok(calculator) => calculator,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let start = NaiveDate::from_ymd_opt(2025, 7, 30).expect("valid date");
+    let end = NaiveDate::from_ymd_opt(2025, 7, 31).expect("valid date");
+    let meters = match calculator.range(".*", start, end, chrono_tz::Asia::Jakarta).await {
+        Ok(meters) => meters,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    println!("{:<20} {:>7} {:>10} {:>10} {:>10}", "Instance", "Address", start, end, "Total");
+    for meter in &meters {
+        let days: Vec<String> = meter
+            .days
+            .iter()
+            .map(|day| day.kwh.map_or("-".to_string(), |kwh| format!("{:.3}", kwh)))
+            .collect();
+        let total = format!("{:.3}", meter.total_kwh());
+        println!("{:<20} {:>7} {:>10} {:>10} {:>10}", meter.instance, meter.address, days[0], days[1], total);
+    }
+    ExitCode::SUCCESS
+}
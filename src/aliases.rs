@@ -0,0 +1,202 @@
+use crate::config::load_map;
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// One value of `ALIASES_FILE`: a display name, or the members of a composite.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Entry {
+    Name(String),
+    Composite(Vec<String>),
+}
+
+/// Addresses that are one meter physically, such as two CTs on a feeder,
+/// reported as one entry named `name` holding their summed deltas.
+pub struct Composite {
+    pub name: String,
+    /// `(instance, address)` of each member.
+    pub members: Vec<(String, String)>,
+}
+
+/// Display names keyed by `instance` or `instance/address`, and composites
+/// keyed by their name, loaded from `ALIASES_FILE` (JSON when the extension
+/// is `.json`, TOML otherwise):
+///
+/// ```toml
+/// "10.3.7.22:8899" = "Chiller 2"
+/// "10.3.7.22:8899/4" = "Chiller 2, Panel B"
+/// "Feeder A" = ["10.3.7.23:8899/2", "10.3.7.23:8899/3"]
+/// ```
+#[derive(Default)]
+pub struct Aliases {
+    names: HashMap<String, String>,
+    /// Sorted by name.
+    composites: Vec<Composite>,
+}
+
+fn address_key(instance: &str, address: &str) -> String {
+    format!("{}/{}", instance, address)
+}
+
+impl Aliases {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let (mut names, mut composites) = (HashMap::new(), Vec::new());
+        let mut seen = HashSet::new();
+        for (key, entry) in load_map::<Entry>(path)? {
+            let members = match entry {
+                Entry::Name(name) => {
+                    names.insert(key, name);
+                    continue;
+                }
+                Entry::Composite(members) if members.is_empty() => {
+                    return Err(format!("composite {:?} in {} has no members", key, path.display()));
+                }
+                Entry::Composite(members) => members,
+            };
+            let members = members
+                .into_iter()
+                .map(|member| {
+                    if !seen.insert(member.clone()) {
+                        let path = path.display();
+                        return Err(format!("{:?} is in more than one composite in {}", member, path));
+                    }
+                    match member.rsplit_once('/') {
+                        Some((instance, address)) => Ok((instance.to_string(), address.to_string())),
+                        None => Err(format!(
+                            "composite {:?} in {} needs `instance/address` members, got {:?}",
+                            key,
+                            path.display(),
+                            member
+                        )),
+                    }
+                })
+                .collect::<Result<_, _>>()?;
+            composites.push(Composite { name: key, members });
+        }
+        composites.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(Self { names, composites })
+    }
+
+    pub fn composites(&self) -> &[Composite] {
+        &self.composites
+    }
+
+    /// The most specific name for a meter: its address alias, else its
+    /// instance alias.
+    pub fn name(&self, instance: &str, address: &str) -> Option<&str> {
+        self.names
+            .get(&address_key(instance, address))
+            .or_else(|| self.names.get(instance))
+            .map(String::as_str)
+    }
+
+    /// Every instance, display name and composite name the file mentions.
+    pub fn identifiers(&self) -> impl Iterator<Item = &str> {
+        let keys = self
+            .names
+            .keys()
+            .map(|key| key.rsplit_once('/').map_or(key.as_str(), |(instance, _)| instance));
+        let members = self
+            .composites
+            .iter()
+            .flat_map(|c| c.members.iter().map(|(instance, _)| instance.as_str()));
+        keys.chain(self.names.values().map(String::as_str))
+            .chain(self.composites.iter().map(|c| c.name.as_str()))
+            .chain(members)
+    }
+
+    /// Resolves a display name back to its instance and, for address
+    /// aliases, the address.
+    pub fn resolve(&self, name: &str) -> Option<(String, Option<String>)> {
+        self.names
+            .iter()
+            .find(|(_, alias)| alias.as_str() == name)
+            .map(|(key, _)| match key.rsplit_once('/') {
+                Some((instance, address)) => (instance.to_string(), Some(address.to_string())),
+                None => (key.clone(), None),
+            })
+    }
+}
+
+/// Aliases shared with handlers, re-read whenever the file changes.
+#[derive(Clone, Default)]
+pub struct SharedAliases {
+    path: Option<PathBuf>,
+    inner: Arc<RwLock<Arc<Aliases>>>,
+}
+
+impl SharedAliases {
+    pub fn new(path: Option<PathBuf>) -> Result<Self, String> {
+        let aliases = match &path {
+            Some(path) => Aliases::load(path)?,
+            None => Aliases::default(),
+        };
+        Ok(Self {
+            path,
+            inner: Arc::new(RwLock::new(Arc::new(aliases))),
+        })
+    }
+
+    pub fn is_configured(&self) -> bool {
+        self.path.is_some()
+    }
+
+    pub fn current(&self) -> Arc<Aliases> {
+        self.inner.read().unwrap().clone()
+    }
+
+    /// Re-reads the file now, keeping the previous mapping if it fails to parse.
+    pub fn reload(&self) -> Result<(), String> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let aliases = Aliases::load(path)?;
+        *self.inner.write().unwrap() = Arc::new(aliases);
+        Ok(())
+    }
+
+    /// Polls the file's modification time and swaps in the new mapping when
+    /// it changes. A file that fails to parse keeps the previous mapping.
+    pub async fn watch(self) {
+        let Some(path) = self.path.clone() else {
+            return;
+        };
+        let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        let mut last_modified: Option<SystemTime> = modified(&path);
+
+        loop {
+            tokio::time::sleep(RELOAD_INTERVAL).await;
+            let current = modified(&path);
+            if current == last_modified {
+                continue;
+            }
+            last_modified = current;
+
+            match self.reload() {
+                Ok(()) => tracing::info!("Reloaded aliases from {}", path.display()),
+                Err(e) => tracing::error!("Keeping previous aliases: {}", e),
+            }
+        }
+    }
+}
+
+/// Escapes regex metacharacters so an instance name matches literally in a
+/// PromQL `=~` selector, using character classes to avoid backslash quoting.
+pub fn literal_pattern(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            '.' | '+' | '*' | '?' | '(' | ')' | '|' | '[' | ']' | '{' | '}' | '^' | '$' => {
+                format!("[{}]", c)
+            }
+            c => c.to_string(),
+        })
+        .collect()
+}
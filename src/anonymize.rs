@@ -0,0 +1,168 @@
+use axum::http::StatusCode;
+use hmac::{Hmac, KeyInit, Mac};
+use sha2::Sha256;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use crate::{config::Settings, prometheus::SkippedSeries, state::AppState, usage::UsageEntry};
+
+const PREFIX: &str = "anon-";
+/// Hex digits of the HMAC kept, 48 bits: collisions stay unlikely with
+/// many thousands of names.
+const DIGITS: usize = 12;
+/// Shortest `ANONYMIZE_KEY` accepted, in bytes.
+const MIN_KEY_BYTES: usize = 16;
+/// Most pseudonyms remembered for `reveal`; further ones are still found
+/// among the known instances and aliases.
+const MAX_REMEMBERED: usize = 100_000;
+
+/// Replaces instances and aliases with `anon-<hex>` pseudonyms for
+/// `anonymize=true`. Each is an HMAC of the name under `ANONYMIZE_KEY`, so a
+/// name always gets the same pseudonym and series stay joinable across
+/// requests, while the name cannot be recovered without the key.
+pub struct Anonymizer {
+    key: Vec<u8>,
+    /// Names seen since startup, by pseudonym.
+    remembered: Mutex<HashMap<String, String>>,
+}
+
+impl Anonymizer {
+    pub fn from_settings(settings: &Settings) -> Result<Option<Arc<Self>>, String> {
+        let key = &settings.anonymize_key;
+        if key.is_empty() {
+            return Ok(None);
+        }
+        if key.len() < MIN_KEY_BYTES {
+            return Err(format!("`ANONYMIZE_KEY` must be at least {} bytes", MIN_KEY_BYTES));
+        }
+        Ok(Some(Arc::new(Self {
+            key: key.as_bytes().to_vec(),
+            remembered: Mutex::default(),
+        })))
+    }
+
+    fn digest(&self, name: &str) -> String {
+        let mut mac =
+            <Hmac<Sha256> as KeyInit>::new_from_slice(&self.key).expect("HMAC takes any key length");
+        mac.update(name.as_bytes());
+        let digest = mac.finalize().into_bytes();
+        let hex: String = digest.iter().map(|byte| format!("{:02x}", byte)).collect();
+        format!("{}{}", PREFIX, &hex[..DIGITS])
+    }
+
+    pub fn pseudonym(&self, name: &str) -> String {
+        let pseudonym = self.digest(name);
+        let mut remembered = self.remembered.lock().unwrap();
+        if remembered.len() < MAX_REMEMBERED {
+            remembered.entry(pseudonym.clone()).or_insert_with(|| name.to_string());
+        }
+        pseudonym
+    }
+
+    /// The name behind `pseudonym`: one handed out since startup, or else
+    /// the first of `candidates` that maps to it.
+    pub fn reveal<'a>(
+        &self,
+        pseudonym: &str,
+        mut candidates: impl Iterator<Item = &'a str>,
+    ) -> Option<String> {
+        if let Some(name) = self.remembered.lock().unwrap().get(pseudonym) {
+            return Some(name.clone());
+        }
+        candidates.find(|name| self.digest(name) == pseudonym).map(str::to_string)
+    }
+
+    /// Replaces the instance, display name and composite names of `entry`,
+    /// including the `instance` label. Addresses and figures are kept.
+    pub fn entry(&self, entry: &mut UsageEntry) {
+        entry.instance = self.pseudonym(&entry.instance);
+        entry.name = entry.name.as_deref().map(|name| self.pseudonym(name));
+        entry.part_of = entry.part_of.as_deref().map(|name| self.pseudonym(name));
+        if let Some(members) = &mut entry.members {
+            for member in members {
+                if let Some((instance, address)) = member.rsplit_once('/') {
+                    *member = format!("{}/{}", self.pseudonym(instance), address);
+                }
+            }
+        }
+        if let Some(instance) = entry.labels.get_mut("instance") {
+            *instance = self.pseudonym(instance);
+        }
+        if let Some(applied) = &mut entry.correction {
+            applied.correction.instance = self.pseudonym(&applied.correction.instance);
+            applied.correction.author = self.pseudonym(&applied.correction.author);
+        }
+    }
+
+    /// Replaces the instance of a series left out for its value.
+    pub fn skipped(&self, series: &mut SkippedSeries) {
+        series.instance = self.pseudonym(&series.instance);
+    }
+}
+
+/// The anonymizer with `anonymize=true`, which needs `ANONYMIZE_KEY`.
+pub fn requested<'a>(
+    state: &'a AppState,
+    params: &HashMap<String, String>,
+) -> Result<Option<&'a Arc<Anonymizer>>, StatusCode> {
+    match params.get("anonymize").map(String::as_str) {
+        None | Some("false") => Ok(None),
+        Some("true") => state.config.anonymizer.as_ref().map(Some).ok_or(StatusCode::BAD_REQUEST),
+        Some(_) => Err(StatusCode::BAD_REQUEST),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "0123456789abcdef";
+
+    fn anonymizer(key: &str) -> Arc<Anonymizer> {
+        let settings = Settings {
+            anonymize_key: key.to_string(),
+            ..Settings::default()
+        };
+        Anonymizer::from_settings(&settings).unwrap().unwrap()
+    }
+
+    #[test]
+    fn pseudonyms_are_stable_for_one_key() {
+        let pseudonym = anonymizer(KEY).pseudonym("meter-a:9100");
+        assert_eq!(anonymizer(KEY).pseudonym("meter-a:9100"), pseudonym);
+        assert!(pseudonym.starts_with(PREFIX));
+        assert_eq!(pseudonym.len(), PREFIX.len() + DIGITS);
+        assert_ne!(anonymizer(KEY).pseudonym("meter-b:9100"), pseudonym);
+    }
+
+    #[test]
+    fn pseudonyms_differ_across_keys() {
+        let other = anonymizer("fedcba9876543210");
+        assert_ne!(other.pseudonym("meter-a:9100"), anonymizer(KEY).pseudonym("meter-a:9100"));
+    }
+
+    #[test]
+    fn reveal_finds_the_name() {
+        let running = anonymizer(KEY);
+        let pseudonym = running.pseudonym("meter-a:9100");
+        assert_eq!(running.reveal(&pseudonym, std::iter::empty()).as_deref(), Some("meter-a:9100"));
+
+        // After a restart only the candidates are left to search.
+        let restarted = anonymizer(KEY);
+        let candidates = ["meter-b:9100", "meter-a:9100"];
+        assert_eq!(restarted.reveal(&pseudonym, candidates.into_iter()).as_deref(), Some("meter-a:9100"));
+        assert_eq!(restarted.reveal(&pseudonym, ["meter-b:9100"].into_iter()), None);
+        assert_eq!(restarted.reveal("anon-000000000000", candidates.into_iter()), None);
+    }
+
+    #[test]
+    fn short_key_is_refused() {
+        let settings = Settings {
+            anonymize_key: "too short".to_string(),
+            ..Settings::default()
+        };
+        assert!(Anonymizer::from_settings(&settings).is_err());
+    }
+}
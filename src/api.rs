@@ -0,0 +1,264 @@
+use axum::{
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Offset, SecondsFormat, Utc};
+use serde::Serialize;
+
+use crate::{
+    api::unit::Unit,
+    cache::CacheStatus,
+    corrections::AppliedCorrection,
+    prometheus::SkippedSeries,
+    state::AppState,
+    usage::{Freshness, UsageEntry, UsageRequest},
+};
+
+pub mod admin;
+pub mod alerts;
+pub mod annotations;
+pub mod compare;
+pub mod corrections;
+pub mod demand;
+pub mod electrical;
+pub mod graphql;
+pub mod grpc;
+pub mod histogram;
+pub mod html;
+pub mod jobs;
+pub mod labels;
+pub mod latest;
+pub mod pdf;
+pub mod profile;
+pub mod range;
+pub mod reconcile;
+pub mod rolling;
+pub mod support;
+pub mod table;
+pub mod targets;
+pub mod unit;
+pub mod v1;
+pub mod v2;
+
+/// Quotes a CSV field when it contains a delimiter, quote or newline.
+pub fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Sets `Cache-Control` on a usage response from `freshness`, and
+/// `Last-Modified` from the newest sample in it. When the request's
+/// `If-Modified-Since` is no earlier than that sample, an empty 304 with the
+/// same headers goes out instead. `Freshness::Private` responses are always
+/// sent in full.
+pub fn with_caching(
+    request: &HeaderMap,
+    freshness: Freshness,
+    last_modified: Option<DateTime<Utc>>,
+    response: Response,
+) -> Response {
+    let last_modified = last_modified.filter(|_| freshness != Freshness::Private);
+    let since = request
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| DateTime::parse_from_rfc2822(v).ok());
+    let unchanged = last_modified
+        .zip(since)
+        .is_some_and(|(modified, since)| modified.timestamp() <= since.timestamp());
+    let mut response = if unchanged && response.status() == StatusCode::OK {
+        StatusCode::NOT_MODIFIED.into_response()
+    } else {
+        response
+    };
+    let headers = response.headers_mut();
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static(freshness.cache_control()));
+    if let Some(modified) = last_modified {
+        let modified = modified.format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+        headers.extend(HeaderValue::try_from(modified).ok().map(|v| (header::LAST_MODIFIED, v)));
+    }
+    response
+}
+
+/// The `Warning` of a response with the last known results, read at
+/// `as_of`, because Prometheus was down.
+pub fn degraded_warning(as_of: DateTime<Utc>) -> HeaderValue {
+    let as_of = as_of.to_rfc3339_opts(SecondsFormat::Secs, true);
+    HeaderValue::try_from(format!("110 power-usage \"Prometheus is unreachable; data as of {}\"", as_of))
+        .unwrap_or(HeaderValue::from_static("110 power-usage \"Response is stale\""))
+}
+
+/// `Cache-Control: no-store` on a response that did not set its own, for
+/// the admin and debug routes.
+pub async fn no_store(mut response: Response) -> Response {
+    let headers = response.headers_mut();
+    if !headers.contains_key(header::CACHE_CONTROL) {
+        headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    }
+    response
+}
+
+/// A meter whose reading was left out, in `QueryMeta::skipped_meters`.
+#[derive(Serialize, PartialEq)]
+struct SkippedMeter {
+    instance: String,
+    address: String,
+}
+
+/// What the server resolved a usage request to: `meta` on v2, and on v1
+/// with `meta=true`.
+#[derive(Serialize)]
+pub struct QueryMeta {
+    target: String,
+    datetime: String,
+    curr_time: DateTime<Utc>,
+    prev_time: DateTime<Utc>,
+    timezone: String,
+    utc_offset: String,
+    lookback: String,
+    /// Prometheus base URL, without credentials.
+    backend: String,
+    /// Energy unit of the figures, from `unit=`.
+    unit: &'static str,
+    /// How `USAGE_CACHE_TTL` answered: `hit`, `stale` or `miss`, which it
+    /// always is without one.
+    cache: &'static str,
+    /// Set with `truncate=true` when more than `MAX_INSTANCES` matched.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    truncated: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_instances: Option<usize>,
+    /// Set when several series reported the same meter and only one was
+    /// used, see `PREFER_JOB`.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    duplicate_series: bool,
+    /// How many series were dropped for it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    discarded_series: Option<usize>,
+    /// The corrections in place of computed figures, with their reasons.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    corrections: Vec<AppliedCorrection>,
+    /// How many series were left out because their value was not a number,
+    /// such as `NaN` from a meter still booting.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    skipped_series: Option<usize>,
+    /// The meters of those series, which read as missing.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    skipped_meters: Vec<SkippedMeter>,
+    /// Set when Prometheus was down and the results are the last known,
+    /// with `fallback=last_known`.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    degraded: bool,
+    /// When those results were read from Prometheus.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    as_of: Option<DateTime<Utc>>,
+    generated_at: DateTime<Utc>,
+}
+
+impl QueryMeta {
+    pub fn new(state: &AppState, req: &UsageRequest, unit: Unit, truncated_from: Option<usize>) -> Self {
+        Self {
+            target: req.target.clone(),
+            datetime: req.local_dt.to_rfc3339(),
+            curr_time: req.curr_dt,
+            prev_time: req.prev_dt,
+            timezone: req.local_dt.timezone().name().to_string(),
+            utc_offset: req.local_dt.offset().fix().to_string(),
+            lookback: state.prometheus.lookback(),
+            backend: state.prometheus.display_url(),
+            unit: unit.name(),
+            cache: "miss",
+            truncated: truncated_from.is_some(),
+            total_instances: truncated_from,
+            duplicate_series: false,
+            discarded_series: None,
+            corrections: Vec::new(),
+            skipped_series: None,
+            skipped_meters: Vec::new(),
+            degraded: false,
+            as_of: None,
+            generated_at: Utc::now(),
+        }
+    }
+
+    /// Records how the usage cache answered, when it is enabled.
+    pub fn cached(mut self, status: Option<CacheStatus>) -> Self {
+        if let Some(status) = status {
+            self.cache = status.name();
+        }
+        self
+    }
+
+    /// Records the series `dedupe_series` dropped, when there were any.
+    pub fn deduped(mut self, discarded_series: usize) -> Self {
+        self.duplicate_series = discarded_series > 0;
+        self.discarded_series = Some(discarded_series).filter(|n| *n > 0);
+        self
+    }
+
+    /// Records the corrections applied to `entries`.
+    pub fn corrected<'a>(mut self, entries: impl IntoIterator<Item = &'a UsageEntry>) -> Self {
+        self.corrections = entries.into_iter().filter_map(|entry| entry.correction.clone()).collect();
+        self
+    }
+
+    /// Records the series left out for their value, when there were any.
+    pub fn skipped(mut self, skipped: &[SkippedSeries]) -> Self {
+        self.skipped_series = Some(skipped.len()).filter(|n| *n > 0);
+        self.skipped_meters = skipped
+            .iter()
+            .map(|series| SkippedMeter {
+                instance: series.instance.clone(),
+                address: series.address.clone(),
+            })
+            .collect();
+        self.skipped_meters.dedup();
+        self
+    }
+
+    /// Records that the results are the last known ones, read at `as_of`.
+    pub fn degraded(mut self, as_of: Option<DateTime<Utc>>) -> Self {
+        self.degraded = as_of.is_some();
+        self.as_of = as_of;
+        self
+    }
+
+    /// The same fields as `# key: value` lines, to precede a CSV header.
+    pub fn csv_comments(&self) -> String {
+        let total_instances = self.total_instances.map(|n| n.to_string());
+        let discarded_series = self.discarded_series.map(|n| n.to_string());
+        let corrections = Some(self.corrections.len()).filter(|n| *n > 0).map(|n| n.to_string());
+        let skipped_series = self.skipped_series.map(|n| n.to_string());
+        let utc = |t: DateTime<Utc>| t.to_rfc3339_opts(SecondsFormat::AutoSi, true);
+        let (curr_time, prev_time) = (utc(self.curr_time), utc(self.prev_time));
+        let generated_at = utc(self.generated_at);
+        let as_of = self.as_of.map(utc);
+        let fields = [
+            ("target", Some(self.target.as_str())),
+            ("datetime", Some(&self.datetime)),
+            ("curr_time", Some(&curr_time)),
+            ("prev_time", Some(&prev_time)),
+            ("timezone", Some(&self.timezone)),
+            ("utc_offset", Some(&self.utc_offset)),
+            ("lookback", Some(&self.lookback)),
+            ("backend", Some(&self.backend)),
+            ("unit", Some(self.unit)),
+            ("cache", Some(self.cache)),
+            ("truncated", self.truncated.then_some("true")),
+            ("total_instances", total_instances.as_deref()),
+            ("duplicate_series", self.duplicate_series.then_some("true")),
+            ("discarded_series", discarded_series.as_deref()),
+            ("corrections", corrections.as_deref()),
+            ("skipped_series", skipped_series.as_deref()),
+            ("degraded", self.degraded.then_some("true")),
+            ("as_of", as_of.as_deref()),
+            ("generated_at", Some(&generated_at)),
+        ];
+        fields
+            .into_iter()
+            .filter_map(|(key, value)| Some(format!("# {}: {}\n", key, value?.replace('\n', " "))))
+            .collect()
+    }
+}
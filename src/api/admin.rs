@@ -0,0 +1,487 @@
+use axum::{
+    extract::{Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{NaiveDate, Utc};
+use lettre::message::Mailbox;
+use serde::Serialize;
+use futures_util::future;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    future::Future,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    api::table::{Cell, Table},
+    api_keys::KeyUsage,
+    cache::CacheStats,
+    error::{error_response, json_error, ApiError},
+    mailer::{parse_mailbox, MailAttachment},
+    object_store::Upload,
+    period::{local_midnight, previous_day},
+    prometheus::Prometheus,
+    range::daily_usage,
+    reload,
+    remote_write::DailySample,
+    selector,
+    shadow::ShadowStatus,
+    state::AppState,
+    status::Outcome,
+    version::{GIT_COMMIT, VERSION},
+    warmup::WarmTarget,
+};
+
+/// Checks the `Authorization: Bearer` header against `ADMIN_TOKEN`. Without
+/// a configured token the admin routes do not exist.
+pub fn authorize(state: &AppState, headers: &HeaderMap) -> Result<(), StatusCode> {
+    let Some(token) = &state.config.admin_token else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    let given = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    if constant_time_eq(given.as_bytes(), token.as_bytes()) {
+        Ok(())
+    } else {
+        Err(StatusCode::UNAUTHORIZED)
+    }
+}
+
+/// Compares without stopping at the first difference, so the time taken
+/// says nothing about how much of the token was right.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[derive(Serialize)]
+struct SendTestResponse {
+    sent: bool,
+    recipients: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// `POST /admin/reports/send-test`: sends a short test email to `to`, or
+/// with `report=NAME` generates that report from real data now and sends it
+/// to its recipients (or to `to` when given).
+pub async fn send_test_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(params): Query<HashMap<String, String>>,
+) -> Response {
+    if let Err(code) = authorize(&state, &headers) {
+        return error_response(code);
+    }
+    let Some(mailer) = &state.config.mailer else {
+        return json_error(StatusCode::SERVICE_UNAVAILABLE, "SMTP is not configured");
+    };
+    let to = match params.get("to").map(|to| parse_mailbox("to", to)).transpose() {
+        Ok(to) => to,
+        Err(_) => return error_response(StatusCode::BAD_REQUEST),
+    };
+
+    let (recipients, result) = match params.get("report") {
+        Some(name) => {
+            let Some(report) = state.config.reports.iter().find(|r| &r.name == name) else {
+                return error_response(StatusCode::NOT_FOUND);
+            };
+            let today = Utc::now().with_timezone(&state.config.timezone).date_naive();
+            let generated = match report.generate(&state, today).await {
+                Ok(generated) => generated,
+                Err(code) => return error_response(code),
+            };
+            let recipients = to.map(|to| vec![to]).unwrap_or_else(|| report.recipients.clone());
+            if recipients.is_empty() {
+                return error_response(StatusCode::BAD_REQUEST);
+            }
+            let result = report.deliver_to(&state, &generated, &recipients).await;
+            (recipients, result)
+        }
+        None => {
+            let Some(to) = to else {
+                return error_response(StatusCode::BAD_REQUEST);
+            };
+            let text = "This is a test email from power-usage; SMTP delivery works.\n".to_string();
+            let result = mailer.send(std::slice::from_ref(&to), "Power usage SMTP test", text, None).await;
+            (vec![to], result)
+        }
+    };
+
+    let status = if result.is_ok() { StatusCode::OK } else { StatusCode::BAD_GATEWAY };
+    let response = SendTestResponse {
+        sent: result.is_ok(),
+        recipients: recipients.iter().map(ToString::to_string).collect(),
+        error: result.err(),
+    };
+    (status, Json(response)).into_response()
+}
+
+/// How long each sink may take in `/admin/sinks/test` before it counts as failed.
+const SINK_TEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// The instance of the synthetic meter `/admin/sinks/test` sends without a `target`.
+const TEST_INSTANCE: &str = "power-usage-sinks-test";
+
+#[derive(Serialize)]
+struct SinkTest {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    /// Where the test landed: the S3 key or the file written.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    written_to: Option<String>,
+    elapsed_ms: f64,
+}
+
+#[derive(Serialize)]
+struct SinksTestResponse {
+    date: NaiveDate,
+    /// The `target` the figures were read for; none for the synthetic meter.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target: Option<String>,
+    meters: usize,
+    sinks: BTreeMap<&'static str, SinkTest>,
+}
+
+/// Runs one sink's delivery within `SINK_TEST_TIMEOUT`.
+async fn timed(delivery: impl Future<Output = Result<Option<String>, String>>) -> SinkTest {
+    let started = Instant::now();
+    let result = tokio::time::timeout(SINK_TEST_TIMEOUT, delivery)
+        .await
+        .unwrap_or_else(|_| Err(format!("timed out after {}s", SINK_TEST_TIMEOUT.as_secs())));
+    let elapsed_ms = (started.elapsed().as_secs_f64() * 1000.0 * 10.0).round() / 10.0;
+    match result {
+        Ok(written_to) => SinkTest { ok: true, error: None, written_to, elapsed_ms },
+        Err(e) => SinkTest { ok: false, error: Some(e), written_to: None, elapsed_ms },
+    }
+}
+
+/// `POST /admin/sinks/test`: sends one day's figures through every configured
+/// sink at once, without counting them or recording them as a scheduled run.
+/// The figures are real for `target` on `date` (yesterday by default), or a
+/// single synthetic meter without a `target`. Email goes to `to`, or else to
+/// every `REPORTS_FILE` recipient.
+pub async fn sinks_test_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(params): Query<HashMap<String, String>>,
+) -> Response {
+    if let Err(code) = authorize(&state, &headers) {
+        return error_response(code);
+    }
+    let config = &state.config;
+    if config.mailer.is_none()
+        && config.object_store.is_none()
+        && config.remote_write.is_none()
+        && config.textfile.is_none()
+    {
+        return json_error(StatusCode::SERVICE_UNAVAILABLE, "No sinks are configured");
+    }
+    let today = Utc::now().with_timezone(&config.timezone).date_naive();
+    let date = match params.get("date") {
+        Some(date) => match date.parse::<NaiveDate>() {
+            Ok(date) => Some(date),
+            Err(_) => return error_response(StatusCode::BAD_REQUEST),
+        },
+        None => previous_day(today),
+    };
+    let Some((date, day)) = date.and_then(|date| Some((date, local_midnight(date, config.timezone)?))) else {
+        return error_response(StatusCode::BAD_REQUEST);
+    };
+    let to = match params.get("to").map(|to| parse_mailbox("to", to)).transpose() {
+        Ok(to) => to,
+        Err(_) => return error_response(StatusCode::BAD_REQUEST),
+    };
+
+    let target = params.get("target").cloned();
+    let meters: Vec<(String, String, f64)> = match &target {
+        Some(target) => match daily_usage(&state, &selector::energy(target, &[]), date, 1).await {
+            Ok(series) => series
+                .into_iter()
+                .filter_map(|meter| Some((meter.instance, meter.address, meter.days.first()?.1?)))
+                .collect(),
+            Err(code) => return error_response(code),
+        },
+        None => vec![(TEST_INSTANCE.to_string(), "1".to_string(), 1.0)],
+    };
+    let mut table = Table::new(vec!["Target", "Address", "Date", "Daily_KWh"]);
+    for (instance, address, kwh) in &meters {
+        table.push(vec![
+            Cell::Text(instance.clone()),
+            Cell::Text(address.clone()),
+            Cell::Text(date.to_string()),
+            Cell::Num(*kwh),
+        ]);
+    }
+    let csv = table.to_csv();
+    let name = target.as_deref().unwrap_or(TEST_INSTANCE);
+
+    let email = async {
+        let mailer = config.mailer.as_ref()?;
+        let recipients: Vec<Mailbox> = match &to {
+            Some(to) => vec![to.clone()],
+            None => {
+                let mut seen = HashSet::new();
+                let all = config.reports.iter().flat_map(|r| r.recipients.iter());
+                all.filter(|mailbox| seen.insert(mailbox.email.to_string())).cloned().collect()
+            }
+        };
+        Some(timed(async {
+            if recipients.is_empty() {
+                return Err("no recipients: pass `to=` or list some in `REPORTS_FILE`".to_string());
+            }
+            let attachment = MailAttachment {
+                filename: format!("sinks-test-{}.csv", date),
+                content_type: "text/csv; charset=utf-8",
+                body: csv.clone().into_bytes(),
+            };
+            let text = format!(
+                "This is a sink test from power-usage for {} on {}; {} meters are attached.\n",
+                name,
+                date,
+                meters.len()
+            );
+            let subject = format!("Power usage sink test ({})", date);
+            mailer.try_send(&recipients, &subject, text, Some(attachment)).await?;
+            Ok(Some(recipients.iter().map(ToString::to_string).collect::<Vec<_>>().join(", ")))
+        })
+        .await)
+    };
+    let s3 = async {
+        let store = config.object_store.as_ref()?;
+        // Suffixed so it never replaces a real report's object.
+        let key = format!("{}.sinks-test", store.key(name, "sinks-test", date));
+        Some(timed(async {
+            store.try_upload(&key, csv.clone().into_bytes(), "text/csv; charset=utf-8").await?;
+            Ok(Some(key.clone()))
+        })
+        .await)
+    };
+    let remote_write = async {
+        let remote_write = config.remote_write.as_ref()?;
+        let samples: Vec<DailySample> = meters
+            .iter()
+            .map(|(instance, address, daily_kwh)| DailySample {
+                instance: instance.clone(),
+                address: address.clone(),
+                daily_kwh: *daily_kwh,
+                timestamp: day,
+            })
+            .collect();
+        Some(timed(async { remote_write.try_push(&samples).await.map(|()| None) }).await)
+    };
+    let textfile = async {
+        let textfile = config.textfile.as_ref()?;
+        Some(timed(async { textfile.try_write(&meters, day).await.map(Some) }).await)
+    };
+    let (email, s3, remote_write, textfile) = tokio::join!(email, s3, remote_write, textfile);
+
+    let sinks: BTreeMap<&'static str, SinkTest> = [
+        email.map(|test| ("email", test)),
+        s3.map(|test| ("s3", test)),
+        remote_write.map(|test| ("remote_write", test)),
+        textfile.map(|test| ("textfile", test)),
+    ]
+    .into_iter()
+    .flatten()
+    .collect();
+    let status = if sinks.values().all(|test| test.ok) { StatusCode::OK } else { StatusCode::BAD_GATEWAY };
+    let response = SinksTestResponse {
+        date,
+        target,
+        meters: meters.len(),
+        sinks,
+    };
+    (status, Json(response)).into_response()
+}
+
+#[derive(Serialize)]
+struct StatusResponse {
+    version: &'static str,
+    commit: &'static str,
+    backends: Vec<BackendStatus>,
+    /// With `SHADOW_PROMETHEUS_HOST`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    shadow: Option<ShadowStatus>,
+    cache: CacheSummary,
+    /// Latest scheduled run per `REPORTS_FILE` report; `null` before the first.
+    reports: BTreeMap<String, Option<Outcome>>,
+    /// Latest result per configured sink: `email`, `s3`, `remote_write` and `textfile`.
+    sinks: BTreeMap<&'static str, Option<Outcome>>,
+    /// Latest successful S3 upload per target.
+    uploads: BTreeMap<String, Upload>,
+}
+
+/// One Prometheus backend as probed for this response.
+#[derive(Serialize)]
+struct BackendStatus {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    site: Option<String>,
+    url: String,
+    ok: bool,
+    latency_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct CacheSummary {
+    /// `/api/v1/targets` results cached.
+    targets: usize,
+    /// With `USAGE_CACHE_TTL`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    usage: Option<CacheStats>,
+}
+
+async fn probe(site: Option<String>, prometheus: &Prometheus) -> BackendStatus {
+    let started = Instant::now();
+    let result = prometheus.probe().await;
+    BackendStatus {
+        site,
+        url: prometheus.display_url(),
+        ok: result.is_ok(),
+        latency_ms: (started.elapsed().as_secs_f64() * 1e4).round() / 10.0,
+        error: result.err().map(|code| code.to_string()),
+    }
+}
+
+/// `GET /admin/status`: the service's view of its own background work, with
+/// every backend probed now.
+pub async fn status_handler(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if let Err(code) = authorize(&state, &headers) {
+        return error_response(code);
+    }
+    let config = &state.config;
+    let probes = std::iter::once(probe(None, &state.prometheus))
+        .chain(state.sites.iter().map(|(name, prometheus)| probe(Some(name.clone()), prometheus)));
+    let sinks = [
+        config.mailer.as_ref().map(|mailer| ("email", mailer.last_send.get())),
+        config.object_store.as_ref().map(|store| ("s3", store.last_upload.get())),
+        config.remote_write.as_ref().map(|remote_write| ("remote_write", remote_write.last_push.get())),
+        config.textfile.as_ref().map(|textfile| ("textfile", textfile.last_write.get())),
+    ];
+    let status = StatusResponse {
+        version: VERSION,
+        commit: GIT_COMMIT,
+        backends: future::join_all(probes).await,
+        shadow: state.shadow.as_ref().map(|shadow| shadow.status()),
+        cache: CacheSummary {
+            targets: state.targets_cache.len(),
+            usage: state.usage_cache.as_ref().map(|cache| cache.stats()),
+        },
+        reports: config.reports.iter().map(|report| (report.name.clone(), report.last_run.get())).collect(),
+        sinks: sinks.into_iter().flatten().collect(),
+        uploads: config.object_store.as_ref().map(|store| store.last_uploads()).unwrap_or_default(),
+    };
+    Json(status).into_response()
+}
+
+#[derive(Serialize)]
+struct ReloadResponse {
+    /// `KEY: old -> new` for each reloaded setting that changed.
+    changed: Vec<String>,
+    /// Changes to settings that only apply after a restart.
+    restart_required: Vec<String>,
+}
+
+/// `POST /admin/reload`: the same reload as SIGHUP. Invalid settings are
+/// refused with 422 and every error, leaving the running configuration as
+/// it was.
+pub async fn reload_handler(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if let Err(code) = authorize(&state, &headers) {
+        return error_response(code);
+    }
+    match reload::reload(&state.config) {
+        Ok(reloaded) => Json(ReloadResponse {
+            changed: reloaded.changed,
+            restart_required: reloaded.restart_required,
+        })
+        .into_response(),
+        Err(errors) => ApiError::new(StatusCode::UNPROCESSABLE_ENTITY, "configuration is invalid")
+            .with("errors", errors)
+            .into_response(),
+    }
+}
+
+#[derive(Serialize)]
+struct CacheResponse {
+    #[serde(flatten)]
+    usage: CacheStats,
+    /// `WARM_TARGETS` by expression.
+    warm_targets: BTreeMap<String, WarmTarget>,
+}
+
+/// `GET /admin/cache`: the usage cache's size and lookups, and when each warm target was last
+/// refreshed. 404 without `USAGE_CACHE_TTL`.
+pub async fn cache_handler(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if let Err(code) = authorize(&state, &headers) {
+        return error_response(code);
+    }
+    let Some(cache) = &state.usage_cache else {
+        return error_response(StatusCode::NOT_FOUND);
+    };
+    Json(CacheResponse {
+        usage: cache.stats(),
+        warm_targets: state.warmup.targets(&state.config.warm_targets),
+    })
+    .into_response()
+}
+
+#[derive(Serialize)]
+struct KeysUsageResponse {
+    timezone: String,
+    keys: BTreeMap<String, KeyUsage>,
+}
+
+/// `GET /admin/keys/usage`: requests per `KEYS_FILE` key today and this
+/// month against its quotas. 404 without `KEYS_FILE`.
+pub async fn keys_usage_handler(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if let Err(code) = authorize(&state, &headers) {
+        return error_response(code);
+    }
+    let Some(keys) = &state.config.api_keys else {
+        return error_response(StatusCode::NOT_FOUND);
+    };
+    let tz = state.config.timezone;
+    Json(KeysUsageResponse {
+        timezone: tz.name().to_string(),
+        keys: keys.usage(tz),
+    })
+    .into_response()
+}
+
+#[derive(Serialize)]
+struct RevealResponse {
+    pseudonym: String,
+    name: String,
+}
+
+/// `GET /admin/anonymize/reveal?pseudonym=anon-...`: the instance or alias
+/// behind a pseudonym from `anonymize=true`, looked up among the names
+/// handed out since startup, the known instances and `ALIASES_FILE`.
+pub async fn reveal_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(params): Query<HashMap<String, String>>,
+) -> Response {
+    if let Err(code) = authorize(&state, &headers) {
+        return error_response(code);
+    }
+    let Some(anonymizer) = &state.config.anonymizer else {
+        return json_error(StatusCode::NOT_FOUND, "ANONYMIZE_KEY is not configured");
+    };
+    let Some(pseudonym) = params.get("pseudonym") else {
+        return error_response(StatusCode::BAD_REQUEST);
+    };
+    let instances = state.known_instances.all();
+    let aliases = state.config.aliases.current();
+    let candidates = instances.iter().map(String::as_str).chain(aliases.identifiers());
+    match anonymizer.reveal(pseudonym, candidates) {
+        Some(name) => Json(RevealResponse { pseudonym: pseudonym.clone(), name }).into_response(),
+        None => json_error(StatusCode::NOT_FOUND, "unknown pseudonym"),
+    }
+}
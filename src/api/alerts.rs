@@ -0,0 +1,89 @@
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use std::collections::HashMap;
+
+use crate::{
+    audit,
+    error::{error_response, Error},
+    state::AppState,
+    usage::{compute_usage, UsageRequest},
+};
+
+#[derive(Serialize)]
+struct Alert {
+    instance: String,
+    address: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    daily_kwh: f64,
+    threshold_kwh: f64,
+    excess_kwh: f64,
+}
+
+#[derive(Serialize)]
+struct AlertsResponse {
+    target: String,
+    datetime: String,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    truncated: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_instances: Option<usize>,
+    results: Vec<Alert>,
+}
+
+pub async fn alerts_handler(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    match handle_alerts(&state, params).await {
+        Ok(response) => response.into_response(),
+        Err(code) => error_response(code),
+    }
+}
+
+/// Only the meters whose daily consumption exceeded their threshold, the
+/// largest excess first. Needs `threshold_kwh` unless `THRESHOLDS_FILE`
+/// is configured.
+async fn handle_alerts(
+    state: &AppState,
+    params: HashMap<String, String>,
+) -> Result<Response, Error> {
+    let req = UsageRequest::from_params(&params, state)?;
+    if req.threshold_kwh.is_none() && !state.config.tunables().thresholds.is_configured() {
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+    audit::note_range(req.prev_dt, req.curr_dt);
+    let usage = compute_usage(state, &req).await?;
+
+    let mut results: Vec<Alert> = usage
+        .entries
+        .into_iter()
+        .filter_map(|entry| {
+            let (daily_kwh, threshold_kwh) = (entry.daily_kwh?, entry.threshold_kwh?);
+            (daily_kwh > threshold_kwh).then_some(Alert {
+                instance: entry.instance,
+                address: entry.address,
+                name: entry.name,
+                daily_kwh,
+                threshold_kwh,
+                excess_kwh: daily_kwh - threshold_kwh,
+            })
+        })
+        .collect();
+    results.sort_by(|a, b| b.excess_kwh.total_cmp(&a.excess_kwh));
+    audit::note_rows(results.len());
+
+    let response = AlertsResponse {
+        target: req.target,
+        datetime: req.local_dt.to_rfc3339(),
+        truncated: usage.truncated_from.is_some(),
+        total_instances: usage.truncated_from,
+        results,
+    };
+    Ok((StatusCode::OK, Json(response)).into_response())
+}
@@ -0,0 +1,172 @@
+use axum::{
+    body::Bytes,
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::{
+    api::range::{anomalies, MAX_DAYS},
+    api_keys::KEY_TARGETS,
+    error::{error_response, Error},
+    period::{days_inclusive, local_midnight},
+    range::{daily_usage, DailySeries},
+    state::AppState,
+    usage::{resolve_selector, resolve_target},
+};
+
+/// The parts of a SimpleJSON / JSON API annotation request that are used:
+///
+/// ```json
+/// {"range": {"from": "2025-08-01T00:00:00.000Z", "to": "2025-08-08T00:00:00.000Z"},
+///  "annotation": {"name": "Meter events", "query": "meter-7.*", "enable": true}}
+/// ```
+#[derive(Deserialize)]
+struct AnnotationRequest {
+    range: TimeRange,
+    annotation: AnnotationQuery,
+}
+
+#[derive(Deserialize)]
+struct TimeRange {
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+struct AnnotationQuery {
+    /// Target regex, as `target=` elsewhere.
+    query: String,
+    /// Everything else Grafana sent, echoed back as SimpleJSON expects.
+    #[serde(flatten)]
+    rest: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Annotation {
+    annotation: serde_json::Value,
+    /// Start and end of the local day, in milliseconds since the epoch.
+    time: i64,
+    time_end: i64,
+    title: String,
+    text: String,
+    tags: Vec<String>,
+}
+
+/// One detected event: a day on which a meter's counter went backwards,
+/// or whose consumption was anomalous.
+struct Event {
+    date: NaiveDate,
+    kind: &'static str,
+    title: String,
+    text: String,
+}
+
+/// `POST /annotations`: counter resets and anomalous days of the queried
+/// target over the dashboard's time range, as Grafana annotations. Of the
+/// query parameters only the API key's `KEY_TARGETS` are used.
+pub async fn annotations_handler(
+    State(state): State<AppState>,
+    Query(mut params): Query<HashMap<String, String>>,
+    body: Bytes,
+) -> Response {
+    let Ok(request) = serde_json::from_slice::<AnnotationRequest>(&body) else {
+        return error_response(StatusCode::BAD_REQUEST);
+    };
+    let key_targets = params.remove_entry(KEY_TARGETS);
+    match handle_annotations(&state, request, key_targets).await {
+        Ok(annotations) => Json(annotations).into_response(),
+        Err(code) => error_response(code),
+    }
+}
+
+async fn handle_annotations(
+    state: &AppState,
+    request: AnnotationRequest,
+    key_targets: Option<(String, String)>,
+) -> Result<Vec<Annotation>, Error> {
+    let tz = state.config.timezone;
+    let (from, to) = (request.range.from, request.range.to);
+    let first = from.with_timezone(&tz).date_naive();
+    let days = days_inclusive(first, to.with_timezone(&tz).date_naive())
+        .filter(|days| (1..=MAX_DAYS).contains(days))
+        .ok_or(StatusCode::BAD_REQUEST)?;
+
+    let mut params = HashMap::from([("target".to_string(), request.annotation.query.clone())]);
+    params.extend(key_targets);
+    let (target, _) = resolve_target(&params, state)?;
+    let selector = resolve_selector(&params, &target)?;
+    let series = daily_usage(state, &selector, first, days).await?;
+
+    let echoed = {
+        let mut annotation = request.annotation.rest;
+        annotation.insert("query".to_string(), request.annotation.query.into());
+        serde_json::Value::Object(annotation)
+    };
+    let mut annotations = Vec::new();
+    for meter in &series {
+        for event in events(meter, state.config.tunables().anomaly_mads) {
+            let (Some(start), Some(end)) = (
+                local_midnight(event.date, tz),
+                event.date.succ_opt().and_then(|next| local_midnight(next, tz)),
+            ) else {
+                continue;
+            };
+            // Days cut by the range still show, but not ones wholly outside it.
+            if end <= from || start >= to {
+                continue;
+            }
+            annotations.push(Annotation {
+                annotation: echoed.clone(),
+                time: start.timestamp_millis(),
+                time_end: end.timestamp_millis(),
+                title: event.title,
+                text: event.text,
+                tags: vec![event.kind.to_string(), meter.instance.clone()],
+            });
+        }
+    }
+    annotations.sort_by_key(|a| a.time);
+    Ok(annotations)
+}
+
+/// Resets are days whose counter went backwards. Like every implausible
+/// day they have no value in `days`, so they are found on the counters and
+/// never reach the anomaly detection, which they would otherwise dominate.
+fn events(meter: &DailySeries, threshold: f64) -> Vec<Event> {
+    let label = format!("{}/{}", meter.instance, meter.address);
+    let named = match &meter.name {
+        Some(name) => format!(" ({})", name),
+        None => String::new(),
+    };
+
+    let values: Vec<Option<f64>> = meter.days.iter().map(|(_, kwh)| *kwh).collect();
+    let anomalies = anomalies(&values, threshold);
+    let counter = |i: usize| meter.counters.get(i).copied().flatten();
+
+    let mut events = Vec::new();
+    for (i, (date, kwh)) in meter.days.iter().enumerate() {
+        let reset = counter(i).zip(counter(i + 1)).filter(|(start, end)| end < start);
+        if let Some((start, end)) = reset {
+            events.push(Event {
+                date: *date,
+                kind: "counter_reset",
+                title: format!("Counter reset on {}", label),
+                text: format!("The counter{} went back by {:.2} kWh on {}", named, start - end, date),
+            });
+        } else if let Some((kwh, anomaly)) = kwh.zip(anomalies[i]) {
+            events.push(Event {
+                date: *date,
+                kind: "anomaly",
+                title: format!("Unusually {} consumption on {}", anomaly, label),
+                text: format!("{:.2} kWh{} on {}", kwh, named, date),
+            });
+        }
+    }
+    events
+}
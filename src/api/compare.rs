@@ -0,0 +1,164 @@
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::NaiveDate;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{
+    api::{csv_field, v1::wants_csv},
+    audit,
+    error::{error_response, Error},
+    natural::Natural,
+    period::days_inclusive,
+    range::{daily_usage, DailySeries},
+    state::AppState,
+    usage::{resolve_selector, resolve_target},
+};
+
+#[derive(Serialize)]
+struct DayUsage {
+    date: NaiveDate,
+    daily_kwh: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct MeterComparison {
+    instance: String,
+    address: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    date_a: DayUsage,
+    date_b: DayUsage,
+    /// `date_b` minus `date_a`.
+    diff_kwh: Option<f64>,
+    /// `diff_kwh` relative to `date_a`.
+    diff_percent: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct CompareResponse {
+    target: String,
+    date_a: NaiveDate,
+    date_b: NaiveDate,
+    timezone: String,
+    results: Vec<MeterComparison>,
+}
+
+/// One meter's usage on date A and date B.
+#[derive(Default)]
+struct Pair {
+    name: Option<String>,
+    kwh: [Option<f64>; 2],
+}
+
+pub async fn compare_handler(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    match handle_compare(&state, params).await {
+        Ok(response) => response.into_response(),
+        Err(code) => error_response(code),
+    }
+}
+
+fn parse_date(value: Option<&String>) -> Result<NaiveDate, StatusCode> {
+    value
+        .and_then(|v| NaiveDate::parse_from_str(v, "%Y-%m-%d").ok())
+        .ok_or(StatusCode::BAD_REQUEST)
+}
+
+/// Local-day consumption on `date_a` and `date_b` side by side, from the
+/// four midnight readings fetched concurrently. Meters seen on only one of
+/// the dates are kept with `null` on the other side.
+async fn handle_compare(
+    state: &AppState,
+    params: HashMap<String, String>,
+) -> Result<Response, Error> {
+    let date_a = parse_date(params.get("date_a"))?;
+    let date_b = parse_date(params.get("date_b"))?;
+    let (target, address) = resolve_target(&params, state)?;
+    let selector = resolve_selector(&params, &target)?;
+
+    let (series_a, series_b) = tokio::try_join!(
+        daily_usage(state, &selector, date_a, 1),
+        daily_usage(state, &selector, date_b, 1),
+    )?;
+
+    let mut meters: BTreeMap<(String, Natural), Pair> = BTreeMap::new();
+    for (side, series) in [series_a, series_b].into_iter().enumerate() {
+        for DailySeries { instance, address, name, days, .. } in series {
+            let key = (instance, Natural(address));
+            let pair = meters.entry(key).or_default();
+            pair.name = pair.name.take().or(name);
+            pair.kwh[side] = days.first().and_then(|(_, kwh)| *kwh);
+        }
+    }
+
+    let results: Vec<MeterComparison> = meters
+        .into_iter()
+        .filter(|((_, Natural(addr)), _)| address.as_ref().is_none_or(|a| a == addr))
+        .map(|((instance, Natural(address)), Pair { name, kwh: [kwh_a, kwh_b] })| {
+            let diff_kwh = kwh_a.zip(kwh_b).map(|(a, b)| b - a);
+            MeterComparison {
+                instance,
+                address,
+                name,
+                date_a: DayUsage { date: date_a, daily_kwh: kwh_a },
+                date_b: DayUsage { date: date_b, daily_kwh: kwh_b },
+                diff_kwh,
+                diff_percent: diff_kwh
+                    .zip(kwh_a)
+                    .filter(|(_, a)| *a != 0.0)
+                    .map(|(diff, a)| (diff / a * 10000.0).round() / 100.0),
+            }
+        })
+        .collect();
+
+    let (first, last) = (date_a.min(date_b), date_a.max(date_b));
+    if let Some(days) = days_inclusive(first, last) {
+        audit::note_days(state.config.timezone, first, days);
+    }
+    audit::note_rows(results.len());
+
+    if wants_csv(&params) {
+        return Ok((StatusCode::OK, render_csv(state, &results)).into_response());
+    }
+
+    let response = CompareResponse {
+        target,
+        date_a,
+        date_b,
+        timezone: state.config.timezone.name().to_string(),
+        results,
+    };
+    Ok((StatusCode::OK, Json(response)).into_response())
+}
+
+fn render_csv(state: &AppState, results: &[MeterComparison]) -> String {
+    let with_names = state.config.aliases.is_configured();
+    let mut csv_data = String::from("Target,Address,KWh_A,KWh_B,Diff,Diff_Percent");
+    csv_data.push_str(if with_names { ",Name\n" } else { "\n" });
+
+    let cell = |value: Option<f64>| value.map(|v| v.to_string()).unwrap_or_default();
+    for meter in results {
+        csv_data.push_str(&format!(
+            "{},{},{},{},{},{}",
+            meter.instance,
+            meter.address,
+            cell(meter.date_a.daily_kwh),
+            cell(meter.date_b.daily_kwh),
+            cell(meter.diff_kwh),
+            cell(meter.diff_percent)
+        ));
+        if with_names {
+            csv_data.push(',');
+            csv_data.push_str(&csv_field(meter.name.as_deref().unwrap_or_default()));
+        }
+        csv_data.push('\n');
+    }
+    csv_data
+}
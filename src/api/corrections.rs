@@ -0,0 +1,78 @@
+use axum::{
+    body::Bytes,
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use std::collections::HashMap;
+
+use crate::{
+    api::admin::authorize,
+    corrections::NewCorrection,
+    error::{error_response, json_error},
+    state::AppState,
+};
+
+/// `GET /api/v1/corrections?instance=...`: the corrections recorded, of
+/// one instance when given, by date.
+pub async fn list_handler(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Response {
+    Json(state.config.corrections.list(params.get("instance").map(String::as_str))).into_response()
+}
+
+/// `POST /api/v1/corrections`, with the admin token: records one meter's
+/// corrected consumption on one day, which every report then shows
+/// instead of the computed one.
+pub async fn create_handler(State(state): State<AppState>, headers: HeaderMap, body: Bytes) -> Response {
+    if let Err(code) = authorize(&state, &headers) {
+        return error_response(code);
+    }
+    let new = match serde_json::from_slice::<NewCorrection>(&body) {
+        Ok(new) => new,
+        Err(e) => return json_error(StatusCode::BAD_REQUEST, format!("invalid correction: {}", e)),
+    };
+    if let Err(message) = new.validate() {
+        return json_error(StatusCode::BAD_REQUEST, message);
+    }
+    match state.config.corrections.add(new).await {
+        Ok(correction) => {
+            tracing::info!(
+                instance = %correction.instance,
+                address = %correction.address,
+                date = %correction.date,
+                author = %correction.author,
+                "Correction recorded"
+            );
+            (StatusCode::CREATED, Json(correction)).into_response()
+        }
+        Err(message) => {
+            tracing::error!("{}", message);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to save the correction")
+        }
+    }
+}
+
+/// `DELETE /api/v1/corrections/{id}`, with the admin token.
+pub async fn delete_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Response {
+    if let Err(code) = authorize(&state, &headers) {
+        return error_response(code);
+    }
+    match state.config.corrections.remove(&id).await {
+        Ok(Some(correction)) => {
+            tracing::info!(id = %correction.id, "Correction removed");
+            Json(correction).into_response()
+        }
+        Ok(None) => json_error(StatusCode::NOT_FOUND, "unknown correction"),
+        Err(message) => {
+            tracing::error!("{}", message);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "Failed to save the corrections")
+        }
+    }
+}
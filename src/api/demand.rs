@@ -0,0 +1,249 @@
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use serde::Serialize;
+use std::{collections::HashMap, time::Duration};
+
+use crate::{
+    api::{csv_field, v1::wants_csv},
+    audit,
+    config::parse_duration,
+    error::{error_response, Error},
+    natural::Natural,
+    period::{local_midnight, parse_month},
+    state::AppState,
+    usage::{resolve_selector, resolve_target},
+};
+
+/// Intervals listed per meter, the highest first.
+const TOP_INTERVALS: usize = 5;
+/// Points Prometheus returns per series from one range query at most.
+const MAX_POINTS: u64 = 11_000;
+
+#[derive(Serialize)]
+struct DemandInterval {
+    /// Local start and end of the interval.
+    start: String,
+    end: String,
+    /// Average power over the interval.
+    avg_kw: f64,
+}
+
+#[derive(Serialize)]
+struct MeterDemand {
+    instance: String,
+    address: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    max_demand_kw: Option<f64>,
+    /// Local start of the interval with the highest demand.
+    max_demand_at: Option<String>,
+    /// Intervals with a reading at both ends.
+    intervals: usize,
+    /// Intervals left out because the counter went backwards.
+    resets: usize,
+    top: Vec<DemandInterval>,
+}
+
+#[derive(Serialize)]
+struct DemandResponse {
+    target: String,
+    month: String,
+    interval: String,
+    timezone: String,
+    results: Vec<MeterDemand>,
+}
+
+pub async fn max_demand_handler(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    match handle_max_demand(&state, params).await {
+        Ok(response) => response.into_response(),
+        Err(code) => error_response(code),
+    }
+}
+
+/// Average power per interval from consecutive readings exactly one `step`
+/// apart, with whether the counter went backwards. Pairs further apart
+/// span a gap in the data and are skipped.
+fn interval_demand(points: &[(DateTime<Utc>, f64)], step: Duration) -> Vec<(DateTime<Utc>, Option<f64>)> {
+    let hours = step.as_secs_f64() / 3600.0;
+    points
+        .windows(2)
+        .filter(|pair| (pair[1].0 - pair[0].0).to_std().ok() == Some(step))
+        .map(|pair| {
+            let delta = pair[1].1 - pair[0].1;
+            (pair[0].0, (delta >= 0.0).then(|| delta / hours))
+        })
+        .collect()
+}
+
+fn meter_demand(
+    (instance, address): (String, String),
+    points: &[(DateTime<Utc>, f64)],
+    step: Duration,
+    tz: Tz,
+    name: Option<String>,
+) -> MeterDemand {
+    let demand = interval_demand(points, step);
+    let resets = demand.iter().filter(|(_, kw)| kw.is_none()).count();
+    let mut valid: Vec<(DateTime<Utc>, f64)> = demand.iter().filter_map(|(t, kw)| Some((*t, (*kw)?))).collect();
+    valid.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+
+    let local = |t: DateTime<Utc>| t.with_timezone(&tz).to_rfc3339();
+    let top: Vec<DemandInterval> = valid
+        .iter()
+        .take(TOP_INTERVALS)
+        .map(|(start, kw)| DemandInterval {
+            start: local(*start),
+            end: local(*start + step),
+            avg_kw: *kw,
+        })
+        .collect();
+    MeterDemand {
+        instance,
+        address,
+        name,
+        max_demand_kw: top.first().map(|i| i.avg_kw),
+        max_demand_at: top.first().map(|i| i.start.clone()),
+        intervals: demand.len(),
+        resets,
+        top,
+    }
+}
+
+/// Maximum demand per meter over one local month: the counters are read
+/// every `interval` by one range query, and each interval's consumption is
+/// turned into its average power. Intervals across a counter reset are
+/// excluded rather than counted as a spike.
+async fn handle_max_demand(
+    state: &AppState,
+    params: HashMap<String, String>,
+) -> Result<Response, Error> {
+    let (target, address) = resolve_target(&params, state)?;
+    let selector = resolve_selector(&params, &target)?;
+    let month = params.get("month").ok_or(StatusCode::BAD_REQUEST)?;
+    let (first, days) = parse_month(month).ok_or(StatusCode::BAD_REQUEST)?;
+    let interval = params.get("interval").map_or("15m", String::as_str);
+    let step = parse_duration(interval)
+        .filter(|step| step.as_secs() >= 60 && step.subsec_nanos() == 0)
+        .ok_or(StatusCode::BAD_REQUEST)?;
+
+    let tz = state.config.timezone;
+    let start = local_midnight(first, tz).ok_or(StatusCode::BAD_REQUEST)?;
+    let end = first
+        .checked_add_days(chrono::Days::new(days.into()))
+        .and_then(|next| local_midnight(next, tz))
+        .ok_or(StatusCode::BAD_REQUEST)?;
+    if (end - start).num_seconds() as u64 / step.as_secs() >= MAX_POINTS {
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+
+    audit::note_range(start, end);
+    let series = state.prometheus.get_data_range(&selector, start, end, step).await?;
+    let aliases = state.config.aliases.current();
+    let mut results: Vec<MeterDemand> = series
+        .into_iter()
+        .filter(|((_, addr), _)| address.as_ref().is_none_or(|a| a == addr))
+        .map(|(key, points)| {
+            let name = aliases.name(&key.0, &key.1).map(str::to_string);
+            meter_demand(key, &points, step, tz, name)
+        })
+        .collect();
+    results.sort_by_key(|m| (m.instance.clone(), Natural(m.address.clone())));
+    audit::note_rows(results.len());
+
+    if wants_csv(&params) {
+        return Ok((StatusCode::OK, render_csv(state, &results)).into_response());
+    }
+
+    let response = DemandResponse {
+        target,
+        month: month.clone(),
+        interval: interval.to_string(),
+        timezone: tz.name().to_string(),
+        results,
+    };
+    Ok((StatusCode::OK, Json(response)).into_response())
+}
+
+/// One row per listed interval, ranked from 1; a meter without any usable
+/// interval still gets a row, with the other columns empty.
+fn render_csv(state: &AppState, results: &[MeterDemand]) -> String {
+    let with_names = state.config.aliases.is_configured();
+    let mut csv_data = String::from("Target,Address,Rank,Start,End,Avg_kW,Resets");
+    csv_data.push_str(if with_names { ",Name\n" } else { "\n" });
+
+    for meter in results {
+        let rows: Vec<String> = if meter.top.is_empty() {
+            vec![",,,".to_string()]
+        } else {
+            meter
+                .top
+                .iter()
+                .enumerate()
+                .map(|(i, interval)| format!("{},{},{},{}", i + 1, interval.start, interval.end, interval.avg_kw))
+                .collect()
+        };
+        for row in rows {
+            csv_data.push_str(&format!("{},{},{},{}", meter.instance, meter.address, row, meter.resets));
+            if with_names {
+                csv_data.push(',');
+                csv_data.push_str(&csv_field(meter.name.as_deref().unwrap_or_default()));
+            }
+            csv_data.push('\n');
+        }
+    }
+    csv_data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STEP: Duration = Duration::from_secs(15 * 60);
+
+    fn readings(kwh: &[(&str, f64)]) -> Vec<(DateTime<Utc>, f64)> {
+        kwh.iter().map(|(at, kwh)| (at.parse().unwrap(), *kwh)).collect()
+    }
+
+    #[test]
+    fn counter_reset_is_left_out() {
+        let points = readings(&[
+            ("2025-08-01T00:00:00Z", 100.0),
+            ("2025-08-01T00:15:00Z", 101.0),
+            ("2025-08-01T00:30:00Z", 103.0),
+            ("2025-08-01T00:45:00Z", 0.5),
+            ("2025-08-01T01:00:00Z", 1.5),
+        ]);
+        let kw: Vec<Option<f64>> = interval_demand(&points, STEP).into_iter().map(|(_, kw)| kw).collect();
+        assert_eq!(kw, [Some(4.0), Some(8.0), None, Some(4.0)]);
+
+        let key = ("meter-a:9100".to_string(), "1".to_string());
+        let demand = meter_demand(key, &points, STEP, chrono_tz::Asia::Jakarta, None);
+        assert_eq!(demand.intervals, 4);
+        assert_eq!(demand.resets, 1);
+        assert_eq!(demand.max_demand_kw, Some(8.0));
+        assert_eq!(demand.max_demand_at.as_deref(), Some("2025-08-01T07:15:00+07:00"));
+        let top: Vec<f64> = demand.top.iter().map(|interval| interval.avg_kw).collect();
+        assert_eq!(top, [8.0, 4.0, 4.0]);
+    }
+
+    #[test]
+    fn readings_further_apart_than_a_step_are_skipped() {
+        let points = readings(&[
+            ("2025-08-01T00:00:00Z", 100.0),
+            ("2025-08-01T00:45:00Z", 130.0),
+            ("2025-08-01T01:00:00Z", 131.0),
+        ]);
+        let demand = interval_demand(&points, STEP);
+        assert_eq!(demand.len(), 1);
+        assert_eq!(demand[0].1, Some(4.0));
+    }
+}
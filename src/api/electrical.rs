@@ -0,0 +1,169 @@
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use futures_util::future::try_join_all;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{
+    api::{csv_field, v1::wants_csv},
+    api_keys::restrict,
+    config::split_list,
+    error::{error_response, Error},
+    natural::Natural,
+    period::{resolve_local, Dst},
+    selector,
+    state::AppState,
+    usage::resolve_target,
+};
+
+#[derive(Serialize)]
+struct MeterSnapshot {
+    instance: String,
+    address: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    /// One field per requested metric, `null` when the meter lacks it.
+    #[serde(flatten)]
+    values: BTreeMap<String, Option<f64>>,
+    sample_times: BTreeMap<String, DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+struct ElectricalResponse {
+    target: String,
+    datetime: DateTime<Utc>,
+    metrics: Vec<String>,
+    results: Vec<MeterSnapshot>,
+}
+
+pub async fn electrical_handler(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    match handle_electrical(&state, params).await {
+        Ok(response) => response.into_response(),
+        Err(code) => error_response(code),
+    }
+}
+
+/// Accepts an RFC 3339 timestamp or a local `YYYY-MM-DDTHH:MM`; now if absent.
+fn parse_datetime(state: &AppState, value: Option<&String>) -> Result<DateTime<Utc>, StatusCode> {
+    let Some(value) = value else {
+        return Ok(Utc::now());
+    };
+    if let Ok(dt) = DateTime::parse_from_rfc3339(value) {
+        return Ok(dt.with_timezone(&Utc));
+    }
+    NaiveDateTime::parse_from_str(value, "%Y-%m-%dT%H:%M")
+        .ok()
+        .and_then(|dt| resolve_local(dt, state.config.timezone, Dst::Early))
+        .map(|dt| dt.with_timezone(&Utc))
+        .ok_or(StatusCode::BAD_REQUEST)
+}
+
+/// Snapshot of several gauges (and the energy counter) per meter, one
+/// `last_over_time` query per metric issued concurrently and merged by
+/// instance/address. Metrics outside `ELECTRICAL_METRICS` are rejected.
+async fn handle_electrical(
+    state: &AppState,
+    params: HashMap<String, String>,
+) -> Result<Response, Error> {
+    let (target, address) = resolve_target(&params, state)?;
+    let datetime = parse_datetime(state, params.get("datetime"))?;
+    let allowed = &state.config.electrical_metrics;
+    let metrics = match params.get("metrics") {
+        Some(value) => split_list(value),
+        None => allowed.clone(),
+    };
+    if metrics.is_empty() || metrics.iter().any(|m| !allowed.contains(m)) {
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+    let extra = match params.get("selector") {
+        Some(value) => selector::parse(value).map_err(|_| StatusCode::BAD_REQUEST)?,
+        None => Vec::new(),
+    };
+
+    let prometheus = &state.prometheus;
+    let lookback = &prometheus.lookback();
+    let fetched = try_join_all(metrics.iter().map(|metric| {
+        let selector = restrict(selector::metric(metric, &target, &extra), &params);
+        async move {
+            let (data, times) = tokio::try_join!(
+                prometheus.get_data_within(&selector, datetime, lookback),
+                prometheus.get_sample_times(&selector, datetime, lookback),
+            )?;
+            Ok::<_, Error>((metric, data.data, times))
+        }
+    }))
+    .await?;
+
+    let aliases = state.config.aliases.current();
+    let mut meters: BTreeMap<(String, String), MeterSnapshot> = BTreeMap::new();
+    for (metric, data, times) in fetched {
+        for (instance, samples) in data {
+            for sample in samples {
+                if address.as_ref().is_some_and(|a| a != &sample.address) {
+                    continue;
+                }
+                let key = (instance.clone(), sample.address.clone());
+                let meter = meters.entry(key.clone()).or_insert_with(|| MeterSnapshot {
+                    name: aliases.name(&instance, &sample.address).map(str::to_string),
+                    instance: instance.clone(),
+                    address: sample.address.clone(),
+                    values: metrics.iter().map(|m| (m.clone(), None)).collect(),
+                    sample_times: BTreeMap::new(),
+                });
+                meter.values.insert(metric.clone(), Some(sample.value));
+                if let Some(time) = times.get(&key) {
+                    meter.sample_times.insert(metric.clone(), *time);
+                }
+            }
+        }
+    }
+    let mut results: Vec<MeterSnapshot> = meters.into_values().collect();
+    results.sort_by_key(|m| (m.instance.clone(), Natural(m.address.clone())));
+
+    if wants_csv(&params) {
+        return Ok((StatusCode::OK, render_csv(state, &metrics, &results)).into_response());
+    }
+
+    let response = ElectricalResponse {
+        target,
+        datetime,
+        metrics,
+        results,
+    };
+    Ok((StatusCode::OK, Json(response)).into_response())
+}
+
+/// `Target,Address` followed by one column per metric, empty where missing.
+fn render_csv(state: &AppState, metrics: &[String], results: &[MeterSnapshot]) -> String {
+    let with_names = state.config.aliases.is_configured();
+    let mut csv_data = String::from("Target,Address");
+    for metric in metrics {
+        csv_data.push(',');
+        csv_data.push_str(metric);
+    }
+    csv_data.push_str(if with_names { ",Name\n" } else { "\n" });
+
+    for meter in results {
+        csv_data.push_str(&format!("{},{}", meter.instance, meter.address));
+        for metric in metrics {
+            csv_data.push(',');
+            if let Some(Some(value)) = meter.values.get(metric) {
+                csv_data.push_str(&value.to_string());
+            }
+        }
+        if with_names {
+            csv_data.push(',');
+            csv_data.push_str(&csv_field(meter.name.as_deref().unwrap_or_default()));
+        }
+        csv_data.push('\n');
+    }
+    csv_data
+}
@@ -0,0 +1,312 @@
+use async_graphql::{
+    http::GraphiQLSource, Context, EmptyMutation, EmptySubscription, ErrorExtensions, Object, Schema,
+    SimpleObject,
+};
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::{Html, IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, NaiveDate, Utc};
+use std::{collections::HashMap, sync::LazyLock};
+
+use crate::{
+    api::{admin::authorize, range::MAX_DAYS, targets::cached_targets, unit::Unit, QueryMeta},
+    error::{error_response, Error},
+    period::{days_inclusive, last_date},
+    range::{daily_usage, DailySeries},
+    selector,
+    state::AppState,
+    usage::{compute_usage, resolve_selector, resolve_target, UsageEntry, UsageRequest},
+};
+
+/// Deepest selection accepted; the schema itself is four levels deep.
+const MAX_DEPTH: usize = 6;
+/// Most fields one query may select, counting each field once.
+const MAX_COMPLEXITY: usize = 200;
+
+type UsageSchema = Schema<Query, EmptyMutation, EmptySubscription>;
+
+static SCHEMA: LazyLock<UsageSchema> = LazyLock::new(|| {
+    Schema::build(Query, EmptyMutation, EmptySubscription)
+        .limit_depth(MAX_DEPTH)
+        .limit_complexity(MAX_COMPLEXITY)
+        .finish()
+});
+
+/// Same fields as the v2 `meta` object.
+#[derive(SimpleObject)]
+struct Meta {
+    target: String,
+    datetime: String,
+    curr_time: DateTime<Utc>,
+    prev_time: DateTime<Utc>,
+    timezone: String,
+    utc_offset: String,
+    lookback: String,
+    backend: String,
+    unit: String,
+    cache: String,
+    truncated: bool,
+    total_instances: Option<usize>,
+    generated_at: DateTime<Utc>,
+}
+
+/// One meter, as in the v2 `results`.
+#[derive(SimpleObject)]
+struct Entry {
+    instance: String,
+    address: String,
+    name: Option<String>,
+    prev_kwh: Option<f64>,
+    curr_kwh: f64,
+    daily_kwh: Option<f64>,
+    avg_power_watt: Option<f64>,
+    period_hours: Option<f64>,
+    avg_power_watt_24h: Option<f64>,
+    over_threshold: Option<bool>,
+    prev_sample_time: Option<DateTime<Utc>>,
+    curr_sample_time: Option<DateTime<Utc>>,
+    flags: Vec<String>,
+}
+
+#[derive(SimpleObject)]
+struct DailyUsage {
+    meta: Meta,
+    results: Vec<Entry>,
+}
+
+#[derive(SimpleObject)]
+struct Day {
+    date: NaiveDate,
+    daily_kwh: Option<f64>,
+    flags: Vec<String>,
+}
+
+#[derive(SimpleObject)]
+struct MeterReport {
+    instance: String,
+    address: String,
+    name: Option<String>,
+    total_kwh: f64,
+    days: Vec<Day>,
+}
+
+/// As the JSON range report, without smoothing, anomalies or splits.
+#[derive(SimpleObject)]
+struct RangeUsage {
+    target: String,
+    start: NaiveDate,
+    end: NaiveDate,
+    timezone: String,
+    results: Vec<MeterReport>,
+}
+
+#[derive(SimpleObject)]
+struct Instance {
+    instance: String,
+    addresses: Vec<String>,
+    last_sample_time: Option<DateTime<Utc>>,
+}
+
+impl From<QueryMeta> for Meta {
+    fn from(meta: QueryMeta) -> Self {
+        Self {
+            target: meta.target,
+            datetime: meta.datetime,
+            curr_time: meta.curr_time,
+            prev_time: meta.prev_time,
+            timezone: meta.timezone,
+            utc_offset: meta.utc_offset,
+            lookback: meta.lookback,
+            backend: meta.backend,
+            unit: meta.unit.to_string(),
+            cache: meta.cache.to_string(),
+            truncated: meta.truncated,
+            total_instances: meta.total_instances,
+            generated_at: meta.generated_at,
+        }
+    }
+}
+
+/// As `v2::PowerUsageEntry`, always in kWh; the week comparison is not exposed.
+impl From<UsageEntry> for Entry {
+    fn from(entry: UsageEntry) -> Self {
+        Self {
+            over_threshold: entry.over_threshold(),
+            instance: entry.instance,
+            address: entry.address,
+            name: entry.name,
+            prev_kwh: entry.prev_kwh,
+            curr_kwh: entry.curr_kwh,
+            daily_kwh: entry.daily_kwh,
+            avg_power_watt: entry.avg_power_watt,
+            period_hours: entry.period_hours,
+            avg_power_watt_24h: entry.avg_power_watt_24h,
+            prev_sample_time: entry.prev_sample_time,
+            curr_sample_time: entry.curr_sample_time,
+            flags: entry.flags.into_iter().map(str::to_string).collect(),
+        }
+    }
+}
+
+impl From<DailySeries> for MeterReport {
+    fn from(meter: DailySeries) -> Self {
+        Self {
+            total_kwh: meter.total_kwh(),
+            days: meter
+                .days
+                .into_iter()
+                .map(|(date, daily_kwh)| Day {
+                    date,
+                    daily_kwh,
+                    flags: daily_kwh.is_none().then(|| "missing".to_string()).into_iter().collect(),
+                })
+                .collect(),
+            instance: meter.instance,
+            address: meter.address,
+            name: meter.name,
+        }
+    }
+}
+
+/// The HTTP status a query would have failed with, as the error's `code`
+/// extension, and a failed Prometheus call's `error_kind`, with the message
+/// `error_response` gives.
+fn graphql_error(error: impl Into<Error>) -> async_graphql::Error {
+    let error = error.into();
+    let code = error.status();
+    let kind = error.failure().map(|failure| failure.kind);
+    let invalid = match error {
+        Error::Invalid(message) => Some(message),
+        _ => None,
+    };
+    let message = kind.map(|kind| kind.message()).or(invalid).unwrap_or(match code {
+        StatusCode::BAD_REQUEST => "Invalid request",
+        StatusCode::NOT_FOUND => "Not found",
+        StatusCode::UNPROCESSABLE_ENTITY => "Too many instances match; narrow the target",
+        StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT => {
+            "Prometheus query failed"
+        }
+        _ => "Internal server error",
+    });
+    async_graphql::Error::new(message).extend_with(|_, e| {
+        e.set("code", code.as_u16());
+        if let Some(kind) = kind {
+            e.set("error_kind", kind.name());
+        }
+    })
+}
+
+/// Arguments as the query parameters of the matching REST endpoint, with
+/// absent ones left out.
+fn params<const N: usize>(fields: [(&str, Option<&str>); N]) -> HashMap<String, String> {
+    fields
+        .into_iter()
+        .filter_map(|(key, value)| Some((key.to_string(), value?.to_string())))
+        .collect()
+}
+
+struct Query;
+
+#[Object]
+impl Query {
+    /// Consumption over the day ending at `date` `time`, as `GET /api/v2/power-usage`.
+    async fn daily_usage(
+        &self,
+        ctx: &Context<'_>,
+        target: String,
+        date: NaiveDate,
+        #[graphql(default = "00:00")] time: String,
+        tz: Option<String>,
+        selector: Option<String>,
+    ) -> async_graphql::Result<DailyUsage> {
+        let state = ctx.data::<AppState>()?;
+        let date = date.to_string();
+        let params = params([
+            ("target", Some(&target)),
+            ("date", Some(&date)),
+            ("time", Some(&time)),
+            ("tz", tz.as_deref()),
+            ("selector", selector.as_deref()),
+        ]);
+        let req = UsageRequest::from_params(&params, state).map_err(graphql_error)?;
+        let usage = compute_usage(state, &req).await.map_err(graphql_error)?;
+        Ok(DailyUsage {
+            meta: QueryMeta::new(state, &req, Unit::Kwh, usage.truncated_from).cached(usage.cache).into(),
+            results: usage.entries.into_iter().map(Entry::from).collect(),
+        })
+    }
+
+    /// Consumption per local day from `start` through `end`, as `GET /api/v1/power-usage/range`.
+    async fn range_usage(
+        &self,
+        ctx: &Context<'_>,
+        target: String,
+        start: NaiveDate,
+        end: NaiveDate,
+        selector: Option<String>,
+    ) -> async_graphql::Result<RangeUsage> {
+        let state = ctx.data::<AppState>()?;
+        let days = days_inclusive(start, end)
+            .filter(|days| *days <= MAX_DAYS)
+            .ok_or_else(|| graphql_error(StatusCode::BAD_REQUEST))?;
+        let params = params([("target", Some(&target)), ("selector", selector.as_deref())]);
+        let (target, address) = resolve_target(&params, state).map_err(graphql_error)?;
+        let selector = resolve_selector(&params, &target).map_err(graphql_error)?;
+        let mut series = daily_usage(state, &selector, start, days).await.map_err(graphql_error)?;
+        if let Some(address) = &address {
+            series.retain(|s| &s.address == address);
+        }
+        Ok(RangeUsage {
+            target,
+            start,
+            end: last_date(start, days).ok_or_else(|| graphql_error(StatusCode::BAD_REQUEST))?,
+            timezone: state.config.timezone.name().to_string(),
+            results: series.into_iter().map(MeterReport::from).collect(),
+        })
+    }
+
+    /// Instances reporting energy, as `GET /api/v1/targets` without paging.
+    async fn instances(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(name = "match", default = ".+")] pattern: String,
+    ) -> async_graphql::Result<Vec<Instance>> {
+        let state = ctx.data::<AppState>()?;
+        let targets = cached_targets(state, selector::energy(&pattern, &[])).await.map_err(graphql_error)?;
+        Ok(targets
+            .iter()
+            .map(|target| Instance {
+                instance: target.instance.clone(),
+                addresses: target.addresses.clone(),
+                last_sample_time: target.last_sample_time,
+            })
+            .collect())
+    }
+}
+
+/// `POST /graphql`, with `GRAPHQL=true`. Errors are reported in the
+/// response's `errors`, with a 200 as GraphQL clients expect.
+pub async fn graphql_handler(
+    State(state): State<AppState>,
+    Json(request): Json<async_graphql::Request>,
+) -> Response {
+    if !state.config.graphql {
+        return error_response(StatusCode::NOT_FOUND);
+    }
+    Json(SCHEMA.execute(request.data(state)).await).into_response()
+}
+
+/// `GET /graphql`: the GraphiQL playground, behind `ADMIN_TOKEN`.
+pub async fn graphiql_handler(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if !state.config.graphql {
+        return error_response(StatusCode::NOT_FOUND);
+    }
+    if let Err(code) = authorize(&state, &headers) {
+        return error_response(code);
+    }
+    let endpoint = format!("{}/graphql", state.config.base_path);
+    Html(GraphiQLSource::build().endpoint(&endpoint).finish()).into_response()
+}
@@ -0,0 +1,225 @@
+use axum::http::StatusCode;
+use chrono::{DateTime, NaiveDate, SecondsFormat, Utc};
+use std::{collections::HashMap, net::SocketAddr};
+use tonic::{Request, Response, Status};
+
+use crate::{
+    api::{range::MAX_DAYS, unit::Unit, QueryMeta},
+    error::Error,
+    period::{billing_period, days_inclusive, last_date, parse_month},
+    range::{daily_usage, DailySeries},
+    server::shutdown_signal,
+    state::AppState,
+    usage::{compute_usage, resolve_selector, resolve_target, UsageEntry, UsageRequest},
+};
+
+mod proto {
+    tonic::include_proto!("power_usage.v1");
+}
+
+use proto::{
+    power_usage_server::{PowerUsage, PowerUsageServer},
+    DailyRequest, DailyResponse, Day, Entry, Meta, MeterReport, MonthlyRequest, RangeRequest, RangeResponse,
+};
+
+/// Served by reflection, so `grpcurl` needs no copy of the `.proto` file.
+const FILE_DESCRIPTOR_SET: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/power_usage_descriptor.bin"));
+
+/// Serves the `PowerUsage` service and reflection on `addr` until shutdown.
+/// Only called when `GRPC_BIND_ADDR` is set.
+pub async fn serve(addr: SocketAddr, state: AppState) {
+    let reflection = match tonic_reflection::server::Builder::configure()
+        .register_encoded_file_descriptor_set(FILE_DESCRIPTOR_SET)
+        .build_v1()
+    {
+        Ok(reflection) => reflection,
+        Err(e) => {
+            tracing::error!("Failed to build gRPC reflection: {}", e);
+            return;
+        }
+    };
+    tracing::info!("gRPC server running on {}", addr);
+    let result = tonic::transport::Server::builder()
+        .add_service(PowerUsageServer::new(Service { state }))
+        .add_service(reflection)
+        .serve_with_shutdown(addr, shutdown_signal())
+        .await;
+    if let Err(e) = result {
+        tracing::error!("gRPC server failed: {}", e);
+    }
+}
+
+struct Service {
+    state: AppState,
+}
+
+/// The same failures as the HTTP API, in gRPC terms, with a failed
+/// Prometheus call's own message.
+fn status(error: impl Into<Error>) -> Status {
+    let error = error.into();
+    if let Error::Invalid(message) = error {
+        return Status::invalid_argument(message);
+    }
+    let upstream = error.failure().map(|failure| failure.kind.message());
+    match error.status() {
+        StatusCode::BAD_REQUEST => Status::invalid_argument("Invalid request"),
+        StatusCode::NOT_FOUND => Status::not_found("Not found"),
+        StatusCode::UNPROCESSABLE_ENTITY => {
+            Status::failed_precondition("Too many instances match; narrow the target or pass truncate=true")
+        }
+        StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::TOO_MANY_REQUESTS => {
+            Status::unavailable(upstream.unwrap_or("Prometheus query failed"))
+        }
+        StatusCode::GATEWAY_TIMEOUT => {
+            Status::deadline_exceeded(upstream.unwrap_or("Prometheus query timed out"))
+        }
+        _ => Status::internal(upstream.unwrap_or("Internal server error")),
+    }
+}
+
+/// Request fields as the query parameters of the matching HTTP endpoint;
+/// empty strings stand for omitted parameters.
+fn params<const N: usize>(fields: [(&str, &str); N]) -> HashMap<String, String> {
+    fields
+        .into_iter()
+        .filter(|(_, value)| !value.is_empty())
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+fn timestamp(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::AutoSi, true)
+}
+
+impl From<QueryMeta> for Meta {
+    fn from(meta: QueryMeta) -> Self {
+        Self {
+            target: meta.target,
+            datetime: meta.datetime,
+            curr_time: timestamp(meta.curr_time),
+            prev_time: timestamp(meta.prev_time),
+            timezone: meta.timezone,
+            utc_offset: meta.utc_offset,
+            lookback: meta.lookback,
+            backend: meta.backend,
+            unit: meta.unit.to_string(),
+            cache: meta.cache.to_string(),
+            truncated: meta.truncated,
+            total_instances: meta.total_instances.map(|n| n as u64),
+            generated_at: timestamp(meta.generated_at),
+        }
+    }
+}
+
+/// As `v2::PowerUsageEntry::new`; the week comparison is not exposed.
+fn entry(entry: UsageEntry, unit: Unit) -> Entry {
+    Entry {
+        over_threshold: entry.over_threshold(),
+        instance: entry.instance,
+        address: entry.address,
+        name: entry.name,
+        prev_kwh: entry.prev_kwh.map(|kwh| unit.convert(kwh)),
+        curr_kwh: unit.convert(entry.curr_kwh),
+        daily_kwh: entry.daily_kwh.map(|kwh| unit.convert(kwh)),
+        avg_power_watt: entry.avg_power_watt,
+        period_hours: entry.period_hours,
+        avg_power_watt_24h: entry.avg_power_watt_24h,
+        prev_sample_time: entry.prev_sample_time.map(timestamp),
+        curr_sample_time: entry.curr_sample_time.map(timestamp),
+        flags: entry.flags.into_iter().map(str::to_string).collect(),
+    }
+}
+
+fn meter_report(meter: DailySeries) -> MeterReport {
+    MeterReport {
+        total_kwh: meter.total_kwh(),
+        days: meter
+            .days
+            .into_iter()
+            .map(|(date, daily_kwh)| Day {
+                date: date.to_string(),
+                daily_kwh,
+                flags: daily_kwh.is_none().then(|| "missing".to_string()).into_iter().collect(),
+            })
+            .collect(),
+        instance: meter.instance,
+        address: meter.address,
+        name: meter.name,
+    }
+}
+
+/// Daily consumption per meter for `days` local days from `start`, as the
+/// JSON range, weekly and monthly reports.
+async fn report(
+    state: &AppState,
+    target: &str,
+    selector: &str,
+    start: NaiveDate,
+    days: u32,
+) -> Result<RangeResponse, Error> {
+    if days == 0 || days > MAX_DAYS {
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+    let params = params([("target", target), ("selector", selector)]);
+    let (target, address) = resolve_target(&params, state)?;
+    let selector = resolve_selector(&params, &target)?;
+    let mut series = daily_usage(state, &selector, start, days).await?;
+    if let Some(address) = &address {
+        series.retain(|s| &s.address == address);
+    }
+    Ok(RangeResponse {
+        target,
+        start: start.to_string(),
+        end: last_date(start, days).ok_or(StatusCode::BAD_REQUEST)?.to_string(),
+        timezone: state.config.timezone.name().to_string(),
+        results: series.into_iter().map(meter_report).collect(),
+    })
+}
+
+#[tonic::async_trait]
+impl PowerUsage for Service {
+    async fn get_daily(&self, request: Request<DailyRequest>) -> Result<Response<DailyResponse>, Status> {
+        let request = request.into_inner();
+        let params = params([
+            ("target", &request.target),
+            ("date", &request.date),
+            ("time", &request.time),
+            ("selector", &request.selector),
+            ("unit", &request.unit),
+            ("truncate", if request.truncate { "true" } else { "" }),
+        ]);
+        let req = UsageRequest::from_params(&params, &self.state).map_err(status)?;
+        let unit = Unit::from_params(&params).map_err(status)?;
+        let usage = compute_usage(&self.state, &req).await.map_err(status)?;
+        Ok(Response::new(DailyResponse {
+            meta: Some(QueryMeta::new(&self.state, &req, unit, usage.truncated_from).cached(usage.cache).into()),
+            results: usage.entries.into_iter().map(|e| entry(e, unit)).collect(),
+        }))
+    }
+
+    async fn get_range(&self, request: Request<RangeRequest>) -> Result<Response<RangeResponse>, Status> {
+        let request = request.into_inner();
+        let parse = |value: &str| NaiveDate::parse_from_str(value, "%Y-%m-%d").ok();
+        let (start, end) = parse(&request.start)
+            .zip(parse(&request.end))
+            .ok_or_else(|| status(StatusCode::BAD_REQUEST))?;
+        let days = days_inclusive(start, end).ok_or_else(|| status(StatusCode::BAD_REQUEST))?;
+        report(&self.state, &request.target, &request.selector, start, days)
+            .await
+            .map(Response::new)
+            .map_err(status)
+    }
+
+    async fn get_monthly(&self, request: Request<MonthlyRequest>) -> Result<Response<RangeResponse>, Status> {
+        let request = request.into_inner();
+        let period = parse_month(&request.month).and_then(|month| match request.billing_day {
+            None => Some(month),
+            Some(day) => billing_period(month.0, Some(day).filter(|d| (1..=31).contains(d))?),
+        });
+        let (first, days) = period.ok_or_else(|| status(StatusCode::BAD_REQUEST))?;
+        report(&self.state, &request.target, &request.selector, first, days)
+            .await
+            .map(Response::new)
+            .map_err(status)
+    }
+}
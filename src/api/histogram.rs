@@ -0,0 +1,149 @@
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{
+    audit,
+    error::{error_response, Error},
+    period::parse_month,
+    range::{daily_usage, instance_totals},
+    state::AppState,
+    stats::median,
+    usage::{resolve_selector, resolve_target},
+};
+
+/// Number of buckets when no edges are given.
+const AUTO_BUCKETS: usize = 5;
+
+#[derive(Serialize)]
+struct Bucket {
+    /// Inclusive lower edge, `-Inf` for the first bucket.
+    lower: String,
+    /// Exclusive upper edge, `+Inf` for the last bucket.
+    upper: String,
+    count: usize,
+}
+
+#[derive(Serialize)]
+struct Distribution {
+    days: usize,
+    min: Option<f64>,
+    median: Option<f64>,
+    max: Option<f64>,
+    buckets: Vec<Bucket>,
+}
+
+#[derive(Serialize)]
+struct HistogramResponse {
+    target: String,
+    month: String,
+    edges: Vec<f64>,
+    overall: Distribution,
+    instances: BTreeMap<String, Distribution>,
+}
+
+pub async fn histogram_handler(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    match handle_histogram(&state, params).await {
+        Ok(response) => response.into_response(),
+        Err(code) => error_response(code),
+    }
+}
+
+fn parse_edges(value: &str) -> Option<Vec<f64>> {
+    let edges: Vec<f64> = value
+        .split(',')
+        .map(|v| v.trim().parse().ok().filter(|v: &f64| v.is_finite()))
+        .collect::<Option<_>>()?;
+    let ascending = edges.windows(2).all(|w| w[0] < w[1]);
+    (!edges.is_empty() && ascending).then_some(edges)
+}
+
+/// `0, step, 2*step, ...` with a 1/2/5 step that spreads `max` over
+/// `AUTO_BUCKETS` buckets.
+fn auto_edges(max: f64) -> Vec<f64> {
+    if max <= 0.0 {
+        return vec![0.0];
+    }
+    let raw = max / AUTO_BUCKETS as f64;
+    let magnitude = 10f64.powf(raw.log10().floor());
+    let step = [1.0, 2.0, 5.0, 10.0]
+        .into_iter()
+        .map(|m| m * magnitude)
+        .find(|step| *step >= raw)
+        .unwrap_or(10.0 * magnitude);
+    (0..AUTO_BUCKETS).map(|i| i as f64 * step).collect()
+}
+
+fn distribution(mut values: Vec<f64>, edges: &[f64]) -> Distribution {
+    let mut counts = vec![0; edges.len() + 1];
+    for value in &values {
+        let bucket = edges.iter().take_while(|edge| *value >= **edge).count();
+        counts[bucket] += 1;
+    }
+    let buckets = counts
+        .into_iter()
+        .enumerate()
+        .map(|(i, count)| Bucket {
+            lower: i.checked_sub(1).map_or("-Inf".to_string(), |j| edges[j].to_string()),
+            upper: edges.get(i).map_or("+Inf".to_string(), f64::to_string),
+            count,
+        })
+        .collect();
+
+    let median = median(&mut values);
+    Distribution {
+        days: values.len(),
+        min: values.first().copied(),
+        median,
+        max: values.last().copied(),
+        buckets,
+    }
+}
+
+/// Distribution of daily consumption over one month: each instance's
+/// meters are summed per local day, and those days are counted into the
+/// buckets given by `buckets` (or chosen from the data), per instance and
+/// across all instances.
+async fn handle_histogram(
+    state: &AppState,
+    params: HashMap<String, String>,
+) -> Result<Response, Error> {
+    let (target, _) = resolve_target(&params, state)?;
+    let selector = resolve_selector(&params, &target)?;
+    let month = params.get("month").ok_or(StatusCode::BAD_REQUEST)?;
+    let (first, days) = parse_month(month).ok_or(StatusCode::BAD_REQUEST)?;
+    let edges = params
+        .get("buckets")
+        .map(|v| parse_edges(v).ok_or(StatusCode::BAD_REQUEST))
+        .transpose()?;
+
+    audit::note_days(state.config.timezone, first, days);
+    let series = daily_usage(state, &selector, first, days).await?;
+    audit::note_rows(series.len());
+    let per_instance: BTreeMap<String, Vec<f64>> = instance_totals(&series)
+        .into_iter()
+        .map(|(instance, days)| (instance, days.into_iter().filter_map(|(_, kwh)| kwh).collect()))
+        .collect();
+    let all: Vec<f64> = per_instance.values().flatten().copied().collect();
+
+    let edges = edges.unwrap_or_else(|| auto_edges(all.iter().copied().fold(0.0, f64::max)));
+    let response = HistogramResponse {
+        target,
+        month: month.clone(),
+        overall: distribution(all, &edges),
+        instances: per_instance
+            .into_iter()
+            .map(|(instance, values)| (instance, distribution(values, &edges)))
+            .collect(),
+        edges,
+    };
+    Ok((StatusCode::OK, Json(response)).into_response())
+}
@@ -0,0 +1,166 @@
+use askama::Template;
+use axum::http::StatusCode;
+use chrono::Utc;
+
+use crate::api::{
+    table::{Cell, Table},
+    unit::Unit,
+};
+
+/// Bars drawn in the top consumers chart, however many rows there are.
+const TOP_CONSUMERS: usize = 10;
+const LABEL_WIDTH: usize = 220;
+const BAR_WIDTH: usize = 380;
+const BAR_SPACING: usize = 24;
+/// Longer chart labels are cut short to fit `LABEL_WIDTH`.
+const LABEL_CHARS: usize = 32;
+
+struct Header {
+    name: &'static str,
+    numeric: bool,
+}
+
+struct HtmlCell {
+    text: String,
+    /// Unrounded value the table sorts by.
+    value: Option<f64>,
+}
+
+struct Bar {
+    label: String,
+    value: String,
+    y: usize,
+    width: usize,
+}
+
+#[derive(Template)]
+#[template(path = "report.html")]
+struct Report {
+    target: String,
+    datetime: String,
+    total: String,
+    unit: &'static str,
+    headers: Vec<Header>,
+    rows: Vec<Vec<HtmlCell>>,
+    bars: Vec<Bar>,
+    label_width: usize,
+    chart_width: usize,
+    chart_height: usize,
+    generated_at: String,
+}
+
+/// A self-contained page for `table`: summary figures, a bar chart of the
+/// rows using the most `Daily_KWh` and the table itself, sortable by any
+/// column. Numbers are rounded to `precision` decimals and written in the
+/// table's number format; energy is in `unit`.
+pub fn render(
+    table: &Table,
+    target: &str,
+    datetime: &str,
+    precision: usize,
+    unit: Unit,
+) -> Result<String, StatusCode> {
+    let format = |value: f64| table.number(value, Some(precision));
+    let headers: Vec<Header> = table
+        .labels()
+        .iter()
+        .zip(table.numeric())
+        .map(|(name, numeric)| Header { name, numeric })
+        .collect();
+    let rows = table
+        .rows()
+        .iter()
+        .map(|row| {
+            row.iter()
+                .map(|cell| match cell {
+                    Cell::Text(text) => HtmlCell { text: text.clone(), value: None },
+                    Cell::Int(value) => HtmlCell { text: value.to_string(), value: Some(*value as f64) },
+                    Cell::Num(value) => HtmlCell { text: format(*value), value: Some(*value) },
+                    Cell::Missing => HtmlCell { text: String::new(), value: None },
+                    Cell::List(items) => HtmlCell { text: items.join(", "), value: None },
+                })
+                .collect()
+        })
+        .collect();
+
+    let bars = top_consumers(table)
+        .into_iter()
+        .enumerate()
+        .map(|(i, (label, value, fraction))| Bar {
+            label: if label.chars().count() > LABEL_CHARS {
+                label.chars().take(LABEL_CHARS - 1).chain(['…']).collect()
+            } else {
+                label
+            },
+            value: format(value),
+            y: i * BAR_SPACING,
+            width: (fraction * BAR_WIDTH as f64).round() as usize,
+        })
+        .collect::<Vec<_>>();
+
+    let report = Report {
+        target: target.to_string(),
+        datetime: datetime.to_string(),
+        total: format(table.total_of("Daily_KWh").unwrap_or_default()),
+        unit: unit.symbol(),
+        headers,
+        rows,
+        label_width: LABEL_WIDTH,
+        // Room for the value printed after the longest bar.
+        chart_width: LABEL_WIDTH + BAR_WIDTH + 80,
+        chart_height: bars.len() * BAR_SPACING,
+        bars,
+        generated_at: Utc::now().to_rfc3339(),
+    };
+    report.render().map_err(|e| {
+        tracing::error!("HTML report rendering failed: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+/// The `TOP_CONSUMERS` rows with the largest `Daily_KWh`, as label, value
+/// and fraction of the largest value. Rows are labelled by their `Name`, or
+/// else by the cells left of the first measurement.
+fn top_consumers(table: &Table) -> Vec<(String, f64, f64)> {
+    let headers = table.headers();
+    let Some(kwh) = headers.iter().position(|h| *h == "Daily_KWh") else {
+        return Vec::new();
+    };
+    let name = headers.iter().position(|h| *h == "Name");
+
+    let mut consumers: Vec<(String, f64)> = table
+        .rows()
+        .iter()
+        .filter_map(|row| {
+            let Some(Cell::Num(value)) = row.get(kwh) else {
+                return None;
+            };
+            let named = name.and_then(|i| match row.get(i) {
+                Some(Cell::Text(text)) if !text.is_empty() => Some(text.clone()),
+                _ => None,
+            });
+            let label = named.unwrap_or_else(|| {
+                row.iter()
+                    .map_while(|cell| match cell {
+                        Cell::Text(text) => Some(text.clone()),
+                        Cell::Int(value) => Some(value.to_string()),
+                        Cell::Num(_) | Cell::Missing | Cell::List(_) => None,
+                    })
+                    .collect::<Vec<_>>()
+                    .join(" / ")
+            });
+            Some((label, *value))
+        })
+        .collect();
+    consumers.sort_by(|a, b| b.1.total_cmp(&a.1));
+    consumers.truncate(TOP_CONSUMERS);
+
+    let largest = consumers.first().map_or(0.0, |(_, value)| *value);
+    consumers
+        .into_iter()
+        .map(|(label, value)| {
+            let fraction = if largest > 0.0 { (value / largest).max(0.0) } else { 0.0 };
+            (label, value, fraction)
+        })
+        .collect()
+}
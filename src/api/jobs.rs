@@ -0,0 +1,84 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use std::collections::HashMap;
+
+use crate::{
+    api_keys::KeyName,
+    error::{json_error, ApiError},
+    jobs::JobFile,
+    state::AppState,
+};
+
+fn owner(key: &Option<Extension<KeyName>>) -> Option<&str> {
+    key.as_ref().map(|Extension(KeyName(name))| name.as_str())
+}
+
+fn unknown_job() -> Response {
+    json_error(StatusCode::NOT_FOUND, "unknown job")
+}
+
+/// `POST /api/v1/jobs?report=range&...`: queues the report with the rest of
+/// the parameters and answers at once with 202 and the job.
+pub async fn submit_handler(
+    State(state): State<AppState>,
+    key: Option<Extension<KeyName>>,
+    Query(mut params): Query<HashMap<String, String>>,
+) -> Response {
+    let Some(report) = params.remove("report") else {
+        return ApiError::new(StatusCode::BAD_REQUEST, "missing report").into_response();
+    };
+    let owner = owner(&key).map(str::to_string);
+    match state.jobs.submit(&state, owner, &report, params) {
+        Ok(job) => {
+            let location = format!("{}/api/v1/jobs/{}", state.config.base_path, job.id);
+            (StatusCode::ACCEPTED, [(header::LOCATION, location)], Json(job)).into_response()
+        }
+        Err(error) => error.into_response(),
+    }
+}
+
+pub async fn job_handler(
+    State(state): State<AppState>,
+    key: Option<Extension<KeyName>>,
+    Path(id): Path<String>,
+) -> Response {
+    match state.jobs.get(&state, &id, owner(&key)) {
+        Some(job) => Json(job).into_response(),
+        None => unknown_job(),
+    }
+}
+
+/// Cancels an unfinished job, or removes a finished one with its result.
+pub async fn cancel_handler(
+    State(state): State<AppState>,
+    key: Option<Extension<KeyName>>,
+    Path(id): Path<String>,
+) -> Response {
+    let owner = owner(&key);
+    if state.jobs.get(&state, &id, owner).is_none() {
+        return unknown_job();
+    }
+    match state.jobs.cancel(&state, &id, owner) {
+        Some(job) => Json(job).into_response(),
+        None => StatusCode::NO_CONTENT.into_response(),
+    }
+}
+
+pub async fn result_handler(
+    State(state): State<AppState>,
+    key: Option<Extension<KeyName>>,
+    Path(id): Path<String>,
+) -> Response {
+    match state.jobs.file(&state, &id, owner(&key)).await {
+        Ok(JobFile { content_type, filename, body }) => {
+            let disposition = format!("attachment; filename=\"{}\"", filename);
+            let headers = [(header::CONTENT_TYPE, content_type), (header::CONTENT_DISPOSITION, disposition)];
+            (StatusCode::OK, headers, body).into_response()
+        }
+        Err(error) => error.into_response(),
+    }
+}
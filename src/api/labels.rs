@@ -0,0 +1,62 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::Utc;
+use std::{collections::HashMap, sync::Arc};
+
+use crate::{
+    api_keys::restrict,
+    config::parse_duration,
+    error::{error_response, ApiError, Error},
+    selector,
+    state::AppState,
+};
+
+/// The `match` when none is given: every instance.
+const ALL: &str = ".+";
+
+/// `GET /api/v1/labels/<label>/values?match=<target>`: the values of one
+/// label on the energy series of the matching instances that reported
+/// within `TARGETS_WINDOW`, as a plain JSON array for dashboard variables.
+/// Cached for `TARGETS_CACHE_TTL`.
+pub async fn label_values_handler(
+    State(state): State<AppState>,
+    Path(label): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Response {
+    if !selector::is_label_name(&label) {
+        let error = ApiError::new(StatusCode::BAD_REQUEST, "invalid label name");
+        return error.with("label", label).into_response();
+    }
+    let pattern = params.get("match").map_or(ALL, String::as_str);
+    let selector = restrict(selector::energy(pattern, &[]), &params);
+    match cached_values(&state, &label, &selector).await {
+        Ok(values) => Json(values.as_slice()).into_response(),
+        Err(code) => error_response(code),
+    }
+}
+
+async fn cached_values(
+    state: &AppState,
+    label: &str,
+    selector: &str,
+) -> Result<Arc<Vec<String>>, Error> {
+    // Label names cannot contain a colon, so the key is unambiguous.
+    let key = format!("{}:{}", label, selector);
+    if let Some(values) = state.label_values_cache.get(&key) {
+        return Ok(values);
+    }
+    let end = Utc::now();
+    let window = parse_duration(&state.config.targets_window).unwrap_or_default();
+    let start = end - chrono::Duration::from_std(window).unwrap_or_default();
+    let values = state
+        .prometheus
+        .label_values(label, selector, start, end)
+        .await?;
+    let values = Arc::new(values);
+    state.label_values_cache.insert(key, values.clone());
+    Ok(values)
+}
@@ -0,0 +1,117 @@
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::HashMap;
+
+use crate::{
+    config::parse_duration,
+    error::{error_response, Error},
+    state::AppState,
+    usage::{limit_instances, resolve_selector, resolve_target, wants_truncate},
+};
+
+#[derive(Serialize)]
+struct LatestReading {
+    instance: String,
+    address: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    kwh: f64,
+    sample_time: Option<DateTime<Utc>>,
+    age_seconds: Option<i64>,
+    stale: bool,
+}
+
+#[derive(Serialize)]
+struct LatestResponse {
+    target: String,
+    datetime: DateTime<Utc>,
+    lookback: String,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    truncated: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_instances: Option<usize>,
+    results: Vec<LatestReading>,
+}
+
+pub async fn latest_handler(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    match handle_latest(&state, params).await {
+        Ok(response) => response.into_response(),
+        Err(code) => error_response(code),
+    }
+}
+
+/// Returns the raw counter reading of every matching meter right now,
+/// searching back as far as `LATEST_WINDOW` so dead exporters still show up.
+/// Readings older than the lookback are flagged `stale`, and `min_age`
+/// keeps only meters whose newest sample is at least that old.
+async fn handle_latest(
+    state: &AppState,
+    params: HashMap<String, String>,
+) -> Result<Response, Error> {
+    let (target, address) = resolve_target(&params, state)?;
+    let selector = resolve_selector(&params, &target)?;
+    let min_age = params
+        .get("min_age")
+        .map(|v| parse_duration(v).ok_or(StatusCode::BAD_REQUEST))
+        .transpose()?;
+
+    let now = Utc::now();
+    let tunables = state.config.tunables();
+    let window = &tunables.latest_window;
+    let (data, times) = tokio::try_join!(
+        state.prometheus.get_data_within(&selector, now, window),
+        state.prometheus.get_sample_times(&selector, now, window),
+    )?;
+    let mut readings = data.data;
+    let truncated_from = limit_instances(state, &mut [&mut readings], wants_truncate(&params))?;
+
+    let lookback = parse_duration(&tunables.lookback).unwrap_or_default();
+
+    let aliases = state.config.aliases.current();
+    let mut results = Vec::new();
+    for (instance, samples) in readings {
+        for sample in samples {
+            if address.as_ref().is_some_and(|a| a != &sample.address) {
+                continue;
+            }
+            let sample_time = times.get(&(instance.clone(), sample.address.clone())).copied();
+            let age = sample_time.map(|t| (now - t).to_std().unwrap_or_default());
+
+            if let Some(min_age) = min_age
+                && age.is_none_or(|age| age < min_age)
+            {
+                continue;
+            }
+
+            results.push(LatestReading {
+                instance: instance.clone(),
+                name: aliases.name(&instance, &sample.address).map(str::to_string),
+                address: sample.address,
+                kwh: sample.value,
+                sample_time,
+                age_seconds: age.map(|a| a.as_secs() as i64),
+                stale: age.is_some_and(|age| age > lookback),
+            });
+        }
+    }
+    results.sort_by(|a, b| a.instance.cmp(&b.instance));
+
+    let response = LatestResponse {
+        target,
+        datetime: now,
+        lookback: tunables.lookback.clone(),
+        truncated: truncated_from.is_some(),
+        total_instances: truncated_from,
+        results,
+    };
+    Ok((StatusCode::OK, Json(response)).into_response())
+}
@@ -0,0 +1,202 @@
+use axum::{
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use chrono::{NaiveDate, Utc};
+use printpdf::{BuiltinFont, IndirectFontRef, Line, Mm, PdfDocument, PdfLayerReference, Point};
+use std::time::Duration;
+
+use crate::{error::json_error, range::sum_kwh};
+
+/// Rendering is abandoned after this long rather than holding the request.
+const RENDER_TIMEOUT: Duration = Duration::from_secs(30);
+/// Largest document returned; bigger ones fail instead of being sent.
+const MAX_BYTES: usize = 10 * 1024 * 1024;
+
+const PAGE_WIDTH: f32 = 210.0;
+const PAGE_HEIGHT: f32 = 297.0;
+const MARGIN: f32 = 15.0;
+const LINE_HEIGHT: f32 = 5.0;
+/// Table text is monospaced so columns line up without font metrics.
+const TABLE_SIZE: f32 = 9.0;
+/// Table rows below the heading on the first page and on later pages.
+const FIRST_PAGE_ROWS: usize = 40;
+const PAGE_ROWS: usize = 48;
+/// Columns of the meter label and of each figure, in characters.
+const LABEL_CHARS: usize = 40;
+const FIGURE_CHARS: usize = 13;
+
+/// One meter on a statement.
+pub struct StatementLine {
+    pub meter: String,
+    pub opening: Option<f64>,
+    pub closing: Option<f64>,
+    pub kwh: f64,
+    /// Days left out of `kwh` for lack of a reading.
+    pub missing_days: usize,
+}
+
+/// Per-meter consumption over a period, as printed by `format=pdf`.
+pub struct Statement {
+    pub target: String,
+    pub start: NaiveDate,
+    pub end: NaiveDate,
+    pub days: u32,
+    pub timezone: String,
+    pub lines: Vec<StatementLine>,
+}
+
+impl Statement {
+    fn filename(&self) -> String {
+        format!("power-usage-{}-{}.pdf", self.start, self.end)
+    }
+}
+
+/// Renders `statement` on a blocking thread and returns it as an attachment.
+/// A rendering error, panic, timeout or oversized document is a 500 with a
+/// message saying so.
+pub async fn respond(statement: Statement) -> Response {
+    let filename = statement.filename();
+    let rendering = tokio::task::spawn_blocking(move || render(&statement));
+    let rendered = tokio::time::timeout(RENDER_TIMEOUT, rendering).await;
+    let failure = match rendered {
+        Ok(Ok(Ok(bytes))) if bytes.len() <= MAX_BYTES => {
+            let headers = [
+                (header::CONTENT_TYPE, "application/pdf".to_string()),
+                (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", filename)),
+            ];
+            return (StatusCode::OK, headers, bytes).into_response();
+        }
+        Ok(Ok(Ok(bytes))) => {
+            tracing::error!(bytes = bytes.len(), "PDF report exceeds {} bytes", MAX_BYTES);
+            "PDF report too large; narrow the target"
+        }
+        Ok(Ok(Err(e))) => {
+            tracing::error!("PDF rendering failed: {}", e);
+            "PDF rendering failed"
+        }
+        Ok(Err(e)) => {
+            tracing::error!("PDF rendering panicked: {}", e);
+            "PDF rendering failed"
+        }
+        Err(_) => {
+            tracing::error!("PDF rendering timed out after {:?}", RENDER_TIMEOUT);
+            "PDF rendering timed out"
+        }
+    };
+    json_error(StatusCode::INTERNAL_SERVER_ERROR, failure)
+}
+
+/// The built-in PDF fonts only cover Latin-1 reliably.
+fn printable(text: &str) -> String {
+    text.chars()
+        .map(|c| if c.is_ascii_graphic() || c == ' ' { c } else { '?' })
+        .collect()
+}
+
+/// `text` cut or padded to `width` characters, right-aligned for figures.
+fn column(text: &str, width: usize, right: bool) -> String {
+    let text: String = text.chars().take(width).collect();
+    if right {
+        format!("{:>width$}", text, width = width)
+    } else {
+        format!("{:<width$}", text, width = width)
+    }
+}
+
+fn figure(value: Option<f64>) -> String {
+    value.map(|v| format!("{:.2}", v)).unwrap_or_else(|| "-".to_string())
+}
+
+fn table_row(cells: [&str; 5]) -> String {
+    let mut row = column(cells[0], LABEL_CHARS, false);
+    for cell in &cells[1..] {
+        row.push(' ');
+        row.push_str(&column(cell, FIGURE_CHARS, true));
+    }
+    row
+}
+
+fn rule(layer: &PdfLayerReference, y: f32) {
+    layer.add_line(Line {
+        points: vec![
+            (Point::new(Mm(MARGIN), Mm(y)), false),
+            (Point::new(Mm(PAGE_WIDTH - MARGIN), Mm(y)), false),
+        ],
+        is_closed: false,
+    });
+}
+
+fn render(statement: &Statement) -> Result<Vec<u8>, printpdf::Error> {
+    let title = format!("Power usage statement {} to {}", statement.start, statement.end);
+    let (doc, page, layer) = PdfDocument::new(&title, Mm(PAGE_WIDTH), Mm(PAGE_HEIGHT), "Statement");
+    let bold = doc.add_builtin_font(BuiltinFont::HelveticaBold)?;
+    let regular = doc.add_builtin_font(BuiltinFont::Helvetica)?;
+    let mono = doc.add_builtin_font(BuiltinFont::Courier)?;
+    let mono_bold = doc.add_builtin_font(BuiltinFont::CourierBold)?;
+
+    let rows = statement.lines.len();
+    let pages = 1 + rows.saturating_sub(FIRST_PAGE_ROWS).div_ceil(PAGE_ROWS);
+    let generated_at = Utc::now().format("%Y-%m-%d %H:%M UTC");
+    let header = table_row(["Meter", "Opening kWh", "Closing kWh", "Used kWh", "Missing days"]);
+
+    let mut lines = statement.lines.iter();
+    for number in 1..=pages {
+        let layer = if number == 1 {
+            doc.get_page(page).get_layer(layer)
+        } else {
+            let (page, layer) = doc.add_page(Mm(PAGE_WIDTH), Mm(PAGE_HEIGHT), "Statement");
+            doc.get_page(page).get_layer(layer)
+        };
+        let mut y = PAGE_HEIGHT - MARGIN;
+
+        if number == 1 {
+            layer.use_text("Power usage statement", 16.0, Mm(MARGIN), Mm(y), &bold);
+            y -= 9.0;
+            let details = [
+                format!("Target: {}", printable(&statement.target)),
+                format!(
+                    "Period: {} to {} ({} days, {})",
+                    statement.start, statement.end, statement.days, statement.timezone
+                ),
+                format!("Meters: {}", rows),
+            ];
+            for detail in details {
+                layer.use_text(detail, 10.0, Mm(MARGIN), Mm(y), &regular);
+                y -= LINE_HEIGHT + 0.5;
+            }
+            y -= 4.0;
+        }
+
+        let write = |layer: &PdfLayerReference, text: &str, y: f32, font: &IndirectFontRef| {
+            layer.use_text(text, TABLE_SIZE, Mm(MARGIN), Mm(y), font);
+        };
+        write(&layer, &header, y, &mono_bold);
+        rule(&layer, y - 1.5);
+        y -= LINE_HEIGHT + 1.0;
+
+        let capacity = if number == 1 { FIRST_PAGE_ROWS } else { PAGE_ROWS };
+        for line in lines.by_ref().take(capacity) {
+            let missing = line.missing_days.to_string();
+            let row = table_row([
+                &printable(&line.meter),
+                &figure(line.opening),
+                &figure(line.closing),
+                &figure(Some(line.kwh)),
+                if line.missing_days > 0 { &missing } else { "" },
+            ]);
+            write(&layer, &row, y, &mono);
+            y -= LINE_HEIGHT;
+        }
+
+        if number == pages {
+            let total = sum_kwh(statement.lines.iter().map(|l| l.kwh));
+            rule(&layer, y + LINE_HEIGHT - 1.5);
+            write(&layer, &table_row(["Total", "", "", &figure(Some(total)), ""]), y - 1.0, &mono_bold);
+        }
+
+        let footer = format!("Generated at {}  -  Page {} of {}", generated_at, number, pages);
+        layer.use_text(footer, 8.0, Mm(MARGIN), Mm(MARGIN - 5.0), &regular);
+    }
+    doc.save_to_bytes()
+}
@@ -0,0 +1,175 @@
+use axum::{
+    body::{Body, Bytes},
+    extract::{Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, NaiveDate, Utc};
+use futures_util::{stream, StreamExt};
+use std::{
+    collections::{BTreeSet, HashMap},
+    time::Duration,
+};
+
+use crate::{
+    api::{csv_field, range::MAX_DAYS},
+    audit,
+    config::parse_duration,
+    error::{error_response, json_error, Error},
+    natural::Natural,
+    period::{self, days_inclusive, local_midnight},
+    state::AppState,
+    usage::{resolve_selector, resolve_target},
+};
+
+/// Most rows one profile may have, meters times intervals.
+const MAX_ROWS: u64 = 1_000_000;
+
+pub async fn profile_handler(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    match handle_profile(&state, params).await {
+        Ok(response) => response,
+        Err(code) => error_response(code),
+    }
+}
+
+fn parse_date(value: Option<&String>) -> Result<NaiveDate, StatusCode> {
+    value
+        .and_then(|v| NaiveDate::parse_from_str(v, "%Y-%m-%d").ok())
+        .ok_or(StatusCode::BAD_REQUEST)
+}
+
+/// `interval=`: whole minutes that divide an hour, `30m` by default.
+fn parse_interval(params: &HashMap<String, String>) -> Result<Duration, StatusCode> {
+    let interval = params.get("interval").map_or("30m", String::as_str);
+    parse_duration(interval)
+        .filter(|step| step.subsec_nanos() == 0 && step.as_secs() % 60 == 0)
+        .filter(|step| step.as_secs() > 0 && 3600 % step.as_secs() == 0)
+        .ok_or(StatusCode::BAD_REQUEST)
+}
+
+/// One meter's rows for the intervals starting at `starts`, from readings
+/// keyed by Unix time. An interval lacking either reading, or across
+/// which the counter went backwards, has blank values.
+fn meter_rows(
+    (instance, address, name): (&str, &str, Option<&str>),
+    readings: Option<&HashMap<i64, f64>>,
+    starts: &[DateTime<Utc>],
+    step: Duration,
+    state: &AppState,
+) -> String {
+    let tz = state.config.timezone;
+    let hours = step.as_secs_f64() / 3600.0;
+    let reading = |t: DateTime<Utc>| readings.and_then(|r| r.get(&t.timestamp()).copied());
+    let mut rows = String::new();
+    for start in starts {
+        let end = *start + step;
+        let kwh = reading(*start).zip(reading(end)).map(|(a, b)| b - a).filter(|kwh| *kwh >= 0.0);
+        let cell = |value: Option<f64>| value.map(|v| v.to_string()).unwrap_or_default();
+        rows.push_str(&format!(
+            "{},{},{},{},{}",
+            instance,
+            address,
+            start.with_timezone(&tz).to_rfc3339(),
+            cell(kwh),
+            cell(kwh.map(|kwh| kwh / hours))
+        ));
+        if state.config.aliases.is_configured() {
+            rows.push(',');
+            rows.push_str(&csv_field(name.unwrap_or_default()));
+        }
+        rows.push('\n');
+    }
+    rows
+}
+
+/// Interval load profile from `start` through `end`, streamed as CSV one
+/// local day at a time, each from its own range query. The meters are
+/// fixed up front from every series with a reading anywhere in the range,
+/// so each gets one row per interval, blank where data is missing, and
+/// the row count is known before anything is sent.
+async fn handle_profile(state: &AppState, params: HashMap<String, String>) -> Result<Response, Error> {
+    if params.get("format").is_some_and(|f| f != "csv") {
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+    let (target, address) = resolve_target(&params, state)?;
+    let selector = resolve_selector(&params, &target)?;
+    let first = parse_date(params.get("start"))?;
+    let days = days_inclusive(first, parse_date(params.get("end"))?)
+        .filter(|days| (1..=MAX_DAYS).contains(days))
+        .ok_or(StatusCode::BAD_REQUEST)?;
+    let step = parse_interval(&params)?;
+
+    let tz = state.config.timezone;
+    let midnights: Vec<DateTime<Utc>> = period::dates(first, days + 1)
+        .map(|date| local_midnight(date, tz).ok_or(StatusCode::BAD_REQUEST))
+        .collect::<Result<_, _>>()?;
+    let (start, end) = (midnights[0], midnights[days as usize]);
+
+    audit::note_range(start, end);
+    let window = format!("{}s", (end - start).num_seconds() + step.as_secs() as i64);
+    let seen = state.prometheus.get_data_within(&selector, end, &window).await?.data;
+    let mut meters: Vec<(String, String)> = seen
+        .into_iter()
+        .flat_map(|(instance, samples)| samples.into_iter().map(move |s| (instance.clone(), s.address)))
+        .filter(|(_, addr)| address.as_ref().is_none_or(|a| a == addr))
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect();
+    meters.sort_by_key(|(instance, address)| (instance.clone(), Natural(address.clone())));
+
+    let intervals = (end - start).num_seconds() as u64 / step.as_secs();
+    let rows = meters.len() as u64 * intervals;
+    if rows > MAX_ROWS {
+        let message = format!(
+            "The profile would have {} rows ({} meters, {} intervals each), more than the {} allowed; \
+             narrow the target, shorten the range or use a longer interval",
+            rows,
+            meters.len(),
+            intervals,
+            MAX_ROWS
+        );
+        return Ok(json_error(StatusCode::BAD_REQUEST, message));
+    }
+    audit::note_rows(rows as usize);
+
+    let mut columns = String::from("Instance,Address,Timestamp,Interval_kWh,Avg_kW");
+    columns.push_str(if state.config.aliases.is_configured() { ",Name\n" } else { "\n" });
+    let concurrency = state.config.range_concurrency;
+    let state = state.clone();
+    let body = stream::iter(midnights.windows(2).map(|day| (day[0], day[1])).collect::<Vec<_>>())
+        .map(move |(from, to)| {
+            let (state, selector, meters) = (state.clone(), selector.clone(), meters.clone());
+            async move {
+                let series = state.prometheus.get_data_range(&selector, from, to, step).await.map_err(|code| {
+                    tracing::error!("Profile stream aborted: {}", code);
+                    std::io::Error::other(format!("profile query failed: {}", code))
+                })?;
+                let readings: HashMap<(String, String), HashMap<i64, f64>> = series
+                    .into_iter()
+                    .map(|(key, points)| (key, points.into_iter().map(|(t, v)| (t.timestamp(), v)).collect()))
+                    .collect();
+                let starts: Vec<DateTime<Utc>> =
+                    (0..).map(|i| from + step * i).take_while(|t| *t + step <= to).collect();
+                let aliases = state.config.aliases.current();
+                let chunk: String = meters
+                    .iter()
+                    .map(|key| {
+                        let name = aliases.name(&key.0, &key.1);
+                        meter_rows((&key.0, &key.1, name), readings.get(key), &starts, step, &state)
+                    })
+                    .collect();
+                Ok::<_, std::io::Error>(Bytes::from(chunk))
+            }
+        })
+        .buffered(concurrency);
+
+    let body = stream::once(async { Ok(Bytes::from(columns)) }).chain(body);
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(Body::from_stream(body))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into())
+}
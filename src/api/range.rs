@@ -0,0 +1,911 @@
+use axum::{
+    body::{Body, Bytes},
+    extract::{Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::NaiveDate;
+use futures_util::{stream, StreamExt};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
+
+use crate::{
+    anonymize::{self, Anonymizer},
+    api::{
+        pdf::{self, Statement, StatementLine},
+        table::{check_columns, parse_columns, Cell, HeaderLang, NumberFormat, Table},
+        v1::wants_csv,
+    },
+    audit,
+    corrections::{ignores_corrections, AppliedCorrection},
+    error::{error_response, json_error, Error},
+    flags::{self, Flags},
+    period::{billing_period, days_inclusive, last_date, parse_month, parse_week},
+    range::{
+        daily_rows, daily_usage_in, instance_totals, is_weekend, range_size, series_size, sum_kwh,
+        DailySeries, FailurePolicy,
+    },
+    rounding::{self, RoundingPolicy},
+    state::AppState,
+    stats::{mad, median, moving_average},
+    usage::{resolve_selector, resolve_target, wants_count_only, wants_implausible},
+};
+
+/// Longest range a single request may cover.
+pub const MAX_DAYS: u32 = 366;
+
+/// Fewer days than this make median and MAD meaningless, so anomaly
+/// detection is skipped.
+const MIN_ANOMALY_DAYS: usize = 5;
+
+/// Longest gap `interpolate=linear` fills without `max_gap_days`.
+const DEFAULT_MAX_GAP_DAYS: usize = 3;
+
+/// Columns of the per-day CSV and JSONL output, for `columns=`.
+const DAY_COLUMNS: [&str; 8] = [
+    "Target",
+    "Address",
+    "Date",
+    "Daily_KWh",
+    "Daily_KWh_Smoothed",
+    "Anomaly",
+    "Flags",
+    "Name",
+];
+/// Columns of the `split=weekday` CSV output.
+const SPLIT_COLUMNS: [&str; 5] = ["Target", "Period", "Total_KWh", "Days", "Avg_Daily_KWh"];
+
+#[derive(Serialize)]
+struct DayEntry {
+    date: NaiveDate,
+    daily_kwh: Option<f64>,
+    /// Estimated from the counters on either side of a gap, with `interpolate=linear`.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    interpolated: bool,
+    /// Beyond `MAX_DAILY_KWH` or `MAX_READING_KWH`; the value is left out
+    /// without `include_implausible=true`.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    implausible: bool,
+    /// The `error_kind` of a reading that could not be fetched, which left
+    /// the day without a value under `on_error=best_effort`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'static str>,
+    /// The meter was replaced that day, per `CHANGEOVERS_FILE`, and the
+    /// value spans both meters.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    changeover: bool,
+    /// Replaced by a correction, which `meta=true` lists.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    corrected: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    daily_kwh_smoothed: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    anomaly: Option<&'static str>,
+    flags: Flags,
+}
+
+#[derive(Serialize)]
+struct MeterReport {
+    instance: String,
+    address: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    /// The series spans a meter replacement, stitched as in
+    /// `CHANGEOVERS_FILE`.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    changeover: bool,
+    total_kwh: f64,
+    /// Share of the days with data, and the days without.
+    completeness_percent: f64,
+    missing_dates: Vec<NaiveDate>,
+    /// Whether completeness is below `min_completeness`, when given.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    incomplete: Option<bool>,
+    days: Vec<DayEntry>,
+}
+
+#[derive(Serialize)]
+struct RangeResponse {
+    target: String,
+    start: NaiveDate,
+    end: NaiveDate,
+    timezone: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    smooth: Option<usize>,
+    /// Anomalous days per instance, with `anomaly=true`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    anomalies: Option<BTreeMap<String, usize>>,
+    /// Weekday, weekend and overall totals per instance, with `split=weekday`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    split: Option<BTreeMap<String, Split>>,
+    /// The corrections in place of computed days, with `meta=true`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    corrections: Option<Vec<AppliedCorrection>>,
+    results: Vec<MeterReport>,
+}
+
+#[derive(Default, Serialize)]
+struct PeriodTotal {
+    total_kwh: f64,
+    /// Days with a reading, which the average is taken over.
+    days: usize,
+    avg_daily_kwh: Option<f64>,
+}
+
+impl PeriodTotal {
+    fn add(&mut self, kwh: f64) {
+        self.total_kwh += kwh;
+        self.days += 1;
+        self.avg_daily_kwh = Some(self.total_kwh / self.days as f64);
+    }
+
+    /// Replaces the sum, keeping the days it was taken over.
+    fn set_total(&mut self, total_kwh: f64) {
+        self.total_kwh = total_kwh;
+        self.avg_daily_kwh = (self.days > 0).then(|| total_kwh / self.days as f64);
+    }
+}
+
+#[derive(Default, Serialize)]
+struct Split {
+    weekday: PeriodTotal,
+    weekend: PeriodTotal,
+    total: PeriodTotal,
+}
+
+struct ReportOptions {
+    smooth: Option<usize>,
+    /// Number of MADs from the median beyond which a day is anomalous.
+    anomaly_mads: Option<f64>,
+    split: bool,
+    /// CSV and JSONL columns, from `columns=`.
+    columns: Option<Vec<String>>,
+    numbers: NumberFormat,
+    /// The language of the CSV header, from `header_lang=`.
+    header_lang: HeaderLang,
+    /// Completeness percentage below which a meter is flagged `incomplete`.
+    min_completeness: Option<f64>,
+    /// Leave incomplete meters out instead of flagging them.
+    exclude_incomplete: bool,
+    /// Longest gap in days filled in, with `interpolate=linear`.
+    interpolate: Option<usize>,
+    /// With `anonymize=true`.
+    anonymizer: Option<Arc<Anonymizer>>,
+    /// Keep implausible days in the values and totals, with
+    /// `include_implausible=true`.
+    include_implausible: bool,
+    /// What a failed reading does to the range, from `on_error=`.
+    on_error: FailurePolicy,
+    /// The days as computed, with `corrections=ignore`.
+    ignore_corrections: bool,
+    /// The utility's billing rule, from `rounding=utility`.
+    rounding: Option<RoundingPolicy>,
+}
+
+impl ReportOptions {
+    /// Whether every day can be rendered on its own, without seeing the
+    /// rest of its meter's series first.
+    fn is_streamable(&self) -> bool {
+        self.smooth.is_none() && self.anomaly_mads.is_none() && !self.split
+            && self.min_completeness.is_none()
+            && self.interpolate.is_none()
+    }
+
+    fn is_incomplete(&self, meter: &DailySeries) -> Option<bool> {
+        self.min_completeness.map(|min| meter.completeness_percent() < min)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Json,
+    Csv,
+    Jsonl,
+    Pdf,
+}
+
+impl Format {
+    fn from_params(params: &HashMap<String, String>) -> Result<Self, StatusCode> {
+        match params.get("format").map(String::as_str) {
+            None if wants_csv(params) => Ok(Self::Csv),
+            None | Some("json") => Ok(Self::Json),
+            Some("csv") => Ok(Self::Csv),
+            Some("jsonl") => Ok(Self::Jsonl),
+            Some("pdf") => Ok(Self::Pdf),
+            Some(_) => Err(StatusCode::BAD_REQUEST),
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            Self::Csv => "text/plain; charset=utf-8",
+            Self::Jsonl => "application/x-ndjson",
+            Self::Pdf => "application/pdf",
+        }
+    }
+}
+
+/// The reports over a period of days, each with its own way of naming it.
+#[derive(Clone, Copy)]
+pub enum Report {
+    /// `start=` through `end=`.
+    Range,
+    /// `week=YYYY-Www`, Monday through Sunday of that ISO week.
+    Weekly,
+    /// `month=YYYY-MM`, every day of that month, or with `billing_day=N` the
+    /// billing period starting on day N of that month.
+    Monthly,
+}
+
+impl Report {
+    /// The first day and the number of days `params` name.
+    fn period(self, params: &HashMap<String, String>) -> Result<(NaiveDate, u32), StatusCode> {
+        match self {
+            Self::Range => {
+                let (start, end) = (parse_date(params.get("start"))?, parse_date(params.get("end"))?);
+                Ok((start, days_inclusive(start, end).ok_or(StatusCode::BAD_REQUEST)?))
+            }
+            Self::Weekly => {
+                let monday = params.get("week").and_then(|w| parse_week(w));
+                monday.map(|monday| (monday, 7)).ok_or(StatusCode::BAD_REQUEST)
+            }
+            Self::Monthly => {
+                let month = params.get("month").and_then(|m| parse_month(m));
+                let period = month.and_then(|month| match params.get("billing_day") {
+                    None => Some(month),
+                    Some(day) => billing_period(month.0, day.parse().ok().filter(|d| (1..=31).contains(d))?),
+                });
+                period.ok_or(StatusCode::BAD_REQUEST)
+            }
+        }
+    }
+}
+
+pub async fn range_handler(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    report(&state, Report::Range, &params, false).await
+}
+
+pub async fn weekly_handler(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    report(&state, Report::Weekly, &params, false).await
+}
+
+pub async fn monthly_handler(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    report(&state, Report::Monthly, &params, false).await
+}
+
+/// `report` on the days `params` name, refused over `MAX_RESPONSE_ROWS`
+/// unless `unlimited_rows`, as a job's result is kept rather than sent in
+/// one response.
+pub async fn report(
+    state: &AppState,
+    report: Report,
+    params: &HashMap<String, String>,
+    unlimited_rows: bool,
+) -> Response {
+    match report.period(params) {
+        Ok((first, days)) => respond(handle_report(state, params, first, days, unlimited_rows).await),
+        Err(code) => error_response(code),
+    }
+}
+
+fn respond(result: Result<Response, Error>) -> Response {
+    match result {
+        Ok(response) => response,
+        Err(code) => error_response(code),
+    }
+}
+
+fn parse_date(value: Option<&String>) -> Result<NaiveDate, StatusCode> {
+    value
+        .and_then(|v| NaiveDate::parse_from_str(v, "%Y-%m-%d").ok())
+        .ok_or(StatusCode::BAD_REQUEST)
+}
+
+/// `smooth=N`: odd window size in days, 1 meaning no smoothing.
+fn parse_smooth(params: &HashMap<String, String>) -> Result<Option<usize>, StatusCode> {
+    match params.get("smooth") {
+        None => Ok(None),
+        Some(v) => match v.parse::<usize>() {
+            Ok(window) if window % 2 == 1 => Ok(Some(window)),
+            _ => Err(StatusCode::BAD_REQUEST),
+        },
+    }
+}
+
+/// Classifies each value as `high` or `low` when it lies more than
+/// `threshold` MADs from the median. Skipped for short windows and when
+/// the MAD is (numerically) zero, where any rounding noise would count.
+pub fn anomalies(values: &[Option<f64>], threshold: f64) -> Vec<Option<&'static str>> {
+    let mut present: Vec<f64> = values.iter().flatten().copied().collect();
+    let spread = (present.len() >= MIN_ANOMALY_DAYS)
+        .then(|| median(&mut present))
+        .flatten()
+        .and_then(|median| Some((median, mad(&present, median)?)))
+        .filter(|(median, mad)| *mad > 1e-9 * median.abs().max(1.0));
+
+    values
+        .iter()
+        .map(|value| {
+            let (median, mad) = spread?;
+            let deviation = (value.as_ref()? - median) / mad;
+            if deviation > threshold {
+                Some("high")
+            } else if deviation < -threshold {
+                Some("low")
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+fn meter_report(meter: DailySeries, options: &ReportOptions) -> MeterReport {
+    let incomplete = options.is_incomplete(&meter);
+    let measured: Vec<Option<f64>> = meter.days.iter().map(|(_, kwh)| *kwh).collect();
+    // Smoothing and anomalies see the filled values; totals only measured ones.
+    let filled = match options.interpolate {
+        Some(max_gap) => meter.interpolated(max_gap),
+        None => measured.iter().map(|kwh| (*kwh, false)).collect(),
+    };
+    let values: Vec<Option<f64>> = filled.iter().map(|(kwh, _)| *kwh).collect();
+    let smoothed = options.smooth.map(|window| moving_average(&values, window));
+    let anomalies = options.anomaly_mads.map(|threshold| anomalies(&values, threshold));
+
+    let days = meter
+        .days
+        .iter()
+        .enumerate()
+        .map(|(i, (date, _))| {
+            let (daily_kwh, interpolated) = filled[i];
+            let implausible = meter.implausible.contains(date);
+            let error = meter.failed.iter().find(|(day, _)| day == date).map(|(_, kind)| *kind);
+            let changeover = meter.changeovers.contains(date);
+            let corrected = meter.corrected.iter().any(|applied| applied.correction.date == *date);
+            let mut flags = Flags::default();
+            flags.set_if(flags::IMPLAUSIBLE, implausible);
+            flags.set_if(flags::CHANGEOVER, changeover);
+            flags.set_if(flags::CORRECTED, corrected);
+            if interpolated {
+                flags.set(flags::INTERPOLATED);
+            } else if error.is_some() {
+                flags.set(flags::FAILED);
+            } else if daily_kwh.is_none() && !implausible {
+                flags.set(flags::MISSING);
+            }
+            let smoothed = smoothed.as_ref().map(|s| s[i]);
+            flags.set_if(flags::SMOOTHED_PARTIAL, smoothed.is_some_and(|(_, partial)| partial));
+            flags.set_if(flags::INCOMPLETE, incomplete == Some(true));
+            DayEntry {
+                date: *date,
+                daily_kwh,
+                interpolated,
+                implausible,
+                error,
+                changeover,
+                corrected,
+                daily_kwh_smoothed: smoothed.and_then(|(average, _)| average),
+                anomaly: anomalies.as_ref().and_then(|a| a[i]),
+                flags,
+            }
+        })
+        .collect();
+
+    MeterReport {
+        total_kwh: match options.rounding {
+            Some(rounding) => rounding.total(measured.iter().flatten().copied()),
+            None => sum_kwh(measured.iter().flatten().copied()),
+        },
+        completeness_percent: meter.completeness_percent(),
+        missing_dates: meter.missing_dates(),
+        incomplete,
+        changeover: !meter.changeovers.is_empty(),
+        instance: meter.instance,
+        address: meter.address,
+        name: meter.name,
+        days,
+    }
+}
+
+/// Daily consumption per meter for `days` local days from `start`, shared
+/// by the range, weekly and monthly endpoints.
+async fn handle_report(
+    state: &AppState,
+    params: &HashMap<String, String>,
+    start: NaiveDate,
+    days: u32,
+    unlimited_rows: bool,
+) -> Result<Response, Error> {
+    if days == 0 || days > MAX_DAYS {
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+    audit::note_days(state.config.timezone, start, days);
+    let (mut target, address) = resolve_target(params, state)?;
+    let selector = resolve_selector(params, &target)?;
+    let options = ReportOptions {
+        smooth: parse_smooth(params)?,
+        anomaly_mads: params
+            .get("anomaly")
+            .is_some_and(|v| v == "true")
+            .then_some(state.config.tunables().anomaly_mads),
+        split: match params.get("split").map(String::as_str) {
+            None => false,
+            Some("weekday") => true,
+            Some(_) => return Err(StatusCode::BAD_REQUEST.into()),
+        },
+        columns: parse_columns(params),
+        numbers: NumberFormat::from_params(params)?,
+        header_lang: HeaderLang::from_params(params)?,
+        min_completeness: match params.get("min_completeness") {
+            None => None,
+            Some(v) => Some(
+                v.parse::<f64>()
+                    .ok()
+                    .filter(|v| (0.0..=100.0).contains(v))
+                    .ok_or(StatusCode::BAD_REQUEST)?,
+            ),
+        },
+        exclude_incomplete: params.get("exclude_incomplete").is_some_and(|v| v == "true"),
+        interpolate: match (params.get("interpolate").map(String::as_str), params.get("max_gap_days")) {
+            (None, None) => None,
+            (Some("linear"), None) => Some(DEFAULT_MAX_GAP_DAYS),
+            (Some("linear"), Some(days)) => {
+                Some(days.parse().ok().filter(|days| *days > 0).ok_or(StatusCode::BAD_REQUEST)?)
+            }
+            _ => return Err(StatusCode::BAD_REQUEST.into()),
+        },
+        anonymizer: anonymize::requested(state, params)?.cloned(),
+        include_implausible: wants_implausible(params),
+        on_error: FailurePolicy::from_params(params)?,
+        ignore_corrections: ignores_corrections(params)?,
+        rounding: rounding::requested(&state.config.tunables(), params)?,
+    };
+    if options.exclude_incomplete && options.min_completeness.is_none() {
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+
+    let format = Format::from_params(params)?;
+    if matches!(format, Format::Jsonl | Format::Pdf) && options.split {
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+    if let Some(columns) = &options.columns {
+        if matches!(format, Format::Json | Format::Pdf) {
+            return Err(StatusCode::BAD_REQUEST.into());
+        }
+        let known: &[&str] = if options.split { &SPLIT_COLUMNS } else { &DAY_COLUMNS };
+        if let Err(message) = check_columns(known, columns) {
+            return Ok(json_error(StatusCode::BAD_REQUEST, message));
+        }
+    }
+    if wants_count_only(params) {
+        let size = range_size(state, &selector, start, days).await?;
+        return Ok((StatusCode::NO_CONTENT, size.headers(format != Format::Json)).into_response());
+    }
+    if matches!(format, Format::Csv | Format::Jsonl) && options.is_streamable() {
+        return stream_report(state, selector, address, start, days, format, options);
+    }
+
+    let (timezone, include) = (state.config.timezone, options.include_implausible);
+    let mut series =
+        daily_usage_in(state, timezone, &selector, start, days, include, options.on_error).await?;
+    if !unlimited_rows {
+        series_size(&series).check_rows(&state.config.tunables())?;
+    }
+    if !options.ignore_corrections {
+        state.config.corrections.apply_to_series(&mut series);
+    }
+    if let Some(rounding) = options.rounding {
+        for (_, kwh) in series.iter_mut().flat_map(|meter| meter.days.iter_mut()) {
+            *kwh = kwh.map(|kwh| rounding.entry(kwh));
+        }
+    }
+    if let Some(address) = &address {
+        series.retain(|s| &s.address == address);
+    }
+    if options.exclude_incomplete {
+        series.retain(|s| options.is_incomplete(s) != Some(true));
+    }
+    audit::note_rows(series.len());
+    if let Some(anonymizer) = &options.anonymizer {
+        for meter in &mut series {
+            meter.instance = anonymizer.pseudonym(&meter.instance);
+            meter.name = meter.name.as_deref().map(|name| anonymizer.pseudonym(name));
+            for applied in &mut meter.corrected {
+                applied.correction.instance = meter.instance.clone();
+                applied.correction.author = anonymizer.pseudonym(&applied.correction.author);
+            }
+        }
+        target = anonymizer.pseudonym(&target);
+    }
+    if format == Format::Pdf {
+        let statement = statement(state, target, start, days, series, options.rounding)?;
+        return Ok(pdf::respond(statement).await);
+    }
+    let split = options.split.then(|| split_totals(state, &series, options.rounding));
+    let corrections = params
+        .get("meta")
+        .is_some_and(|v| v == "true")
+        .then(|| series.iter().flat_map(|meter| meter.corrected.iter().cloned()).collect());
+    let results: Vec<MeterReport> = series.into_iter().map(|s| meter_report(s, &options)).collect();
+
+    let table = match (format, &split) {
+        (Format::Json, _) => None,
+        (Format::Csv, Some(split)) => Some(split_table(split)),
+        (_, _) => Some(meter_table(day_table(state, &options), &results)),
+    };
+    let body = match table.map(|table| table.select(options.columns.as_deref())).transpose() {
+        Ok(table) => table.map(|table| match format {
+            Format::Jsonl => table.to_jsonl(json_key),
+            _ => table.number_format(options.numbers).header_lang(options.header_lang).to_csv(),
+        }),
+        Err(_) => return Err(StatusCode::BAD_REQUEST.into()),
+    };
+    if let Some(body) = body {
+        let content_type = [(header::CONTENT_TYPE, format.content_type())];
+        return Ok((StatusCode::OK, content_type, body).into_response());
+    }
+
+    let anomalies = options.anomaly_mads.map(|_| {
+        let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+        for meter in &results {
+            let days = meter.days.iter().filter(|d| d.anomaly.is_some()).count();
+            *counts.entry(meter.instance.clone()).or_default() += days;
+        }
+        counts
+    });
+
+    let response = RangeResponse {
+        target,
+        start,
+        end: last_date(start, days).ok_or(StatusCode::BAD_REQUEST)?,
+        timezone: state.config.timezone.name().to_string(),
+        smooth: options.smooth,
+        anomalies,
+        split,
+        corrections,
+        results,
+    };
+    Ok((StatusCode::OK, Json(response)).into_response())
+}
+
+/// Opening and closing readings and total consumption of each meter, for
+/// `format=pdf`.
+fn statement(
+    state: &AppState,
+    target: String,
+    start: NaiveDate,
+    days: u32,
+    series: Vec<DailySeries>,
+    rounding: Option<RoundingPolicy>,
+) -> Result<Statement, StatusCode> {
+    let lines = series
+        .into_iter()
+        .map(|meter| StatementLine {
+            meter: meter
+                .name
+                .unwrap_or_else(|| format!("{} / {}", meter.instance, meter.address)),
+            opening: meter.counters.first().copied().flatten(),
+            closing: meter.counters.last().copied().flatten(),
+            kwh: match rounding {
+                Some(rounding) => rounding.total(meter.days.iter().filter_map(|(_, kwh)| *kwh)),
+                None => sum_kwh(meter.days.iter().filter_map(|(_, kwh)| *kwh)),
+            },
+            missing_days: meter.days.iter().filter(|(_, kwh)| kwh.is_none()).count(),
+        })
+        .collect();
+    Ok(Statement {
+        target,
+        start,
+        end: last_date(start, days).ok_or(StatusCode::BAD_REQUEST)?,
+        days,
+        timezone: state.config.timezone.name().to_string(),
+        lines,
+    })
+}
+
+/// Streams a plain CSV or JSONL report day by day: the CSV header goes out
+/// at once and each day follows as soon as its closing readings arrive, so
+/// rows are ordered by date rather than by meter. Once the status line is
+/// sent an error cannot be reported, so a failed query is logged and the
+/// body cut short, which clients see as an incomplete response.
+fn stream_report(
+    state: &AppState,
+    selector: String,
+    address: Option<String>,
+    start: NaiveDate,
+    days: u32,
+    format: Format,
+    options: ReportOptions,
+) -> Result<Response, Error> {
+    // Every chunk is narrowed to the columns of the header.
+    let table = day_table(state, &options)
+        .select(options.columns.as_deref())
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    let columns: Vec<String> = table.headers().iter().map(ToString::to_string).collect();
+    let include = options.include_implausible;
+    let rows = daily_rows(state.clone(), selector, start, days, include, options.on_error)?;
+    let aliases = state.config.aliases.clone();
+    let config = (!options.ignore_corrections).then(|| state.config.clone());
+
+    let header = table.header_lang(options.header_lang).csv_header();
+    let header = (format == Format::Csv).then(|| Bytes::from(header));
+    let body = rows.map(move |day| {
+        let day = day.map_err(|code| {
+            tracing::error!("Report stream aborted: {}", code);
+            std::io::Error::other(format!("report query failed: {}", code))
+        })?;
+        let aliases = aliases.current();
+        let mut chunk = Table::new(DAY_COLUMNS.to_vec());
+        for mut row in day {
+            if address.as_ref().is_some_and(|a| &row.address != a) {
+                continue;
+            }
+            let correction = config.as_ref().and_then(|config| {
+                config.corrections.find(&row.instance, &row.address, row.date)
+            });
+            if let Some(correction) = &correction {
+                row.daily_kwh = Some(correction.corrected_daily_kwh);
+                (row.implausible, row.error) = (false, None);
+            }
+            if let Some(rounding) = options.rounding {
+                row.daily_kwh = row.daily_kwh.map(|kwh| rounding.entry(kwh));
+            }
+            let mut flags = Flags::default();
+            flags.set_if(flags::IMPLAUSIBLE, row.implausible);
+            flags.set_if(flags::CHANGEOVER, row.changeover);
+            flags.set_if(flags::CORRECTED, correction.is_some());
+            if !row.implausible && row.error.is_some() {
+                flags.set(flags::FAILED);
+            } else if !row.implausible && row.daily_kwh.is_none() {
+                flags.set(flags::MISSING);
+            }
+            let entry = DayEntry {
+                date: row.date,
+                daily_kwh: row.daily_kwh,
+                interpolated: false,
+                implausible: row.implausible,
+                error: row.error,
+                changeover: row.changeover,
+                corrected: correction.is_some(),
+                daily_kwh_smoothed: None,
+                anomaly: None,
+                flags,
+            };
+            let name = aliases.name(&row.instance, &row.address);
+            match &options.anonymizer {
+                Some(anonymizer) => {
+                    let name = name.map(|name| anonymizer.pseudonym(name));
+                    let instance = anonymizer.pseudonym(&row.instance);
+                    chunk.push(day_row(&instance, &row.address, name.as_deref(), &entry));
+                }
+                None => chunk.push(day_row(&row.instance, &row.address, name, &entry)),
+            }
+        }
+        let chunk = chunk.select(Some(&columns)).map_err(std::io::Error::other)?;
+        Ok::<_, std::io::Error>(Bytes::from(match format {
+            Format::Csv => chunk.number_format(options.numbers).csv_rows(),
+            _ => chunk.to_jsonl(json_key),
+        }))
+    });
+    let body = stream::iter(header.map(Ok)).chain(body);
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, format.content_type())
+        .body(Body::from_stream(body))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into())
+}
+
+/// The `DAY_COLUMNS` table, with the columns it shows by default:
+/// smoothing and anomalies when requested and names when aliases are
+/// configured.
+fn day_table(state: &AppState, options: &ReportOptions) -> Table {
+    let mut hidden = Vec::new();
+    if options.smooth.is_none() {
+        hidden.push("Daily_KWh_Smoothed");
+    }
+    if options.anomaly_mads.is_none() {
+        hidden.push("Anomaly");
+    }
+    if !state.config.aliases.is_configured() {
+        hidden.push("Name");
+    }
+    Table::new(DAY_COLUMNS.to_vec()).optional(&hidden)
+}
+
+/// One day of one meter, in `DAY_COLUMNS` order.
+fn day_row(instance: &str, address: &str, name: Option<&str>, day: &DayEntry) -> Vec<Cell> {
+    let number = |value: Option<f64>| value.map_or(Cell::Missing, Cell::Num);
+    vec![
+        Cell::Text(instance.to_string()),
+        Cell::Text(address.to_string()),
+        Cell::Text(day.date.to_string()),
+        number(day.daily_kwh),
+        number(day.daily_kwh_smoothed),
+        day.anomaly.map_or(Cell::Missing, |a| Cell::Text(a.to_string())),
+        Cell::List(day.flags.names().to_vec()),
+        name.map_or(Cell::Missing, |name| Cell::Text(name.to_string())),
+    ]
+}
+
+/// JSONL keys keep the names of the JSON report's fields.
+fn json_key(header: &str) -> String {
+    match header {
+        "Target" => "instance".to_string(),
+        header => header.to_ascii_lowercase(),
+    }
+}
+
+/// One row per meter and day, in the same meter-major order as JSON.
+fn meter_table(mut table: Table, results: &[MeterReport]) -> Table {
+    for meter in results {
+        for day in &meter.days {
+            table.push(day_row(&meter.instance, &meter.address, meter.name.as_deref(), day));
+        }
+    }
+    table
+}
+
+/// Sums each instance's days into weekday and weekend totals, using the
+/// local calendar and `HOLIDAYS_FILE`.
+fn split_totals(
+    state: &AppState,
+    series: &[DailySeries],
+    rounding: Option<RoundingPolicy>,
+) -> BTreeMap<String, Split> {
+    let holidays = &state.config.tunables().holidays;
+    instance_totals(series)
+        .into_iter()
+        .map(|(instance, days)| {
+            let mut split = Split::default();
+            for (date, kwh) in days {
+                let Some(kwh) = kwh else { continue };
+                if is_weekend(date, holidays) {
+                    split.weekend.add(kwh);
+                } else {
+                    split.weekday.add(kwh);
+                }
+                split.total.add(kwh);
+            }
+            // The utility's totals add up each meter's days, not the
+            // instance's.
+            if let Some(rounding) = rounding {
+                let meter_days: Vec<(NaiveDate, f64)> = series
+                    .iter()
+                    .filter(|meter| meter.instance == instance)
+                    .flat_map(|meter| meter.days.iter().filter_map(|(date, kwh)| Some((*date, (*kwh)?))))
+                    .collect();
+                let total = |weekend: Option<bool>| {
+                    let days = meter_days.iter().filter(|(date, _)| {
+                        weekend.is_none_or(|weekend| is_weekend(*date, holidays) == weekend)
+                    });
+                    rounding.total(days.map(|(_, kwh)| *kwh))
+                };
+                split.weekday.set_total(total(Some(false)));
+                split.weekend.set_total(total(Some(true)));
+                split.total.set_total(total(None));
+            }
+            (instance, split)
+        })
+        .collect()
+}
+
+/// Three summary rows per instance: weekday, weekend and total.
+fn split_table(split: &BTreeMap<String, Split>) -> Table {
+    let mut table = Table::new(SPLIT_COLUMNS.to_vec());
+    for (instance, split) in split {
+        for (period, total) in [
+            ("weekday", &split.weekday),
+            ("weekend", &split.weekend),
+            ("total", &split.total),
+        ] {
+            table.push(vec![
+                Cell::Text(instance.clone()),
+                Cell::Text(period.to_string()),
+                Cell::Num(total.total_kwh),
+                Cell::Int(total.days),
+                total.avg_daily_kwh.map_or(Cell::Missing, Cell::Num),
+            ]);
+        }
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn days(values: &[f64]) -> Vec<Option<f64>> {
+        values.iter().copied().map(Some).collect()
+    }
+
+    #[test]
+    fn spike_beyond_the_threshold_is_flagged() {
+        // Median 10 and MAD 1, so 30 lies 20 MADs out and 7 only 3.
+        let values = days(&[9.0, 10.0, 11.0, 10.0, 30.0, 9.0, 11.0, 7.0]);
+        let flagged = anomalies(&values, 3.5);
+        assert_eq!(flagged, [None, None, None, None, Some("high"), None, None, None]);
+        assert_eq!(anomalies(&days(&[10.0, 11.0, 9.0, 10.0, -10.0]), 3.5)[4], Some("low"));
+    }
+
+    #[test]
+    fn flat_series_is_never_flagged() {
+        let values = days(&[5.0, 5.0, 5.0, 5.0, 5.0, 5.0, 6.0]);
+        assert!(anomalies(&values, 3.5).iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn short_series_is_not_judged() {
+        let series = [1.0, 100.0, 1.0, 1.0];
+        assert!(series.len() < MIN_ANOMALY_DAYS);
+        for len in 0..=series.len() {
+            assert_eq!(anomalies(&days(&series[..len]), 3.5), vec![None; len]);
+        }
+        let gappy = vec![Some(1.0), None, Some(100.0), None, Some(1.0), None, Some(1.0)];
+        assert_eq!(anomalies(&gappy, 3.5), vec![None; gappy.len()]);
+    }
+
+    fn options(interpolate: Option<usize>) -> ReportOptions {
+        ReportOptions {
+            smooth: None,
+            anomaly_mads: None,
+            split: false,
+            columns: None,
+            numbers: NumberFormat::Plain,
+            header_lang: HeaderLang::default(),
+            min_completeness: None,
+            exclude_incomplete: false,
+            interpolate,
+            anonymizer: None,
+            include_implausible: false,
+            on_error: FailurePolicy::BestEffort,
+            ignore_corrections: false,
+            rounding: None,
+        }
+    }
+
+    /// Five days whose middle three lack readings, 30 kWh between them.
+    fn gappy_meter() -> DailySeries {
+        let counters = [Some(0.0), Some(10.0), None, None, Some(40.0), Some(50.0)];
+        let first = NaiveDate::from_ymd_opt(2025, 8, 1).unwrap();
+        DailySeries {
+            instance: "meter-a:9100".to_string(),
+            address: "1".to_string(),
+            name: None,
+            days: first
+                .iter_days()
+                .zip(counters.windows(2))
+                .map(|(date, pair)| (date, pair[0].zip(pair[1]).map(|(from, to)| to - from)))
+                .collect(),
+            counters: counters.to_vec(),
+            implausible: Vec::new(),
+            failed: Vec::new(),
+            changeovers: Vec::new(),
+            corrected: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn interpolation_leaves_the_total_alone() {
+        let measured = meter_report(gappy_meter(), &options(None));
+        let filled = meter_report(gappy_meter(), &options(Some(3)));
+        let kwh = |report: &MeterReport| report.days.iter().map(|day| day.daily_kwh).collect::<Vec<_>>();
+        assert_eq!(kwh(&measured), [Some(10.0), None, None, None, Some(10.0)]);
+        assert_eq!(kwh(&filled), [Some(10.0), Some(10.0), Some(10.0), Some(10.0), Some(10.0)]);
+        assert_eq!(measured.total_kwh, 20.0);
+        assert_eq!(filled.total_kwh, measured.total_kwh);
+        assert_eq!(filled.missing_dates, measured.missing_dates);
+    }
+}
@@ -0,0 +1,167 @@
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::NaiveDate;
+use serde::Serialize;
+use std::collections::HashMap;
+
+use crate::{
+    api_keys::restrict,
+    aliases::literal_pattern,
+    audit,
+    error::{error_response, Error},
+    range::{daily_usage, sum_kwh, DailySeries},
+    selector,
+    state::AppState,
+};
+
+const DEFAULT_TOLERANCE_PERCENT: f64 = 5.0;
+
+#[derive(Serialize)]
+struct Meter {
+    instance: String,
+    address: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    daily_kwh: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct MainMeter {
+    instance: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    address: Option<String>,
+    /// `null` when any of the main meter's addresses has no figure.
+    daily_kwh: Option<f64>,
+    meters: Vec<Meter>,
+}
+
+#[derive(Serialize)]
+struct SubMeters {
+    target: String,
+    /// Sum over the sub-meters with a figure; those in `missing` are left out.
+    daily_kwh: f64,
+    meters: usize,
+    missing: Vec<Meter>,
+}
+
+#[derive(Serialize)]
+struct ReconcileResponse {
+    date: NaiveDate,
+    timezone: String,
+    main: MainMeter,
+    subs: SubMeters,
+    /// Main minus the sum of the sub-meters.
+    discrepancy_kwh: Option<f64>,
+    /// `discrepancy_kwh` relative to the main meter.
+    discrepancy_percent: Option<f64>,
+    tolerance_percent: f64,
+    /// `null` when the main meter has no figure for the day.
+    pass: Option<bool>,
+}
+
+pub async fn reconcile_handler(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    match handle_reconcile(&state, params).await {
+        Ok(response) => response.into_response(),
+        Err(code) => error_response(code),
+    }
+}
+
+fn meter(series: DailySeries) -> Meter {
+    Meter {
+        daily_kwh: series.days.first().and_then(|(_, kwh)| *kwh),
+        instance: series.instance,
+        address: series.address,
+        name: series.name,
+    }
+}
+
+/// The main meter's local-day consumption against the sum of the sub-meters
+/// matching `subs`, both read at the same two midnights. The main meter's own
+/// series are never counted as sub-meters, so `subs` may match its instance.
+async fn handle_reconcile(
+    state: &AppState,
+    params: HashMap<String, String>,
+) -> Result<Response, Error> {
+    let main = params.get("main").ok_or(StatusCode::BAD_REQUEST)?;
+    let main_address = params.get("main_address");
+    let subs = params.get("subs").ok_or(StatusCode::BAD_REQUEST)?;
+    let date = params
+        .get("date")
+        .and_then(|v| NaiveDate::parse_from_str(v, "%Y-%m-%d").ok())
+        .ok_or(StatusCode::BAD_REQUEST)?;
+    let tolerance_percent = match params.get("tolerance_percent") {
+        Some(value) => value
+            .parse::<f64>()
+            .ok()
+            .filter(|t| t.is_finite() && *t >= 0.0)
+            .ok_or(StatusCode::BAD_REQUEST)?,
+        None => DEFAULT_TOLERANCE_PERCENT,
+    };
+
+    let main_selector = restrict(selector::energy(&literal_pattern(main), &[]), &params);
+    let subs_selector = restrict(selector::energy(subs, &[]), &params);
+    let (main_series, sub_series) = tokio::try_join!(
+        daily_usage(state, &main_selector, date, 1),
+        daily_usage(state, &subs_selector, date, 1),
+    )?;
+
+    let main_meters: Vec<Meter> = main_series
+        .into_iter()
+        .filter(|s| main_address.is_none_or(|a| *a == s.address))
+        .map(meter)
+        .collect();
+    let is_main = |m: &Meter| m.instance == *main && main_address.is_none_or(|a| *a == m.address);
+    let (present, missing): (Vec<Meter>, Vec<Meter>) = sub_series
+        .into_iter()
+        .map(meter)
+        .filter(|m| !is_main(m))
+        .partition(|m| m.daily_kwh.is_some());
+
+    let main_kwh: Option<f64> = if main_meters.is_empty() {
+        None
+    } else {
+        main_meters.iter().map(|m| m.daily_kwh).sum()
+    };
+    let subs_kwh = sum_kwh(present.iter().filter_map(|m| m.daily_kwh));
+    let discrepancy_kwh = main_kwh.map(|kwh| kwh - subs_kwh);
+    let discrepancy_percent = discrepancy_kwh
+        .zip(main_kwh)
+        .filter(|(_, kwh)| *kwh != 0.0)
+        .map(|(diff, kwh)| (diff / kwh * 10000.0).round() / 100.0);
+    let pass = discrepancy_kwh.map(|diff| match discrepancy_percent {
+        Some(percent) => percent.abs() <= tolerance_percent,
+        None => diff == 0.0,
+    });
+
+    audit::note_days(state.config.timezone, date, 1);
+    audit::note_rows(main_meters.len() + present.len() + missing.len());
+
+    let response = ReconcileResponse {
+        date,
+        timezone: state.config.timezone.name().to_string(),
+        main: MainMeter {
+            instance: main.clone(),
+            address: main_address.cloned(),
+            daily_kwh: main_kwh,
+            meters: main_meters,
+        },
+        subs: SubMeters {
+            target: subs.clone(),
+            daily_kwh: subs_kwh,
+            meters: present.len() + missing.len(),
+            missing,
+        },
+        discrepancy_kwh,
+        discrepancy_percent,
+        tolerance_percent,
+        pass,
+    };
+    Ok((StatusCode::OK, Json(response)).into_response())
+}
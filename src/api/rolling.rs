@@ -0,0 +1,196 @@
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use futures_util::future;
+use serde::Serialize;
+use std::{collections::HashMap, time::Duration};
+
+use crate::{
+    api::range::MAX_DAYS,
+    audit,
+    config::parse_duration,
+    error::{ApiError, Error},
+    natural::Natural,
+    prometheus::Sample,
+    state::AppState,
+    usage::{
+        avg_power_watt, limit_instances, period_hours, resolve_selector, resolve_target, same_series,
+        wants_truncate,
+    },
+};
+
+/// Windows one call may ask for, each costing two more queries.
+const MAX_WINDOWS: usize = 8;
+
+/// The instants one window was read at.
+#[derive(Serialize)]
+struct WindowMeta {
+    window: String,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+struct WindowUsage {
+    window: String,
+    /// `None` without a reading at the start of the window, or when the
+    /// counter went backwards during it.
+    window_kwh: Option<f64>,
+    avg_daily_kwh: Option<f64>,
+    avg_power_watt: Option<f64>,
+    /// Between the two scrapes, or the window itself when their times are
+    /// unknown.
+    elapsed_hours: Option<f64>,
+}
+
+#[derive(Serialize)]
+struct RollingMeter {
+    instance: String,
+    address: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    curr_kwh: f64,
+    /// In the order of `window=`.
+    windows: Vec<WindowUsage>,
+}
+
+#[derive(Serialize)]
+struct RollingResponse {
+    target: String,
+    windows: Vec<WindowMeta>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    truncated: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_instances: Option<usize>,
+    results: Vec<RollingMeter>,
+}
+
+pub async fn rolling_handler(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    match handle_rolling(&state, params).await {
+        Ok(response) => response.into_response(),
+        Err(error) => error.into_response(),
+    }
+}
+
+/// `window=7d,30d`: each a duration of at most `MAX_DAYS` days, `7d` when
+/// the parameter is missing.
+fn parse_windows(params: &HashMap<String, String>) -> Result<Vec<(String, Duration)>, ApiError> {
+    let max = Duration::from_secs(u64::from(MAX_DAYS) * 86400);
+    let mut windows: Vec<(String, Duration)> = Vec::new();
+    for window in params.get("window").map_or("7d", String::as_str).split(',') {
+        let window = window.trim();
+        let duration = parse_duration(window).filter(|d| *d >= Duration::from_secs(60) && *d <= max);
+        let Some(duration) = duration else {
+            let message = format!("`window` must be durations from 1m to {}d, got {:?}", MAX_DAYS, window);
+            return Err(ApiError::new(StatusCode::BAD_REQUEST, message));
+        };
+        if !windows.iter().any(|(_, d)| *d == duration) {
+            windows.push((window.to_string(), duration));
+        }
+    }
+    if windows.len() > MAX_WINDOWS {
+        let message = format!("`window` takes at most {} windows", MAX_WINDOWS);
+        return Err(ApiError::new(StatusCode::BAD_REQUEST, message));
+    }
+    Ok(windows)
+}
+
+/// Consumption over the trailing `window`s, not aligned to any calendar:
+/// each meter's reading now less its reading `window` ago, with its scrape
+/// times. Every reading is fetched concurrently.
+async fn handle_rolling(state: &AppState, params: HashMap<String, String>) -> Result<Response, ApiError> {
+    let (target, address) = resolve_target(&params, state)?;
+    let selector = resolve_selector(&params, &target)?;
+    let windows = parse_windows(&params)?;
+
+    let now = Utc::now();
+    let lookback = state.prometheus.lookback();
+    let read = |at: DateTime<Utc>| {
+        let (selector, lookback) = (&selector, &lookback);
+        async move {
+            let (data, times) = tokio::try_join!(
+                state.prometheus.get_data(selector, at),
+                state.prometheus.get_sample_times(selector, at, lookback),
+            )?;
+            Ok::<_, Error>((data.data, times))
+        }
+    };
+    let instants: Vec<DateTime<Utc>> = windows.iter().map(|(_, window)| now - *window).collect();
+    let earlier = future::try_join_all(instants.iter().map(|at| read(*at)));
+    let (curr, earlier) = tokio::try_join!(read(now), earlier)?;
+    let (mut curr_data, curr_times) = curr;
+    let truncated_from = limit_instances(state, &mut [&mut curr_data], wants_truncate(&params))?;
+    audit::note_range(instants.iter().copied().min().unwrap_or(now), now);
+
+    let aliases = state.config.aliases.current();
+    let mut results = Vec::new();
+    for (instance, samples) in curr_data {
+        for curr in samples {
+            if address.as_ref().is_some_and(|a| a != &curr.address) {
+                continue;
+            }
+            let meter = (instance.clone(), curr.address.clone());
+            let curr_time = curr_times.get(&meter).copied();
+            let usages = windows
+                .iter()
+                .zip(&earlier)
+                .map(|((window, duration), (data, times))| {
+                    let prev = same_series(data.get(&instance), &curr);
+                    window_usage(window, *duration, &curr, prev, (times.get(&meter).copied(), curr_time))
+                })
+                .collect();
+            results.push(RollingMeter {
+                name: aliases.name(&instance, &curr.address).map(str::to_string),
+                instance: instance.clone(),
+                address: curr.address,
+                curr_kwh: curr.value,
+                windows: usages,
+            });
+        }
+    }
+    results.sort_by_key(|m| (m.instance.clone(), Natural(m.address.clone())));
+    audit::note_rows(results.len());
+
+    let response = RollingResponse {
+        target,
+        windows: windows
+            .iter()
+            .zip(instants)
+            .map(|((window, _), from)| WindowMeta {
+                window: window.clone(),
+                from,
+                to: now,
+            })
+            .collect(),
+        truncated: truncated_from.is_some(),
+        total_instances: truncated_from,
+        results,
+    };
+    Ok((StatusCode::OK, Json(response)).into_response())
+}
+
+fn window_usage(
+    window: &str,
+    duration: Duration,
+    curr: &Sample,
+    prev: Option<&Sample>,
+    (prev_time, curr_time): (Option<DateTime<Utc>>, Option<DateTime<Utc>>),
+) -> WindowUsage {
+    let nominal = duration.as_secs_f64() / 3600.0;
+    let hours = prev.map(|_| period_hours(prev_time, curr_time, nominal));
+    let kwh = prev.map(|prev| curr.value - prev.value).filter(|kwh| *kwh >= 0.0);
+    WindowUsage {
+        window: window.to_string(),
+        window_kwh: kwh,
+        avg_daily_kwh: kwh.zip(hours).map(|(kwh, hours)| kwh / hours * 24.0),
+        avg_power_watt: kwh.zip(hours).map(|(kwh, hours)| avg_power_watt(kwh, hours)),
+        elapsed_hours: hours.map(|h| (h * 1000.0).round() / 1000.0),
+    }
+}
@@ -0,0 +1,142 @@
+use axum::{
+    body::to_bytes,
+    extract::{Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use chrono::Utc;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use crate::{
+    api::{admin::authorize, v1},
+    archive::TarGz,
+    cache::StaleCache,
+    error::{error_response, json_error},
+    logs,
+    prometheus::{Exchange, ExchangeLog, StatsLog},
+    request_id,
+    state::AppState,
+    version::{built_at, GIT_COMMIT, VERSION},
+};
+
+/// Log lines put in a bundle.
+const LOG_LINES: usize = 200;
+/// Long enough for the CSV to be rendered from the JSON's result.
+const PRIVATE_CACHE_TTL: Duration = Duration::from_secs(60);
+/// Largest error body kept in `error.json`.
+const MAX_ERROR_BYTES: usize = 64 * 1024;
+
+/// `GET /admin/support-bundle?target=...&date=...`: runs the v1 query for
+/// the given parameters with every Prometheus exchange recorded, and returns
+/// a `.tar.gz` of what support needs to reproduce the answer.
+pub async fn support_bundle_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(mut params): Query<HashMap<String, String>>,
+) -> Response {
+    if let Err(code) = authorize(&state, &headers) {
+        return error_response(code);
+    }
+    for key in ["csv", "format", "columns", "anonymize", "shadow"] {
+        params.remove(key);
+    }
+    let request_id = request_id::current().unwrap_or_default();
+    let generated_at = Utc::now();
+
+    // Its own caches, so the answer is computed here with every call seen,
+    // and the CSV is then rendered from the same result.
+    let stats = StatsLog::default();
+    let exchanges = ExchangeLog::default();
+    let private = AppState {
+        prometheus: state.prometheus.clone().with_stats(stats.clone()).with_exchanges(exchanges.clone()),
+        usage_cache: Some(Arc::new(StaleCache::new(PRIVATE_CACHE_TTL, Duration::ZERO))),
+        disk_cache: None,
+        in_flight: Arc::default(),
+        shadow: None,
+        ..state.clone()
+    };
+    let json = v1::render(&private, &with(&params, "meta", "true")).await;
+    let csv = match &json {
+        Ok(_) => Some(v1::render(&private, &with(&params, "csv", "true")).await),
+        Err(_) => None,
+    };
+    let exchanges = std::mem::take(&mut *exchanges.lock().unwrap());
+    let stats = std::mem::take(&mut *stats.lock().unwrap());
+
+    let mut bundle = TarGz::new(generated_at.timestamp());
+    let request = json!({
+        "path": "/api/v1/power-usage",
+        "params": params,
+        "request_id": request_id,
+        "generated_at": generated_at,
+    });
+    bundle.add("request.json", &pretty(&request));
+    match toml::to_string(&state.config.live.settings().redacted()) {
+        Ok(config) => bundle.add("config.toml", config.as_bytes()),
+        Err(e) => tracing::warn!("Failed to render configuration: {}", e),
+    }
+    let version = json!({"version": VERSION, "commit": GIT_COMMIT, "built_at": built_at()});
+    bundle.add("version.json", &pretty(&version));
+    bundle.add("promql.txt", promql(&exchanges).as_bytes());
+    bundle.add("responses.json", &pretty(&exchanges));
+    bundle.add("stats.json", &pretty(&stats));
+    match (json, csv) {
+        (Ok(json), Some(Ok(csv))) => {
+            bundle.add("result.json", json.body.as_bytes());
+            bundle.add("result.csv", csv.body.as_bytes());
+        }
+        (Err(error), _) | (Ok(_), Some(Err(error))) => {
+            let response = error.into_response();
+            let status = response.status().as_u16();
+            let body = to_bytes(response.into_body(), MAX_ERROR_BYTES).await.unwrap_or_default();
+            let body = serde_json::from_slice(&body).unwrap_or(Value::Null);
+            bundle.add("error.json", &pretty(&json!({"status": status, "body": body})));
+        }
+        (Ok(_), None) => {}
+    }
+    tracing::info!(exchanges = exchanges.len(), "Built support bundle");
+    let mut log = logs::recent(&request_id, LOG_LINES).join("\n");
+    log.push('\n');
+    bundle.add("logs.txt", log.as_bytes());
+
+    match bundle.finish() {
+        Ok(bytes) => {
+            let filename = format!("support-bundle-{}.tar.gz", request_id);
+            let headers = [
+                (header::CONTENT_TYPE, "application/gzip".to_string()),
+                (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", filename)),
+            ];
+            (StatusCode::OK, headers, bytes).into_response()
+        }
+        Err(e) => {
+            tracing::error!("Failed to compress support bundle: {}", e);
+            json_error(StatusCode::INTERNAL_SERVER_ERROR, "failed to build the support bundle")
+        }
+    }
+}
+
+fn with(params: &HashMap<String, String>, key: &str, value: &str) -> HashMap<String, String> {
+    let mut params = params.clone();
+    params.insert(key.to_string(), value.to_string());
+    params
+}
+
+fn pretty(value: &impl Serialize) -> Vec<u8> {
+    let mut bytes = serde_json::to_vec_pretty(value).unwrap_or_default();
+    bytes.push(b'\n');
+    bytes
+}
+
+/// Each expression sent, with the time it was evaluated at, in order.
+fn promql(exchanges: &[Exchange]) -> String {
+    exchanges
+        .iter()
+        .filter_map(|exchange| {
+            let query = exchange.params.get("query")?;
+            let at = exchange.params.get("time").map_or(String::new(), |time| format!("# at {}\n", time));
+            Some(format!("{}{}\n\n", at, query))
+        })
+        .collect()
+}
@@ -0,0 +1,567 @@
+use axum::http::StatusCode;
+use std::collections::HashMap;
+
+use crate::api::csv_field;
+
+/// A table cell; numbers are formatted per output format.
+pub enum Cell {
+    Text(String),
+    Int(usize),
+    Num(f64),
+    /// No value: an empty field, or `null` in JSONL.
+    Missing,
+    /// Flags and the like: `;`-separated in CSV, an array in JSONL.
+    List(Vec<&'static str>),
+}
+
+impl Cell {
+    fn to_json(&self) -> serde_json::Value {
+        match self {
+            Cell::Text(text) => text.as_str().into(),
+            Cell::Int(value) => (*value).into(),
+            Cell::Num(value) => (*value).into(),
+            Cell::Missing => serde_json::Value::Null,
+            Cell::List(items) => items.as_slice().into(),
+        }
+    }
+}
+
+/// How numbers are written in tabular output. JSON and JSONL always use
+/// `Plain`, whatever was asked for.
+#[derive(Clone, Copy, PartialEq)]
+pub enum NumberFormat {
+    /// `1234.56`, as stored.
+    Plain,
+    /// Indonesian: `1.234,56`.
+    Id,
+}
+
+impl NumberFormat {
+    /// `number_format=id`, or `locale=id-ID`; `plain` and `en` keep the default.
+    pub fn from_params(params: &HashMap<String, String>) -> Result<Self, StatusCode> {
+        match params.get("number_format").or_else(|| params.get("locale")).map(String::as_str) {
+            None | Some("plain" | "en" | "en-US") => Ok(Self::Plain),
+            Some("id" | "id-ID") => Ok(Self::Id),
+            Some(_) => Err(StatusCode::BAD_REQUEST),
+        }
+    }
+
+    /// Rewrites `number`, formatted with a `.` decimal point and no
+    /// grouping, for this format. Anything else is left alone.
+    fn localise(self, number: String) -> String {
+        if self == Self::Plain {
+            return number;
+        }
+        let (sign, digits) = number.strip_prefix('-').map_or(("", number.as_str()), |d| ("-", d));
+        let (int, fraction) = digits.split_once('.').map_or((digits, None), |(i, f)| (i, Some(f)));
+        if int.is_empty() || !int.bytes().all(|b| b.is_ascii_digit()) {
+            return number;
+        }
+        let mut localised = sign.to_string();
+        for (i, digit) in int.chars().enumerate() {
+            if i > 0 && (int.len() - i) % 3 == 0 {
+                localised.push('.');
+            }
+            localised.push(digit);
+        }
+        if let Some(fraction) = fraction {
+            localised.push(',');
+            localised.push_str(fraction);
+        }
+        localised
+    }
+}
+
+/// Column labels in a language other than English.
+struct Language {
+    /// The `header_lang=` value.
+    code: &'static str,
+    /// Each label as written in English, with its unit, and its translation.
+    /// A label missing here is written in English.
+    labels: &'static [(&'static str, &'static str)],
+}
+
+/// The languages of `header_lang=`. Adding one needs only an entry here.
+const LANGUAGES: &[Language] = &[Language {
+    code: "id",
+    labels: &[
+        ("Target", "Target"),
+        ("Address", "Alamat"),
+        ("Phase", "Fasa"),
+        ("Group", "Grup"),
+        ("Name", "Nama"),
+        ("Date", "Tanggal"),
+        ("Period", "Periode"),
+        ("Prev_kWh", "Sebelumnya_kWh"),
+        ("Prev_Wh", "Sebelumnya_Wh"),
+        ("Prev_MWh", "Sebelumnya_MWh"),
+        ("Prev_J", "Sebelumnya_J"),
+        ("Current_kWh", "Saat_Ini_kWh"),
+        ("Current_Wh", "Saat_Ini_Wh"),
+        ("Current_MWh", "Saat_Ini_MWh"),
+        ("Current_J", "Saat_Ini_J"),
+        ("Daily_KWh", "Pemakaian_Harian_kWh"),
+        ("Daily_Wh", "Pemakaian_Harian_Wh"),
+        ("Daily_MWh", "Pemakaian_Harian_MWh"),
+        ("Daily_J", "Pemakaian_Harian_J"),
+        ("Daily_KWh_Smoothed", "Pemakaian_Harian_kWh_Dihaluskan"),
+        ("Total_KWh", "Total_kWh"),
+        ("Avg_Daily_KWh", "Rata_Rata_Harian_kWh"),
+        ("Avg_Power_Watt", "Daya_Rata_Rata_Watt"),
+        ("Cost", "Biaya"),
+        ("Period_Hours", "Periode_Jam"),
+        ("Prev_Sample_Time", "Waktu_Sampel_Sebelumnya"),
+        ("Curr_Sample_Time", "Waktu_Sampel_Saat_Ini"),
+        ("Implausible", "Tidak_Wajar"),
+        ("Estimated", "Perkiraan"),
+        ("Anomaly", "Anomali"),
+        ("Flags", "Penanda"),
+        ("Days", "Hari"),
+        ("Meters", "Meter"),
+    ],
+}];
+
+/// The language column labels are written in, from `header_lang=`. Only
+/// the labels change: `columns=` and JSONL keys stay English.
+#[derive(Clone, Copy, Default)]
+pub struct HeaderLang(Option<&'static Language>);
+
+impl HeaderLang {
+    /// `header_lang=` one of `LANGUAGES`; `en` keeps the default.
+    pub fn from_params(params: &HashMap<String, String>) -> Result<Self, StatusCode> {
+        match params.get("header_lang") {
+            None => Ok(Self::default()),
+            Some(code) => Self::named(code).ok_or(StatusCode::BAD_REQUEST),
+        }
+    }
+
+    /// The language with `code`, `en` or one of `LANGUAGES`.
+    pub fn named(code: &str) -> Option<Self> {
+        if code == "en" {
+            return Some(Self::default());
+        }
+        LANGUAGES.iter().find(|language| language.code == code).map(|language| Self(Some(language)))
+    }
+
+    /// `label` in this language.
+    pub fn label(self, label: &'static str) -> &'static str {
+        let Some(language) = self.0 else {
+            return label;
+        };
+        language
+            .labels
+            .iter()
+            .find(|(english, _)| *english == label)
+            .map_or(label, |(_, translated)| translated)
+    }
+}
+
+/// Rows of a tabular report, rendered as CSV, JSONL or a Markdown table.
+/// Every output is built with all of its known columns; `select` then
+/// narrows them to the `columns=` list, or to the default set.
+pub struct Table {
+    /// Column names, as used by `columns=`.
+    headers: Vec<&'static str>,
+    /// Column names as written out, which `relabel` may change.
+    labels: Vec<&'static str>,
+    /// Columns summed into the `summary=true` totals row.
+    summed: Vec<bool>,
+    /// Totals given by `total_as` rather than summed.
+    totals: Vec<Option<f64>>,
+    /// Columns left out unless `columns=` asks for them.
+    optional: Vec<bool>,
+    rows: Vec<Vec<Cell>>,
+    /// Rows shown but left out of the totals.
+    uncounted: Vec<bool>,
+    numbers: NumberFormat,
+}
+
+/// `columns=`: a comma-separated, ordered list of column names.
+pub fn parse_columns(params: &HashMap<String, String>) -> Option<Vec<String>> {
+    params
+        .get("columns")
+        .map(|columns| columns.split(',').map(|c| c.trim().to_string()).collect())
+}
+
+/// Checks `columns` against the `known` ones, naming the valid columns when
+/// one is unknown or repeated.
+pub fn check_columns(known: &[&str], columns: &[String]) -> Result<(), String> {
+    for (i, column) in columns.iter().enumerate() {
+        if !known.contains(&column.as_str()) {
+            return Err(format!("Unknown column {:?}; valid columns are {}", column, known.join(", ")));
+        }
+        if columns[..i].contains(column) {
+            return Err(format!("Column {:?} is listed twice", column));
+        }
+    }
+    Ok(())
+}
+
+impl Table {
+    pub fn new(headers: Vec<&'static str>) -> Self {
+        Self {
+            summed: vec![false; headers.len()],
+            totals: vec![None; headers.len()],
+            optional: vec![false; headers.len()],
+            labels: headers.clone(),
+            headers,
+            rows: Vec::new(),
+            uncounted: Vec::new(),
+            numbers: NumberFormat::Plain,
+        }
+    }
+
+    /// Renames columns in the output only, e.g. for the unit of their figures.
+    pub fn relabel(mut self, label: impl Fn(&'static str) -> &'static str) -> Self {
+        self.labels = self.headers.iter().map(|header| label(header)).collect();
+        self
+    }
+
+    /// Writes the labels in `lang`, as `relabel` left them.
+    pub fn header_lang(mut self, lang: HeaderLang) -> Self {
+        self.labels = self.labels.iter().map(|label| lang.label(label)).collect();
+        self
+    }
+
+    /// Writes the decimals of CSV, Markdown and HTML output per `numbers`.
+    pub fn number_format(mut self, numbers: NumberFormat) -> Self {
+        self.numbers = numbers;
+        self
+    }
+
+    /// `value` in the table's number format, rounded to `precision`
+    /// decimals when given.
+    pub fn number(&self, value: f64, precision: Option<usize>) -> String {
+        self.numbers.localise(match precision {
+            Some(precision) => format!("{:.*}", precision, value),
+            None => value.to_string(),
+        })
+    }
+
+    /// Marks `columns` as left out of the default column set.
+    pub fn optional(mut self, columns: &[&str]) -> Self {
+        for (header, optional) in self.headers.iter().zip(&mut self.optional) {
+            *optional |= columns.contains(header);
+        }
+        self
+    }
+
+    /// Keeps exactly `columns`, in that order, or without them every column
+    /// not marked optional.
+    pub fn select(self, columns: Option<&[String]>) -> Result<Self, String> {
+        let order: Vec<usize> = match columns {
+            Some(columns) => {
+                check_columns(&self.headers, columns)?;
+                columns
+                    .iter()
+                    .filter_map(|column| self.headers.iter().position(|h| h == column))
+                    .collect()
+            }
+            None => (0..self.headers.len()).filter(|i| !self.optional[*i]).collect(),
+        };
+        let rows = self
+            .rows
+            .into_iter()
+            .map(|row| {
+                let mut cells: Vec<Option<Cell>> = row.into_iter().map(Some).collect();
+                order.iter().map(|i| cells[*i].take().unwrap_or(Cell::Missing)).collect()
+            })
+            .collect();
+        Ok(Self {
+            headers: order.iter().map(|i| self.headers[*i]).collect(),
+            labels: order.iter().map(|i| self.labels[*i]).collect(),
+            summed: order.iter().map(|i| self.summed[*i]).collect(),
+            totals: order.iter().map(|i| self.totals[*i]).collect(),
+            optional: vec![false; order.len()],
+            rows,
+            uncounted: self.uncounted,
+            numbers: self.numbers,
+        })
+    }
+
+    /// Marks `columns` as totalled in the Markdown summary row.
+    pub fn sum(mut self, columns: &[&str]) -> Self {
+        for (header, summed) in self.headers.iter().zip(&mut self.summed) {
+            *summed = columns.contains(header);
+        }
+        self
+    }
+
+    /// Reports `total` as the total of column `header`, for a sum the
+    /// cells cannot be added up to, such as one rounded by the utility.
+    pub fn total_as(&mut self, header: &str, total: f64) {
+        if let Some(i) = self.headers.iter().position(|h| *h == header) {
+            self.totals[i] = Some(total);
+        }
+    }
+
+    pub fn push(&mut self, row: Vec<Cell>) {
+        self.rows.push(row);
+        self.uncounted.push(false);
+    }
+
+    /// Adds a row that the totals leave out.
+    pub fn push_uncounted(&mut self, row: Vec<Cell>) {
+        self.rows.push(row);
+        self.uncounted.push(true);
+    }
+
+    /// Rounds the numbers in column `header` to `decimals` such that they
+    /// add up to their exact total rounded once. Each is rounded down, and
+    /// the units left over go to the values that lost most (largest remainder).
+    /// Rows left out of the totals are rounded on their own.
+    pub fn round_to_total(&mut self, header: &str, decimals: usize) {
+        let Some(i) = self.headers.iter().position(|h| *h == header) else {
+            return;
+        };
+        let scale = 10f64.powi(decimals as i32);
+        let mut values: Vec<(usize, f64, f64)> = self
+            .rows
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(row, cells)| match cells.get(i) {
+                Some(Cell::Num(value)) if self.uncounted[row] => {
+                    cells[i] = Cell::Num((value * scale).round() / scale);
+                    None
+                }
+                Some(Cell::Num(value)) => {
+                    let scaled = value * scale;
+                    Some((row, scaled.floor(), scaled - scaled.floor()))
+                }
+                _ => None,
+            })
+            .collect();
+        let total: f64 = values.iter().map(|(_, floor, rest)| floor + rest).sum();
+        let floors: f64 = values.iter().map(|(_, floor, _)| floor).sum();
+        let leftover = (total.round() - floors).max(0.0) as usize;
+
+        values.sort_by(|a, b| b.2.total_cmp(&a.2));
+        for (n, (row, floor, _)) in values.into_iter().enumerate() {
+            let units = if n < leftover { floor + 1.0 } else { floor };
+            self.rows[row][i] = Cell::Num(units / scale);
+        }
+    }
+
+    pub fn to_csv(&self) -> String {
+        self.csv_header() + &self.csv_rows()
+    }
+
+    pub fn csv_header(&self) -> String {
+        self.labels.join(",") + "\n"
+    }
+
+    /// The rows alone, for output streamed after `csv_header`.
+    pub fn csv_rows(&self) -> String {
+        self.rows.iter().map(|row| self.csv_line(row)).collect()
+    }
+
+    /// The totals row of `summary=true`, to follow `to_csv`.
+    pub fn csv_total(&self) -> String {
+        self.csv_line(&self.summary_row("Total"))
+    }
+
+    fn csv_line(&self, row: &[Cell]) -> String {
+        let cells: Vec<String> = row
+            .iter()
+            .map(|cell| match cell {
+                Cell::Text(text) => csv_field(text),
+                Cell::Int(value) => value.to_string(),
+                // A decimal comma gets the field quoted.
+                Cell::Num(value) => csv_field(&self.number(*value, None)),
+                Cell::Missing => String::new(),
+                Cell::List(items) => csv_field(&items.join(";")),
+            })
+            .collect();
+        cells.join(",") + "\n"
+    }
+
+    /// The totals of the summed columns, labelled `label` in the first
+    /// column without a total, wherever `columns=` put it.
+    fn summary_row(&self, label: &str) -> Vec<Cell> {
+        let at = self.summed.iter().position(|summed| !summed);
+        self.summed
+            .iter()
+            .enumerate()
+            .map(|(i, summed)| match (summed, at) {
+                (true, _) => self.total(i),
+                (false, Some(at)) if at == i => Cell::Text(label.to_string()),
+                (false, _) => Cell::Missing,
+            })
+            .collect()
+    }
+
+    /// One JSON object per row, keyed by `key` of each header and with the
+    /// keys in column order.
+    pub fn to_jsonl(&self, key: impl Fn(&str) -> String) -> String {
+        let keys: Vec<String> = self
+            .labels
+            .iter()
+            .map(|h| serde_json::Value::from(key(h)).to_string())
+            .collect();
+        let mut body = String::new();
+        for row in &self.rows {
+            let fields: Vec<String> = keys
+                .iter()
+                .zip(row)
+                .map(|(key, cell)| format!("{}:{}", key, cell.to_json()))
+                .collect();
+            body.push('{');
+            body.push_str(&fields.join(","));
+            body.push_str("}\n");
+        }
+        body
+    }
+
+    pub fn headers(&self) -> &[&'static str] {
+        &self.headers
+    }
+
+    pub fn labels(&self) -> &[&'static str] {
+        &self.labels
+    }
+
+    pub fn rows(&self) -> &[Vec<Cell>] {
+        &self.rows
+    }
+
+    /// Whether each column holds only numbers.
+    pub fn numeric(&self) -> Vec<bool> {
+        (0..self.headers.len())
+            .map(|i| {
+                self.rows
+                    .iter()
+                    .all(|row| !matches!(row.get(i), Some(Cell::Text(_) | Cell::List(_))))
+            })
+            .collect()
+    }
+
+    /// Sum of the numbers in column `i`, or its `total_as`; integral when
+    /// the column holds only integers.
+    fn total(&self, i: usize) -> Cell {
+        if let Some(total) = self.totals[i] {
+            return Cell::Num(total);
+        }
+        let (mut sum, mut integral) = (0.0, true);
+        let counted = self.rows.iter().zip(&self.uncounted).filter(|(_, uncounted)| !**uncounted);
+        for (row, _) in counted {
+            match row.get(i) {
+                Some(Cell::Int(value)) => sum += *value as f64,
+                Some(Cell::Num(value)) => {
+                    sum += value;
+                    integral = false;
+                }
+                _ => {}
+            }
+        }
+        if integral { Cell::Int(sum as usize) } else { Cell::Num(sum) }
+    }
+
+    /// Sum of the column named `header`, if there is one.
+    pub fn total_of(&self, header: &str) -> Option<f64> {
+        let i = self.headers.iter().position(|h| *h == header)?;
+        match self.total(i) {
+            Cell::Int(value) => Some(value as f64),
+            Cell::Num(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// A GitHub-flavoured table with numeric columns right-aligned and
+    /// decimals rounded to `precision` in the table's number format,
+    /// optionally preceded by `caption` and followed by a totals row.
+    pub fn to_markdown(&self, caption: Option<&str>, precision: usize, summary: bool) -> String {
+        let cell = |cell: &Cell| match cell {
+            Cell::Text(text) => text.replace('|', "\\|"),
+            Cell::Int(value) => value.to_string(),
+            Cell::Num(value) => self.number(*value, Some(precision)),
+            Cell::Missing => String::new(),
+            Cell::List(items) => items.join(", "),
+        };
+        let line = |cells: Vec<String>| format!("| {} |\n", cells.join(" | "));
+
+        let mut markdown = caption.map(|c| format!("{}\n\n", c)).unwrap_or_default();
+        markdown.push_str(&line(self.labels.iter().map(|h| h.to_string()).collect()));
+        markdown.push_str(&line(
+            self.numeric()
+                .iter()
+                .map(|numeric| if *numeric { "---:" } else { "---" }.to_string())
+                .collect(),
+        ));
+        for row in &self.rows {
+            markdown.push_str(&line(row.iter().map(cell).collect()));
+        }
+
+        if summary {
+            markdown.push_str(&line(self.summary_row("**Total**").iter().map(cell).collect()));
+        }
+        markdown
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn localise(numbers: NumberFormat, number: &str) -> String {
+        numbers.localise(number.to_string())
+    }
+
+    #[test]
+    fn plain_numbers_are_kept() {
+        for number in ["0", "1234567.891", "-1234.5", "12.5"] {
+            assert_eq!(localise(NumberFormat::Plain, number), number);
+        }
+    }
+
+    #[test]
+    fn indonesian_groups_thousands_with_dots_and_uses_a_decimal_comma() {
+        let id = NumberFormat::Id;
+        assert_eq!(localise(id, "0"), "0");
+        assert_eq!(localise(id, "999"), "999");
+        assert_eq!(localise(id, "1000"), "1.000");
+        assert_eq!(localise(id, "1234567.891"), "1.234.567,891");
+        assert_eq!(localise(id, "0.25"), "0,25");
+        assert_eq!(localise(id, "-1234.5"), "-1.234,5");
+        assert_eq!(localise(id, "-999999"), "-999.999");
+    }
+
+    #[test]
+    fn non_numbers_are_left_alone() {
+        for text in ["", "-", "NaN", "inf", "-inf", "1e21", ".5"] {
+            assert_eq!(localise(NumberFormat::Id, text), text);
+        }
+    }
+
+    #[test]
+    fn number_format_follows_the_parameters() {
+        let numbers = |pairs: &[(&str, &str)]| {
+            let params = pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+            NumberFormat::from_params(&params)
+        };
+        assert!(numbers(&[]) == Ok(NumberFormat::Plain));
+        assert!(numbers(&[("locale", "id-ID")]) == Ok(NumberFormat::Id));
+        assert!(numbers(&[("number_format", "plain"), ("locale", "id-ID")]) == Ok(NumberFormat::Plain));
+        assert!(numbers(&[("number_format", "fr")]) == Err(StatusCode::BAD_REQUEST));
+    }
+
+    #[test]
+    fn header_labels_follow_the_language() {
+        let id = HeaderLang::named("id").unwrap();
+        assert_eq!(id.label("Address"), "Alamat");
+        assert_eq!(id.label("Daily_KWh"), "Pemakaian_Harian_kWh");
+        assert_eq!(id.label("Not_A_Column"), "Not_A_Column");
+        let en = HeaderLang::named("en").unwrap();
+        assert_eq!(en.label("Address"), "Address");
+        assert!(HeaderLang::named("xx").is_none());
+    }
+
+    #[test]
+    fn every_language_translates_known_labels_once() {
+        for language in LANGUAGES {
+            for (i, (english, _)) in language.labels.iter().enumerate() {
+                let repeated = language.labels[..i].iter().any(|(earlier, _)| earlier == english);
+                assert!(!repeated, "{} lists {:?} twice", language.code, english);
+            }
+        }
+    }
+}
@@ -0,0 +1,226 @@
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use regex::Regex;
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    api_keys::restrict,
+    error::{error_response, ApiError, Error},
+    natural::natural,
+    selector,
+    state::AppState,
+};
+
+const DEFAULT_LIMIT: usize = 1000;
+/// The `match` of a plain `/api/v1/targets`, which lists every instance.
+const ALL: &str = ".+";
+/// Most near-matches suggested for an unknown target.
+const SUGGESTIONS: usize = 5;
+
+#[derive(Clone, Serialize)]
+pub struct TargetInfo {
+    pub instance: String,
+    pub addresses: Vec<String>,
+    pub last_sample_time: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize)]
+struct TargetsResponse<'a> {
+    total: usize,
+    offset: usize,
+    limit: usize,
+    targets: &'a [TargetInfo],
+}
+
+pub async fn targets_handler(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    match handle_targets(&state, params).await {
+        Ok(response) => response.into_response(),
+        Err(code) => error_response(code),
+    }
+}
+
+async fn handle_targets(
+    state: &AppState,
+    params: HashMap<String, String>,
+) -> Result<Response, Error> {
+    let pattern = params.get("match").map_or(ALL, String::as_str);
+    let offset = parse_param(&params, "offset", 0)?;
+    let limit = parse_param(&params, "limit", DEFAULT_LIMIT)?;
+
+    let targets = cached_targets(state, restrict(selector::energy(pattern, &[]), &params)).await?;
+
+    let start = offset.min(targets.len());
+    let end = start.saturating_add(limit).min(targets.len());
+    let response = TargetsResponse {
+        total: targets.len(),
+        offset,
+        limit,
+        targets: &targets[start..end],
+    };
+
+    Ok((StatusCode::OK, Json(response)).into_response())
+}
+
+/// The targets of the series matching `selector`, from the cache while it
+/// is fresh.
+pub async fn cached_targets(state: &AppState, selector: String) -> Result<Arc<Vec<TargetInfo>>, Error> {
+    if let Some(targets) = state.targets_cache.get(&selector) {
+        return Ok(targets);
+    }
+    let targets = Arc::new(discover_targets(state, &selector).await?);
+    state.targets_cache.insert(selector, targets.clone());
+    Ok(targets)
+}
+
+/// Every instance seen reporting since startup, refreshed in the background
+/// so `validate_target` needs no query of its own. Instances are never
+/// dropped, so one that stopped reporting stays valid until a restart.
+#[derive(Default)]
+pub struct KnownInstances(Mutex<Option<BTreeSet<String>>>);
+
+impl KnownInstances {
+    fn extend(&self, targets: &[TargetInfo]) {
+        let mut known = self.0.lock().unwrap();
+        known.get_or_insert_default().extend(targets.iter().map(|t| t.instance.clone()));
+    }
+
+    /// Whether any instance matches `pattern` in full, or `None` before the
+    /// first refresh has finished.
+    fn matching(&self, pattern: &Regex) -> Option<bool> {
+        let known = self.0.lock().unwrap();
+        Some(known.as_ref()?.iter().any(|instance| pattern.is_match(instance)))
+    }
+
+    /// Every instance seen so far, empty before the first refresh.
+    pub fn all(&self) -> Vec<String> {
+        self.0.lock().unwrap().iter().flatten().cloned().collect()
+    }
+
+    /// Up to `SUGGESTIONS` instances closest to `target` by edit distance,
+    /// measured against the instance with or without its port.
+    fn closest(&self, target: &str) -> Vec<String> {
+        let known = self.0.lock().unwrap();
+        let mut ranked: Vec<(usize, &String)> = known
+            .iter()
+            .flatten()
+            .map(|instance| {
+                let host = instance.rsplit_once(':').map_or(instance.as_str(), |(host, _)| host);
+                (edit_distance(target, instance).min(edit_distance(target, host)), instance)
+            })
+            .collect();
+        ranked.sort();
+        ranked.into_iter().take(SUGGESTIONS).map(|(_, instance)| instance.clone()).collect()
+    }
+}
+
+/// Levenshtein distance, in characters.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substituted = diagonal + usize::from(ca != *cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substituted.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// Whether `target` uses regex syntax beyond the dots of a host name or
+/// address.
+fn looks_like_regex(target: &str) -> bool {
+    target.contains(['*', '+', '?', '|', '(', ')', '[', ']', '{', '}', '^', '$', '\\'])
+}
+
+/// With `validate_target=true`, and by default for a `target` that does not
+/// look like a regex, refuses a target matching no known instance with 404
+/// and the closest instance names. Passes until the instance list is first
+/// loaded, and for other backends picked with `prom=`.
+pub fn validate_target(state: &AppState, params: &HashMap<String, String>) -> Result<(), ApiError> {
+    let Some(target) = params.get("target") else {
+        return Ok(());
+    };
+    let validate = match params.get("validate_target").map(String::as_str) {
+        None => !looks_like_regex(target),
+        Some("true") => true,
+        Some("false") => false,
+        Some(_) => return Err(StatusCode::BAD_REQUEST.into()),
+    };
+    if !validate || params.contains_key("prom") {
+        return Ok(());
+    }
+    // An invalid pattern is left for Prometheus to reject.
+    let Ok(pattern) = Regex::new(&format!("^(?:{})$", target)) else {
+        return Ok(());
+    };
+    match state.known_instances.matching(&pattern) {
+        Some(false) => Err(ApiError::unknown_target(target, state.known_instances.closest(target))),
+        _ => Ok(()),
+    }
+}
+
+/// Lists every instance every `TARGETS_CACHE_TTL`, for `validate_target`
+/// and the plain `/api/v1/targets`.
+pub async fn refresh_loop(state: AppState) {
+    let mut interval = tokio::time::interval(state.config.targets_cache_ttl);
+    loop {
+        interval.tick().await;
+        let all = selector::energy(ALL, &[]);
+        match discover_targets(&state, &all).await {
+            Ok(targets) => {
+                state.known_instances.extend(&targets);
+                state.targets_cache.insert(all, Arc::new(targets));
+            }
+            Err(code) => tracing::warn!("Instance list refresh failed: {}", code),
+        }
+    }
+}
+
+fn parse_param(params: &HashMap<String, String>, name: &str, default: usize) -> Result<usize, StatusCode> {
+    params
+        .get(name)
+        .map_or(Ok(default), |v| v.parse().map_err(|_| StatusCode::BAD_REQUEST))
+}
+
+/// Lists every instance/address pair of `selector` that reported within the
+/// configured window, with the newest sample time per instance.
+async fn discover_targets(state: &AppState, selector: &str) -> Result<Vec<TargetInfo>, Error> {
+    let times = state
+        .prometheus
+        .get_sample_times(selector, Utc::now(), &state.config.targets_window)
+        .await?;
+
+    let mut by_instance: BTreeMap<String, TargetInfo> = BTreeMap::new();
+    for ((instance, address), time) in times {
+        let info = by_instance
+            .entry(instance.clone())
+            .or_insert_with(|| TargetInfo {
+                instance,
+                addresses: Vec::new(),
+                last_sample_time: None,
+            });
+        info.addresses.push(address);
+        info.last_sample_time = info.last_sample_time.max(Some(time));
+    }
+
+    let mut targets: Vec<TargetInfo> = by_instance.into_values().collect();
+    for target in &mut targets {
+        target.addresses.sort_by(|a, b| natural(a, b));
+    }
+    Ok(targets)
+}
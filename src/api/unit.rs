@@ -0,0 +1,92 @@
+use axum::http::StatusCode;
+use std::collections::HashMap;
+
+use crate::{estimate::Estimate, usage::WeekComparison};
+
+/// Energy unit of the counter and consumption figures, from `unit=`.
+/// Everything is computed in kWh and only converted when written out, so
+/// thresholds and comparisons are unaffected; power stays in watts.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Unit {
+    Wh,
+    Kwh,
+    Mwh,
+    J,
+}
+
+impl Unit {
+    pub fn from_params(params: &HashMap<String, String>) -> Result<Self, StatusCode> {
+        match params.get("unit").map(String::as_str) {
+            None | Some("kwh") => Ok(Self::Kwh),
+            Some("wh") => Ok(Self::Wh),
+            Some("mwh") => Ok(Self::Mwh),
+            Some("j") => Ok(Self::J),
+            Some(_) => Err(StatusCode::BAD_REQUEST),
+        }
+    }
+
+    /// The `unit=` value, as recorded in `meta`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Wh => "wh",
+            Self::Kwh => "kwh",
+            Self::Mwh => "mwh",
+            Self::J => "j",
+        }
+    }
+
+    /// The unit as printed next to a figure.
+    pub fn symbol(self) -> &'static str {
+        match self {
+            Self::Wh => "Wh",
+            Self::Kwh => "kWh",
+            Self::Mwh => "MWh",
+            Self::J => "J",
+        }
+    }
+
+    /// `kwh` in this unit.
+    pub fn convert(self, kwh: f64) -> f64 {
+        match self {
+            Self::Wh => kwh * 1e3,
+            Self::Kwh => kwh,
+            Self::Mwh => kwh / 1e3,
+            Self::J => kwh * 3.6e6,
+        }
+    }
+
+    /// `comparison` with its kWh figures converted; the percentage is the same
+    /// in any unit.
+    pub fn comparison(self, comparison: WeekComparison) -> WeekComparison {
+        WeekComparison {
+            last_week_kwh: comparison.last_week_kwh.map(|kwh| self.convert(kwh)),
+            change_kwh: comparison.change_kwh.map(|kwh| self.convert(kwh)),
+            ..comparison
+        }
+    }
+
+    /// `estimate` with its reading and rate in this unit.
+    pub fn estimate(self, estimate: Estimate) -> Estimate {
+        Estimate {
+            last_reading_kwh: self.convert(estimate.last_reading_kwh),
+            rate_kwh_per_day: self.convert(estimate.rate_kwh_per_day),
+            ..estimate
+        }
+    }
+
+    /// `header` renamed for this unit when it is one of the kWh columns.
+    pub fn header(self, header: &'static str) -> &'static str {
+        match (self, header) {
+            (Self::Wh, "Prev_kWh") => "Prev_Wh",
+            (Self::Wh, "Current_kWh") => "Current_Wh",
+            (Self::Wh, "Daily_KWh") => "Daily_Wh",
+            (Self::Mwh, "Prev_kWh") => "Prev_MWh",
+            (Self::Mwh, "Current_kWh") => "Current_MWh",
+            (Self::Mwh, "Daily_KWh") => "Daily_MWh",
+            (Self::J, "Prev_kWh") => "Prev_J",
+            (Self::J, "Current_kWh") => "Current_J",
+            (Self::J, "Daily_KWh") => "Daily_J",
+            _ => header,
+        }
+    }
+}
@@ -0,0 +1,720 @@
+use axum::{
+    extract::{Query, State},
+    http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{
+    anonymize,
+    api::{
+        html,
+        table::{check_columns, parse_columns, Cell, HeaderLang, NumberFormat, Table},
+        targets::validate_target,
+        unit::Unit,
+        degraded_warning, with_caching, QueryMeta,
+    },
+    audit,
+    cache::{CacheStatus, X_CACHE},
+    error::{json_error, ApiError},
+    estimate::Estimate,
+    flags::Flags,
+    rounding::RoundingPolicy,
+    shadow,
+    state::AppState,
+    tariff::{Tariff, COST_DECIMALS},
+    usage::{
+        compute_usage, group_usage, imbalance_percent, response_size, wants_count_only, Freshness,
+        GroupUsage, SampleAge, UsageEntry, UsageRequest, WeekComparison, PHASE_LABEL,
+    },
+};
+
+static X_TRUNCATED: HeaderName = HeaderName::from_static("x-truncated");
+static X_TOTAL_INSTANCES: HeaderName = HeaderName::from_static("x-total-instances");
+static X_STORED_KEY: HeaderName = HeaderName::from_static("x-stored-key");
+
+/// Columns `columns=` can pick from in each mode. `Period_Hours`, the
+/// sample times and `Flags` are only shown on request; `Cost` is shown by default when a
+/// tariff is configured, `Name` when aliases are, and `Implausible` and
+/// `Estimated` when a row is.
+const ENTRY_COLUMNS: [&str; 14] = [
+    "Target",
+    "Address",
+    "Prev_kWh",
+    "Current_kWh",
+    "Daily_KWh",
+    "Avg_Power_Watt",
+    "Cost",
+    "Name",
+    "Period_Hours",
+    "Prev_Sample_Time",
+    "Curr_Sample_Time",
+    "Implausible",
+    "Estimated",
+    "Flags",
+];
+const PHASE_COLUMNS: [&str; 15] = [
+    "Target",
+    "Address",
+    "Phase",
+    "Prev_kWh",
+    "Current_kWh",
+    "Daily_KWh",
+    "Avg_Power_Watt",
+    "Cost",
+    "Name",
+    "Period_Hours",
+    "Prev_Sample_Time",
+    "Curr_Sample_Time",
+    "Implausible",
+    "Estimated",
+    "Flags",
+];
+const GROUP_COLUMNS: [&str; 5] = ["Group", "Daily_KWh", "Avg_Power_Watt", "Cost", "Meters"];
+const ON_REQUEST: [&str; 4] = ["Period_Hours", "Prev_Sample_Time", "Curr_Sample_Time", "Flags"];
+
+#[derive(Serialize)]
+struct PowerUsage {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    prev_kwh: f64,
+    curr_kwh: f64,
+    daily_kwh: f64,
+    avg_power_watt: f64,
+    period_hours: f64,
+    /// Deprecated, see `UsageEntry::avg_power_watt_24h`.
+    avg_power_watt_24h: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    over_threshold: Option<bool>,
+    /// Beyond `MAX_DAILY_KWH` or `MAX_READING_KWH`, and so left out of the
+    /// table totals without `include_implausible=true`.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    implausible: bool,
+    /// Extrapolated by `estimate=true`, from `estimate_basis`.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    estimated: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    estimate_basis: Option<Estimate>,
+    /// Replaced by a correction, which `meta=true` lists.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    corrected: bool,
+    /// With `meta=true`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sample_age_seconds: Option<SampleAge>,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    comparison: Option<WeekComparison>,
+    #[serde(skip)]
+    sample_times: (Option<DateTime<Utc>>, Option<DateTime<Utc>>),
+    /// Of `daily_kwh` at the configured tariff, before any unit conversion.
+    #[serde(skip)]
+    cost: Option<f64>,
+    #[serde(skip)]
+    flags: Flags,
+    /// As labelled; v1 JSON lists a meter's entries without it.
+    #[serde(skip)]
+    address: String,
+}
+
+impl PowerUsage {
+    fn in_unit(self, unit: Unit) -> Self {
+        Self {
+            prev_kwh: unit.convert(self.prev_kwh),
+            curr_kwh: unit.convert(self.curr_kwh),
+            daily_kwh: unit.convert(self.daily_kwh),
+            comparison: self.comparison.map(|c| unit.comparison(c)),
+            estimate_basis: self.estimate_basis.map(|e| unit.estimate(e)),
+            ..self
+        }
+    }
+
+    /// `Period_Hours`, the sample timestamps, `Implausible`, `Estimated` and
+    /// `Flags`, the tail of every row.
+    fn tail_cells(&self) -> [Cell; 6] {
+        let flag = |set: bool| if set { Cell::Text("true".to_string()) } else { Cell::Missing };
+        let time = |t: Option<DateTime<Utc>>| t.map_or(Cell::Missing, |t| Cell::Text(t.to_rfc3339()));
+        [
+            Cell::Num(self.period_hours),
+            time(self.sample_times.0),
+            time(self.sample_times.1),
+            flag(self.implausible),
+            flag(self.estimated),
+            Cell::List(self.flags.names().to_vec()),
+        ]
+    }
+
+    /// Puts `row` in `table`, out of the totals when implausible unless
+    /// `include_implausible=true`.
+    fn push_row(&self, table: &mut Table, row: Vec<Cell>, req: &UsageRequest) {
+        if self.implausible && !req.include_implausible {
+            table.push_uncounted(row);
+        } else {
+            table.push(row);
+        }
+    }
+}
+
+pub async fn power_usage_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(params): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    match handle_power_usage(&state, &headers, params).await {
+        Ok(response) => response.into_response(),
+        Err(error) => error.into_response(),
+    }
+}
+
+async fn handle_power_usage(
+    state: &AppState,
+    headers: &HeaderMap,
+    params: HashMap<String, String>,
+) -> Result<Response, ApiError> {
+    let format = Format::from_params(&params)?;
+    // Checked up front so the 400 can name the valid columns.
+    if let Some(Err(message)) = parse_columns(&params).map(|c| check_columns(known_columns(&params), &c)) {
+        return Ok(json_error(StatusCode::BAD_REQUEST, message));
+    }
+    let store = params.get("store").is_some_and(|v| v == "true");
+    if store && (format != Format::Csv || state.config.object_store.is_none()) {
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+    if wants_count_only(&params) {
+        let req = UsageRequest::from_params(&params, state)?;
+        validate_target(state, &params)?;
+        let size = response_size(state, &req).await?;
+        return Ok((StatusCode::NO_CONTENT, size.headers(format != Format::Json)).into_response());
+    }
+    let Output {
+        body,
+        truncated_from,
+        cache,
+        freshness,
+        last_modified,
+        as_of,
+    } = render(state, &params).await?;
+
+    let stored_key = match &state.config.object_store {
+        Some(object_store) if store => {
+            let req = UsageRequest::from_params(&params, state)?;
+            let key = object_store.key(&req.target, "adhoc", req.local_dt.date_naive());
+            let csv = body.clone().into_bytes();
+            if object_store.upload(&req.target, &key, csv, "text/csv; charset=utf-8").await.is_err() {
+                return Ok(json_error(StatusCode::BAD_GATEWAY, "Failed to store the report"));
+            }
+            Some(key)
+        }
+        _ => None,
+    };
+
+    // v1 bodies have no room for metadata, so truncation is reported in headers.
+    let mut response = match format {
+        Format::Csv => (StatusCode::OK, body).into_response(),
+        Format::Json => (StatusCode::OK, [(header::CONTENT_TYPE, "application/json")], body).into_response(),
+        Format::Markdown => {
+            (StatusCode::OK, [(header::CONTENT_TYPE, "text/markdown; charset=utf-8")], body).into_response()
+        }
+        Format::Html => (StatusCode::OK, [(header::CONTENT_TYPE, "text/html; charset=utf-8")], body).into_response(),
+    };
+    if let Some(total) = truncated_from {
+        let headers = response.headers_mut();
+        headers.insert(X_TRUNCATED.clone(), HeaderValue::from_static("true"));
+        headers.insert(X_TOTAL_INSTANCES.clone(), HeaderValue::from(total));
+    }
+    if let Some(key) = stored_key.and_then(|key| HeaderValue::try_from(key).ok()) {
+        response.headers_mut().insert(X_STORED_KEY.clone(), key);
+    }
+    if let Some(cache) = cache {
+        response.headers_mut().insert(X_CACHE.clone(), HeaderValue::from_static(cache.name()));
+    }
+    if let Some(as_of) = as_of {
+        response.headers_mut().insert(header::WARNING, degraded_warning(as_of));
+    }
+    // Storing is a side effect that a cached answer would skip.
+    let freshness = if store { Freshness::Private } else { freshness };
+    Ok(with_caching(headers, freshness, last_modified, response))
+}
+
+pub fn wants_csv(params: &HashMap<String, String>) -> bool {
+    params.get("csv").is_some_and(|v| v == "true")
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Json,
+    Csv,
+    Markdown,
+    Html,
+}
+
+impl Format {
+    fn from_params(params: &HashMap<String, String>) -> Result<Self, StatusCode> {
+        match params.get("format").map(String::as_str) {
+            None if wants_csv(params) => Ok(Self::Csv),
+            None | Some("json") => Ok(Self::Json),
+            Some("csv") => Ok(Self::Csv),
+            Some("markdown") => Ok(Self::Markdown),
+            Some("html") => Ok(Self::Html),
+            Some(_) => Err(StatusCode::BAD_REQUEST),
+        }
+    }
+}
+
+fn known_columns(params: &HashMap<String, String>) -> &'static [&'static str] {
+    if params.contains_key("group_by") {
+        &GROUP_COLUMNS
+    } else if params.get("phase_breakdown").is_some_and(|v| v == "true") {
+        &PHASE_COLUMNS
+    } else {
+        &ENTRY_COLUMNS
+    }
+}
+
+/// A v1 body: JSON, or rows for CSV, Markdown and HTML.
+enum Rendered {
+    Json(String),
+    Table(Table),
+}
+
+/// `precision=N`: decimals shown in Markdown and HTML tables.
+fn parse_precision(params: &HashMap<String, String>) -> Result<usize, StatusCode> {
+    match params.get("precision") {
+        None => Ok(2),
+        Some(v) => v.parse().ok().filter(|p| *p <= 10).ok_or(StatusCode::BAD_REQUEST),
+    }
+}
+
+/// A rendered v1 body, with what its headers report.
+pub struct Output {
+    pub body: String,
+    /// Instances matched before `truncate=true` dropped some.
+    pub truncated_from: Option<usize>,
+    pub cache: Option<CacheStatus>,
+    pub freshness: Freshness,
+    /// The newest sample behind the body.
+    pub last_modified: Option<DateTime<Utc>>,
+    /// When the results were read, if they are the last known ones because
+    /// Prometheus was down.
+    pub as_of: Option<DateTime<Utc>>,
+}
+
+/// Computes the v1 report for `params` and renders it as JSON, CSV with
+/// `csv=true`, or Markdown or HTML with `format=`. `meta=true` adds the
+/// `QueryMeta` to JSON and CSV; `columns=` picks the table columns and
+/// `number_format=` how their numbers are written. Also returns the instance count before
+/// `truncate=true` cut it short, and how the usage cache answered. Shared by
+/// the HTTP handler and the `query` command.
+pub async fn render(state: &AppState, params: &HashMap<String, String>) -> Result<Output, ApiError> {
+    let mut req = UsageRequest::from_params(params, state)?;
+    validate_target(state, params)?;
+    let anonymizer = anonymize::requested(state, params)?;
+    let format = Format::from_params(params)?;
+    let precision = parse_precision(params)?;
+    let columns = parse_columns(params);
+    let numbers = NumberFormat::from_params(params)?;
+    let header_lang = HeaderLang::from_params(params)?;
+    let unit = Unit::from_params(params)?;
+    if columns.is_some() && format == Format::Json {
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+    audit::note_range(req.prev_dt, req.curr_dt);
+    let mut usage = compute_usage(state, &req).await?;
+    usage.require_match(&req)?;
+    audit::note_rows(usage.entries.len());
+    shadow::mirror(state, &req, &usage.entries);
+    let (freshness, last_modified) = (req.freshness(state, Utc::now()), usage.last_modified());
+    let as_of = usage.degraded.then_some(usage.fetched_at);
+    // The next answer may come from Prometheus again.
+    let freshness = if as_of.is_some() { Freshness::Recent } else { freshness };
+    if let Some(anonymizer) = anonymizer {
+        usage.entries.iter_mut().for_each(|entry| anonymizer.entry(entry));
+        usage.skipped_series.iter_mut().for_each(|series| anonymizer.skipped(series));
+        req.target = anonymizer.pseudonym(&req.target);
+    }
+    let meta = params
+        .get("meta")
+        .is_some_and(|v| v == "true")
+        .then(|| {
+            QueryMeta::new(state, &req, unit, usage.truncated_from)
+                .cached(usage.cache)
+                .deduped(usage.discarded_series)
+                .skipped(&usage.skipped_series)
+                .degraded(as_of)
+                .corrected(&usage.entries)
+        });
+    // Sample ages are metadata too.
+    if meta.is_none() {
+        usage.entries.iter_mut().for_each(|entry| entry.sample_age_seconds = None);
+    }
+    let rendered = match render_entries(state, &req, usage.entries, unit, format != Format::Json)? {
+        Rendered::Table(mut table) => {
+            table.round_to_total("Cost", COST_DECIMALS);
+            let table = table.select(columns.as_deref()).map_err(|_| StatusCode::BAD_REQUEST)?;
+            let table = table.number_format(numbers).relabel(|header| unit.header(header));
+            Rendered::Table(table.header_lang(header_lang))
+        }
+        json => json,
+    };
+
+    let local_time = req.local_dt.format("%Y-%m-%d %H:%M (%:z)").to_string();
+
+    let tunables = state.config.tunables();
+    let currency = match &rendered {
+        Rendered::Table(table) if table.headers().contains(&"Cost") => {
+            tunables.tariff.as_ref().map(|tariff| tariff.currency.as_str())
+        }
+        _ => None,
+    };
+
+    let summary = params.get("summary").is_some_and(|v| v == "true");
+    // The plain v1 shapes stay untouched unless `meta=true` is given.
+    let body = match (rendered, format) {
+        (Rendered::Json(body), _) => match meta {
+            None => body,
+            Some(meta) => {
+                let meta = serde_json::to_string(&meta).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+                format!("{{\"meta\":{},\"results\":{}}}", meta, body)
+            }
+        },
+        (Rendered::Table(table), Format::Html) => html::render(&table, &req.target, &local_time, precision, unit)?,
+        (Rendered::Table(table), Format::Markdown) => {
+            let caption = params
+                .get("caption")
+                .is_some_and(|v| v == "true")
+                .then(|| match currency {
+                    Some(currency) => {
+                        format!("Power usage for `{}` at {}, costs in {}", req.target, local_time, currency)
+                    }
+                    None => format!("Power usage for `{}` at {}", req.target, local_time),
+                });
+            table.to_markdown(caption.as_deref(), precision, summary)
+        }
+        (Rendered::Table(table), _) => {
+            let mut comments = meta.map(|meta| meta.csv_comments()).unwrap_or_default();
+            if let Some(currency) = currency {
+                comments.push_str(&format!("# currency: {}\n", currency));
+            }
+            let total = if summary { table.csv_total() } else { String::new() };
+            comments + &table.to_csv() + &total
+        }
+    };
+    Ok(Output {
+        body,
+        truncated_from: usage.truncated_from,
+        cache: usage.cache,
+        freshness,
+        last_modified,
+        as_of,
+    })
+}
+
+fn render_entries(
+    state: &AppState,
+    req: &UsageRequest,
+    entries: Vec<UsageEntry>,
+    unit: Unit,
+    tabular: bool,
+) -> Result<Rendered, StatusCode> {
+    let tunables = state.config.tunables();
+    let tariff = tunables.tariff.as_ref();
+    if let Some(label) = &req.group_by {
+        let groups = group_usage(&entries, label, req.include_implausible, req.rounding);
+        return render_groups(groups, unit, tariff, req.rounding, tabular);
+    }
+    let hidden = default_hidden(state, &entries);
+    if req.phase_breakdown {
+        return render_phases(state, req, entries, unit, tabular, &hidden);
+    }
+
+    // Sorted, so the JSON lists instances in a stable order.
+    let mut result: BTreeMap<String, Vec<PowerUsage>> = BTreeMap::new();
+    // The kWh of the rows in the totals, for `rounding=utility`.
+    let mut counted_kwh = Vec::new();
+
+    for entry in entries {
+        let (
+            Some(prev_kwh),
+            Some(daily_kwh),
+            Some(avg_power_watt),
+            Some(period_hours),
+            Some(avg_power_watt_24h),
+        ) = (
+            entry.prev_kwh,
+            entry.daily_kwh,
+            entry.avg_power_watt,
+            entry.period_hours,
+            entry.avg_power_watt_24h,
+        ) else {
+            continue;
+        };
+
+        let (over_threshold, implausible) = (entry.over_threshold(), entry.is_implausible());
+        if avg_power_watt != 0.0 && (req.include_implausible || !implausible) {
+            counted_kwh.push(daily_kwh);
+        }
+        result.entry(entry.instance).or_default().push(PowerUsage {
+            name: entry.name,
+            over_threshold,
+            implausible,
+            estimated: entry.estimate.is_some(),
+            corrected: entry.correction.is_some(),
+            estimate_basis: entry.estimate,
+            sample_age_seconds: entry.sample_age_seconds,
+            comparison: entry.comparison,
+            prev_kwh,
+            curr_kwh: entry.curr_kwh,
+            daily_kwh,
+            avg_power_watt,
+            period_hours,
+            avg_power_watt_24h,
+            sample_times: (entry.prev_sample_time, entry.curr_sample_time),
+            cost: tariff.map(|tariff| tariff.cost(daily_kwh)),
+            flags: entry.flags,
+            address: entry.address,
+        }
+        .in_unit(unit));
+    }
+
+    if tabular {
+        let mut table = Table::new(ENTRY_COLUMNS.to_vec())
+            .sum(&["Daily_KWh", "Avg_Power_Watt", "Cost"])
+            .optional(&hidden);
+        if let Some(rounding) = req.rounding {
+            total_rounded(&mut table, rounding.total(counted_kwh), unit, tariff);
+        }
+        for (key, usages) in &result {
+            for usage in usages {
+                if usage.avg_power_watt != 0.0 {
+                    let mut row = vec![
+                        Cell::Text(key.clone()),
+                        Cell::Text(usage.address.clone()),
+                        Cell::Num(usage.prev_kwh),
+                        Cell::Num(usage.curr_kwh),
+                        Cell::Num(usage.daily_kwh),
+                        Cell::Num(usage.avg_power_watt),
+                        usage.cost.map_or(Cell::Missing, Cell::Num),
+                        Cell::Text(usage.name.clone().unwrap_or_default()),
+                    ];
+                    row.extend(usage.tail_cells());
+                    usage.push_row(&mut table, row, req);
+                }
+            }
+        }
+        return Ok(Rendered::Table(table));
+    }
+
+    serde_json::to_string(&result)
+        .map(Rendered::Json)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Sets the kWh total of `table` to `kwh`, as `rounding=utility` sums it,
+/// and the cost total to its price, as the utility bills it.
+fn total_rounded(table: &mut Table, kwh: f64, unit: Unit, tariff: Option<&Tariff>) {
+    table.total_as("Daily_KWh", unit.convert(kwh));
+    if let Some(tariff) = tariff {
+        table.total_as("Cost", tariff.cost(kwh));
+    }
+}
+
+/// Columns left out without `columns=`. The Cost and Name columns are only
+/// shown when a tariff or an aliases file is configured, and Implausible and
+/// Estimated when one of `entries` is, so existing imports keep their exact
+/// layout.
+fn default_hidden(state: &AppState, entries: &[UsageEntry]) -> Vec<&'static str> {
+    let mut hidden = ON_REQUEST.to_vec();
+    if state.config.tunables().tariff.is_none() {
+        hidden.push("Cost");
+    }
+    if !state.config.aliases.is_configured() {
+        hidden.push("Name");
+    }
+    if !entries.iter().any(UsageEntry::is_implausible) {
+        hidden.push("Implausible");
+    }
+    if entries.iter().all(|entry| entry.estimate.is_none()) {
+        hidden.push("Estimated");
+    }
+    hidden
+}
+
+/// A meter in `phase_breakdown` mode: single-phase meters keep the usual
+/// shape, multi-phase ones get a sub-object per phase.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum MeterUsage {
+    Single(Box<PowerUsage>),
+    Phased(PhasedUsage),
+}
+
+#[derive(Serialize)]
+struct PhasedUsage {
+    address: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    phases: BTreeMap<String, PowerUsage>,
+    imbalance_percent: Option<f64>,
+}
+
+type Phases = Vec<(String, PowerUsage)>;
+
+/// Keeps the phases of each instance/address apart instead of whichever
+/// series happens to sort first. Entries without a previous reading are
+/// dropped, as in the default mode.
+fn render_phases(
+    state: &AppState,
+    req: &UsageRequest,
+    entries: Vec<UsageEntry>,
+    unit: Unit,
+    tabular: bool,
+    hidden: &[&str],
+) -> Result<Rendered, StatusCode> {
+    let tunables = state.config.tunables();
+    let tariff = tunables.tariff.as_ref();
+    // (instance, address) -> [(phase, usage)], in the order entries arrive.
+    let mut meters: Vec<((String, String), Phases)> = Vec::new();
+    for entry in entries {
+        let (
+            Some(prev_kwh),
+            Some(daily_kwh),
+            Some(avg_power_watt),
+            Some(period_hours),
+            Some(avg_power_watt_24h),
+        ) = (
+            entry.prev_kwh,
+            entry.daily_kwh,
+            entry.avg_power_watt,
+            entry.period_hours,
+            entry.avg_power_watt_24h,
+        ) else {
+            continue;
+        };
+        let phase = entry.labels.get(PHASE_LABEL).cloned().unwrap_or_default();
+        let usage = PowerUsage {
+            over_threshold: entry.over_threshold(),
+            implausible: entry.is_implausible(),
+            estimated: entry.estimate.is_some(),
+            corrected: entry.correction.is_some(),
+            estimate_basis: entry.estimate,
+            sample_age_seconds: entry.sample_age_seconds,
+            comparison: entry.comparison,
+            name: entry.name,
+            prev_kwh,
+            curr_kwh: entry.curr_kwh,
+            daily_kwh,
+            avg_power_watt,
+            period_hours,
+            avg_power_watt_24h,
+            sample_times: (entry.prev_sample_time, entry.curr_sample_time),
+            cost: tariff.map(|tariff| tariff.cost(daily_kwh)),
+            flags: entry.flags,
+            address: entry.address.clone(),
+        }
+        .in_unit(unit);
+        let key = (entry.instance, entry.address);
+        match meters.iter_mut().find(|(k, _)| k == &key) {
+            Some((_, phases)) => phases.push((phase, usage)),
+            None => meters.push((key, vec![(phase, usage)])),
+        }
+    }
+
+    if tabular {
+        let mut table = Table::new(PHASE_COLUMNS.to_vec())
+            .sum(&["Daily_KWh", "Avg_Power_Watt", "Cost"])
+            .optional(hidden);
+        for ((instance, address), phases) in &meters {
+            for (phase, usage) in phases {
+                if usage.avg_power_watt == 0.0 {
+                    continue;
+                }
+                let mut row = vec![
+                    Cell::Text(instance.clone()),
+                    Cell::Text(address.clone()),
+                    Cell::Text(phase.clone()),
+                    Cell::Num(usage.prev_kwh),
+                    Cell::Num(usage.curr_kwh),
+                    Cell::Num(usage.daily_kwh),
+                    Cell::Num(usage.avg_power_watt),
+                    usage.cost.map_or(Cell::Missing, Cell::Num),
+                    Cell::Text(usage.name.clone().unwrap_or_default()),
+                ];
+                row.extend(usage.tail_cells());
+                usage.push_row(&mut table, row, req);
+            }
+        }
+        return Ok(Rendered::Table(table));
+    }
+
+    let mut result: BTreeMap<String, Vec<MeterUsage>> = BTreeMap::new();
+    for ((instance, address), mut phases) in meters {
+        let meter = if phases.len() == 1 {
+            MeterUsage::Single(Box::new(phases.remove(0).1))
+        } else {
+            let dailies: Vec<f64> = phases.iter().map(|(_, u)| u.daily_kwh).collect();
+            MeterUsage::Phased(PhasedUsage {
+                address,
+                name: phases.iter_mut().find_map(|(_, u)| u.name.take()),
+                imbalance_percent: imbalance_percent(&dailies),
+                phases: phases.into_iter().collect(),
+            })
+        };
+        result.entry(instance).or_default().push(meter);
+    }
+    serde_json::to_string(&result)
+        .map(Rendered::Json)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+#[derive(Serialize)]
+struct GroupedPowerUsage {
+    daily_kwh: f64,
+    avg_power_watt: f64,
+    meters: usize,
+}
+
+/// With `group_by`, keys are label values and each holds the summed usage.
+fn render_groups(
+    groups: Vec<GroupUsage>,
+    unit: Unit,
+    tariff: Option<&Tariff>,
+    rounding: Option<RoundingPolicy>,
+    tabular: bool,
+) -> Result<Rendered, StatusCode> {
+    if tabular {
+        let mut table = Table::new(GROUP_COLUMNS.to_vec())
+            .sum(&["Daily_KWh", "Avg_Power_Watt", "Cost", "Meters"]);
+        if tariff.is_none() {
+            table = table.optional(&["Cost"]);
+        }
+        if let Some(rounding) = rounding {
+            let kwh = rounding.total(groups.iter().flat_map(|group| group.meter_kwh.iter().copied()));
+            total_rounded(&mut table, kwh, unit, tariff);
+        }
+        for group in &groups {
+            table.push(vec![
+                Cell::Text(group.group.clone()),
+                Cell::Num(unit.convert(group.daily_kwh)),
+                Cell::Num(group.avg_power_watt),
+                tariff.map_or(Cell::Missing, |tariff| Cell::Num(tariff.cost(group.daily_kwh))),
+                Cell::Int(group.meters),
+            ]);
+        }
+        return Ok(Rendered::Table(table));
+    }
+
+    let result: BTreeMap<String, GroupedPowerUsage> = groups
+        .into_iter()
+        .map(|group| {
+            let usage = GroupedPowerUsage {
+                daily_kwh: unit.convert(group.daily_kwh),
+                avg_power_watt: group.avg_power_watt,
+                meters: group.meters,
+            };
+            (group.group, usage)
+        })
+        .collect();
+    serde_json::to_string(&result)
+        .map(Rendered::Json)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
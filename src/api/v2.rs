@@ -0,0 +1,371 @@
+use axum::{
+    extract::{Query, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use futures_util::future;
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    anonymize,
+    api::{degraded_warning, targets::validate_target, unit::Unit, with_caching, QueryMeta},
+    cache::X_CACHE,
+    error::ApiError,
+    estimate::Estimate,
+    flags::Flags,
+    prometheus::{Prometheus, QueryStats, SkippedSeries, StatsLog},
+    shadow::{self, ShadowDiff},
+    state::AppState,
+    usage::{
+        compute_usage, group_usage, response_size, uncached_usage, wants_count_only, Freshness, GroupUsage,
+        PowerGauge, ResponseSize, UsageEntry, UsageRequest, WeekComparison,
+    },
+};
+
+#[derive(Serialize)]
+struct Meta {
+    #[serde(flatten)]
+    query: QueryMeta,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    group_by: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    explain: Option<Explain>,
+    /// With `prom=all`, the sites that answered and those that failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sites: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    failed_sites: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    debug: Option<Debug>,
+}
+
+/// With `debug=true`: what each query sent to Prometheus cost it, and the
+/// series left out with the values they had. With `shadow=true`: how
+/// `SHADOW_PROMETHEUS_HOST` answered the same request.
+#[derive(Serialize)]
+struct Debug {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    queries: Option<Vec<QueryStats>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    skipped_series: Option<Vec<SkippedSeries>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    shadow: Option<ShadowDiff>,
+}
+
+/// With `explain=true`: the merged selector and the queries sent to Prometheus.
+#[derive(Serialize)]
+struct Explain {
+    selector: String,
+    /// How many instance chunks the query was split into by `CHUNK_SIZE`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    chunks: Option<usize>,
+    queries: Vec<ExplainQuery>,
+}
+
+#[derive(Serialize)]
+struct ExplainQuery {
+    expr: String,
+    time: DateTime<Utc>,
+}
+
+#[derive(Serialize)]
+struct PowerUsageEntry {
+    /// The `PROMETHEUS_SITES` backend the entry came from, with `prom=`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    site: Option<String>,
+    instance: String,
+    address: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    /// Zone the day was computed in, with `TIMEZONES_FILE`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timezone: Option<String>,
+    prev_kwh: Option<f64>,
+    curr_kwh: f64,
+    daily_kwh: Option<f64>,
+    avg_power_watt: Option<f64>,
+    period_hours: Option<f64>,
+    /// Deprecated, see `UsageEntry::avg_power_watt_24h`.
+    avg_power_watt_24h: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    over_threshold: Option<bool>,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    comparison: Option<WeekComparison>,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    power_gauge: Option<PowerGauge>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    members: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    part_of: Option<String>,
+    prev_sample_time: Option<DateTime<Utc>>,
+    curr_sample_time: Option<DateTime<Utc>>,
+    /// Beyond `MAX_DAILY_KWH` or `MAX_READING_KWH`; also in `flags`.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    implausible: bool,
+    /// Added by `estimate=true`; also in `flags`.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    estimated: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    estimate_basis: Option<Estimate>,
+    /// Replaced by a correction, listed in `meta.corrections`; also in `flags`.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    corrected: bool,
+    flags: Flags,
+}
+
+#[derive(Serialize)]
+struct PowerUsageResponse {
+    meta: Meta,
+    results: Results,
+}
+
+/// Per-meter entries, or per-group sums when `group_by` is given.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Results {
+    Meters(Vec<PowerUsageEntry>),
+    Groups(Vec<GroupUsage>),
+}
+
+impl PowerUsageEntry {
+    /// The entry with its kWh figures converted to `unit`.
+    fn new(site: Option<String>, entry: UsageEntry, unit: Unit) -> Self {
+        Self {
+            site,
+            over_threshold: entry.over_threshold(),
+            implausible: entry.is_implausible(),
+            estimated: entry.estimate.is_some(),
+            corrected: entry.correction.is_some(),
+            estimate_basis: entry.estimate.map(|e| unit.estimate(e)),
+            comparison: entry.comparison.map(|c| unit.comparison(c)),
+            power_gauge: entry.power_gauge,
+            members: entry.members,
+            part_of: entry.part_of,
+            instance: entry.instance,
+            address: entry.address,
+            name: entry.name,
+            timezone: entry.timezone.map(|tz| tz.name().to_string()),
+            prev_kwh: entry.prev_kwh.map(|kwh| unit.convert(kwh)),
+            curr_kwh: unit.convert(entry.curr_kwh),
+            daily_kwh: entry.daily_kwh.map(|kwh| unit.convert(kwh)),
+            avg_power_watt: entry.avg_power_watt,
+            period_hours: entry.period_hours,
+            avg_power_watt_24h: entry.avg_power_watt_24h,
+            prev_sample_time: entry.prev_sample_time,
+            curr_sample_time: entry.curr_sample_time,
+            flags: entry.flags,
+        }
+    }
+}
+
+pub async fn power_usage_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(params): Query<HashMap<String, String>>,
+) -> impl IntoResponse {
+    match handle_power_usage(&state, &headers, params).await {
+        Ok(response) => response.into_response(),
+        Err(error) => error.into_response(),
+    }
+}
+
+/// The backends `prom=` asks for, with the site names to tag results with:
+/// `PROMETHEUS_HOST` by default, one named site, or every site with `all`.
+fn backends(
+    state: &AppState,
+    params: &HashMap<String, String>,
+) -> Result<Vec<(Option<String>, AppState)>, StatusCode> {
+    let site = |(name, prometheus): &(String, Prometheus)| {
+        (Some(name.clone()), state.with_backend(prometheus.clone()))
+    };
+    match params.get("prom").map(String::as_str) {
+        None => Ok(vec![(None, state.clone())]),
+        Some("all") if !state.sites.is_empty() => Ok(state.sites.iter().map(site).collect()),
+        Some(name) => state
+            .sites
+            .iter()
+            .find(|(site, _)| site == name)
+            .map(|found| vec![site(found)])
+            .ok_or(StatusCode::BAD_REQUEST),
+    }
+}
+
+async fn handle_power_usage(
+    state: &AppState,
+    headers: &HeaderMap,
+    params: HashMap<String, String>,
+) -> Result<Response, ApiError> {
+    let mut req = UsageRequest::from_params(&params, state)?;
+    validate_target(state, &params)?;
+    let unit = Unit::from_params(&params)?;
+    let backends = backends(state, &params)?;
+    let anonymizer = anonymize::requested(state, &params)?;
+    let forced_shadow = shadow::requested(state, &params)?;
+    // Selectors, query stats and shadow diffs would show the names being hidden.
+    let traced = ["explain", "debug", "shadow"]
+        .iter()
+        .any(|name| params.get(*name).is_some_and(|v| v == "true"));
+    if anonymizer.is_some() && traced {
+        return Err(StatusCode::BAD_REQUEST.into());
+    }
+    if wants_count_only(&params) {
+        let sizes = future::try_join_all(backends.iter().map(|(_, backend)| response_size(backend, &req)));
+        let size = sizes.await?.into_iter().fold(ResponseSize::default(), ResponseSize::add);
+        return Ok((StatusCode::NO_CONTENT, size.headers(false)).into_response());
+    }
+    let explain = if params.get("explain").is_some_and(|v| v == "true") {
+        let prometheus = &state.prometheus;
+        let chunks = prometheus.pair_chunks(&req.selector, req.curr_dt, req.prev_dt).await?;
+        Some(Explain {
+            selector: req.selector.clone(),
+            chunks: (chunks.len() > 1).then_some(chunks.len()),
+            queries: chunks
+                .iter()
+                .flat_map(|chunk| prometheus.pair_queries(chunk, req.curr_dt, req.prev_dt))
+                .map(|(expr, time)| ExplainQuery { expr, time })
+                .collect(),
+        })
+    } else {
+        None
+    };
+
+    // One site being down only drops its results; the request fails when
+    // every backend does. `debug=true` skips the cache, whose answers have
+    // no stats.
+    let stats = params.get("debug").is_some_and(|v| v == "true").then(StatsLog::default);
+    let request = &req;
+    let usage = backends.iter().map(|(_, backend)| {
+        let traced = stats
+            .as_ref()
+            .map(|log| backend.with_backend(backend.prometheus.clone().with_stats(log.clone())));
+        async move {
+            match traced {
+                Some(traced) => uncached_usage(&traced, request).await,
+                None => compute_usage(backend, request).await,
+            }
+        }
+    });
+    let outcomes = future::join_all(usage).await;
+    let queries = stats.map(|log| std::mem::take(&mut *log.lock().unwrap()));
+    let (mut entries, mut answered, mut failed, mut error) = (Vec::new(), Vec::new(), Vec::new(), None);
+    let (mut total_instances, mut discarded_series, mut skipped_series) = (0, 0, Vec::new());
+    let (mut truncated, mut matched) = (false, false);
+    // The worst of the backends' answers, so `hit` means nothing was refetched.
+    let mut cache = None;
+    let mut last_modified = None;
+    // The oldest fetch among the backends answered with the last known results.
+    let mut as_of = None;
+    // What `PROMETHEUS_HOST` answered, to compare with `shadow=true`.
+    let mut primary = None;
+    for ((site, backend), outcome) in backends.iter().zip(outcomes) {
+        match outcome {
+            Ok(usage) => {
+                match forced_shadow {
+                    Some(_) => primary = Some(usage.entries.clone()),
+                    None if site.is_none() => shadow::mirror(state, &req, &usage.entries),
+                    None => {}
+                }
+                matched |= usage.matched;
+                let instances: HashSet<&str> = usage.entries.iter().map(|e| e.instance.as_str()).collect();
+                total_instances += usage.truncated_from.unwrap_or(instances.len());
+                truncated |= usage.truncated_from.is_some();
+                discarded_series += usage.discarded_series;
+                cache = cache.max(usage.cache);
+                last_modified = last_modified.max(usage.last_modified());
+                if usage.degraded {
+                    as_of = Some(as_of.map_or(usage.fetched_at, |t: DateTime<Utc>| t.min(usage.fetched_at)));
+                }
+                skipped_series.extend(usage.skipped_series);
+                entries.extend(usage.entries.into_iter().map(|e| (site.clone(), e)));
+                answered.push((site.clone(), backend));
+            }
+            Err(failure) => {
+                tracing::warn!(site = site.as_deref(), "Usage query failed: {}", failure);
+                failed.extend(site.clone());
+                error.get_or_insert(failure);
+            }
+        }
+    }
+    let Some((_, first)) = answered.first() else {
+        return Err(error.unwrap_or(StatusCode::BAD_GATEWAY.into()).into());
+    };
+    if !matched && !req.empty_ok {
+        return Err(ApiError::no_series(&req.target));
+    }
+    let shadow = match forced_shadow.zip(primary) {
+        Some((shadow, primary)) => Some(shadow.compare(state, &req, &primary).await),
+        None => None,
+    };
+    if let Some(anonymizer) = anonymizer {
+        entries.iter_mut().for_each(|(_, entry)| anonymizer.entry(entry));
+        skipped_series.iter_mut().for_each(|series| anonymizer.skipped(series));
+        req.target = anonymizer.pseudonym(&req.target);
+    }
+    let debug = (queries.is_some() || shadow.is_some()).then(|| Debug {
+        skipped_series: queries.as_ref().map(|_| skipped_series.clone()),
+        queries,
+        shadow,
+    });
+
+    let mut query = QueryMeta::new(first, &req, unit, truncated.then_some(total_instances))
+        .cached(cache)
+        .deduped(discarded_series)
+        .skipped(&skipped_series)
+        .degraded(as_of)
+        .corrected(entries.iter().map(|(_, e)| e));
+    let results = match &req.group_by {
+        Some(label) => {
+            let entries: Vec<UsageEntry> = entries.into_iter().map(|(_, e)| e).collect();
+            Results::Groups(
+                group_usage(&entries, label, req.include_implausible, req.rounding)
+                    .into_iter()
+                    .map(|group| GroupUsage { daily_kwh: unit.convert(group.daily_kwh), ..group })
+                    .collect(),
+            )
+        }
+        None => Results::Meters(
+            entries
+                .into_iter()
+                .map(|(site, e)| PowerUsageEntry::new(site, e, unit))
+                .collect(),
+        ),
+    };
+
+    let fanned_out = params.get("prom").is_some_and(|v| v == "all");
+    // A site that failed, or one that was down, may answer on the next try.
+    let freshness = match (traced, failed.is_empty() && as_of.is_none()) {
+        (true, _) => Freshness::Private,
+        (false, false) => Freshness::Recent,
+        (false, true) => req.freshness(state, Utc::now()),
+    };
+    if fanned_out {
+        query.backend = answered
+            .iter()
+            .map(|(_, backend)| backend.prometheus.display_url())
+            .collect::<Vec<_>>()
+            .join(", ");
+    }
+    let response = PowerUsageResponse {
+        meta: Meta {
+            query,
+            group_by: req.group_by,
+            explain,
+            sites: fanned_out.then(|| answered.iter().filter_map(|(site, _)| site.clone()).collect()),
+            failed_sites: fanned_out.then_some(failed),
+            debug,
+        },
+        results,
+    };
+
+    let mut response = (StatusCode::OK, Json(response)).into_response();
+    if let Some(cache) = cache {
+        response.headers_mut().insert(X_CACHE.clone(), HeaderValue::from_static(cache.name()));
+    }
+    if let Some(as_of) = as_of {
+        response.headers_mut().insert(header::WARNING, degraded_warning(as_of));
+    }
+    Ok(with_caching(headers, freshness, last_modified, response))
+}
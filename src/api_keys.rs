@@ -0,0 +1,400 @@
+use axum::{
+    extract::{Query, Request, State},
+    http::{header, HeaderMap, StatusCode, Uri},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Datelike, Months, NaiveDate, Utc};
+use chrono_tz::Tz;
+use regex::Regex;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use crate::{
+    atomic_file::write_atomically,
+    api::admin::constant_time_eq,
+    config::Settings,
+    error::{json_error, ApiError},
+    period::local_midnight,
+    selector,
+    state::AppState,
+};
+
+/// How often changed counters are written to `KEYS_STATE_FILE`.
+const PERSIST_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The query parameter `api_key_middleware` sets to the `targets` of a key
+/// limited to some, in place of any the caller sent.
+pub const KEY_TARGETS: &str = "key_targets";
+
+/// Routes outside `/api/` that serve usage and need a key as well.
+const GUARDED: [&str; 2] = ["/annotations", "/probe"];
+/// The route whose target is in the request body, so it is only narrowed
+/// by `restrict`.
+const TARGET_IN_BODY: &str = "/annotations";
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeyEntry {
+    name: String,
+    key: String,
+    targets: Option<String>,
+    daily_quota: Option<u64>,
+    monthly_quota: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeysFile {
+    #[serde(default)]
+    key: Vec<KeyEntry>,
+}
+
+/// A client allowed on `/api/*`, from `KEYS_FILE`:
+///
+/// ```toml
+/// [[key]]
+/// name = "acme"
+/// key = "3f9c0d7e6b..."
+/// targets = "meter-a\\..*"
+/// daily_quota = 1000
+/// monthly_quota = 20000
+/// ```
+struct ApiKey {
+    name: String,
+    key: String,
+    /// The pattern as configured, and anchored, that the requested target
+    /// must match; any target without it.
+    targets: Option<(String, Regex)>,
+    daily_quota: Option<u64>,
+    monthly_quota: Option<u64>,
+}
+
+/// Requests one key made in the current local day and month.
+#[derive(Clone, Default, Deserialize, Serialize)]
+struct Counters {
+    day: Option<NaiveDate>,
+    daily: u64,
+    /// First day of the month `monthly` counts.
+    month: Option<NaiveDate>,
+    monthly: u64,
+}
+
+impl Counters {
+    /// Starts the day or month afresh once `today` has moved past it.
+    fn roll_over(&mut self, today: NaiveDate) {
+        if self.day != Some(today) {
+            self.day = Some(today);
+            self.daily = 0;
+        }
+        let month = today.with_day(1);
+        if self.month != month {
+            self.month = month;
+            self.monthly = 0;
+        }
+    }
+}
+
+/// The configured keys with their request counters, kept in memory and,
+/// with `KEYS_STATE_FILE`, saved so a restart does not reset the quotas.
+pub struct ApiKeys {
+    keys: Vec<ApiKey>,
+    state_file: Option<PathBuf>,
+    /// Counters by key name.
+    usage: Mutex<HashMap<String, Counters>>,
+    /// Whether `usage` changed since it was last saved.
+    dirty: AtomicBool,
+}
+
+impl ApiKeys {
+    pub fn from_settings(settings: &Settings) -> Result<Option<Self>, String> {
+        let Some(path) = &settings.keys_file else {
+            if settings.keys_state_file.is_some() {
+                return Err("`KEYS_STATE_FILE` needs `KEYS_FILE`".to_string());
+            }
+            return Ok(None);
+        };
+        // Neither checks a key, so with keys they would be a way around them.
+        if settings.graphql.trim() == "true" {
+            return Err("`GRAPHQL=true` cannot be used with `KEYS_FILE`".to_string());
+        }
+        if !settings.grpc_bind_addr.trim().is_empty() {
+            return Err("`GRPC_BIND_ADDR` cannot be used with `KEYS_FILE`".to_string());
+        }
+        let keys = load_keys(path)?;
+        let usage = match &settings.keys_state_file {
+            Some(state_file) if state_file.exists() => load_usage(state_file)?,
+            _ => HashMap::new(),
+        };
+        Ok(Some(Self {
+            keys,
+            state_file: settings.keys_state_file.clone(),
+            usage: Mutex::new(usage),
+            dirty: AtomicBool::new(false),
+        }))
+    }
+
+    fn find(&self, given: &str) -> Option<&ApiKey> {
+        // Every key is compared, so the time taken does not tell which one was close.
+        self.keys.iter().fold(None, |found, key| {
+            let matches = constant_time_eq(given.as_bytes(), key.key.as_bytes());
+            found.or(matches.then_some(key))
+        })
+    }
+
+    /// Counts a request by `key`, or refuses it when either quota is used up.
+    fn consume(&self, key: &ApiKey, tz: Tz) -> Result<(), ApiError> {
+        let today = Utc::now().with_timezone(&tz).date_naive();
+        let mut usage = self.usage.lock().unwrap();
+        let counters = usage.entry(key.name.clone()).or_default();
+        counters.roll_over(today);
+        let exceeded = [
+            ("daily", counters.daily, key.daily_quota, today.succ_opt()),
+            ("monthly", counters.monthly, key.monthly_quota, next_month(today)),
+        ]
+        .into_iter()
+        .find(|(_, used, quota, _)| quota.is_some_and(|quota| *used >= quota));
+        if let Some((period, _, quota, reset)) = exceeded {
+            let reset = reset.and_then(|date| local_midnight(date, tz));
+            return Err(quota_exceeded(period, quota.unwrap_or_default(), reset, tz));
+        }
+        counters.daily += 1;
+        counters.monthly += 1;
+        self.dirty.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Each key's consumption against its quotas, by name.
+    pub fn usage(&self, tz: Tz) -> BTreeMap<String, KeyUsage> {
+        let today = Utc::now().with_timezone(&tz).date_naive();
+        let usage = self.usage.lock().unwrap();
+        let reset = |date: Option<NaiveDate>| {
+            let midnight = date.and_then(|date| local_midnight(date, tz));
+            midnight.map(|t| t.with_timezone(&tz).to_rfc3339())
+        };
+        self.keys
+            .iter()
+            .map(|key| {
+                let mut counters = usage.get(&key.name).cloned().unwrap_or_default();
+                counters.roll_over(today);
+                let usage = KeyUsage {
+                    targets: key.targets.as_ref().map(|(pattern, _)| pattern.clone()),
+                    daily: QuotaUsage {
+                        used: counters.daily,
+                        quota: key.daily_quota,
+                        resets_at: reset(today.succ_opt()),
+                    },
+                    monthly: QuotaUsage {
+                        used: counters.monthly,
+                        quota: key.monthly_quota,
+                        resets_at: reset(next_month(today)),
+                    },
+                };
+                (key.name.clone(), usage)
+            })
+            .collect()
+    }
+
+    /// Writes the counters to `KEYS_STATE_FILE` if they changed.
+    async fn save(&self) {
+        let Some(path) = &self.state_file else {
+            return;
+        };
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return;
+        }
+        let body = serde_json::to_vec(&*self.usage.lock().unwrap());
+        let Ok(body) = body else {
+            return;
+        };
+        if let Err(e) = write_atomically(path, body).await {
+            tracing::warn!("Failed to save key usage to {}: {}", path.display(), e);
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct QuotaUsage {
+    used: u64,
+    quota: Option<u64>,
+    /// Local time the count starts again from zero.
+    resets_at: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct KeyUsage {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    targets: Option<String>,
+    daily: QuotaUsage,
+    monthly: QuotaUsage,
+}
+
+fn load_keys(path: &Path) -> Result<Vec<ApiKey>, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+    let file: KeysFile =
+        toml::from_str(&contents).map_err(|e| format!("failed to parse {}: {}", path.display(), e))?;
+
+    let (mut names, mut keys) = (HashSet::new(), HashSet::new());
+    file.key
+        .into_iter()
+        .map(|entry| {
+            if !names.insert(entry.name.clone()) {
+                return Err(format!("`KEYS_FILE` has more than one key named {:?}", entry.name));
+            }
+            if entry.key.trim().is_empty() || !keys.insert(entry.key.clone()) {
+                return Err(format!("`KEYS_FILE` key {:?} is empty or shared with another", entry.name));
+            }
+            let targets = entry
+                .targets
+                .map(|pattern| match Regex::new(&format!("^(?:{})$", pattern)) {
+                    Ok(regex) => Ok((pattern, regex)),
+                    Err(e) => Err(format!("`KEYS_FILE` key {:?} has an invalid `targets`: {}", entry.name, e)),
+                })
+                .transpose()?;
+            Ok(ApiKey {
+                name: entry.name,
+                key: entry.key,
+                targets,
+                daily_quota: entry.daily_quota,
+                monthly_quota: entry.monthly_quota,
+            })
+        })
+        .collect()
+}
+
+fn load_usage(path: &Path) -> Result<HashMap<String, Counters>, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+    serde_json::from_str(&contents).map_err(|e| format!("failed to parse {}: {}", path.display(), e))
+}
+
+fn next_month(today: NaiveDate) -> Option<NaiveDate> {
+    today.with_day(1)?.checked_add_months(Months::new(1))
+}
+
+fn quota_exceeded(period: &'static str, quota: u64, reset: Option<DateTime<Utc>>, tz: Tz) -> ApiError {
+    let mut error = ApiError::new(StatusCode::TOO_MANY_REQUESTS, format!("{} quota exceeded", period))
+        .with("quota", period)
+        .with("limit", quota);
+    if let Some(reset) = reset {
+        error = error.with("resets_at", reset.with_timezone(&tz).to_rfc3339());
+        error = error.retry_after((reset - Utc::now()).num_seconds().max(1));
+    }
+    error
+}
+
+/// The key given in `X-Api-Key`, or else as an `Authorization: Bearer` token.
+fn given_key(headers: &HeaderMap) -> Option<&str> {
+    let bearer = || headers.get(header::AUTHORIZATION)?.to_str().ok()?.strip_prefix("Bearer ");
+    headers.get("x-api-key").and_then(|v| v.to_str().ok()).or_else(bearer)
+}
+
+/// The instance patterns a request asks for: `target`, the instance behind
+/// `target_name`, the `match` of `/api/v1/targets`, or the `main` and `subs`
+/// of `/api/v1/power-usage/reconcile`.
+fn requested_targets(params: &HashMap<String, String>, state: &AppState) -> Vec<String> {
+    if let Some(target) = params.get("target").or_else(|| params.get("match")) {
+        return vec![target.clone()];
+    }
+    if let Some(name) = params.get("target_name") {
+        return state.config.aliases.current().resolve(name).map(|(instance, _)| instance).into_iter().collect();
+    }
+    ["main", "subs"].iter().filter_map(|name| params.get(*name).cloned()).collect()
+}
+
+/// `selector` narrowed to the `targets` of the request's key, if it is
+/// limited to some. A `target` is a regex itself, so matching it against
+/// `targets` as a string does not tell which instances it selects; the extra
+/// `instance=~` matcher has Prometheus leave out every one outside them.
+pub fn restrict(selector: String, params: &HashMap<String, String>) -> String {
+    match params.get(KEY_TARGETS) {
+        Some(targets) => {
+            let matcher = format!("instance=~\"{}\"", selector::quote(targets));
+            selector::with_matchers(&selector, &matcher)
+        }
+        None => selector,
+    }
+}
+
+/// `uri` with `params` as its query and `KEY_TARGETS` set to `targets`.
+fn with_key_targets(uri: &Uri, params: &HashMap<String, String>, targets: &str) -> Option<Uri> {
+    let mut url = Url::parse("http://localhost/").ok()?;
+    url.query_pairs_mut()
+        .extend_pairs(params.iter().filter(|(name, _)| *name != KEY_TARGETS))
+        .append_pair(KEY_TARGETS, targets);
+    format!("{}?{}", uri.path(), url.query()?).parse().ok()
+}
+
+/// The name of the key a request was let in with, as a request extension.
+#[derive(Clone)]
+pub struct KeyName(pub String);
+
+/// With `KEYS_FILE` set, requires a known key on every `/api/*` request and
+/// the `GUARDED` routes, refuses targets outside the key's `targets` with
+/// 403, and counts the request against its quotas, refusing it with 429 once
+/// one is used up. A key limited to some targets must always name one,
+/// except on the routes of a job, whose target was checked when it was
+/// submitted, and on `TARGET_IN_BODY`, and has them passed on in
+/// `KEY_TARGETS` for `restrict`.
+pub async fn api_key_middleware(State(state): State<AppState>, mut req: Request, next: Next) -> Response {
+    let Some(keys) = &state.config.api_keys else {
+        return next.run(req).await;
+    };
+    let path = req.uri().path();
+    let path = path.strip_prefix(state.config.base_path.as_str()).unwrap_or(path);
+    if !path.starts_with("/api/") && !GUARDED.contains(&path) {
+        return next.run(req).await;
+    }
+    let in_body = path == TARGET_IN_BODY;
+    let of_job = path.starts_with("/api/v1/jobs/");
+
+    let Some(key) = given_key(req.headers()).and_then(|given| keys.find(given.trim())) else {
+        return json_error(StatusCode::UNAUTHORIZED, "Missing or invalid API key");
+    };
+    if let Some((pattern, allowed)) = key.targets.as_ref().filter(|_| !of_job) {
+        let params = Query::<HashMap<String, String>>::try_from_uri(req.uri()).map(|q| q.0).unwrap_or_default();
+        let targets = requested_targets(&params, &state);
+        let denied = targets.iter().find(|target| !allowed.is_match(target));
+        if !in_body && (targets.is_empty() || denied.is_some()) {
+            let error = ApiError::new(StatusCode::FORBIDDEN, "target not allowed for this API key")
+                .with("allowed", pattern.as_str());
+            return match denied {
+                Some(target) => error.with("target", target.as_str()),
+                None => error,
+            }
+            .into_response();
+        }
+        match with_key_targets(req.uri(), &params, pattern) {
+            Some(uri) => *req.uri_mut() = uri,
+            None => return json_error(StatusCode::BAD_REQUEST, "invalid query string"),
+        }
+    }
+    if let Err(error) = keys.consume(key, state.config.timezone) {
+        return error.into_response();
+    }
+    req.extensions_mut().insert(KeyName(key.name.clone()));
+    next.run(req).await
+}
+
+/// Saves the key counters every `PERSIST_INTERVAL` when `KEYS_STATE_FILE` is set.
+pub async fn persist_loop(state: AppState) {
+    let Some(keys) = state.config.api_keys.as_ref().filter(|keys| keys.state_file.is_some()) else {
+        return;
+    };
+    let mut interval = tokio::time::interval(PERSIST_INTERVAL);
+    loop {
+        interval.tick().await;
+        keys.save().await;
+    }
+}
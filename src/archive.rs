@@ -0,0 +1,57 @@
+use flate2::{write::GzEncoder, Compression};
+use std::io::Write;
+
+const BLOCK: usize = 512;
+
+/// A gzipped ustar archive of regular files, built in memory.
+pub struct TarGz {
+    tar: Vec<u8>,
+    mtime: i64,
+}
+
+impl TarGz {
+    /// An empty archive whose files are all dated `mtime`, in seconds since
+    /// the epoch.
+    pub fn new(mtime: i64) -> Self {
+        Self { tar: Vec::new(), mtime }
+    }
+
+    /// Appends `contents` as `name`, which must fit the 100 bytes of a ustar
+    /// name.
+    pub fn add(&mut self, name: &str, contents: &[u8]) {
+        let mut header = [0u8; BLOCK];
+        let name = &name.as_bytes()[..name.len().min(100)];
+        header[..name.len()].copy_from_slice(name);
+        octal(&mut header[100..108], 0o644);
+        octal(&mut header[108..116], 0);
+        octal(&mut header[116..124], 0);
+        octal(&mut header[124..136], contents.len() as u64);
+        octal(&mut header[136..148], self.mtime.max(0) as u64);
+        header[156] = b'0';
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        // The checksum is taken with its own field as spaces.
+        header[148..156].fill(b' ');
+        let checksum: u64 = header.iter().map(|&b| u64::from(b)).sum();
+        octal(&mut header[148..155], checksum);
+
+        self.tar.extend_from_slice(&header);
+        self.tar.extend_from_slice(contents);
+        let padding = (BLOCK - contents.len() % BLOCK) % BLOCK;
+        self.tar.resize(self.tar.len() + padding, 0);
+    }
+
+    /// The archive, ended by two empty blocks, and gzipped.
+    pub fn finish(mut self) -> std::io::Result<Vec<u8>> {
+        self.tar.resize(self.tar.len() + 2 * BLOCK, 0);
+        let mut gzip = GzEncoder::new(Vec::new(), Compression::default());
+        gzip.write_all(&self.tar)?;
+        gzip.finish()
+    }
+}
+
+/// `value` as zero-padded octal digits ending in a NUL, filling `field`.
+fn octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}\0", value, width = field.len() - 1);
+    field.copy_from_slice(&digits.as_bytes()[digits.len() - field.len()..]);
+}
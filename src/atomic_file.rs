@@ -0,0 +1,10 @@
+use std::{io, path::Path};
+
+/// Writes `body` to `path` through `<name>.tmp` beside it, renamed into
+/// place once complete, so a crash never leaves half a file behind and
+/// readers that skip other extensions never see one being written.
+pub async fn write_atomically(path: &Path, body: impl AsRef<[u8]>) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    tokio::fs::write(&tmp, body).await?;
+    tokio::fs::rename(&tmp, path).await
+}
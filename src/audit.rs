@@ -0,0 +1,270 @@
+use axum::{
+    extract::{Query, Request, State},
+    http::{header, HeaderMap},
+    middleware::Next,
+    response::Response,
+};
+use chrono::{DateTime, NaiveDate, Utc};
+use chrono_tz::Tz;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::PathBuf,
+};
+use tokio::sync::mpsc;
+
+use crate::{
+    client_ip::ClientIp,
+    config::Settings,
+    period::{last_date, local_midnight},
+    request_id,
+    state::AppState,
+};
+
+pub const AUDIT_ENTRIES_TOTAL: &str = "audit_entries_total";
+pub const AUDIT_FAILURES_TOTAL: &str = "audit_failures_total";
+
+/// Entries waiting for the writer. Once it falls this far behind, new
+/// entries are dropped and counted instead of holding up requests.
+const QUEUE: usize = 4096;
+/// Hex digits of the SHA-256 kept as the key fingerprint.
+const FINGERPRINT_CHARS: usize = 16;
+
+/// The audit log file from `AUDIT_LOG_PATH`, and the size from
+/// `AUDIT_LOG_MAX_BYTES` at which it is rotated.
+pub struct AuditSettings {
+    path: PathBuf,
+    max_bytes: u64,
+}
+
+impl AuditSettings {
+    pub fn from_settings(settings: &Settings) -> Result<Option<Self>, String> {
+        let Some(path) = &settings.audit_log_path else {
+            return Ok(None);
+        };
+        let max_bytes = settings.audit_log_max_bytes.trim().parse::<u64>().ok().filter(|v| *v > 0);
+        let max_bytes = max_bytes.ok_or_else(|| {
+            format!("`AUDIT_LOG_MAX_BYTES` must be a positive integer, got {:?}", settings.audit_log_max_bytes)
+        })?;
+        Ok(Some(Self {
+            path: path.clone(),
+            max_bytes,
+        }))
+    }
+}
+
+/// One request to `/api/v1/*`: who asked for what, and how much came back.
+/// The readings themselves are never logged.
+#[derive(Serialize)]
+struct AuditEntry {
+    timestamp: DateTime<Utc>,
+    request_id: Option<String>,
+    client_ip: Option<String>,
+    /// Leading hex digits of the SHA-256 of the `X-Api-Key` or bearer token.
+    key_fingerprint: Option<String>,
+    method: String,
+    path: String,
+    target: Option<String>,
+    /// The instants the request resolved to, when it covers a period.
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    status: u16,
+    /// Meters, intervals or rows returned, depending on the endpoint.
+    rows: Option<usize>,
+}
+
+/// Sends entries to the writer thread, which owns the file, so a slow or
+/// failing disk never holds up a request.
+#[derive(Clone)]
+pub struct AuditLog {
+    sender: mpsc::Sender<AuditEntry>,
+}
+
+impl AuditLog {
+    pub fn start(settings: &AuditSettings, tz: Tz) -> Self {
+        let (sender, receiver) = mpsc::channel(QUEUE);
+        let writer = Writer {
+            path: settings.path.clone(),
+            max_bytes: settings.max_bytes,
+            tz,
+            current: None,
+        };
+        std::thread::spawn(move || writer.run(receiver));
+        Self { sender }
+    }
+
+    fn send(&self, entry: AuditEntry) {
+        if self.sender.try_send(entry).is_err() {
+            tracing::warn!("Audit log writer is behind, dropping an entry");
+            metrics::counter!(AUDIT_FAILURES_TOTAL, "reason" => "dropped").increment(1);
+        }
+    }
+}
+
+/// The open log file, with the local date of its entries and its size.
+struct Current {
+    file: File,
+    date: NaiveDate,
+    size: u64,
+}
+
+struct Writer {
+    path: PathBuf,
+    max_bytes: u64,
+    tz: Tz,
+    current: Option<Current>,
+}
+
+impl Writer {
+    fn run(mut self, mut receiver: mpsc::Receiver<AuditEntry>) {
+        while let Some(entry) = receiver.blocking_recv() {
+            let date = entry.timestamp.with_timezone(&self.tz).date_naive();
+            let written = serde_json::to_vec(&entry)
+                .map_err(std::io::Error::from)
+                .and_then(|mut line| {
+                    line.push(b'\n');
+                    self.write(&line, date)
+                });
+            match written {
+                Ok(()) => metrics::counter!(AUDIT_ENTRIES_TOTAL).increment(1),
+                Err(e) => {
+                    tracing::warn!("Failed to write the audit log {}: {}", self.path.display(), e);
+                    metrics::counter!(AUDIT_FAILURES_TOTAL, "reason" => "write").increment(1);
+                }
+            }
+        }
+    }
+
+    /// Appends `line`, first moving the file aside as `<path>.<date>` when
+    /// the local day has changed or the line would take it past `max_bytes`.
+    /// A file left by an earlier run is continued, or rotated if it is from
+    /// another day.
+    fn write(&mut self, line: &[u8], date: NaiveDate) -> std::io::Result<()> {
+        // Taken out so that any error leaves the file to be reopened for the
+        // next entry, in case it was moved away.
+        let current = match self.current.take() {
+            Some(current) => current,
+            None => self.open(date)?,
+        };
+        let full = current.size + line.len() as u64 > self.max_bytes;
+        let mut current = if current.size > 0 && (current.date != date || full) {
+            let rotated = self.rotated_path(current.date);
+            drop(current);
+            fs::rename(&self.path, rotated)?;
+            self.open(date)?
+        } else {
+            current
+        };
+        current.file.write_all(line)?;
+        current.date = date;
+        current.size += line.len() as u64;
+        self.current = Some(current);
+        Ok(())
+    }
+
+    fn open(&self, date: NaiveDate) -> std::io::Result<Current> {
+        let file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        let metadata = file.metadata()?;
+        let modified = metadata.modified().ok().map(DateTime::<Utc>::from);
+        Ok(Current {
+            file,
+            date: modified.map_or(date, |t| t.with_timezone(&self.tz).date_naive()),
+            size: metadata.len(),
+        })
+    }
+
+    /// `<path>.<date>`, then `<path>.<date>.1` and so on for later
+    /// rotations on the same day.
+    fn rotated_path(&self, date: NaiveDate) -> PathBuf {
+        let base = format!("{}.{}", self.path.display(), date);
+        std::iter::once(PathBuf::from(&base))
+            .chain((1..).map(|n| PathBuf::from(format!("{}.{}", base, n))))
+            .find(|path| !path.exists())
+            .unwrap_or_else(|| PathBuf::from(base))
+    }
+}
+
+/// What the handler resolved the request to, filled in through `note_*`.
+#[derive(Default)]
+struct Resolved {
+    range: Option<(DateTime<Utc>, DateTime<Utc>)>,
+    rows: Option<usize>,
+}
+
+tokio::task_local! {
+    static RESOLVED: RefCell<Resolved>;
+}
+
+/// Records the instants the current request covers. Does nothing outside an
+/// audited request, such as from the `query` command.
+pub fn note_range(from: DateTime<Utc>, to: DateTime<Utc>) {
+    RESOLVED.try_with(|r| r.borrow_mut().range = Some((from, to))).ok();
+}
+
+/// Records `days` local days from `first`, midnight to midnight, as the
+/// range the current request covers.
+pub fn note_days(tz: Tz, first: NaiveDate, days: u32) {
+    let end = last_date(first, days).and_then(|last| last.succ_opt());
+    if let Some((from, to)) = local_midnight(first, tz).zip(end.and_then(|end| local_midnight(end, tz))) {
+        note_range(from, to);
+    }
+}
+
+/// Records how many meters or rows the current request returned.
+pub fn note_rows(rows: usize) {
+    RESOLVED.try_with(|r| r.borrow_mut().rows = Some(rows)).ok();
+}
+
+/// Identifies the caller's credential without logging it.
+fn key_fingerprint(headers: &HeaderMap) -> Option<String> {
+    let bearer = || headers.get(header::AUTHORIZATION)?.to_str().ok()?.strip_prefix("Bearer ");
+    let key = headers.get("x-api-key").and_then(|v| v.to_str().ok()).or_else(bearer)?;
+    let digest = Sha256::digest(key.trim().as_bytes());
+    let hex: String = digest.iter().map(|byte| format!("{:02x}", byte)).collect();
+    Some(hex[..FINGERPRINT_CHARS].to_string())
+}
+
+/// Writes an entry for every `/api/v1/*` request when `AUDIT_LOG_PATH` is
+/// set. Runs inside the request id and client IP middleware; the handler
+/// adds the resolved range and row count.
+pub async fn audit_middleware(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let Some(audit) = state.audit.clone() else {
+        return next.run(req).await;
+    };
+    let path = req.uri().path().to_string();
+    if !path.strip_prefix(state.config.base_path.as_str()).unwrap_or(&path).starts_with("/api/v1/") {
+        return next.run(req).await;
+    }
+
+    let timestamp = Utc::now();
+    let params = Query::<HashMap<String, String>>::try_from_uri(req.uri()).map(|q| q.0).unwrap_or_default();
+    let target = params.get("target").or_else(|| params.get("target_name")).cloned();
+    let client_ip = req.extensions().get::<ClientIp>().and_then(|ip| ip.0).map(|ip| ip.to_string());
+    let key_fingerprint = key_fingerprint(req.headers());
+    let method = req.method().to_string();
+
+    let (response, resolved) = RESOLVED
+        .scope(RefCell::default(), async {
+            let response = next.run(req).await;
+            (response, RESOLVED.with(RefCell::take))
+        })
+        .await;
+    audit.send(AuditEntry {
+        timestamp,
+        request_id: request_id::current(),
+        client_ip,
+        key_fingerprint,
+        method,
+        path,
+        target,
+        from: resolved.range.map(|(from, _)| from),
+        to: resolved.range.map(|(_, to)| to),
+        status: response.status().as_u16(),
+        rows: resolved.rows,
+    });
+    response
+}
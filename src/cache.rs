@@ -0,0 +1,239 @@
+use axum::http::HeaderName;
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    hash::Hash,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::OnceCell;
+
+/// A small in-memory map whose entries expire after a fixed time-to-live.
+pub struct TtlCache<K, V> {
+    ttl: Duration,
+    entries: Mutex<HashMap<K, (Instant, V)>>,
+}
+
+impl<K: Eq + Hash, V: Clone> TtlCache<K, V> {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Option<V> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .get(key)
+            .filter(|(inserted, _)| inserted.elapsed() < self.ttl)
+            .map(|(_, value)| value.clone())
+    }
+
+    /// Entries still within their time-to-live.
+    pub fn len(&self) -> usize {
+        let entries = self.entries.lock().unwrap();
+        entries.values().filter(|(inserted, _)| inserted.elapsed() < self.ttl).count()
+    }
+
+    pub fn insert(&self, key: K, value: V) {
+        let mut entries = self.entries.lock().unwrap();
+        let ttl = self.ttl;
+        entries.retain(|_, (inserted, _)| inserted.elapsed() < ttl);
+        entries.insert(key, (Instant::now(), value));
+    }
+
+    /// Inserts `value` unless `key` has one within its time-to-live, and
+    /// returns whether it did.
+    pub fn insert_new(&self, key: K, value: V) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        let ttl = self.ttl;
+        entries.retain(|_, (inserted, _)| inserted.elapsed() < ttl);
+        if entries.contains_key(&key) {
+            return false;
+        }
+        entries.insert(key, (Instant::now(), value));
+        true
+    }
+}
+
+/// Set to the `CacheStatus` name on responses served through a `StaleCache`.
+pub static X_CACHE: HeaderName = HeaderName::from_static("x-cache");
+
+/// How a cached value was found, ordered from best to worst.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CacheStatus {
+    /// Within its time-to-live.
+    Hit,
+    /// Past its time-to-live but inside the stale window, so served while
+    /// it is refreshed.
+    Stale,
+    Miss,
+}
+
+impl CacheStatus {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Hit => "hit",
+            Self::Stale => "stale",
+            Self::Miss => "miss",
+        }
+    }
+}
+
+struct StaleEntry<V> {
+    inserted: Instant,
+    fresh_for: Duration,
+    stale_for: Duration,
+    value: V,
+}
+
+/// Like `TtlCache`, but an entry past its time-to-live is still returned,
+/// marked stale, for a while longer, so the caller can answer from it and
+/// refresh it in the background. `begin_refresh` admits one refresh per key.
+pub struct StaleCache<K, V> {
+    ttl: Duration,
+    stale: Duration,
+    /// How long past both windows an entry is still kept for `last_known`.
+    retain: Duration,
+    entries: Mutex<HashMap<K, StaleEntry<V>>>,
+    refreshing: Mutex<HashSet<K>>,
+    /// Lookups since startup, indexed by `CacheStatus`.
+    lookups: [AtomicU64; 3],
+}
+
+/// A `StaleCache`'s size and lookups since startup.
+#[derive(Serialize)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub hits: u64,
+    pub stale: u64,
+    pub misses: u64,
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> StaleCache<K, V> {
+    pub fn new(ttl: Duration, stale: Duration) -> Self {
+        Self {
+            ttl,
+            stale,
+            retain: Duration::ZERO,
+            entries: Mutex::new(HashMap::new()),
+            refreshing: Mutex::new(HashSet::new()),
+            lookups: Default::default(),
+        }
+    }
+
+    /// Keeps entries for `retain` past both windows, for `last_known`.
+    pub fn retaining(mut self, retain: Duration) -> Self {
+        self.retain = retain;
+        self
+    }
+
+    /// The value however old, as long as it is still kept. Not counted as
+    /// a lookup.
+    pub fn last_known(&self, key: &K) -> Option<V> {
+        let entries = self.entries.lock().unwrap();
+        let entry = entries.get(key)?;
+        let kept = entry.inserted.elapsed() < entry.fresh_for + entry.stale_for + self.retain;
+        kept.then(|| entry.value.clone())
+    }
+
+    /// The value with `CacheStatus::Hit` or `Stale`, or `None` once it is
+    /// past both windows.
+    pub fn get(&self, key: &K) -> Option<(V, CacheStatus)> {
+        let entries = self.entries.lock().unwrap();
+        let found = entries.get(key).and_then(|entry| {
+            let age = entry.inserted.elapsed();
+            if age < entry.fresh_for {
+                Some((entry.value.clone(), CacheStatus::Hit))
+            } else if age < entry.fresh_for + entry.stale_for {
+                Some((entry.value.clone(), CacheStatus::Stale))
+            } else {
+                None
+            }
+        });
+        let status = found.as_ref().map_or(CacheStatus::Miss, |(_, status)| *status);
+        self.lookups[status as usize].fetch_add(1, Ordering::Relaxed);
+        found
+    }
+
+    /// Stores `value` with the configured time-to-live and stale window.
+    pub fn insert(&self, key: K, value: V) {
+        self.insert_for(key, value, self.ttl, self.stale);
+    }
+
+    /// Stores `value` fresh for `fresh_for`, then stale for `stale_for`.
+    pub fn insert_for(&self, key: K, value: V, fresh_for: Duration, stale_for: Duration) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, entry| entry.inserted.elapsed() < entry.fresh_for + entry.stale_for + self.retain);
+        let inserted = Instant::now();
+        entries.insert(key, StaleEntry { inserted, fresh_for, stale_for, value });
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let entries = self.entries.lock().unwrap();
+        let live = entries.values().filter(|entry| entry.inserted.elapsed() < entry.fresh_for + entry.stale_for);
+        let lookups = |status: CacheStatus| self.lookups[status as usize].load(Ordering::Relaxed);
+        CacheStats {
+            entries: live.count(),
+            hits: lookups(CacheStatus::Hit),
+            stale: lookups(CacheStatus::Stale),
+            misses: lookups(CacheStatus::Miss),
+        }
+    }
+
+    /// Whether the caller should refresh `key`: false while another refresh
+    /// of it is still running. Pair with `end_refresh`.
+    pub fn begin_refresh(&self, key: &K) -> bool {
+        self.refreshing.lock().unwrap().insert(key.clone())
+    }
+
+    pub fn end_refresh(&self, key: &K) {
+        self.refreshing.lock().unwrap().remove(key);
+    }
+}
+
+/// Runs one computation per key at a time: callers arriving while it is in
+/// flight await its result, errors included, instead of starting their own.
+/// The entry is removed once the computation completes.
+pub struct SingleFlight<K, V> {
+    flights: Mutex<HashMap<K, Arc<OnceCell<V>>>>,
+}
+
+impl<K, V> Default for SingleFlight<K, V> {
+    fn default() -> Self {
+        Self {
+            flights: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> SingleFlight<K, V> {
+    /// The result of `compute`, or of the call already running for `key`,
+    /// with whether it was shared. Should the running call be dropped, one
+    /// of its waiters computes instead.
+    pub async fn run<F, Fut>(&self, key: K, compute: F) -> (V, bool)
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = V>,
+    {
+        let cell = self.flights.lock().unwrap().entry(key.clone()).or_default().clone();
+        let mut led = false;
+        let value = cell
+            .get_or_init(|| {
+                led = true;
+                compute()
+            })
+            .await
+            .clone();
+        let mut flights = self.flights.lock().unwrap();
+        if flights.get(&key).is_some_and(|running| Arc::ptr_eq(running, &cell)) {
+            flights.remove(&key);
+        }
+        (value, !led)
+    }
+}
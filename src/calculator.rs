@@ -0,0 +1,340 @@
+use axum::http::StatusCode;
+use chrono::{NaiveDate, NaiveDateTime};
+use chrono_tz::Tz;
+use serde::Serialize;
+use std::{collections::HashMap, fmt, future::Future, pin::Pin, sync::Arc};
+
+use crate::{
+    api::range::MAX_DAYS,
+    config::Config,
+    error,
+    period::days_inclusive,
+    prometheus::Prometheus,
+    range::{daily_usage_in, DailySeries, FailurePolicy},
+    state::AppState,
+    usage::{compute_usage, resolve_selector, resolve_target, UsageEntry, UsageRequest},
+};
+
+/// The future a [`MetricsBackend`] answers with.
+pub type BackendFuture<'a> = Pin<Box<dyn Future<Output = Result<Vec<u8>, String>> + Send + 'a>>;
+
+/// A source of Prometheus HTTP API responses.
+///
+/// Each call is a `GET` of `path`, such as `api/v1/query`, with the query
+/// string `params`; the answer is the JSON body in the Prometheus API format,
+/// including `"status": "error"` bodies. An `Err` is reported as an
+/// unreachable Prometheus.
+///
+/// ```
+/// use power_usage::{BackendFuture, Config, MetricsBackend, Settings, UsageCalculator};
+///
+/// /// Knows no series at all.
+/// struct Empty;
+///
+/// impl MetricsBackend for Empty {
+///     fn get<'a>(&'a self, _path: &'a str, _params: &'a [(&'static str, String)]) -> BackendFuture<'a> {
+///         let body = r#"{"status":"success","data":{"resultType":"vector","result":[]}}"#;
+///         Box::pin(async move { Ok(body.as_bytes().to_vec()) })
+///     }
+/// }
+///
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let settings = Settings {
+///     prometheus_host: "http://prometheus.invalid".to_string(),
+///     ..Settings::default()
+/// };
+/// let config = Config::from_settings(&settings).map_err(|errors| errors.join("; "))?;
+/// let calculator = UsageCalculator::with_backend(config, Empty)?;
+/// let at = chrono::Utc::now().naive_utc();
+/// assert!(calculator.daily(".*", at, chrono_tz::UTC).await?.is_empty());
+/// # Ok(())
+/// # }
+/// ```
+pub trait MetricsBackend: Send + Sync {
+    fn get<'a>(&'a self, path: &'a str, params: &'a [(&'static str, String)]) -> BackendFuture<'a>;
+}
+
+/// The Prometheus at `PROMETHEUS_HOST`, with the client settings of the
+/// configuration, or its fixtures with `BACKEND=fixture`. The default
+/// backend of [`UsageCalculator::new`]; useful on its own for wrapping in
+/// another [`MetricsBackend`].
+///
+/// ```
+/// use power_usage::{Config, MetricsBackend, PrometheusBackend, Settings};
+///
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let settings = Settings {
+///     backend: "fixture".to_string(),
+///     fixture_dir: Some(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/golden/fixtures").into()),
+///     ..Settings::default()
+/// };
+/// let config = Config::from_settings(&settings).map_err(|errors| errors.join("; "))?;
+/// let backend = PrometheusBackend::new(&config)?;
+/// let body = backend.get("api/v1/query", &[("query", "vector(1)".to_string())]).await?;
+/// assert!(String::from_utf8(body)?.contains("success"));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct PrometheusBackend {
+    prometheus: Prometheus,
+}
+
+impl PrometheusBackend {
+    pub fn new(config: &Config) -> Result<Self, Error> {
+        let prometheus = Prometheus::new(config, config.prometheus_url.clone()).map_err(Error::new)?;
+        Ok(Self { prometheus })
+    }
+}
+
+impl MetricsBackend for PrometheusBackend {
+    fn get<'a>(&'a self, path: &'a str, params: &'a [(&'static str, String)]) -> BackendFuture<'a> {
+        Box::pin(async move {
+            let answer = self.prometheus.get(path, params).await;
+            answer.map(|(_, body)| body).map_err(|error| Error::from(error).to_string())
+        })
+    }
+}
+
+/// Why a calculation failed, with the status the HTTP API answers the same
+/// failure with.
+#[derive(Clone, Debug)]
+pub struct Error {
+    status: u16,
+    message: String,
+}
+
+impl Error {
+    fn new(message: impl Into<String>) -> Self {
+        Self {
+            status: StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+            message: message.into(),
+        }
+    }
+
+    fn invalid(message: impl Into<String>) -> Self {
+        Self {
+            status: StatusCode::BAD_REQUEST.as_u16(),
+            ..Self::new(message)
+        }
+    }
+
+    /// The HTTP status, e.g. 400 for an invalid target or 502 when
+    /// Prometheus fails.
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+}
+
+impl From<StatusCode> for Error {
+    fn from(code: StatusCode) -> Self {
+        error::Error::from(code).into()
+    }
+}
+
+impl From<error::Error> for Error {
+    fn from(error: error::Error) -> Self {
+        Self {
+            status: error.status().as_u16(),
+            message: error.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// One meter's consumption over the day before `at`, as an entry of
+/// `/api/v1/power-usage`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct DailyUsage {
+    pub instance: String,
+    pub address: String,
+    /// The alias from `ALIASES_FILE`, if any.
+    pub name: Option<String>,
+    /// The reading a day earlier; `None` when the meter had not reported.
+    pub prev_kwh: Option<f64>,
+    pub curr_kwh: f64,
+    pub daily_kwh: Option<f64>,
+    /// Average power between the two readings.
+    pub avg_power_watt: Option<f64>,
+    /// Hours between the two readings.
+    pub period_hours: Option<f64>,
+    /// Beyond `MAX_DAILY_KWH` or `MAX_READING_KWH`, so best left out of
+    /// any total.
+    pub implausible: bool,
+    /// Data quality flags such as `missing_prev`, as in the API.
+    pub flags: Vec<String>,
+}
+
+impl From<UsageEntry> for DailyUsage {
+    fn from(entry: UsageEntry) -> Self {
+        Self {
+            implausible: entry.is_implausible(),
+            instance: entry.instance,
+            address: entry.address,
+            name: entry.name,
+            prev_kwh: entry.prev_kwh,
+            curr_kwh: entry.curr_kwh,
+            daily_kwh: entry.daily_kwh,
+            avg_power_watt: entry.avg_power_watt,
+            period_hours: entry.period_hours,
+            flags: entry.flags.into_iter().map(str::to_string).collect(),
+        }
+    }
+}
+
+/// One meter's consumption per local day, as a series of
+/// `/api/v1/power-usage/range`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct MeterDays {
+    pub instance: String,
+    pub address: String,
+    /// The alias from `ALIASES_FILE`, if any.
+    pub name: Option<String>,
+    pub days: Vec<DayUsage>,
+}
+
+impl MeterDays {
+    /// The sum over the days with both readings.
+    pub fn total_kwh(&self) -> f64 {
+        self.days.iter().filter_map(|day| day.kwh).fold(0.0, |total, kwh| total + kwh)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct DayUsage {
+    pub date: NaiveDate,
+    /// `None` when either midnight's reading is missing, or when the day
+    /// is implausible.
+    pub kwh: Option<f64>,
+    /// Beyond `MAX_DAILY_KWH`, or with a reading beyond `MAX_READING_KWH`.
+    pub implausible: bool,
+}
+
+impl From<DailySeries> for MeterDays {
+    fn from(series: DailySeries) -> Self {
+        Self {
+            instance: series.instance,
+            address: series.address,
+            name: series.name,
+            days: series
+                .days
+                .into_iter()
+                .map(|(date, kwh)| DayUsage {
+                    implausible: series.implausible.contains(&date),
+                    date,
+                    kwh,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Computes daily usage the way the HTTP API does, with the aliases, caches
+/// and query strategy of the configuration. Cheap to clone; clones share
+/// connections and caches.
+#[derive(Clone)]
+pub struct UsageCalculator {
+    state: AppState,
+}
+
+impl UsageCalculator {
+    /// Queries the backend the configuration names, as the server does.
+    pub fn new(config: Config) -> Result<Self, Error> {
+        Ok(Self {
+            state: AppState::new(config).map_err(Error::new)?,
+        })
+    }
+
+    /// Answers every Prometheus call from `backend` instead.
+    pub fn with_backend(config: Config, backend: impl MetricsBackend + 'static) -> Result<Self, Error> {
+        let calculator = Self::new(config)?;
+        let prometheus = calculator.state.prometheus.clone().with_source(Arc::new(backend));
+        Ok(Self {
+            state: calculator.state.with_backend(prometheus),
+        })
+    }
+
+    /// The consumption of every meter of the instances matching `target`, a
+    /// regular expression, over the day before `at` in `tz`, to the minute.
+    /// Meters without a current reading are left out.
+    ///
+    /// ```
+    /// # use power_usage::{Config, Settings, UsageCalculator};
+    /// # #[tokio::main(flavor = "current_thread")]
+    /// # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let settings = Settings {
+    /// #     backend: "fixture".to_string(),
+    /// #     fixture_dir: Some(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/golden/fixtures").into()),
+    /// #     ..Settings::default()
+    /// # };
+    /// # let calculator = UsageCalculator::new(Config::from_settings(&settings).map_err(|e| e.join("; "))?)?;
+    /// let at = chrono::NaiveDate::from_ymd_opt(2025, 8, 1).unwrap().and_hms_opt(0, 0, 0).unwrap();
+    /// let usage = calculator.daily(".*", at, chrono_tz::Asia::Jakarta).await?;
+    /// let new_meter = usage.iter().find(|meter| meter.instance == "golden-b:9100").unwrap();
+    /// assert_eq!(new_meter.prev_kwh, None);
+    /// assert_eq!(new_meter.daily_kwh, None);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn daily(&self, target: &str, at: NaiveDateTime, tz: Tz) -> Result<Vec<DailyUsage>, Error> {
+        let params = HashMap::from([
+            ("target".to_string(), target.to_string()),
+            ("date".to_string(), at.format("%Y-%m-%d").to_string()),
+            ("time".to_string(), at.format("%H:%M").to_string()),
+            ("tz".to_string(), tz.name().to_string()),
+        ]);
+        let req = UsageRequest::from_params(&params, &self.state)?;
+        let usage = compute_usage(&self.state, &req).await?;
+        Ok(usage.entries.into_iter().map(DailyUsage::from).collect())
+    }
+
+    /// The consumption of every meter of the instances matching `target` on
+    /// each local day of `tz` from `start` through `end`, at most 366 days.
+    ///
+    /// ```
+    /// # use power_usage::{Config, Settings, UsageCalculator};
+    /// # #[tokio::main(flavor = "current_thread")]
+    /// # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// # let settings = Settings {
+    /// #     backend: "fixture".to_string(),
+    /// #     fixture_dir: Some(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/golden/fixtures").into()),
+    /// #     ..Settings::default()
+    /// # };
+    /// # let calculator = UsageCalculator::new(Config::from_settings(&settings).map_err(|e| e.join("; "))?)?;
+    /// let start = chrono::NaiveDate::from_ymd_opt(2025, 7, 30).unwrap();
+    /// let end = start.succ_opt().unwrap();
+    /// let meters = calculator.range(".*", start, end, chrono_tz::Asia::Jakarta).await?;
+    /// let meter = meters.iter().find(|meter| meter.instance == "golden-a:9100").unwrap();
+    /// assert_eq!(meter.days.len(), 2);
+    /// assert_eq!(meter.total_kwh(), 25.0);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn range(
+        &self,
+        target: &str,
+        start: NaiveDate,
+        end: NaiveDate,
+        tz: Tz,
+    ) -> Result<Vec<MeterDays>, Error> {
+        let days = days_inclusive(start, end)
+            .filter(|days| (1..=MAX_DAYS).contains(days))
+            .ok_or_else(|| Error::invalid(format!("the range must cover 1 to {} days", MAX_DAYS)))?;
+        let params = HashMap::from([("target".to_string(), target.to_string())]);
+        let (target, _) = resolve_target(&params, &self.state)?;
+        let selector = resolve_selector(&params, &target)?;
+        let policy = FailurePolicy::FailFast;
+        let mut series = daily_usage_in(&self.state, tz, &selector, start, days, false, policy).await?;
+        self.state.config.corrections.apply_to_series(&mut series);
+        Ok(series.into_iter().map(MeterDays::from).collect())
+    }
+}
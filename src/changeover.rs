@@ -0,0 +1,180 @@
+use chrono::NaiveDate;
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+};
+
+type Meter = (String, String);
+
+/// A physical meter swapped for another during `date`. Readings before the
+/// swap are the old meter's, after it the new one's, which may also carry
+/// a new instance or address, or be the same meter with its counter reset.
+#[derive(Clone, Deserialize)]
+pub struct Changeover {
+    pub date: NaiveDate,
+    pub old_instance: String,
+    pub old_address: String,
+    /// The old meter's counter when it was taken out.
+    pub old_final_reading: f64,
+    pub new_instance: String,
+    pub new_address: String,
+    /// The new meter's counter when it was put in.
+    pub new_initial_reading: f64,
+}
+
+impl Changeover {
+    fn old_meter(&self) -> Meter {
+        (self.old_instance.clone(), self.old_address.clone())
+    }
+
+    fn new_meter(&self) -> Meter {
+        (self.new_instance.clone(), self.new_address.clone())
+    }
+}
+
+#[derive(Deserialize)]
+struct ChangeoversFile {
+    #[serde(default)]
+    changeover: Vec<Changeover>,
+}
+
+/// Reads `CHANGEOVERS_FILE`: a JSON array of changeovers when the extension
+/// is `.json`, `[[changeover]]` tables otherwise. They come back by date.
+///
+/// ```toml
+/// [[changeover]]
+/// date = "2025-03-14"
+/// old_instance = "10.3.7.22:8899"
+/// old_address = "4"
+/// old_final_reading = 18230.5
+/// new_instance = "10.3.7.31:8899"
+/// new_address = "1"
+/// new_initial_reading = 0.0
+/// ```
+pub fn load_changeovers(path: &Path) -> Result<Vec<Changeover>, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+    let parsed = if path.extension().is_some_and(|ext| ext == "json") {
+        serde_json::from_str(&contents).map_err(|e| e.to_string())
+    } else {
+        toml::from_str(&contents).map(|file: ChangeoversFile| file.changeover).map_err(|e| e.to_string())
+    };
+    let mut changeovers: Vec<Changeover> =
+        parsed.map_err(|e| format!("failed to parse {}: {}", path.display(), e))?;
+
+    let mut seen = HashSet::new();
+    for changeover in &changeovers {
+        let old = format!("{}/{}", changeover.old_instance, changeover.old_address);
+        for (name, reading) in [
+            ("old_final_reading", changeover.old_final_reading),
+            ("new_initial_reading", changeover.new_initial_reading),
+        ] {
+            if !reading.is_finite() || reading < 0.0 {
+                return Err(format!(
+                    "`CHANGEOVERS_FILE` has an invalid {} for {:?} on {}: {}",
+                    name, old, changeover.date, reading
+                ));
+            }
+        }
+        if !seen.insert((changeover.old_meter(), changeover.date)) {
+            return Err(format!(
+                "`CHANGEOVERS_FILE` replaces {:?} more than once on {}",
+                old, changeover.date
+            ));
+        }
+    }
+    changeovers.sort_by_key(|changeover| changeover.date);
+    Ok(changeovers)
+}
+
+/// One changeover on a day of a range.
+#[derive(Clone)]
+struct Step {
+    /// The index of the midnight starting the changeover's day.
+    boundary: usize,
+    date: NaiveDate,
+    old: Meter,
+    new: Meter,
+    /// Added to the new meter's readings after the swap, so they continue
+    /// the old meter's counter.
+    offset: f64,
+}
+
+/// How the changeovers on the days of a range rewrite its readings. Up to
+/// the changeover's day the old meter's readings are taken as the new
+/// one's, and after it the new meter's readings are raised by the old
+/// meter's final reading less the new one's initial reading. On the day
+/// itself consumption thus comes to `(old_final - old_start) + (new_end -
+/// new_initial)`, and every other day is as either meter measured it.
+#[derive(Clone, Default)]
+pub struct Stitch {
+    /// By date, so that a meter replaced twice is stitched twice.
+    steps: Vec<Step>,
+}
+
+impl Stitch {
+    /// The changeovers among `changeovers` that fall on one of the days
+    /// starting at the midnights of `dates`, the last of which only ends the
+    /// final day.
+    pub fn new(changeovers: &[Changeover], dates: &[NaiveDate]) -> Self {
+        let days = &dates[..dates.len().saturating_sub(1)];
+        let mut offsets: HashMap<Meter, f64> = HashMap::new();
+        let steps = changeovers
+            .iter()
+            .filter_map(|changeover| {
+                let boundary = days.iter().position(|date| *date == changeover.date)?;
+                let (old, new) = (changeover.old_meter(), changeover.new_meter());
+                let own = changeover.old_final_reading - changeover.new_initial_reading;
+                let carried = offsets.get(&old).copied().unwrap_or(0.0);
+                offsets.insert(new.clone(), carried + own);
+                // A counter reset keeps the offsets of earlier steps on the meter.
+                let offset = if old == new { own } else { carried + own };
+                Some(Step {
+                    boundary,
+                    date: changeover.date,
+                    old,
+                    new,
+                    offset,
+                })
+            })
+            .collect();
+        Self { steps }
+    }
+
+    /// Rewrites the readings taken at the midnight with index `boundary`.
+    pub fn apply(&self, boundary: usize, snapshot: &mut HashMap<Meter, f64>) {
+        for step in &self.steps {
+            if step.old == step.new {
+                if boundary > step.boundary {
+                    snapshot.entry(step.new.clone()).and_modify(|kwh| *kwh += step.offset);
+                }
+                continue;
+            }
+            let old = snapshot.remove(&step.old);
+            if boundary <= step.boundary {
+                snapshot.remove(&step.new);
+                if let Some(kwh) = old {
+                    snapshot.insert(step.new.clone(), kwh);
+                }
+            } else {
+                snapshot.entry(step.new.clone()).and_modify(|kwh| *kwh += step.offset);
+            }
+        }
+    }
+
+    /// The changeover days stitched into the series reported as `meter`,
+    /// following a meter replaced more than once to its latest.
+    pub fn days_of(&self, meter: &Meter) -> Vec<NaiveDate> {
+        let mut days = Vec::new();
+        let mut current = meter;
+        for step in self.steps.iter().rev() {
+            if &step.new == current {
+                days.push(step.date);
+                current = &step.old;
+            }
+        }
+        days.reverse();
+        days
+    }
+}
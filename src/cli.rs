@@ -0,0 +1,300 @@
+use clap::{Parser, Subcommand};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+use crate::{
+    api,
+    config::{Config, Settings},
+    state::AppState,
+};
+
+#[derive(Parser)]
+#[command(version, about = "Daily power usage computed from Prometheus energy counters")]
+pub struct Cli {
+    /// TOML configuration file; environment variables override its values
+    #[arg(long, global = true, env = "CONFIG_FILE")]
+    pub config: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run the HTTP server (the default)
+    Serve,
+    /// Validate the configuration and probe Prometheus, reporting every problem found
+    CheckConfig,
+    /// Compute a report once and print it to stdout
+    Query {
+        /// Regex filter for the `instance` label
+        #[arg(long)]
+        target: String,
+        /// Local date, `YYYY-MM-DD`
+        #[arg(long)]
+        date: String,
+        /// Local time, `HH:MM`
+        #[arg(long, default_value = "00:00")]
+        time: String,
+        /// Print CSV instead of JSON
+        #[arg(long)]
+        csv: bool,
+    },
+    /// Run canned requests against recorded responses and compare the output
+    /// byte for byte with golden files
+    Verify {
+        /// Directory with `cases.txt`, the Prometheus responses in `fixtures/`
+        /// and the golden outputs in `expected/`
+        #[arg(long)]
+        fixtures: PathBuf,
+        /// Rewrite the golden files from the current output instead
+        #[arg(long)]
+        update: bool,
+    },
+}
+
+fn print_errors(errors: &[String]) {
+    eprintln!("Configuration errors:");
+    for e in errors {
+        eprintln!("  - {}", e);
+    }
+}
+
+/// Loads and validates the configuration, printing every problem found.
+pub fn load_config(settings: Result<Settings, String>) -> Result<Config, ExitCode> {
+    let settings = settings.map_err(|e| {
+        print_errors(&[e]);
+        ExitCode::FAILURE
+    })?;
+    Config::from_settings(&settings).map_err(|errors| {
+        print_errors(&errors);
+        ExitCode::FAILURE
+    })
+}
+
+pub async fn check_config(settings: Result<Settings, String>) -> ExitCode {
+    let config = match load_config(settings) {
+        Ok(config) => config,
+        Err(code) => return code,
+    };
+
+    let mut errors = Vec::new();
+
+    if let Some(tls) = &config.tls
+        && let Err(e) = tls.load().await
+    {
+        errors.push(e);
+    }
+
+    match AppState::new(config) {
+        Ok(state) => {
+            if state.prometheus.probe().await.is_err() {
+                errors.push(format!(
+                    "Prometheus at {} did not answer a probe query",
+                    state.prometheus.display_url()
+                ));
+            }
+        }
+        Err(e) => errors.push(e),
+    }
+
+    if errors.is_empty() {
+        println!("Configuration OK");
+        ExitCode::SUCCESS
+    } else {
+        print_errors(&errors);
+        ExitCode::FAILURE
+    }
+}
+
+pub async fn query(config: Config, target: String, date: String, time: String, csv: bool) -> ExitCode {
+    let state = match AppState::new(config) {
+        Ok(state) => state,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut params = HashMap::from([
+        ("target".to_string(), target),
+        ("date".to_string(), date),
+        ("time".to_string(), time),
+    ]);
+    if csv {
+        params.insert("csv".to_string(), "true".to_string());
+    }
+
+    match api::v1::render(&state, &params).await {
+        Ok(api::v1::Output { body, truncated_from, .. }) => {
+            if let Some(total) = truncated_from {
+                eprintln!("Warning: only the first of {} matching instances are shown", total);
+            }
+            if csv {
+                print!("{}", body);
+            } else {
+                println!("{}", body);
+            }
+            ExitCode::SUCCESS
+        }
+        Err(code) => {
+            eprintln!("Query failed: {}", code);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// One line of `cases.txt`: the golden file and the request producing it.
+struct Case {
+    file: String,
+    request: String,
+}
+
+/// `<file> <path?query>` per line, with blank lines and `#` comments skipped.
+fn read_cases(path: &Path) -> Result<Vec<Case>, String> {
+    let contents =
+        std::fs::read_to_string(path).map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+    contents
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(number, line)| match line.split_once(char::is_whitespace) {
+            Some((file, request)) if request.trim_start().starts_with('/') => Ok(Case {
+                file: file.to_string(),
+                request: request.trim_start().to_string(),
+            }),
+            _ => Err(format!("{}:{}: expected `<file> /<path>?<query>`", path.display(), number)),
+        })
+        .collect()
+}
+
+/// The first line where `actual` differs from `expected`, for the report.
+fn first_difference(expected: &[u8], actual: &[u8]) -> String {
+    let (expected, actual) = (String::from_utf8_lossy(expected), String::from_utf8_lossy(actual));
+    let mut expected_lines = expected.split_inclusive('\n');
+    let mut actual_lines = actual.split_inclusive('\n');
+    let mut number = 1;
+    loop {
+        match (expected_lines.next(), actual_lines.next()) {
+            (Some(e), Some(a)) if e == a => number += 1,
+            (e, a) => {
+                return format!(
+                    "line {}:\n    expected: {:?}\n    actual:   {:?}",
+                    number,
+                    e.unwrap_or("(end of file)"),
+                    a.unwrap_or("(end of file)")
+                );
+            }
+        }
+    }
+}
+
+/// Serves the routes on a local port with `BACKEND=fixture` on `dir/fixtures`
+/// and fetches every case. The configuration file and environment are
+/// ignored, so aliases or a tariff on this machine cannot change the output.
+pub async fn verify(dir: &Path, update: bool) -> ExitCode {
+    let cases = match read_cases(&dir.join("cases.txt")) {
+        Ok(cases) => cases,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let settings = Settings {
+        prometheus_host: "http://fixture.invalid".to_string(),
+        backend: "fixture".to_string(),
+        fixture_dir: Some(dir.join("fixtures")),
+        ..Settings::default()
+    };
+    let config = match load_config(Ok(settings)) {
+        Ok(config) => config,
+        Err(code) => return code,
+    };
+    let state = match AppState::new(config) {
+        Ok(state) => state,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let listener = match tokio::net::TcpListener::bind("127.0.0.1:0").await {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Failed to bind a local port: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let base = match listener.local_addr() {
+        Ok(addr) => format!("http://{}", addr),
+        Err(e) => {
+            eprintln!("Failed to bind a local port: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let app = crate::app(&state).into_make_service_with_connect_info::<SocketAddr>();
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    let client = reqwest::Client::new();
+    let expected_dir = dir.join("expected");
+    let mut failed = 0;
+    for case in &cases {
+        let fetched = match client.get(format!("{}{}", base, case.request)).send().await {
+            Ok(response) => {
+                let status = response.status();
+                response.bytes().await.map(|body| (status, body)).map_err(|e| e.to_string())
+            }
+            Err(e) => Err(e.to_string()),
+        };
+        let body = match fetched {
+            Ok((status, body)) if status.is_success() => body,
+            Ok((status, body)) => {
+                let body = String::from_utf8_lossy(&body);
+                println!("FAIL {}: {} answered {}: {}", case.file, case.request, status, body);
+                failed += 1;
+                continue;
+            }
+            Err(e) => {
+                println!("FAIL {}: {} failed: {}", case.file, case.request, e);
+                failed += 1;
+                continue;
+            }
+        };
+        let path = expected_dir.join(&case.file);
+        let expected = std::fs::read(&path).ok();
+        if expected.as_deref() == Some(&body[..]) {
+            println!("ok   {}", case.file);
+            continue;
+        }
+        if update {
+            let written = std::fs::create_dir_all(&expected_dir).and_then(|()| std::fs::write(&path, &body));
+            match written {
+                Ok(()) => println!("updated {}", case.file),
+                Err(e) => {
+                    println!("FAIL {}: failed to write {}: {}", case.file, path.display(), e);
+                    failed += 1;
+                }
+            }
+            continue;
+        }
+        failed += 1;
+        match expected {
+            Some(expected) => {
+                println!("FAIL {}: output differs at {}", case.file, first_difference(&expected, &body))
+            }
+            None => println!("FAIL {}: no golden file {}, run with --update", case.file, path.display()),
+        }
+    }
+
+    if failed == 0 {
+        println!("{} cases match", cases.len());
+        ExitCode::SUCCESS
+    } else {
+        println!("{} of {} cases failed", failed, cases.len());
+        ExitCode::FAILURE
+    }
+}
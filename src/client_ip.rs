@@ -0,0 +1,187 @@
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::HeaderMap,
+    middleware::Next,
+    response::Response,
+};
+use ipnet::IpNet;
+use std::net::{IpAddr, SocketAddr};
+
+use crate::state::AppState;
+
+/// The address of the client that originated the request, after unwrapping
+/// any trusted proxies. `None` only when a local peer sent no forwarding headers.
+#[derive(Clone, Copy)]
+pub struct ClientIp(pub Option<IpAddr>);
+
+/// Parses the configured CIDR list; bare addresses are accepted as
+/// single-host networks.
+pub fn parse_trusted_proxies(entries: &[String]) -> Result<Vec<IpNet>, String> {
+    entries
+        .iter()
+        .map(|s| {
+            s.parse::<IpNet>()
+                .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| format!("invalid `TRUSTED_PROXIES` entry: {}", s))
+        })
+        .collect()
+}
+
+fn is_trusted(trusted: &[IpNet], ip: &IpAddr) -> bool {
+    trusted.iter().any(|net| net.contains(ip))
+}
+
+/// Extracts the node from a `Forwarded` `for=` value: `1.2.3.4`,
+/// `1.2.3.4:80`, `"[2001:db8::1]:80"`. Obfuscated identifiers yield `None`.
+fn parse_forwarded_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split(']').next()?.parse().ok();
+    }
+    node.parse()
+        .ok()
+        .or_else(|| node.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+}
+
+/// The proxy chain as reported by the headers, nearest hop last.
+fn forwarded_chain(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let xff: Vec<Option<IpAddr>> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|s| s.trim().parse().ok())
+        .collect();
+    if !xff.is_empty() {
+        return xff;
+    }
+
+    headers
+        .get_all("forwarded")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.split_once('=')?;
+                key.trim()
+                    .eq_ignore_ascii_case("for")
+                    .then(|| parse_forwarded_node(value))
+            })
+        })
+        .collect()
+}
+
+/// Determines the client IP. Forwarding headers are only honoured when the
+/// direct peer is a trusted proxy (or a local Unix socket peer); the
+/// right-most untrusted hop is taken as the client so prepended entries
+/// can't be spoofed.
+fn resolve_client_ip(
+    trusted: &[IpNet],
+    peer: Option<IpAddr>,
+    headers: &HeaderMap,
+) -> Option<IpAddr> {
+    if let Some(ip) = peer
+        && !is_trusted(trusted, &ip)
+    {
+        return Some(ip);
+    }
+
+    let mut fallback = peer;
+    for hop in forwarded_chain(headers).into_iter().rev() {
+        let Some(ip) = hop else {
+            // An unknown or obfuscated hop can't be looked past.
+            break;
+        };
+        if !is_trusted(trusted, &ip) {
+            return Some(ip);
+        }
+        fallback = Some(ip);
+    }
+    fallback
+}
+
+pub async fn client_ip_middleware(
+    State(state): State<AppState>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let peer = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|info| info.0.ip());
+    let client_ip = resolve_client_ip(&state.config.trusted_proxies, peer, req.headers());
+    req.extensions_mut().insert(ClientIp(client_ip));
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    fn trusted() -> Vec<IpNet> {
+        parse_trusted_proxies(&["10.0.0.0/8".to_string(), "192.0.2.1".to_string()]).unwrap()
+    }
+
+    #[test]
+    fn forged_header_from_an_untrusted_peer() {
+        let forged = headers(&[("x-forwarded-for", "203.0.113.9")]);
+        assert_eq!(resolve_client_ip(&trusted(), Some(ip("198.51.100.4")), &forged), Some(ip("198.51.100.4")));
+    }
+
+    #[test]
+    fn right_most_untrusted_hop() {
+        let chain = headers(&[("x-forwarded-for", "203.0.113.9, 198.51.100.4, 10.1.2.3")]);
+        assert_eq!(resolve_client_ip(&trusted(), Some(ip("10.0.0.1")), &chain), Some(ip("198.51.100.4")));
+        let split = headers(&[("x-forwarded-for", "203.0.113.9"), ("x-forwarded-for", "192.0.2.1")]);
+        assert_eq!(resolve_client_ip(&trusted(), Some(ip("10.0.0.1")), &split), Some(ip("203.0.113.9")));
+    }
+
+    #[test]
+    fn chain_of_trusted_hops() {
+        let chain = headers(&[("x-forwarded-for", "10.9.9.9, 192.0.2.1")]);
+        assert_eq!(resolve_client_ip(&trusted(), Some(ip("10.0.0.1")), &chain), Some(ip("10.9.9.9")));
+        assert_eq!(resolve_client_ip(&trusted(), Some(ip("10.0.0.1")), &HeaderMap::new()), Some(ip("10.0.0.1")));
+        assert_eq!(resolve_client_ip(&trusted(), None, &HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn obfuscated_hop_stops_the_walk() {
+        let forwarded = headers(&[("forwarded", "for=203.0.113.9, for=_hidden, for=10.1.2.3;proto=https")]);
+        assert_eq!(
+            forwarded_chain(&forwarded),
+            vec![Some(ip("203.0.113.9")), None, Some(ip("10.1.2.3"))]
+        );
+        assert_eq!(resolve_client_ip(&trusted(), Some(ip("10.0.0.1")), &forwarded), Some(ip("10.1.2.3")));
+    }
+
+    #[test]
+    fn forwarded_nodes() {
+        assert_eq!(parse_forwarded_node("\"[2001:db8::1]:8080\""), Some(ip("2001:db8::1")));
+        assert_eq!(parse_forwarded_node("\"[2001:db8::1]\""), Some(ip("2001:db8::1")));
+        assert_eq!(parse_forwarded_node("198.51.100.4:80"), Some(ip("198.51.100.4")));
+        assert_eq!(parse_forwarded_node(" 198.51.100.4"), Some(ip("198.51.100.4")));
+        assert_eq!(parse_forwarded_node("unknown"), None);
+        assert_eq!(parse_forwarded_node("_hidden"), None);
+    }
+
+    #[test]
+    fn x_forwarded_for_before_forwarded() {
+        let both = headers(&[("forwarded", "for=203.0.113.9"), ("x-forwarded-for", "198.51.100.4")]);
+        assert_eq!(forwarded_chain(&both), vec![Some(ip("198.51.100.4"))]);
+        let ipv6 = headers(&[("forwarded", "For=\"[2001:db8::1]:4711\"")]);
+        assert_eq!(resolve_client_ip(&trusted(), Some(ip("10.0.0.1")), &ipv6), Some(ip("2001:db8::1")));
+    }
+}
@@ -0,0 +1,1061 @@
+use chrono::NaiveDate;
+use chrono_tz::Tz;
+use ipnet::IpNet;
+use reqwest::Url;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::{
+    aliases::SharedAliases,
+    anonymize::Anonymizer,
+    changeover::{load_changeovers, Changeover},
+    api_keys::ApiKeys,
+    audit::AuditSettings,
+    client_ip,
+    corrections::Corrections,
+    fixture::Fixtures,
+    jobs::JobLimits,
+    mailer::Mailer,
+    object_store::ObjectStore,
+    prometheus::{ClientTuning, QueryStrategy},
+    reload::Live,
+    remote_write::RemoteWrite,
+    reports::{load_reports, Report},
+    rounding::RoundingPolicy,
+    selector::is_label_name,
+    server::{BindAddr, TlsFiles},
+    tariff::Tariff,
+    textfile::Textfile,
+    thresholds::Thresholds,
+    timezones::Timezones,
+    usage::Fallback,
+};
+
+/// Raw settings read from an optional TOML file, overridden by environment
+/// variables of the same name in upper case, with defaults for the rest.
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct Settings {
+    /// The file these were read from, read again on reload.
+    #[serde(skip)]
+    pub config_file: Option<PathBuf>,
+    pub prometheus_host: String,
+    pub prometheus_sites: Vec<String>,
+    pub prometheus_timeout: String,
+    pub shadow_prometheus_host: String,
+    pub shadow_sample_rate: String,
+    pub shadow_tolerance_kwh: String,
+    pub shadow_timeout: String,
+    pub prometheus_http2: String,
+    pub pool_idle_timeout: String,
+    pub pool_max_idle_per_host: String,
+    pub tcp_keepalive: String,
+    pub request_timeout: String,
+    pub backend: String,
+    pub fixture_dir: Option<PathBuf>,
+    pub fixture_latency: String,
+    pub fixture_record: String,
+    pub lookback: String,
+    pub fallback_lookback: String,
+    pub query_strategy: String,
+    pub chunk_size: String,
+    pub range_concurrency: String,
+    pub range_max_failed_fraction: String,
+    pub timezone: String,
+    pub bind_addr: String,
+    pub grpc_bind_addr: String,
+    pub graphql: String,
+    pub base_path: String,
+    pub socket_mode: String,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub trusted_proxies: Vec<String>,
+    pub targets_window: String,
+    pub targets_cache_ttl: String,
+    pub usage_cache_ttl: String,
+    pub usage_cache_stale: String,
+    pub fallback_mode: String,
+    pub latest_window: String,
+    pub aliases_file: Option<PathBuf>,
+    pub thresholds_file: Option<PathBuf>,
+    pub holidays_file: Option<PathBuf>,
+    pub changeovers_file: Option<PathBuf>,
+    pub timezones_file: Option<PathBuf>,
+    pub electrical_metrics: Vec<String>,
+    pub anomaly_mads: String,
+    pub power_mismatch_percent: String,
+    pub max_daily_kwh: String,
+    pub max_reading_kwh: String,
+    pub prefer_job: String,
+    pub max_instances: String,
+    pub max_response_rows: String,
+    pub usage_metrics_targets: Vec<String>,
+    pub usage_metrics_interval: String,
+    pub warm_targets: Vec<String>,
+    pub warm_budget: String,
+    pub usage_metrics_stale: String,
+    pub textfile_path: Option<PathBuf>,
+    pub textfile_stale: String,
+    pub remote_write_url: String,
+    pub remote_write_username: String,
+    pub remote_write_password: String,
+    pub remote_write_bearer_token: String,
+    pub reports_file: Option<PathBuf>,
+    pub smtp_host: String,
+    pub smtp_port: String,
+    pub smtp_tls: String,
+    pub smtp_username: String,
+    pub smtp_password: String,
+    pub smtp_from: String,
+    pub admin_token: String,
+    pub s3_endpoint: String,
+    pub s3_bucket: String,
+    pub s3_region: String,
+    pub s3_access_key: String,
+    pub s3_secret_key: String,
+    pub s3_key_template: String,
+    pub tariff_per_kwh: String,
+    pub tariff_currency: String,
+    pub rounding_stage: String,
+    pub rounding_decimals: String,
+    pub rounding_ties: String,
+    pub audit_log_path: Option<PathBuf>,
+    pub audit_log_max_bytes: String,
+    pub keys_file: Option<PathBuf>,
+    pub keys_state_file: Option<PathBuf>,
+    pub corrections_file: Option<PathBuf>,
+    pub cache_dir: Option<PathBuf>,
+    pub jobs_workers: String,
+    pub jobs_per_key: String,
+    pub jobs_retention: String,
+    pub anonymize_key: String,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            config_file: None,
+            prometheus_host: String::new(),
+            prometheus_sites: Vec::new(),
+            prometheus_timeout: "5s".to_string(),
+            shadow_prometheus_host: String::new(),
+            shadow_sample_rate: "0.05".to_string(),
+            shadow_tolerance_kwh: "0.001".to_string(),
+            shadow_timeout: "10s".to_string(),
+            prometheus_http2: "false".to_string(),
+            pool_idle_timeout: "90s".to_string(),
+            pool_max_idle_per_host: String::new(),
+            tcp_keepalive: String::new(),
+            request_timeout: "25s".to_string(),
+            backend: "prometheus".to_string(),
+            fixture_dir: None,
+            fixture_latency: String::new(),
+            fixture_record: "false".to_string(),
+            lookback: "10m".to_string(),
+            fallback_lookback: String::new(),
+            query_strategy: "separate".to_string(),
+            chunk_size: String::new(),
+            range_concurrency: "4".to_string(),
+            range_max_failed_fraction: "0.5".to_string(),
+            timezone: "Asia/Jakarta".to_string(),
+            bind_addr: "0.0.0.0:9118".to_string(),
+            grpc_bind_addr: String::new(),
+            graphql: "false".to_string(),
+            base_path: String::new(),
+            socket_mode: "0660".to_string(),
+            tls_cert: None,
+            tls_key: None,
+            trusted_proxies: Vec::new(),
+            targets_window: "1h".to_string(),
+            targets_cache_ttl: "5m".to_string(),
+            usage_cache_ttl: String::new(),
+            usage_cache_stale: String::new(),
+            fallback_mode: "none".to_string(),
+            latest_window: "1d".to_string(),
+            aliases_file: None,
+            thresholds_file: None,
+            holidays_file: None,
+            changeovers_file: None,
+            timezones_file: None,
+            electrical_metrics: ["voltage", "current", "power", "energy"]
+                .map(str::to_string)
+                .to_vec(),
+            anomaly_mads: "3".to_string(),
+            power_mismatch_percent: "10".to_string(),
+            max_daily_kwh: "100000".to_string(),
+            max_reading_kwh: String::new(),
+            prefer_job: String::new(),
+            max_instances: "5000".to_string(),
+            max_response_rows: "100000".to_string(),
+            usage_metrics_targets: Vec::new(),
+            usage_metrics_interval: "15m".to_string(),
+            warm_targets: Vec::new(),
+            warm_budget: "10s".to_string(),
+            usage_metrics_stale: "3d".to_string(),
+            textfile_path: None,
+            textfile_stale: "3d".to_string(),
+            remote_write_url: String::new(),
+            remote_write_username: String::new(),
+            remote_write_password: String::new(),
+            remote_write_bearer_token: String::new(),
+            reports_file: None,
+            smtp_host: String::new(),
+            smtp_port: String::new(),
+            smtp_tls: "starttls".to_string(),
+            smtp_username: String::new(),
+            smtp_password: String::new(),
+            smtp_from: String::new(),
+            admin_token: String::new(),
+            s3_endpoint: String::new(),
+            s3_bucket: String::new(),
+            s3_region: "us-east-1".to_string(),
+            s3_access_key: String::new(),
+            s3_secret_key: String::new(),
+            s3_key_template: "{target}/{year}/{month}/{date}.csv".to_string(),
+            tariff_per_kwh: String::new(),
+            tariff_currency: "IDR".to_string(),
+            rounding_stage: "entry".to_string(),
+            rounding_decimals: "0".to_string(),
+            rounding_ties: "half_up".to_string(),
+            audit_log_path: None,
+            audit_log_max_bytes: "104857600".to_string(),
+            keys_file: None,
+            keys_state_file: None,
+            corrections_file: None,
+            cache_dir: None,
+            jobs_workers: "2".to_string(),
+            jobs_per_key: "3".to_string(),
+            jobs_retention: "1h".to_string(),
+            anonymize_key: String::new(),
+        }
+    }
+}
+
+fn env_var(name: &str) -> Option<String> {
+    std::env::var(name).ok()
+}
+
+impl Settings {
+    pub fn load(path: Option<&Path>) -> Result<Self, String> {
+        let mut settings = match path {
+            Some(path) => {
+                let contents = std::fs::read_to_string(path)
+                    .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+                toml::from_str(&contents)
+                    .map_err(|e| format!("failed to parse {}: {}", path.display(), e))?
+            }
+            None => Settings::default(),
+        };
+        settings.config_file = path.map(Path::to_path_buf);
+        settings.apply_env();
+        Ok(settings)
+    }
+
+    fn apply_env(&mut self) {
+        let strings = [
+            ("PROMETHEUS_HOST", &mut self.prometheus_host),
+            ("PROMETHEUS_TIMEOUT", &mut self.prometheus_timeout),
+            ("SHADOW_PROMETHEUS_HOST", &mut self.shadow_prometheus_host),
+            ("SHADOW_SAMPLE_RATE", &mut self.shadow_sample_rate),
+            ("SHADOW_TOLERANCE_KWH", &mut self.shadow_tolerance_kwh),
+            ("SHADOW_TIMEOUT", &mut self.shadow_timeout),
+            ("PROMETHEUS_HTTP2", &mut self.prometheus_http2),
+            ("POOL_IDLE_TIMEOUT", &mut self.pool_idle_timeout),
+            ("POOL_MAX_IDLE_PER_HOST", &mut self.pool_max_idle_per_host),
+            ("TCP_KEEPALIVE", &mut self.tcp_keepalive),
+            ("REQUEST_TIMEOUT", &mut self.request_timeout),
+            ("BACKEND", &mut self.backend),
+            ("FIXTURE_LATENCY", &mut self.fixture_latency),
+            ("FIXTURE_RECORD", &mut self.fixture_record),
+            ("LOOKBACK", &mut self.lookback),
+            ("FALLBACK_LOOKBACK", &mut self.fallback_lookback),
+            ("QUERY_STRATEGY", &mut self.query_strategy),
+            ("CHUNK_SIZE", &mut self.chunk_size),
+            ("RANGE_CONCURRENCY", &mut self.range_concurrency),
+            ("RANGE_MAX_FAILED_FRACTION", &mut self.range_max_failed_fraction),
+            ("TIMEZONE", &mut self.timezone),
+            ("BIND_ADDR", &mut self.bind_addr),
+            ("GRPC_BIND_ADDR", &mut self.grpc_bind_addr),
+            ("GRAPHQL", &mut self.graphql),
+            ("BASE_PATH", &mut self.base_path),
+            ("SOCKET_MODE", &mut self.socket_mode),
+            ("TARGETS_WINDOW", &mut self.targets_window),
+            ("TARGETS_CACHE_TTL", &mut self.targets_cache_ttl),
+            ("USAGE_CACHE_TTL", &mut self.usage_cache_ttl),
+            ("USAGE_CACHE_STALE", &mut self.usage_cache_stale),
+            ("FALLBACK_MODE", &mut self.fallback_mode),
+            ("LATEST_WINDOW", &mut self.latest_window),
+            ("ANOMALY_MADS", &mut self.anomaly_mads),
+            ("POWER_MISMATCH_PERCENT", &mut self.power_mismatch_percent),
+            ("MAX_DAILY_KWH", &mut self.max_daily_kwh),
+            ("MAX_READING_KWH", &mut self.max_reading_kwh),
+            ("PREFER_JOB", &mut self.prefer_job),
+            ("MAX_INSTANCES", &mut self.max_instances),
+            ("MAX_RESPONSE_ROWS", &mut self.max_response_rows),
+            ("USAGE_METRICS_INTERVAL", &mut self.usage_metrics_interval),
+            ("WARM_BUDGET", &mut self.warm_budget),
+            ("USAGE_METRICS_STALE", &mut self.usage_metrics_stale),
+            ("TEXTFILE_STALE", &mut self.textfile_stale),
+            ("REMOTE_WRITE_URL", &mut self.remote_write_url),
+            ("REMOTE_WRITE_USERNAME", &mut self.remote_write_username),
+            ("REMOTE_WRITE_PASSWORD", &mut self.remote_write_password),
+            ("REMOTE_WRITE_BEARER_TOKEN", &mut self.remote_write_bearer_token),
+            ("SMTP_HOST", &mut self.smtp_host),
+            ("SMTP_PORT", &mut self.smtp_port),
+            ("SMTP_TLS", &mut self.smtp_tls),
+            ("SMTP_USERNAME", &mut self.smtp_username),
+            ("SMTP_PASSWORD", &mut self.smtp_password),
+            ("SMTP_FROM", &mut self.smtp_from),
+            ("ADMIN_TOKEN", &mut self.admin_token),
+            ("S3_ENDPOINT", &mut self.s3_endpoint),
+            ("S3_BUCKET", &mut self.s3_bucket),
+            ("S3_REGION", &mut self.s3_region),
+            ("S3_ACCESS_KEY", &mut self.s3_access_key),
+            ("S3_SECRET_KEY", &mut self.s3_secret_key),
+            ("S3_KEY_TEMPLATE", &mut self.s3_key_template),
+            ("TARIFF_PER_KWH", &mut self.tariff_per_kwh),
+            ("TARIFF_CURRENCY", &mut self.tariff_currency),
+            ("ROUNDING_STAGE", &mut self.rounding_stage),
+            ("ROUNDING_DECIMALS", &mut self.rounding_decimals),
+            ("ROUNDING_TIES", &mut self.rounding_ties),
+            ("AUDIT_LOG_MAX_BYTES", &mut self.audit_log_max_bytes),
+            ("JOBS_WORKERS", &mut self.jobs_workers),
+            ("JOBS_PER_KEY", &mut self.jobs_per_key),
+            ("JOBS_RETENTION", &mut self.jobs_retention),
+            ("ANONYMIZE_KEY", &mut self.anonymize_key),
+        ];
+        for (name, field) in strings {
+            if let Some(v) = env_var(name) {
+                *field = v;
+            }
+        }
+
+        if let Some(v) = env_var("FIXTURE_DIR") {
+            self.fixture_dir = Some(PathBuf::from(v));
+        }
+        if let Some(v) = env_var("TLS_CERT") {
+            self.tls_cert = Some(PathBuf::from(v));
+        }
+        if let Some(v) = env_var("TLS_KEY") {
+            self.tls_key = Some(PathBuf::from(v));
+        }
+        if let Some(v) = env_var("ALIASES_FILE") {
+            self.aliases_file = Some(PathBuf::from(v));
+        }
+        if let Some(v) = env_var("THRESHOLDS_FILE") {
+            self.thresholds_file = Some(PathBuf::from(v));
+        }
+        if let Some(v) = env_var("HOLIDAYS_FILE") {
+            self.holidays_file = Some(PathBuf::from(v));
+        }
+        if let Some(v) = env_var("CHANGEOVERS_FILE") {
+            self.changeovers_file = Some(PathBuf::from(v));
+        }
+        if let Some(v) = env_var("TIMEZONES_FILE") {
+            self.timezones_file = Some(PathBuf::from(v));
+        }
+        if let Some(v) = env_var("AUDIT_LOG_PATH") {
+            self.audit_log_path = Some(PathBuf::from(v));
+        }
+        if let Some(v) = env_var("KEYS_FILE") {
+            self.keys_file = Some(PathBuf::from(v));
+        }
+        if let Some(v) = env_var("KEYS_STATE_FILE") {
+            self.keys_state_file = Some(PathBuf::from(v));
+        }
+        if let Some(v) = env_var("CORRECTIONS_FILE") {
+            self.corrections_file = Some(PathBuf::from(v));
+        }
+        if let Some(v) = env_var("CACHE_DIR") {
+            self.cache_dir = Some(PathBuf::from(v));
+        }
+        if let Some(v) = env_var("TEXTFILE_PATH") {
+            self.textfile_path = Some(PathBuf::from(v));
+        }
+        if let Some(v) = env_var("REPORTS_FILE") {
+            self.reports_file = Some(PathBuf::from(v));
+        }
+        if let Some(v) = env_var("PROMETHEUS_SITES") {
+            self.prometheus_sites = split_list(&v);
+        }
+        if let Some(v) = env_var("TRUSTED_PROXIES") {
+            self.trusted_proxies = split_list(&v);
+        }
+        if let Some(v) = env_var("ELECTRICAL_METRICS") {
+            self.electrical_metrics = split_list(&v);
+        }
+        if let Some(v) = env_var("USAGE_METRICS_TARGETS") {
+            self.usage_metrics_targets = split_list(&v);
+        }
+        if let Some(v) = env_var("WARM_TARGETS") {
+            self.warm_targets = split_list(&v);
+        }
+    }
+
+    /// A copy safe to log: credentials embedded in URLs are masked.
+    pub fn redacted(&self) -> Self {
+        let mut settings = self.clone();
+        settings.prometheus_host = redact_userinfo(&settings.prometheus_host);
+        for site in &mut settings.prometheus_sites {
+            *site = redact_userinfo(site);
+        }
+        settings.remote_write_url = redact_userinfo(&settings.remote_write_url);
+        settings.shadow_prometheus_host = redact_userinfo(&settings.shadow_prometheus_host);
+        for secret in [
+            &mut settings.remote_write_password,
+            &mut settings.remote_write_bearer_token,
+            &mut settings.smtp_password,
+            &mut settings.admin_token,
+            &mut settings.s3_secret_key,
+            &mut settings.anonymize_key,
+        ] {
+            if !secret.is_empty() {
+                *secret = "***".to_string();
+            }
+        }
+        settings
+    }
+}
+
+pub fn split_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Reads a flat `key = value` map from a JSON file (by `.json` extension)
+/// or TOML file, as used by `ALIASES_FILE` and `THRESHOLDS_FILE`.
+pub fn load_map<T: DeserializeOwned>(path: &Path) -> Result<HashMap<String, T>, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+    if path.extension().is_some_and(|ext| ext == "json") {
+        serde_json::from_str(&contents).map_err(|e| e.to_string())
+    } else {
+        toml::from_str(&contents).map_err(|e| e.to_string())
+    }
+    .map_err(|e| format!("failed to parse {}: {}", path.display(), e))
+}
+
+/// Reads one `YYYY-MM-DD` date per line; blank lines and `#` comments are skipped.
+fn load_holidays(path: &Path) -> Result<HashSet<NaiveDate>, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+    contents
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            NaiveDate::parse_from_str(line, "%Y-%m-%d")
+                .map_err(|_| format!("`HOLIDAYS_FILE` has an invalid date: {:?}", line))
+        })
+        .collect()
+}
+
+fn redact_userinfo(host: &str) -> String {
+    let (scheme, rest) = host.split_once("://").unwrap_or(("", host));
+    match rest.rsplit_once('@') {
+        Some((_, host)) if scheme.is_empty() => format!("***@{}", host),
+        Some((_, host)) => format!("{}://***@{}", scheme, host),
+        None => host.to_string(),
+    }
+}
+
+/// Parses durations like `500ms`, `5s`, `10m`, `1h` or `2d`.
+pub fn parse_duration(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    let split = value.find(|c: char| !c.is_ascii_digit())?;
+    let (amount, unit) = value.split_at(split);
+    let amount: u64 = amount.parse().ok()?;
+    let secs = match unit {
+        "ms" => return Some(Duration::from_millis(amount)),
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        _ => return None,
+    };
+    Some(Duration::from_secs(amount * secs))
+}
+
+/// Validated, typed configuration shared with handlers through axum state.
+pub struct Config {
+    pub prometheus_url: Url,
+    /// Named backends a request may pick with `prom=`, in configured order.
+    pub prometheus_sites: Vec<(String, Url)>,
+    pub prometheus_timeout: Duration,
+    /// The backend a sample of usage requests is also run against, with
+    /// `SHADOW_PROMETHEUS_HOST`.
+    pub shadow_url: Option<Url>,
+    /// Share of usage requests compared with the shadow, above 0 and at most 1.
+    pub shadow_sample_rate: f64,
+    /// Difference in `daily_kwh` beyond which the backends disagree.
+    pub shadow_tolerance_kwh: f64,
+    /// How long a comparison may wait for the shadow.
+    pub shadow_timeout: Duration,
+    /// HTTP version, pooling and keep-alive of the Prometheus client.
+    pub prometheus_client: ClientTuning,
+    /// How long a whole request may take before it is answered with a 504.
+    pub request_timeout: Duration,
+    /// Replaying or recording Prometheus responses, from `BACKEND` and
+    /// `FIXTURE_RECORD`.
+    pub fixtures: Option<Fixtures>,
+    pub query_strategy: QueryStrategy,
+    /// Splits usage queries matching more instances than this, from `CHUNK_SIZE`.
+    pub chunk_size: Option<usize>,
+    /// Snapshot queries in flight at once when walking a range of days.
+    pub range_concurrency: usize,
+    /// Share of a range's days that may fail with `on_error=best_effort`
+    /// before the whole range does.
+    pub range_max_failed_fraction: f64,
+    pub timezone: Tz,
+    pub bind_addrs: Vec<BindAddr>,
+    /// Where the gRPC API listens; it is not served at all without one.
+    pub grpc_bind_addr: Option<SocketAddr>,
+    /// Whether `/graphql` is served.
+    pub graphql: bool,
+    pub base_path: String,
+    pub tls: Option<TlsFiles>,
+    pub trusted_proxies: Vec<IpNet>,
+    pub targets_window: String,
+    pub targets_cache_ttl: Duration,
+    /// How long usage results are cached, from `USAGE_CACHE_TTL`; not at all without it.
+    pub usage_cache_ttl: Option<Duration>,
+    /// How long past the TTL a cached result is still served while it is
+    /// fetched again, from `USAGE_CACHE_STALE`.
+    pub usage_cache_stale: Duration,
+    /// What usage requests without `fallback=` get while Prometheus is
+    /// down, from `FALLBACK_MODE`.
+    pub fallback: Fallback,
+    pub aliases: SharedAliases,
+    /// Zones of instances whose day is not `timezone`'s, from `TIMEZONES_FILE`.
+    pub timezones: Timezones,
+    pub electrical_metrics: Vec<String>,
+    /// The settings that can change without a restart.
+    pub live: Arc<Live>,
+    pub usage_metrics_targets: Vec<String>,
+    pub usage_metrics_interval: Duration,
+    /// Targets put into the usage cache at startup and after midnight.
+    pub warm_targets: Vec<String>,
+    /// How long the first warmup may hold up listening.
+    pub warm_budget: Duration,
+    pub usage_metrics_stale: Duration,
+    pub textfile: Option<Textfile>,
+    pub remote_write: Option<RemoteWrite>,
+    /// Reports generated after local midnight, from `REPORTS_FILE`.
+    pub reports: Vec<Report>,
+    pub mailer: Option<Mailer>,
+    pub object_store: Option<ObjectStore>,
+    /// Bearer token required by the `/admin` routes, which are disabled
+    /// without one.
+    pub admin_token: Option<String>,
+    /// Where requests to `/api/v1/*` are recorded, from `AUDIT_LOG_PATH`.
+    pub audit: Option<AuditSettings>,
+    /// Keys required on `/api/*`, with their quotas, from `KEYS_FILE`.
+    pub api_keys: Option<ApiKeys>,
+    /// Daily values corrected through `/api/v1/corrections`.
+    pub corrections: Corrections,
+    /// Where settled usage results are kept across restarts.
+    pub cache_dir: Option<PathBuf>,
+    /// Workers, per-key limit and retention of `/api/v1/jobs`.
+    pub jobs: JobLimits,
+    /// Pseudonyms for `anonymize=true`, from `ANONYMIZE_KEY`.
+    pub anonymizer: Option<Arc<Anonymizer>>,
+}
+
+/// The settings reloaded on SIGHUP and `POST /admin/reload`, each named in
+/// `RELOADABLE`. A request sees the version current when it started.
+pub struct Tunables {
+    pub lookback: String,
+    /// A wider window a usage reading is queried again over when `lookback`
+    /// finds none, from `FALLBACK_LOOKBACK`.
+    pub fallback_lookback: Option<String>,
+    pub latest_window: String,
+    pub thresholds: Thresholds,
+    /// Dates counted as weekend days by `split=weekday`.
+    pub holidays: HashSet<NaiveDate>,
+    /// Meter replacements from `CHANGEOVERS_FILE`, by date.
+    pub changeovers: Vec<Changeover>,
+    pub anomaly_mads: f64,
+    /// Disagreement between the gauge and counter averages, in percent,
+    /// beyond which `avg_power_source=gauge` flags `power_mismatch`.
+    pub power_mismatch_percent: f64,
+    /// Daily consumption, either way, beyond which a day is `implausible`.
+    pub max_daily_kwh: f64,
+    /// Counter reading beyond which it is `implausible`, when set.
+    pub max_reading_kwh: Option<f64>,
+    /// The `job` whose series is kept when several report the same meter.
+    pub prefer_job: Option<String>,
+    /// Most instances one usage query may return before it is refused or,
+    /// with `truncate=true`, cut short.
+    pub max_instances: usize,
+    /// Most rows a usage report may answer with in one response; larger
+    /// ones are streamed or run as jobs instead.
+    pub max_response_rows: usize,
+    /// Price of energy, for the `Cost` column.
+    pub tariff: Option<Tariff>,
+    /// The utility's billing rule, for `rounding=utility`.
+    pub rounding: RoundingPolicy,
+    /// How many reloads came before these, so that results cached under
+    /// older settings are not served.
+    pub generation: u64,
+}
+
+/// The `Settings` fields behind `Tunables`; changes to any other only take
+/// effect after a restart.
+pub const RELOADABLE: [&str; 18] = [
+    "lookback",
+    "fallback_lookback",
+    "latest_window",
+    "thresholds_file",
+    "holidays_file",
+    "changeovers_file",
+    "anomaly_mads",
+    "power_mismatch_percent",
+    "max_daily_kwh",
+    "max_reading_kwh",
+    "prefer_job",
+    "max_instances",
+    "max_response_rows",
+    "tariff_per_kwh",
+    "tariff_currency",
+    "rounding_stage",
+    "rounding_decimals",
+    "rounding_ties",
+];
+
+impl Tunables {
+    pub fn from_settings(settings: &Settings) -> Result<Self, Vec<String>> {
+        let mut errors = Vec::new();
+        let lookback = promql_duration_setting("LOOKBACK", &settings.lookback, &mut errors);
+        let fallback_lookback = match settings.fallback_lookback.trim() {
+            "" => Some(None),
+            v if parse_duration(v).is_some_and(|v| Some(v) <= parse_duration(&settings.lookback)) => {
+                errors.push("`FALLBACK_LOOKBACK` must be longer than `LOOKBACK`".to_string());
+                None
+            }
+            v => promql_duration_setting("FALLBACK_LOOKBACK", v, &mut errors).map(Some),
+        };
+        let latest_window =
+            promql_duration_setting("LATEST_WINDOW", &settings.latest_window, &mut errors);
+        let thresholds = check(Thresholds::from_settings(settings), &mut errors);
+        let holidays = match &settings.holidays_file {
+            Some(path) => check(load_holidays(path), &mut errors),
+            None => Some(HashSet::new()),
+        };
+        let changeovers = match &settings.changeovers_file {
+            Some(path) => check(load_changeovers(path), &mut errors),
+            None => Some(Vec::new()),
+        };
+        let anomaly_mads = check(
+            settings
+                .anomaly_mads
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|v| v.is_finite() && *v > 0.0)
+                .ok_or_else(|| format!("`ANOMALY_MADS` must be a positive number, got {:?}", settings.anomaly_mads)),
+            &mut errors,
+        );
+        let power_mismatch_percent = check(
+            settings
+                .power_mismatch_percent
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|v| v.is_finite() && *v > 0.0)
+                .ok_or_else(|| {
+                    format!(
+                        "`POWER_MISMATCH_PERCENT` must be a positive number, got {:?}",
+                        settings.power_mismatch_percent
+                    )
+                }),
+            &mut errors,
+        );
+        let max_daily_kwh = check(positive_number("MAX_DAILY_KWH", &settings.max_daily_kwh), &mut errors);
+        let max_reading_kwh = match settings.max_reading_kwh.trim() {
+            "" => Some(None),
+            _ => check(positive_number("MAX_READING_KWH", &settings.max_reading_kwh), &mut errors).map(Some),
+        };
+        let max_instances = check(
+            settings
+                .max_instances
+                .trim()
+                .parse::<usize>()
+                .ok()
+                .filter(|v| *v > 0)
+                .ok_or_else(|| format!("`MAX_INSTANCES` must be a positive integer, got {:?}", settings.max_instances)),
+            &mut errors,
+        );
+        let max_response_rows = check(
+            settings
+                .max_response_rows
+                .trim()
+                .parse::<usize>()
+                .ok()
+                .filter(|v| *v > 0)
+                .ok_or_else(|| {
+                    format!(
+                        "`MAX_RESPONSE_ROWS` must be a positive integer, got {:?}",
+                        settings.max_response_rows
+                    )
+                }),
+            &mut errors,
+        );
+        let tariff = check(Tariff::from_settings(settings), &mut errors);
+        let rounding = check(RoundingPolicy::from_settings(settings), &mut errors);
+
+        let tunables = (|| {
+            Some(Self {
+                lookback: lookback?,
+                fallback_lookback: fallback_lookback?,
+                latest_window: latest_window?,
+                thresholds: thresholds?,
+                holidays: holidays?,
+                changeovers: changeovers?,
+                anomaly_mads: anomaly_mads?,
+                power_mismatch_percent: power_mismatch_percent?,
+                max_daily_kwh: max_daily_kwh?,
+                max_reading_kwh: max_reading_kwh?,
+                prefer_job: Some(settings.prefer_job.trim().to_string()).filter(|job| !job.is_empty()),
+                max_instances: max_instances?,
+                max_response_rows: max_response_rows?,
+                tariff: tariff?,
+                rounding: rounding?,
+                generation: 0,
+            })
+        })();
+        match tunables {
+            Some(tunables) if errors.is_empty() => Ok(tunables),
+            _ => Err(errors),
+        }
+    }
+}
+
+/// Parses a positive duration setting, recording an error naming `name` otherwise.
+fn duration_setting(name: &str, value: &str, errors: &mut Vec<String>) -> Option<Duration> {
+    let duration = parse_duration(value).filter(|d| !d.is_zero());
+    if duration.is_none() {
+        errors.push(format!("`{}` must be a positive duration like `5s`, got {:?}", name, value));
+    }
+    duration
+}
+
+impl Config {
+    /// The reloadable settings as of the start of the current request, or
+    /// the latest outside one.
+    pub fn tunables(&self) -> Arc<Tunables> {
+        self.live.current()
+    }
+
+    /// Validates every setting, collecting all problems instead of stopping
+    /// at the first one.
+    pub fn from_settings(settings: &Settings) -> Result<Self, Vec<String>> {
+        let mut errors = Vec::new();
+
+        let fixtures = check(Fixtures::from_settings(settings), &mut errors);
+        // Replays need no Prometheus; the directory stands in for its URL.
+        let prometheus_url = match fixtures.as_ref().and_then(Option::as_ref) {
+            Some(Fixtures::Replay { dir, .. }) => check(
+                std::path::absolute(dir)
+                    .ok()
+                    .and_then(|dir| Url::from_directory_path(dir).ok())
+                    .ok_or_else(|| format!("`FIXTURE_DIR` is not a valid path: {}", dir.display())),
+                &mut errors,
+            ),
+            _ => check(parse_prometheus_url("`PROMETHEUS_HOST`", &settings.prometheus_host), &mut errors),
+        };
+        let prometheus_sites = check(parse_sites(&settings.prometheus_sites), &mut errors);
+        let prometheus_timeout =
+            duration_setting("PROMETHEUS_TIMEOUT", &settings.prometheus_timeout, &mut errors);
+        let shadow_url = match settings.shadow_prometheus_host.trim() {
+            "" => Some(None),
+            host => check(parse_prometheus_url("`SHADOW_PROMETHEUS_HOST`", host), &mut errors).map(Some),
+        };
+        let shadow_sample_rate = check(
+            positive_number("SHADOW_SAMPLE_RATE", &settings.shadow_sample_rate)
+                .and_then(|rate| match rate <= 1.0 {
+                    true => Ok(rate),
+                    false => Err(format!("`SHADOW_SAMPLE_RATE` must be at most 1, got {}", rate)),
+                }),
+            &mut errors,
+        );
+        let shadow_tolerance_kwh =
+            check(positive_number("SHADOW_TOLERANCE_KWH", &settings.shadow_tolerance_kwh), &mut errors);
+        let shadow_timeout = duration_setting("SHADOW_TIMEOUT", &settings.shadow_timeout, &mut errors);
+        let prometheus_client = check(ClientTuning::from_settings(settings), &mut errors);
+        let request_timeout = duration_setting("REQUEST_TIMEOUT", &settings.request_timeout, &mut errors);
+        let query_strategy = check(QueryStrategy::parse(&settings.query_strategy), &mut errors);
+        let fallback = check(Fallback::parse(&settings.fallback_mode), &mut errors);
+        let chunk_size = check(
+            match settings.chunk_size.trim() {
+                "" => Ok(None),
+                v => v.parse::<usize>().ok().filter(|v| *v > 0).map(Some).ok_or_else(|| {
+                    format!("`CHUNK_SIZE` must be a positive integer, got {:?}", settings.chunk_size)
+                }),
+            },
+            &mut errors,
+        );
+        let range_concurrency = check(
+            settings
+                .range_concurrency
+                .trim()
+                .parse::<usize>()
+                .ok()
+                .filter(|v| *v > 0)
+                .ok_or_else(|| {
+                    let value = &settings.range_concurrency;
+                    format!("`RANGE_CONCURRENCY` must be a positive integer, got {:?}", value)
+                }),
+            &mut errors,
+        );
+        let range_max_failed_fraction = check(
+            settings
+                .range_max_failed_fraction
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|v| (0.0..=1.0).contains(v))
+                .ok_or_else(|| {
+                    format!(
+                        "`RANGE_MAX_FAILED_FRACTION` must be between 0 and 1, got {:?}",
+                        settings.range_max_failed_fraction
+                    )
+                }),
+            &mut errors,
+        );
+        let timezone = check(
+            settings
+                .timezone
+                .parse::<Tz>()
+                .map_err(|_| format!("`TIMEZONE` is not an IANA timezone: {:?}", settings.timezone)),
+            &mut errors,
+        );
+        let bind_addrs = check(BindAddr::from_settings(settings), &mut errors);
+        let grpc_bind_addr = check(
+            Some(settings.grpc_bind_addr.trim())
+                .filter(|addr| !addr.is_empty())
+                .map(|addr| {
+                    addr.parse().map_err(|_| {
+                        format!("`GRPC_BIND_ADDR` is not a valid socket address: {:?}", settings.grpc_bind_addr)
+                    })
+                })
+                .transpose(),
+            &mut errors,
+        );
+        let graphql = check(
+            settings
+                .graphql
+                .trim()
+                .parse::<bool>()
+                .map_err(|_| format!("`GRAPHQL` must be `true` or `false`, got {:?}", settings.graphql)),
+            &mut errors,
+        );
+        let tls = check(TlsFiles::from_settings(settings), &mut errors);
+        let trusted_proxies = check(
+            client_ip::parse_trusted_proxies(&settings.trusted_proxies),
+            &mut errors,
+        );
+        let targets_window =
+            promql_duration_setting("TARGETS_WINDOW", &settings.targets_window, &mut errors);
+        let targets_cache_ttl =
+            duration_setting("TARGETS_CACHE_TTL", &settings.targets_cache_ttl, &mut errors);
+        let usage_cache_ttl = match settings.usage_cache_ttl.trim() {
+            "" => Some(None),
+            v => duration_setting("USAGE_CACHE_TTL", v, &mut errors).map(Some),
+        };
+        let usage_cache_stale = match settings.usage_cache_stale.trim() {
+            "" => Some(Duration::ZERO),
+            _ if settings.usage_cache_ttl.trim().is_empty() => {
+                errors.push("`USAGE_CACHE_STALE` needs `USAGE_CACHE_TTL`".to_string());
+                None
+            }
+            v => duration_setting("USAGE_CACHE_STALE", v, &mut errors),
+        };
+        let aliases = check(SharedAliases::new(settings.aliases_file.clone()), &mut errors);
+        let tunables = Tunables::from_settings(settings).map_err(|e| errors.extend(e)).ok();
+        let timezones = check(Timezones::from_settings(settings), &mut errors);
+        let electrical_metrics = check(metric_names(&settings.electrical_metrics), &mut errors);
+        let usage_metrics_interval =
+            duration_setting("USAGE_METRICS_INTERVAL", &settings.usage_metrics_interval, &mut errors);
+        let warm_budget = duration_setting("WARM_BUDGET", &settings.warm_budget, &mut errors);
+        if !settings.warm_targets.is_empty() && settings.usage_cache_ttl.trim().is_empty() {
+            errors.push("`WARM_TARGETS` needs `USAGE_CACHE_TTL`".to_string());
+        }
+        if settings.cache_dir.is_some() && settings.usage_cache_ttl.trim().is_empty() {
+            errors.push("`CACHE_DIR` needs `USAGE_CACHE_TTL`".to_string());
+        }
+        if settings.fallback_mode.trim() == "last_known" && settings.usage_cache_ttl.trim().is_empty() {
+            errors.push("`FALLBACK_MODE=last_known` needs `USAGE_CACHE_TTL`".to_string());
+        }
+        let usage_metrics_stale =
+            duration_setting("USAGE_METRICS_STALE", &settings.usage_metrics_stale, &mut errors);
+        let textfile = check(Textfile::from_settings(settings), &mut errors);
+        let remote_write = check(RemoteWrite::from_settings(settings), &mut errors);
+        let reports = match &settings.reports_file {
+            Some(path) => check(load_reports(path), &mut errors),
+            None => Some(Vec::new()),
+        };
+        let mailer = check(Mailer::from_settings(settings), &mut errors);
+        let object_store = check(ObjectStore::from_settings(settings), &mut errors);
+        let audit = check(AuditSettings::from_settings(settings), &mut errors);
+        let api_keys = check(ApiKeys::from_settings(settings), &mut errors);
+        let corrections = check(Corrections::from_settings(settings), &mut errors);
+        let jobs = check(JobLimits::from_settings(settings), &mut errors);
+        let anonymizer = check(Anonymizer::from_settings(settings), &mut errors);
+
+        let config = (|| {
+            Some(Self {
+                prometheus_url: prometheus_url?,
+                prometheus_sites: prometheus_sites?,
+                prometheus_timeout: prometheus_timeout?,
+                shadow_url: shadow_url?,
+                shadow_sample_rate: shadow_sample_rate?,
+                shadow_tolerance_kwh: shadow_tolerance_kwh?,
+                shadow_timeout: shadow_timeout?,
+                prometheus_client: prometheus_client?,
+                request_timeout: request_timeout?,
+                fixtures: fixtures?,
+                query_strategy: query_strategy?,
+                chunk_size: chunk_size?,
+                range_concurrency: range_concurrency?,
+                range_max_failed_fraction: range_max_failed_fraction?,
+                timezone: timezone?,
+                bind_addrs: bind_addrs?,
+                grpc_bind_addr: grpc_bind_addr?,
+                graphql: graphql?,
+                base_path: normalize_base_path(&settings.base_path),
+                tls: tls?,
+                trusted_proxies: trusted_proxies?,
+                targets_window: targets_window?,
+                targets_cache_ttl: targets_cache_ttl?,
+                usage_cache_ttl: usage_cache_ttl?,
+                usage_cache_stale: usage_cache_stale?,
+                fallback: fallback?,
+                aliases: aliases?,
+                timezones: timezones?,
+                electrical_metrics: electrical_metrics?,
+                live: Arc::new(Live::new(tunables?, settings)),
+                usage_metrics_targets: settings.usage_metrics_targets.clone(),
+                usage_metrics_interval: usage_metrics_interval?,
+                warm_targets: settings.warm_targets.clone(),
+                warm_budget: warm_budget?,
+                usage_metrics_stale: usage_metrics_stale?,
+                textfile: textfile?,
+                remote_write: remote_write?,
+                reports: reports?,
+                mailer: mailer?,
+                object_store: object_store?,
+                admin_token: Some(settings.admin_token.clone()).filter(|t| !t.is_empty()),
+                audit: audit?,
+                api_keys: api_keys?,
+                corrections: corrections?,
+                cache_dir: settings.cache_dir.clone(),
+                jobs: jobs?,
+                anonymizer: anonymizer?,
+            })
+        })();
+
+        match config {
+            Some(config) if errors.is_empty() => Ok(config),
+            _ => Err(errors),
+        }
+    }
+}
+
+fn positive_number(name: &str, value: &str) -> Result<f64, String> {
+    value
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .filter(|v| v.is_finite() && *v > 0.0)
+        .ok_or_else(|| format!("`{}` must be a positive number, got {:?}", name, value))
+}
+
+fn check<T>(result: Result<T, String>, errors: &mut Vec<String>) -> Option<T> {
+    result.map_err(|e| errors.push(e)).ok()
+}
+
+fn metric_names(names: &[String]) -> Result<Vec<String>, String> {
+    match names.iter().find(|name| !is_label_name(name)) {
+        Some(name) => Err(format!("`ELECTRICAL_METRICS` contains an invalid metric name: {:?}", name)),
+        None => Ok(names.to_vec()),
+    }
+}
+
+/// Validates a duration that is passed to PromQL verbatim, which needs whole seconds.
+fn promql_duration_setting(name: &str, value: &str, errors: &mut Vec<String>) -> Option<String> {
+    let valid = parse_duration(value).is_some_and(|d| d.as_secs() > 0 && d.subsec_nanos() == 0);
+    if !valid {
+        errors.push(format!(
+            "`{}` must be a duration of whole seconds like `10m`, got {:?}",
+            name, value
+        ));
+    }
+    valid.then(|| value.trim().to_string())
+}
+
+/// Accepts `host:port` (plain HTTP assumed) or a full URL, optionally with a
+/// path prefix. `setting` names the value in errors.
+fn parse_prometheus_url(setting: &str, host: &str) -> Result<Url, String> {
+    if host.is_empty() {
+        return Err(format!("{} not set", setting));
+    }
+    let with_scheme = if host.contains("://") {
+        host.to_string()
+    } else {
+        format!("http://{}", host)
+    };
+    let mut url = Url::parse(&with_scheme)
+        .map_err(|e| format!("{} is not a valid URL ({}): {:?}", setting, e, redact_userinfo(host)))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(format!("{} must use http or https, got {:?}", setting, url.scheme()));
+    }
+    // Keep any path prefix when joining API paths onto it.
+    if !url.path().ends_with('/') {
+        url.set_path(&format!("{}/", url.path()));
+    }
+    Ok(url)
+}
+
+/// Parses `PROMETHEUS_SITES` entries of the form `name=url`. Names are
+/// label-like and unique; `all` is reserved for fanning out.
+fn parse_sites(entries: &[String]) -> Result<Vec<(String, Url)>, String> {
+    let mut sites: Vec<(String, Url)> = Vec::new();
+    for entry in entries {
+        let Some((name, url)) = entry.split_once('=') else {
+            return Err(format!(
+                "`PROMETHEUS_SITES` entries must be `name=url`, got {:?}",
+                redact_userinfo(entry)
+            ));
+        };
+        let name = name.trim();
+        if !is_label_name(name) || name == "all" {
+            return Err(format!("`PROMETHEUS_SITES` has an invalid site name: {:?}", name));
+        }
+        if sites.iter().any(|(existing, _)| existing == name) {
+            return Err(format!("`PROMETHEUS_SITES` lists site {:?} twice", name));
+        }
+        let url = parse_prometheus_url(&format!("`PROMETHEUS_SITES` entry {:?}", name), url.trim())?;
+        sites.push((name.to_string(), url));
+    }
+    Ok(sites)
+}
+
+/// Normalises `base_path` to `/prefix` form; empty or `/` means no prefix.
+fn normalize_base_path(path: &str) -> String {
+    let path = path.trim_matches('/');
+    if path.is_empty() {
+        String::new()
+    } else {
+        format!("/{}", path)
+    }
+}
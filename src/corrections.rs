@@ -0,0 +1,210 @@
+use axum::http::StatusCode;
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use crate::{atomic_file::write_atomically, config::Settings, range::DailySeries};
+
+/// One local day's consumption of a meter as corrected by hand, reported
+/// instead of the one computed from its counter.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Correction {
+    pub id: String,
+    pub instance: String,
+    pub address: String,
+    pub date: NaiveDate,
+    pub corrected_daily_kwh: f64,
+    pub reason: String,
+    pub author: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// The body of `POST /api/v1/corrections`.
+#[derive(Deserialize)]
+pub struct NewCorrection {
+    pub instance: String,
+    pub address: String,
+    pub date: NaiveDate,
+    pub corrected_daily_kwh: f64,
+    pub reason: String,
+    pub author: String,
+}
+
+impl NewCorrection {
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.corrected_daily_kwh.is_finite() || self.corrected_daily_kwh < 0.0 {
+            return Err(format!("`corrected_daily_kwh` must be at least 0, got {}", self.corrected_daily_kwh));
+        }
+        let fields = [
+            ("instance", &self.instance),
+            ("address", &self.address),
+            ("reason", &self.reason),
+            ("author", &self.author),
+        ];
+        for (field, value) in fields {
+            if value.trim().is_empty() {
+                return Err(format!("`{}` must not be empty", field));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A correction as applied to an entry or day, for `meta=true`: what it
+/// says and what it replaced.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct AppliedCorrection {
+    #[serde(flatten)]
+    pub correction: Correction,
+    pub computed_daily_kwh: Option<f64>,
+}
+
+/// Whether a request asks for the values as computed, with
+/// `corrections=ignore`, rather than as corrected.
+pub fn ignores_corrections(params: &HashMap<String, String>) -> Result<bool, StatusCode> {
+    match params.get("corrections").map(String::as_str) {
+        None | Some("apply") => Ok(false),
+        Some("ignore") => Ok(true),
+        Some(_) => Err(StatusCode::BAD_REQUEST),
+    }
+}
+
+/// The corrections, in memory and, with `CORRECTIONS_FILE`, saved there on
+/// every change.
+pub struct Corrections {
+    file: Option<PathBuf>,
+    entries: Mutex<Vec<Correction>>,
+    /// Held while a change is saved, so changes are written in order.
+    saving: tokio::sync::Mutex<()>,
+}
+
+impl Corrections {
+    pub fn from_settings(settings: &Settings) -> Result<Self, String> {
+        let entries = match &settings.corrections_file {
+            Some(path) if path.exists() => load_corrections(path)?,
+            _ => Vec::new(),
+        };
+        Ok(Self {
+            file: settings.corrections_file.clone(),
+            entries: Mutex::new(entries),
+            saving: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    /// Every correction, or those of `instance`, by date and meter.
+    pub fn list(&self, instance: Option<&str>) -> Vec<Correction> {
+        let mut list: Vec<Correction> = self
+            .entries
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|c| instance.is_none_or(|instance| c.instance == instance))
+            .cloned()
+            .collect();
+        list.sort_by(|a, b| (a.date, &a.instance, &a.address).cmp(&(b.date, &b.instance, &b.address)));
+        list
+    }
+
+    /// The correction of `instance`/`address` on `date`.
+    pub fn find(&self, instance: &str, address: &str, date: NaiveDate) -> Option<Correction> {
+        let entries = self.entries.lock().unwrap();
+        entries.iter().find(|c| c.instance == instance && c.address == address && c.date == date).cloned()
+    }
+
+    /// Identifies the current corrections, empty when there are none, so
+    /// usage cached before a change is not served after it.
+    pub fn version(&self) -> String {
+        let entries = self.entries.lock().unwrap();
+        if entries.is_empty() {
+            return String::new();
+        }
+        let digest = Sha256::digest(serde_json::to_vec(&*entries).unwrap_or_default());
+        digest.iter().take(8).map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    /// Records `new`, once `validate`d, replacing any earlier correction of
+    /// the same meter and day. Fails only when it cannot be saved.
+    pub async fn add(&self, new: NewCorrection) -> Result<Correction, String> {
+        let correction = Correction {
+            id: uuid::Uuid::now_v7().to_string(),
+            instance: new.instance,
+            address: new.address,
+            date: new.date,
+            corrected_daily_kwh: new.corrected_daily_kwh,
+            reason: new.reason,
+            author: new.author,
+            created_at: Utc::now(),
+        };
+        let added = correction.clone();
+        self.change(move |entries| {
+            let meter_day = |c: &Correction| (c.instance.clone(), c.address.clone(), c.date);
+            let replaced = meter_day(&correction);
+            entries.retain(|c| meter_day(c) != replaced);
+            entries.push(correction);
+        })
+        .await?;
+        Ok(added)
+    }
+
+    /// Removes the correction with `id`, returning it.
+    pub async fn remove(&self, id: &str) -> Result<Option<Correction>, String> {
+        let mut removed = None;
+        self.change(|entries| {
+            if let Some(at) = entries.iter().position(|c| c.id == id) {
+                removed = Some(entries.remove(at));
+            }
+        })
+        .await?;
+        Ok(removed)
+    }
+
+    /// Applies `edit` to a copy of the corrections, saves that to
+    /// `CORRECTIONS_FILE` with `write_atomically`, and only then puts it in
+    /// place, so a failed save changes nothing.
+    async fn change(&self, edit: impl FnOnce(&mut Vec<Correction>)) -> Result<(), String> {
+        let _saving = self.saving.lock().await;
+        let mut entries = self.entries.lock().unwrap().clone();
+        edit(&mut entries);
+        if let Some(path) = &self.file {
+            let body = serde_json::to_vec_pretty(&entries).map_err(|e| e.to_string())?;
+            write_atomically(path, body).await.map_err(|e| format!("failed to save {}: {}", path.display(), e))?;
+        }
+        *self.entries.lock().unwrap() = entries;
+        Ok(())
+    }
+
+    /// Puts the corrected days of each meter in `series` in place of the
+    /// computed ones. A corrected day is neither implausible nor failed.
+    pub fn apply_to_series(&self, series: &mut [DailySeries]) {
+        let entries = self.entries.lock().unwrap();
+        for meter in series {
+            for correction in entries.iter() {
+                if (&correction.instance, &correction.address) != (&meter.instance, &meter.address) {
+                    continue;
+                }
+                let Some(day) = meter.days.iter_mut().find(|(date, _)| *date == correction.date) else {
+                    continue;
+                };
+                meter.corrected.push(AppliedCorrection {
+                    correction: correction.clone(),
+                    computed_daily_kwh: day.1,
+                });
+                day.1 = Some(correction.corrected_daily_kwh);
+                meter.implausible.retain(|date| *date != correction.date);
+                meter.failed.retain(|(date, _)| *date != correction.date);
+            }
+            meter.corrected.sort_by_key(|applied| applied.correction.date);
+        }
+    }
+}
+
+fn load_corrections(path: &Path) -> Result<Vec<Correction>, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+    serde_json::from_str(&contents).map_err(|e| format!("failed to parse {}: {}", path.display(), e))
+}
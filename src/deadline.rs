@@ -0,0 +1,60 @@
+use axum::{
+    extract::{Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use crate::{error::ApiError, state::AppState};
+
+tokio::task_local! {
+    /// Prometheus calls the current request has completed, kept outside the
+    /// handler so they can still be read once it has been dropped.
+    static COMPLETED: Arc<AtomicUsize>;
+}
+
+/// Counts `queries` completed Prometheus calls towards the current request.
+/// Does nothing outside a request, such as in the background loops and
+/// jobs, whose clients count with `Prometheus::counting` instead.
+pub fn note_queries(queries: usize) {
+    COMPLETED.try_with(|completed| completed.fetch_add(queries, Ordering::Relaxed)).ok();
+}
+
+/// Runs `future` with its completed Prometheus calls counted in `completed`.
+async fn counting<F: Future>(completed: Arc<AtomicUsize>, future: F) -> F::Output {
+    COMPLETED.scope(completed, future).await
+}
+
+/// Answers with a 504 once a request has run for `REQUEST_TIMEOUT`, giving
+/// up on the handler, so a load balancer with a shorter patience never
+/// throws away a finished response. Only the time to the response head
+/// counts: streamed bodies keep going after it.
+pub async fn deadline_middleware(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let budget = state.config.request_timeout;
+    let completed = Arc::new(AtomicUsize::new(0));
+    let path = req.uri().path().to_string();
+    let run = counting(completed.clone(), next.run(req));
+    match tokio::time::timeout(budget, run).await {
+        Ok(response) => response,
+        Err(_) => {
+            let completed = completed.load(Ordering::Relaxed);
+            tracing::warn!(path = %path, completed, "Request exceeded REQUEST_TIMEOUT of {:?}", budget);
+            ApiError::new(StatusCode::GATEWAY_TIMEOUT, "Request took longer than REQUEST_TIMEOUT")
+                .with("error_kind", "request_timeout")
+                .with("timeout_ms", budget.as_millis() as u64)
+                .with("completed_queries", completed)
+                .with(
+                    "hint",
+                    "Narrow the target or range, or use the streamed CSV/JSONL of /api/v1/power-usage/range",
+                )
+                .into_response()
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
+
+use crate::{
+    atomic_file::write_atomically,
+    cache::StaleCache,
+    state::AppState,
+    usage::{Usage, LAST_KNOWN_FOR, SETTLED_TTL},
+};
+
+/// How often settled results are written to `CACHE_DIR`, besides at shutdown.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// One settled result as stored in `CACHE_DIR`. Files written by another
+/// version may not match today's `Usage`, so they are never read back.
+#[derive(Deserialize, Serialize)]
+struct StoredEntry {
+    version: String,
+    key: String,
+    stored_at: DateTime<Utc>,
+    usage: Usage,
+}
+
+/// Keeps the usage results for settled days, which never change, in
+/// `CACHE_DIR` so a restart does not have to query them again. Recent
+/// results stay in memory only.
+pub struct DiskCache {
+    dir: PathBuf,
+    /// Settled results not written yet, by cache key.
+    pending: Mutex<HashMap<String, (DateTime<Utc>, Usage)>>,
+}
+
+impl DiskCache {
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            pending: Mutex::default(),
+        }
+    }
+
+    /// Queues a settled result for the next `flush`.
+    pub fn queue(&self, key: String, usage: Usage) {
+        self.pending.lock().unwrap().insert(key, (Utc::now(), usage));
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{:016x}.json", fnv1a(key)))
+    }
+
+    /// The result last stored for `key`, queued or written, for
+    /// `fallback=last_known` once the memory cache no longer holds it.
+    pub async fn last_known(&self, key: &str) -> Option<Usage> {
+        if let Some((_, usage)) = self.pending.lock().unwrap().get(key) {
+            return Some(usage.clone());
+        }
+        let body = tokio::fs::read(self.path(key)).await.ok()?;
+        let entry: StoredEntry = serde_json::from_slice(&body).ok()?;
+        let kept = entry.version == env!("CARGO_PKG_VERSION") && entry.key == key;
+        kept.then_some(entry.usage)
+    }
+
+    /// Puts every stored result younger than `SETTLED_TTL` into `cache`.
+    /// Older ones are left for `last_known` until `LAST_KNOWN_FOR` past
+    /// that; those, unreadable and other-version files are deleted.
+    pub fn load(&self, cache: &StaleCache<String, Usage>) {
+        let Ok(files) = fs::read_dir(&self.dir) else {
+            return;
+        };
+        let mut loaded = 0;
+        for path in files.filter_map(|file| Some(file.ok()?.path())) {
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            let usable = read_entry(&path).and_then(|entry| {
+                let age = (Utc::now() - entry.stored_at).to_std().ok()?;
+                (age < SETTLED_TTL + LAST_KNOWN_FOR).then_some((SETTLED_TTL.checked_sub(age), entry))
+            });
+            match usable {
+                Some((Some(left), entry)) if !left.is_zero() => {
+                    cache.insert_for(entry.key, entry.usage, left, Duration::ZERO);
+                    loaded += 1;
+                }
+                Some(_) => {}
+                None => {
+                    fs::remove_file(&path).ok();
+                }
+            }
+        }
+        tracing::info!("Loaded {} cached usage results from {}", loaded, self.dir.display());
+    }
+
+    /// Writes the queued results with `write_atomically`. Failures are only
+    /// logged.
+    pub async fn flush(&self) {
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+        if pending.is_empty() {
+            return;
+        }
+        if let Err(e) = tokio::fs::create_dir_all(&self.dir).await {
+            tracing::warn!("Failed to create {}: {}", self.dir.display(), e);
+            return;
+        }
+        for (key, (stored_at, usage)) in pending {
+            let path = self.path(&key);
+            let entry = StoredEntry {
+                version: env!("CARGO_PKG_VERSION").to_string(),
+                key,
+                stored_at,
+                usage,
+            };
+            let Ok(body) = serde_json::to_vec(&entry) else {
+                continue;
+            };
+            if let Err(e) = write_atomically(&path, body).await {
+                tracing::warn!("Failed to write {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+fn read_entry(path: &Path) -> Option<StoredEntry> {
+    let entry: StoredEntry = serde_json::from_slice(&fs::read(path).ok()?).ok()?;
+    (entry.version == env!("CARGO_PKG_VERSION")).then_some(entry)
+}
+
+/// Stable across builds, unlike `DefaultHasher`, so restarts find the same files.
+fn fnv1a(key: &str) -> u64 {
+    key.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Flushes settled results to `CACHE_DIR` every `FLUSH_INTERVAL`.
+pub async fn flush_loop(state: AppState) {
+    let Some(disk_cache) = &state.disk_cache else {
+        return;
+    };
+    let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+    loop {
+        interval.tick().await;
+        disk_cache.flush().await;
+    }
+}
@@ -0,0 +1,216 @@
+use axum::{
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::{any::Any, borrow::Cow, fmt};
+
+use crate::{metrics::PANICS_TOTAL, prometheus::Failure, request_id};
+
+#[derive(Serialize)]
+struct ErrorBody {
+    error: Cow<'static, str>,
+    /// Anything the error carries beyond its message.
+    #[serde(flatten)]
+    details: Map<String, Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+}
+
+pub fn json_error(code: StatusCode, error: impl Into<Cow<'static, str>>) -> Response {
+    ApiError::new(code, error).into_response()
+}
+
+pub fn error_response(error: impl Into<ApiError>) -> Response {
+    error.into().into_response()
+}
+
+fn message(code: StatusCode) -> &'static str {
+    match code {
+        StatusCode::UNPROCESSABLE_ENTITY => "Too many instances match; narrow the target or pass truncate=true",
+        StatusCode::PAYLOAD_TOO_LARGE => {
+            "Response would exceed MAX_RESPONSE_ROWS; stream it with format=csv on /api/v1/power-usage/range \
+             or run it as a job with POST /api/v1/jobs"
+        }
+        StatusCode::UNAUTHORIZED => "Missing or invalid admin token",
+        StatusCode::NOT_FOUND => "Not found",
+        StatusCode::INTERNAL_SERVER_ERROR => "Internal server error",
+        StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT => {
+            "Prometheus query failed"
+        }
+        _ => "Invalid request",
+    }
+}
+
+/// Why a step of a request failed, passed up through `Result` to the
+/// handler: a status with its usual message, a 400 with its own, or a
+/// failed Prometheus call, which keeps what went wrong for the error body.
+#[derive(Clone)]
+pub enum Error {
+    Status(StatusCode),
+    Invalid(&'static str),
+    Prometheus(Failure),
+}
+
+impl Error {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::Status(code) => *code,
+            Self::Invalid(_) => StatusCode::BAD_REQUEST,
+            Self::Prometheus(failure) => failure.kind.status(),
+        }
+    }
+
+    /// The failed Prometheus call, if that is what failed.
+    pub fn failure(&self) -> Option<&Failure> {
+        match self {
+            Self::Status(_) | Self::Invalid(_) => None,
+            Self::Prometheus(failure) => Some(failure),
+        }
+    }
+}
+
+impl From<StatusCode> for Error {
+    fn from(code: StatusCode) -> Self {
+        Self::Status(code)
+    }
+}
+
+impl From<Failure> for Error {
+    fn from(failure: Failure) -> Self {
+        Self::Prometheus(failure)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Status(code) => write!(f, "{}: {}", code, message(*code)),
+            Self::Invalid(message) => write!(f, "{}: {}", StatusCode::BAD_REQUEST, message),
+            Self::Prometheus(failure) => write!(f, "{}: {}", failure.kind.status(), failure.kind.message()),
+        }
+    }
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        ApiError::from(self).into_response()
+    }
+}
+
+/// A failed request as its status and JSON error body. Plain status codes
+/// convert into one with the usual message, so handlers can return either
+/// through `?`; the fields added with `with` are written next to `error`.
+/// A failed Prometheus call also gets its `error_kind`, the rejected
+/// expression, Prometheus' own error message and its `Retry-After`.
+pub struct ApiError {
+    code: StatusCode,
+    error: Cow<'static, str>,
+    details: Map<String, Value>,
+    retry_after: Option<HeaderValue>,
+}
+
+impl ApiError {
+    pub fn new(code: StatusCode, error: impl Into<Cow<'static, str>>) -> Self {
+        Self {
+            code,
+            error: error.into(),
+            details: Map::new(),
+            retry_after: None,
+        }
+    }
+
+    pub fn with(mut self, key: &str, value: impl Into<Value>) -> Self {
+        self.details.insert(key.to_string(), value.into());
+        self
+    }
+
+    /// Sets `Retry-After`, in seconds.
+    pub fn retry_after(mut self, seconds: i64) -> Self {
+        self.retry_after = Some(HeaderValue::from(seconds));
+        self
+    }
+
+    /// `target` matches none of the instances known to be reporting.
+    pub fn unknown_target(target: &str, suggestions: Vec<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, "target matches no known instance")
+            .with("target", target)
+            .with("suggestions", suggestions)
+            .with("hint", "GET /api/v1/targets to list instances, or validate_target=false to query anyway")
+    }
+
+    /// The query ran but `target` matched no series at all.
+    pub fn no_series(target: &str) -> Self {
+        Self::new(StatusCode::NOT_FOUND, "no series matched")
+            .with("target", target)
+            .with("hint", "GET /api/v1/targets to list instances")
+    }
+}
+
+impl From<StatusCode> for ApiError {
+    fn from(code: StatusCode) -> Self {
+        Self::new(code, message(code))
+    }
+}
+
+impl From<Failure> for ApiError {
+    fn from(failure: Failure) -> Self {
+        let kind = failure.kind;
+        let mut error = Self::new(kind.status(), kind.message()).with("error_kind", kind.name());
+        if let Some(expr) = failure.expr {
+            error = error.with("expr", expr);
+        }
+        if let Some(upstream) = failure.upstream {
+            error = error.with("upstream", upstream);
+        }
+        error.retry_after = failure.retry_after.and_then(|v| HeaderValue::try_from(v).ok());
+        error
+    }
+}
+
+impl From<Error> for ApiError {
+    fn from(error: Error) -> Self {
+        match error {
+            Error::Status(code) => code.into(),
+            Error::Invalid(message) => Self::new(StatusCode::BAD_REQUEST, message),
+            Error::Prometheus(failure) => failure.into(),
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.code, self.error)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = ErrorBody {
+            error: self.error,
+            details: self.details,
+            request_id: request_id::current(),
+        };
+        let mut response = (self.code, Json(body)).into_response();
+        if let Some(retry_after) = self.retry_after {
+            response.headers_mut().insert(header::RETRY_AFTER, retry_after);
+        }
+        response
+    }
+}
+
+/// Turns a caught handler panic into a logged, counted 500 response.
+pub fn panic_response(err: Box<dyn Any + Send + 'static>) -> Response {
+    let message = err
+        .downcast_ref::<String>()
+        .map(String::as_str)
+        .or_else(|| err.downcast_ref::<&str>().copied())
+        .unwrap_or("unknown panic");
+
+    tracing::error!(panic = %message, "handler panicked");
+    metrics::counter!(PANICS_TOTAL).increment(1);
+
+    json_error(StatusCode::INTERNAL_SERVER_ERROR, "Internal server error")
+}
@@ -0,0 +1,185 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+use crate::{
+    error::Error,
+    flags::{self, Flags},
+    natural::Natural,
+    prometheus::{self, Prometheus},
+    state::AppState,
+    usage::{
+        avg_power_watt, hours_between, is_implausible, note_implausible, Usage, UsageEntry, UsageRequest,
+        PHASE_LABEL,
+    },
+};
+
+/// Days the consumption rate is averaged over, and the furthest back the
+/// last reading may be.
+const BASIS_DAYS: i64 = 7;
+/// How finely the readings before a missing one are looked at.
+const STEP: std::time::Duration = std::time::Duration::from_secs(15 * 60);
+
+/// How an estimated entry's current reading was extrapolated, with
+/// `estimate=true`.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Estimate {
+    /// When the meter was last read, to within 15 minutes.
+    pub last_reading_time: DateTime<Utc>,
+    pub last_reading_kwh: f64,
+    /// Average daily consumption over the `basis_days` up to the last
+    /// reading, at which the counter is carried forward.
+    pub rate_kwh_per_day: f64,
+    pub basis_days: f64,
+}
+
+/// One series' readings every `STEP`, oldest first.
+struct History {
+    labels: HashMap<String, String>,
+    points: Vec<(DateTime<Utc>, f64)>,
+}
+
+/// With `estimate=true`: adds an entry for every meter of `req` that misses
+/// its current reading but was read within `BASIS_DAYS`, extrapolating the
+/// last reading at the meter's average daily consumption over the
+/// `BASIS_DAYS` before it. The previous reading is the real one when there
+/// is one, and extrapolated the same way when the meter stopped before it.
+/// Estimates are added to a result already taken from or put in the usage
+/// cache, so they are never cached.
+pub async fn add_estimates(
+    state: &AppState,
+    req: &UsageRequest,
+    usage: &mut Usage,
+) -> Result<(), Error> {
+    let expr = Prometheus::last_over_time_expr(&req.selector, &format!("{}s", STEP.as_secs()));
+    let start = req.curr_dt - Duration::days(2 * BASIS_DAYS);
+    let series = state.prometheus.query_range(&expr, start, req.curr_dt, STEP).await?;
+
+    // Where several jobs report a meter, the one read last.
+    let mut latest: HashMap<(String, String, Option<String>), History> = HashMap::new();
+    for item in series {
+        let mut labels: HashMap<String, String> = match serde_json::from_value(item["metric"].clone()) {
+            Ok(labels) => labels,
+            Err(_) => continue,
+        };
+        labels.remove("__name__");
+        let points: Vec<(DateTime<Utc>, f64)> = item["values"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|point| {
+                let time = DateTime::from_timestamp_millis((point[0].as_f64()? * 1000.0) as i64)?;
+                Some((time, prometheus::parse_value(point[1].as_str()?).ok()?))
+            })
+            .collect();
+        let instance = labels.get("instance").cloned().unwrap_or_else(|| "unknown".to_string());
+        let address = labels.get("address").cloned().unwrap_or_default();
+        let key = (instance, address, labels.get(PHASE_LABEL).cloned());
+        let newer = latest.get(&key).is_none_or(|kept| kept.points.last() < points.last());
+        if newer {
+            latest.insert(key, History { labels, points });
+        }
+    }
+
+    let mut estimated = 0;
+    for ((instance, address, phase), history) in latest {
+        let read = usage.entries.iter().any(|e| {
+            e.instance == instance && e.address == address && e.labels.get(PHASE_LABEL) == phase.as_ref()
+        });
+        if read || req.address.as_ref().is_some_and(|only| *only != address) {
+            continue;
+        }
+        let Some(entry) = estimate(state, req, instance, address, history) else {
+            continue;
+        };
+        let at = usage
+            .entries
+            .iter()
+            .position(|e| order(e) > order(&entry))
+            .unwrap_or(usage.entries.len());
+        usage.entries.insert(at, entry);
+        estimated += 1;
+    }
+    usage.matched |= estimated > 0;
+    Ok(())
+}
+
+/// Entries by instance, then address in `natural` order.
+fn order(entry: &UsageEntry) -> (&str, Natural) {
+    (&entry.instance, Natural(entry.address.clone()))
+}
+
+fn estimate(
+    state: &AppState,
+    req: &UsageRequest,
+    instance: String,
+    address: String,
+    history: History,
+) -> Option<UsageEntry> {
+    let &(last_time, last_kwh) = history.points.last()?;
+    if last_time < req.curr_dt - Duration::days(BASIS_DAYS) {
+        return None;
+    }
+    let &(first_time, first_kwh) =
+        history.points.iter().find(|(time, _)| *time >= last_time - Duration::days(BASIS_DAYS))?;
+    let basis_days = (last_time - first_time).num_seconds() as f64 / 86400.0;
+    let rate = (last_kwh - first_kwh) / basis_days;
+    // Less than a day says little about a daily rate, and a counter reset
+    // in the basis nothing at all.
+    if basis_days < 1.0 || !rate.is_finite() || rate < 0.0 {
+        return None;
+    }
+    let at = |time: DateTime<Utc>| last_kwh + rate * (time - last_time).num_seconds() as f64 / 86400.0;
+    let curr_kwh = at(req.curr_dt);
+    let prev_kwh = match history.points.iter().find(|(time, _)| *time == req.prev_dt) {
+        Some((_, kwh)) => Some(*kwh),
+        None if last_time < req.prev_dt => Some(at(req.prev_dt)),
+        None => None,
+    };
+
+    let tunables = state.config.tunables();
+    let daily = prev_kwh.map(|prev| curr_kwh - prev);
+    let hours = hours_between(req.prev_dt, req.curr_dt);
+    let mut flags = Flags::default();
+    flags.set(flags::ESTIMATED);
+    flags.set_if(flags::MISSING_PREV, prev_kwh.is_none());
+    let implausible = is_implausible(&tunables, prev_kwh, Some(curr_kwh));
+    if implausible {
+        note_implausible(state, (&instance, &address), (req.prev_dt, req.curr_dt), prev_kwh, Some(curr_kwh));
+    }
+    flags.set_if(flags::IMPLAUSIBLE, implausible);
+    let name = state.config.aliases.current().name(&instance, &address).map(str::to_string);
+    let threshold_kwh = tunables
+        .thresholds
+        .for_meter(&instance, &address, name.as_deref())
+        .or(req.threshold_kwh);
+    Some(UsageEntry {
+        instance,
+        address,
+        name,
+        timezone: None,
+        prev_kwh,
+        curr_kwh,
+        daily_kwh: daily,
+        avg_power_watt: daily.map(|kwh| avg_power_watt(kwh, hours)),
+        period_hours: prev_kwh.map(|_| (hours * 1000.0).round() / 1000.0),
+        avg_power_watt_24h: daily.map(|kwh| avg_power_watt(kwh, 24.0)),
+        prev_sample_time: None,
+        curr_sample_time: None,
+        flags,
+        labels: history.labels,
+        threshold_kwh,
+        comparison: None,
+        power_gauge: None,
+        members: None,
+        part_of: None,
+        sample_age_seconds: None,
+        correction: None,
+        estimate: Some(Estimate {
+            last_reading_time: last_time,
+            last_reading_kwh: last_kwh,
+            rate_kwh_per_day: rate,
+            basis_days: (basis_days * 100.0).round() / 100.0,
+        }),
+    })
+}
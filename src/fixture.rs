@@ -0,0 +1,148 @@
+use serde_json::{json, Value};
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::config::{parse_duration, Settings};
+
+/// Canned Prometheus API responses, one JSON file per call in `FIXTURE_DIR`,
+/// for working offline and for the integration tests. With `BACKEND=fixture`
+/// every call is answered from the directory; with `FIXTURE_RECORD=true` the
+/// real backend writes each successful response into it.
+#[derive(Clone)]
+pub enum Fixtures {
+    Replay { dir: PathBuf, latency: Duration },
+    Record { dir: PathBuf },
+}
+
+impl Fixtures {
+    pub fn from_settings(settings: &Settings) -> Result<Option<Self>, String> {
+        let record = settings.fixture_record.trim().parse::<bool>().map_err(|_| {
+            format!("`FIXTURE_RECORD` must be `true` or `false`, got {:?}", settings.fixture_record)
+        })?;
+        let dir = || {
+            let missing = "`FIXTURE_DIR` must be set with `BACKEND=fixture` or `FIXTURE_RECORD=true`";
+            settings.fixture_dir.clone().ok_or_else(|| missing.to_string())
+        };
+        match settings.backend.trim() {
+            "prometheus" if record => Ok(Some(Self::Record { dir: dir()? })),
+            "prometheus" => Ok(None),
+            "fixture" if record => Err("`FIXTURE_RECORD=true` needs `BACKEND=prometheus`".to_string()),
+            "fixture" => {
+                let dir = dir()?;
+                if !dir.is_dir() {
+                    return Err(format!("`FIXTURE_DIR` is not a directory: {}", dir.display()));
+                }
+                let latency = Some(settings.fixture_latency.trim())
+                    .filter(|v| !v.is_empty())
+                    .map(|v| {
+                        parse_duration(v).ok_or_else(|| {
+                            format!("`FIXTURE_LATENCY` must be a duration like `50ms`, got {:?}", v)
+                        })
+                    })
+                    .transpose()?
+                    .unwrap_or_default();
+                Ok(Some(Self::Replay { dir, latency }))
+            }
+            other => Err(format!("`BACKEND` must be `prometheus` or `fixture`, got {:?}", other)),
+        }
+    }
+
+    /// The body recorded for this call, after the artificial latency, or
+    /// `None` with the file that was looked for logged. Only replays answer.
+    pub async fn replay(&self, path: &str, query: &[(&str, String)]) -> Option<Vec<u8>> {
+        let Self::Replay { dir, latency } = self else {
+            return None;
+        };
+        tokio::time::sleep(*latency).await;
+        let file = fixture_path(dir, path, query);
+        let contents = match tokio::fs::read(&file).await {
+            Ok(contents) => contents,
+            Err(e) => {
+                tracing::warn!("No fixture {} for {} {:?}: {}", file.display(), path, query, e);
+                return None;
+            }
+        };
+        match serde_json::from_slice::<Value>(&contents) {
+            Ok(mut fixture) => serde_json::to_vec(&fixture["response"].take()).ok(),
+            Err(e) => {
+                tracing::warn!("Invalid fixture {}: {}", file.display(), e);
+                None
+            }
+        }
+    }
+
+    /// Writes a successful response for replaying later. Failures are only
+    /// logged; recording never fails the call itself.
+    pub async fn record(&self, path: &str, query: &[(&str, String)], response: &Value) {
+        let Self::Record { dir } = self else {
+            return;
+        };
+        let params: serde_json::Map<String, Value> =
+            query.iter().map(|(key, value)| (key.to_string(), value.clone().into())).collect();
+        let fixture = json!({"path": path, "query": params, "response": response});
+        let file = fixture_path(dir, path, query);
+        let written = match serde_json::to_vec_pretty(&fixture) {
+            Ok(body) => match tokio::fs::create_dir_all(dir).await {
+                Ok(()) => tokio::fs::write(&file, body).await,
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = written {
+            tracing::warn!("Failed to record fixture {}: {}", file.display(), e);
+        }
+    }
+}
+
+/// `<key>.json`, where the key hashes the API path and the query
+/// parameters sorted by name, with whitespace in the expression collapsed
+/// so reformatting a query does not lose its fixture. FNV-1a keeps the
+/// names stable across builds and Rust versions.
+fn fixture_path(dir: &Path, path: &str, query: &[(&str, String)]) -> PathBuf {
+    let mut params: Vec<(&str, String)> = query
+        .iter()
+        .map(|(key, value)| match *key {
+            "query" => (*key, value.split_whitespace().collect::<Vec<_>>().join(" ")),
+            _ => (*key, value.clone()),
+        })
+        .collect();
+    params.sort();
+    let mut normalised = path.to_string();
+    for (key, value) in params {
+        normalised.push_str(&format!("\n{}={}", key, value));
+    }
+    let hash = normalised.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    });
+    dir.join(format!("{:016x}.json", hash))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(pairs: &[(&'static str, &str)]) -> Vec<(&'static str, String)> {
+        pairs.iter().map(|(key, value)| (*key, value.to_string())).collect()
+    }
+
+    #[test]
+    fn key_of_a_recorded_fixture() {
+        // tests/fixtures/05f54ae3839d58b2.json; a new hash would orphan every fixture.
+        let file = fixture_path(Path::new("fixtures"), "api/v1/query", &query(&[("query", "vector(1)")]));
+        assert_eq!(file, Path::new("fixtures/05f54ae3839d58b2.json"));
+    }
+
+    #[test]
+    fn key_ignores_parameter_order_and_query_whitespace() {
+        let dir = Path::new("fixtures");
+        let file = fixture_path(dir, "api/v1/query", &query(&[("query", "a or b"), ("time", "2025-08-01T00:00:00Z")]));
+        let reordered = query(&[("time", "2025-08-01T00:00:00Z"), ("query", "  a\n    or b ")]);
+        assert_eq!(fixture_path(dir, "api/v1/query", &reordered), file);
+        let later = query(&[("query", "a or b"), ("time", "2025-08-02T00:00:00Z")]);
+        assert_ne!(fixture_path(dir, "api/v1/query", &later), file);
+        let elsewhere = query(&[("query", "a or b"), ("time", "2025-08-01T00:00:00Z")]);
+        assert_ne!(fixture_path(dir, "api/v1/query_range", &elsewhere), file);
+    }
+}
@@ -0,0 +1,154 @@
+use axum::http::StatusCode;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::HashMap;
+
+/// No previous reading, so no consumption.
+pub const MISSING_PREV: &str = "missing_prev";
+/// A counter that went backwards, or beyond `MAX_DAILY_KWH` or
+/// `MAX_READING_KWH`.
+pub const IMPLAUSIBLE: &str = "implausible";
+/// Reported by more than one series with the same labels.
+pub const DUPLICATE_SERIES: &str = "duplicate_series";
+/// The averaged `power` gauge disagrees with the counter beyond
+/// `POWER_MISMATCH_PERCENT`.
+pub const POWER_MISMATCH: &str = "power_mismatch";
+/// A composite summed without all of its members.
+pub const PARTIAL_COMPOSITE: &str = "partial_composite";
+/// Extrapolated by `estimate=true`.
+pub const ESTIMATED: &str = "estimated";
+/// Replaced by a correction.
+pub const CORRECTED: &str = "corrected";
+/// A report day with a meter changeover.
+pub const CHANGEOVER: &str = "changeover";
+/// A report day filled in by `interpolate=linear`.
+pub const INTERPOLATED: &str = "interpolated";
+/// A report day whose query failed.
+pub const FAILED: &str = "failed";
+/// A report day with no consumption.
+pub const MISSING: &str = "missing";
+/// A report day averaged over a shorter `smooth=` window at the edge of
+/// the range.
+pub const SMOOTHED_PARTIAL: &str = "smoothed_partial";
+/// A report day of a meter below `min_completeness=`.
+pub const INCOMPLETE: &str = "incomplete";
+
+/// Every flag an entry or report day may carry. A new detection adds its
+/// name here and sets it with `Flags::set`; `flagged_only=true` and
+/// `exclude_flags=` then cover it with nothing more.
+pub const REGISTRY: [&str; 13] = [
+    MISSING_PREV,
+    IMPLAUSIBLE,
+    DUPLICATE_SERIES,
+    POWER_MISMATCH,
+    PARTIAL_COMPOSITE,
+    ESTIMATED,
+    CORRECTED,
+    CHANGEOVER,
+    INTERPOLATED,
+    FAILED,
+    MISSING,
+    SMOOTHED_PARTIAL,
+    INCOMPLETE,
+];
+
+/// An entry's flags, each once, in the order they were set. Serialized as
+/// an array of names, and read back, from `CACHE_DIR`, only when every
+/// name is in `REGISTRY`.
+#[derive(Clone, Default, Serialize)]
+#[serde(transparent)]
+pub struct Flags(Vec<&'static str>);
+
+impl Flags {
+    pub fn set(&mut self, flag: &'static str) {
+        if !self.contains(flag) {
+            self.0.push(flag);
+        }
+    }
+
+    /// Sets `flag` when `condition` holds.
+    pub fn set_if(&mut self, flag: &'static str, condition: bool) {
+        if condition {
+            self.set(flag);
+        }
+    }
+
+    pub fn unset(&mut self, flag: &str) {
+        self.0.retain(|set| *set != flag);
+    }
+
+    pub fn contains(&self, flag: &str) -> bool {
+        self.0.contains(&flag)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn names(&self) -> &[&'static str] {
+        &self.0
+    }
+
+    /// Every flag of `self` and then of `other`.
+    pub fn union(&mut self, other: &Flags) {
+        for flag in other.names() {
+            self.set(flag);
+        }
+    }
+}
+
+impl IntoIterator for Flags {
+    type Item = &'static str;
+    type IntoIter = std::vec::IntoIter<&'static str>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+impl<'de> Deserialize<'de> for Flags {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let names = Vec::<String>::deserialize(deserializer)?;
+        let mut flags = Self::default();
+        for name in names {
+            let flag = known(&name).ok_or_else(|| format!("unknown flag {:?}", name));
+            flags.set(flag.map_err(serde::de::Error::custom)?);
+        }
+        Ok(flags)
+    }
+}
+
+/// The registry's name for `name`.
+pub fn known(name: &str) -> Option<&'static str> {
+    REGISTRY.iter().find(|flag| **flag == name).copied()
+}
+
+/// Which entries a request keeps by their flags: with `flagged_only=true`,
+/// only those with at least one, and with `exclude_flags=`, a comma-separated
+/// list of names, none with any of those.
+#[derive(Clone, Default)]
+pub struct FlagFilter {
+    flagged_only: bool,
+    exclude: Vec<&'static str>,
+}
+
+impl FlagFilter {
+    /// A 400 for a name not in `REGISTRY`.
+    pub fn from_params(params: &HashMap<String, String>) -> Result<Self, StatusCode> {
+        let exclude = params
+            .get("exclude_flags")
+            .into_iter()
+            .flat_map(|names| names.split(','))
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(|name| known(name).ok_or(StatusCode::BAD_REQUEST))
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            flagged_only: params.get("flagged_only").is_some_and(|v| v == "true"),
+            exclude,
+        })
+    }
+
+    pub fn keeps(&self, flags: &Flags) -> bool {
+        (!self.flagged_only || !flags.is_empty()) && !self.exclude.iter().any(|flag| flags.contains(flag))
+    }
+}
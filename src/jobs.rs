@@ -0,0 +1,458 @@
+use axum::{
+    body::{to_bytes, Bytes},
+    extract::{Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::Value;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::{sync::Semaphore, task::AbortHandle};
+
+use crate::{
+    api::{self, range::Report},
+    config::{parse_duration, Settings},
+    error::ApiError,
+    state::AppState,
+};
+
+/// The endpoints a job may run, by the name given as `report=`.
+pub const REPORTS: [&str; 4] = ["range", "weekly", "monthly", "profile"];
+/// Largest result a job may produce.
+const MAX_RESULT_BYTES: usize = 256 * 1024 * 1024;
+/// How often finished jobs past `JOBS_RETENTION` are removed.
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How many jobs run at once, how many one API key may have unfinished,
+/// and how long finished ones are kept.
+pub struct JobLimits {
+    pub workers: usize,
+    pub per_key: usize,
+    pub retention: Duration,
+}
+
+impl JobLimits {
+    pub fn from_settings(settings: &Settings) -> Result<Self, String> {
+        let count = |name: &str, value: &str| {
+            value
+                .trim()
+                .parse::<usize>()
+                .ok()
+                .filter(|v| *v > 0)
+                .ok_or_else(|| format!("`{}` must be a positive integer, got {:?}", name, value))
+        };
+        let retention = &settings.jobs_retention;
+        Ok(Self {
+            workers: count("JOBS_WORKERS", &settings.jobs_workers)?,
+            per_key: count("JOBS_PER_KEY", &settings.jobs_per_key)?,
+            retention: parse_duration(retention).filter(|d| !d.is_zero()).ok_or_else(|| {
+                format!("`JOBS_RETENTION` must be a positive duration like `1h`, got {:?}", retention)
+            })?,
+        })
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Done,
+    Failed,
+    Cancelled,
+}
+
+impl JobStatus {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Queued => "queued",
+            Self::Running => "running",
+            Self::Done => "done",
+            Self::Failed => "failed",
+            Self::Cancelled => "cancelled",
+        }
+    }
+
+    fn is_finished(self) -> bool {
+        !matches!(self, Self::Queued | Self::Running)
+    }
+}
+
+/// Where a finished job's file is kept.
+enum Stored {
+    Memory(Bytes),
+    /// The key in `S3_BUCKET`.
+    ObjectStore(String),
+}
+
+struct JobResult {
+    content_type: String,
+    filename: String,
+    bytes: usize,
+    stored: Stored,
+}
+
+struct Job {
+    /// The API key that submitted it, from `KEYS_FILE`.
+    owner: Option<String>,
+    report: String,
+    params: BTreeMap<String, String>,
+    status: JobStatus,
+    created_at: DateTime<Utc>,
+    started_at: Option<DateTime<Utc>>,
+    finished_at: Option<DateTime<Utc>>,
+    completed_queries: Arc<AtomicUsize>,
+    error: Option<String>,
+    result: Option<JobResult>,
+    abort: Option<AbortHandle>,
+}
+
+impl Job {
+    /// Whether it counts toward `JOBS_PER_KEY`: it is still to run, or its
+    /// result takes up memory.
+    fn is_held(&self) -> bool {
+        let in_memory = self.result.as_ref().is_some_and(|r| matches!(r.stored, Stored::Memory(_)));
+        !self.status.is_finished() || in_memory
+    }
+}
+
+#[derive(Serialize)]
+pub struct ResultView {
+    content_type: String,
+    filename: String,
+    bytes: usize,
+    url: String,
+}
+
+/// A job as `/api/v1/jobs/<id>` reports it.
+#[derive(Serialize)]
+pub struct JobView {
+    pub id: String,
+    report: String,
+    params: BTreeMap<String, String>,
+    status: JobStatus,
+    created_at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    started_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    finished_at: Option<DateTime<Utc>>,
+    /// Prometheus queries run so far, the only measure of progress.
+    completed_queries: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<ResultView>,
+    /// When a finished job is removed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<DateTime<Utc>>,
+}
+
+/// A finished job's file, read back for `/api/v1/jobs/<id>/result`.
+pub struct JobFile {
+    pub content_type: String,
+    pub filename: String,
+    pub body: Bytes,
+}
+
+/// Reports run in the background for `/api/v1/jobs`, at most `JOBS_WORKERS`
+/// at a time. Jobs and in-memory results are lost on restart.
+pub struct Jobs {
+    jobs: Mutex<HashMap<String, Job>>,
+    workers: Arc<Semaphore>,
+}
+
+impl Jobs {
+    pub fn new(limits: &JobLimits) -> Self {
+        Self {
+            jobs: Mutex::default(),
+            workers: Arc::new(Semaphore::new(limits.workers)),
+        }
+    }
+
+    /// Queues `report` with `params`, refusing it with 429 while `owner`
+    /// already has `JOBS_PER_KEY` jobs unfinished or holding a result in
+    /// memory, which can be up to `MAX_RESULT_BYTES` each until `JOBS_RETENTION`.
+    pub fn submit(
+        &self,
+        state: &AppState,
+        owner: Option<String>,
+        report: &str,
+        params: HashMap<String, String>,
+    ) -> Result<JobView, ApiError> {
+        if !REPORTS.contains(&report) {
+            let error = ApiError::new(StatusCode::BAD_REQUEST, "unknown report");
+            return Err(error.with("reports", REPORTS.to_vec()));
+        }
+        let mut jobs = self.jobs.lock().unwrap();
+        let limit = state.config.jobs.per_key;
+        let held = jobs.values().filter(|j| j.owner == owner && j.is_held()).count();
+        if held >= limit {
+            let error = "too many jobs unfinished or holding a result";
+            return Err(ApiError::new(StatusCode::TOO_MANY_REQUESTS, error).with("limit", limit).retry_after(60));
+        }
+
+        let id = uuid::Uuid::now_v7().to_string();
+        let task = tokio::spawn(run(state.clone(), id.clone(), report.to_string(), params.clone()));
+        let job = Job {
+            owner,
+            report: report.to_string(),
+            params: params.into_iter().collect(),
+            status: JobStatus::Queued,
+            created_at: Utc::now(),
+            started_at: None,
+            finished_at: None,
+            completed_queries: Arc::default(),
+            error: None,
+            result: None,
+            abort: Some(task.abort_handle()),
+        };
+        let view = view(state, &id, &job);
+        jobs.insert(id, job);
+        Ok(view)
+    }
+
+    /// The job `id`, if it exists and belongs to `owner`.
+    pub fn get(&self, state: &AppState, id: &str, owner: Option<&str>) -> Option<JobView> {
+        let jobs = self.jobs.lock().unwrap();
+        let job = jobs.get(id).filter(|job| job.owner.as_deref() == owner)?;
+        Some(view(state, id, job))
+    }
+
+    /// The finished file of job `id`, or the job's status while it has none.
+    pub async fn file(&self, state: &AppState, id: &str, owner: Option<&str>) -> Result<JobFile, ApiError> {
+        let (content_type, filename, stored) = {
+            let jobs = self.jobs.lock().unwrap();
+            let job = jobs
+                .get(id)
+                .filter(|job| job.owner.as_deref() == owner)
+                .ok_or_else(|| ApiError::new(StatusCode::NOT_FOUND, "unknown job"))?;
+            let Some(result) = &job.result else {
+                let error = ApiError::new(StatusCode::CONFLICT, "job has no result");
+                return Err(error.with("status", job.status.name()));
+            };
+            let stored = match &result.stored {
+                Stored::Memory(body) => Ok(body.clone()),
+                Stored::ObjectStore(key) => Err(key.clone()),
+            };
+            (result.content_type.clone(), result.filename.clone(), stored)
+        };
+        let body = match stored {
+            Ok(body) => body,
+            Err(key) => {
+                let store = state.config.object_store.as_ref().ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+                let body = store.get(&key).await.map_err(|e| {
+                    tracing::error!(key, "Reading job result from S3 failed: {}", e);
+                    ApiError::new(StatusCode::BAD_GATEWAY, "failed to read the result from S3")
+                })?;
+                Bytes::from(body)
+            }
+        };
+        Ok(JobFile { content_type, filename, body })
+    }
+
+    /// Cancels job `id` if it is unfinished, and removes it with its result
+    /// if it is not. `None` when there is no such job for `owner`.
+    pub fn cancel(&self, state: &AppState, id: &str, owner: Option<&str>) -> Option<JobView> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let job = jobs.get_mut(id).filter(|job| job.owner.as_deref() == owner)?;
+        if !job.status.is_finished() {
+            if let Some(abort) = job.abort.take() {
+                abort.abort();
+            }
+            job.status = JobStatus::Cancelled;
+            job.finished_at = Some(Utc::now());
+            return Some(view(state, id, job));
+        }
+        let job = jobs.remove(id)?;
+        discard(state, job);
+        None
+    }
+
+    /// Marks job `id` running, returning its query counter, unless it was
+    /// cancelled in the meantime.
+    fn start(&self, id: &str) -> Option<Arc<AtomicUsize>> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let job = jobs.get_mut(id).filter(|job| job.status == JobStatus::Queued)?;
+        job.status = JobStatus::Running;
+        job.started_at = Some(Utc::now());
+        Some(job.completed_queries.clone())
+    }
+
+    fn finish(&self, state: &AppState, id: &str, outcome: Result<JobResult, String>) {
+        let mut jobs = self.jobs.lock().unwrap();
+        let Some(job) = jobs.get_mut(id) else {
+            return;
+        };
+        if job.status != JobStatus::Running {
+            if let Ok(JobResult { stored: Stored::ObjectStore(key), .. }) = outcome {
+                delete_object(state, key);
+            }
+            return;
+        }
+        job.finished_at = Some(Utc::now());
+        job.abort = None;
+        match outcome {
+            Ok(result) => {
+                job.status = JobStatus::Done;
+                job.result = Some(result);
+            }
+            Err(e) => {
+                job.status = JobStatus::Failed;
+                job.error = Some(e);
+            }
+        }
+    }
+
+    /// Removes the jobs that finished more than `retention` ago.
+    fn expire(&self, state: &AppState, retention: Duration) {
+        let now = Utc::now();
+        let expired: Vec<Job> = {
+            let mut jobs = self.jobs.lock().unwrap();
+            let ids: Vec<String> = jobs
+                .iter()
+                .filter(|(_, job)| {
+                    job.finished_at.is_some_and(|t| (now - t).to_std().unwrap_or_default() > retention)
+                })
+                .map(|(id, _)| id.clone())
+                .collect();
+            ids.iter().filter_map(|id| jobs.remove(id)).collect()
+        };
+        for job in expired {
+            discard(state, job);
+        }
+    }
+}
+
+fn view(state: &AppState, id: &str, job: &Job) -> JobView {
+    let retention = chrono::Duration::from_std(state.config.jobs.retention).unwrap_or_default();
+    JobView {
+        id: id.to_string(),
+        report: job.report.clone(),
+        params: job.params.clone(),
+        status: job.status,
+        created_at: job.created_at,
+        started_at: job.started_at,
+        finished_at: job.finished_at,
+        completed_queries: job.completed_queries.load(Ordering::Relaxed),
+        error: job.error.clone(),
+        result: job.result.as_ref().map(|result| ResultView {
+            content_type: result.content_type.clone(),
+            filename: result.filename.clone(),
+            bytes: result.bytes,
+            url: format!("{}/api/v1/jobs/{}/result", state.config.base_path, id),
+        }),
+        expires_at: job.finished_at.map(|t| t + retention),
+    }
+}
+
+/// Deletes a removed job's file from S3, in the background.
+fn discard(state: &AppState, job: Job) {
+    if let Some(JobResult { stored: Stored::ObjectStore(key), .. }) = job.result {
+        delete_object(state, key);
+    }
+}
+
+fn delete_object(state: &AppState, key: String) {
+    let state = state.clone();
+    tokio::spawn(async move {
+        let Some(store) = &state.config.object_store else {
+            return;
+        };
+        if let Err(e) = store.delete(&key).await {
+            tracing::warn!(key, "Deleting job result from S3 failed: {}", e);
+        }
+    });
+}
+
+/// Waits for a worker, then renders the report as its endpoint would
+/// and keeps the file, in S3 when it is configured.
+async fn run(state: AppState, id: String, report: String, params: HashMap<String, String>) {
+    let Ok(_worker) = state.jobs.workers.clone().acquire_owned().await else {
+        return;
+    };
+    let Some(completed) = state.jobs.start(&id) else {
+        return;
+    };
+    tracing::info!(job = %id, report = %report, "Job started");
+    let state = state.with_backend(state.prometheus.clone().counting(completed));
+    let response = render(&state, &report, params).await;
+    let outcome = keep(&state, &id, &report, response).await;
+    if let Err(e) = &outcome {
+        tracing::warn!(job = %id, "Job failed: {}", e);
+    }
+    state.jobs.finish(&state, &id, outcome);
+}
+
+/// The report's response, without `MAX_RESPONSE_ROWS`.
+async fn render(state: &AppState, report: &str, params: HashMap<String, String>) -> Response {
+    let report = match report {
+        "range" => Report::Range,
+        "weekly" => Report::Weekly,
+        "monthly" => Report::Monthly,
+        _ => return api::profile::profile_handler(State(state.clone()), Query(params)).await.into_response(),
+    };
+    api::range::report(state, report, &params, true).await
+}
+
+/// Reads the whole response into a result, or the error it carries.
+async fn keep(state: &AppState, id: &str, report: &str, response: Response) -> Result<JobResult, String> {
+    let status = response.status();
+    let headers = response.headers().clone();
+    let body = to_bytes(response.into_body(), MAX_RESULT_BYTES)
+        .await
+        .map_err(|_| format!("result is larger than {} MiB or was cut short", MAX_RESULT_BYTES >> 20))?;
+    if !status.is_success() {
+        let error = serde_json::from_slice::<Value>(&body)
+            .ok()
+            .and_then(|body| body["error"].as_str().map(str::to_string));
+        return Err(match error {
+            Some(error) => format!("{}: {}", status, error),
+            None => status.to_string(),
+        });
+    }
+
+    let header = |name| headers.get(name).and_then(|v| v.to_str().ok());
+    let content_type = header(header::CONTENT_TYPE).unwrap_or("application/octet-stream").to_string();
+    let extension = match content_type.split(';').next().unwrap_or_default() {
+        "application/json" => "json",
+        "application/x-ndjson" => "jsonl",
+        "application/pdf" => "pdf",
+        _ => "csv",
+    };
+    let filename = header(header::CONTENT_DISPOSITION)
+        .and_then(|v| v.split("filename=\"").nth(1))
+        .and_then(|v| v.strip_suffix('"'))
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("power-usage-{}-{}.{}", report, id, extension));
+
+    let bytes = body.len();
+    let stored = match &state.config.object_store {
+        Some(store) => {
+            let key = format!("jobs/{}.{}", id, extension);
+            store
+                .try_upload(&key, body.to_vec(), &content_type)
+                .await
+                .map_err(|e| format!("S3 upload failed: {}", e))?;
+            Stored::ObjectStore(key)
+        }
+        None => Stored::Memory(body),
+    };
+    Ok(JobResult { content_type, filename, bytes, stored })
+}
+
+/// Removes finished jobs once they are older than `JOBS_RETENTION`.
+pub async fn cleanup_loop(state: AppState) {
+    let mut interval = tokio::time::interval(CLEANUP_INTERVAL);
+    loop {
+        interval.tick().await;
+        state.jobs.expire(&state, state.config.jobs.retention);
+    }
+}
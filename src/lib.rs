@@ -0,0 +1,281 @@
+//! Daily electricity usage from energy counters in Prometheus.
+//!
+//! The crate is mainly the `power-usage` server. Its computation is also
+//! available as a library: a [`UsageCalculator`] answers the same questions
+//! as `/api/v1/power-usage` and `/api/v1/power-usage/range`, from a
+//! [`Config`] and a [`MetricsBackend`]: the Prometheus in the configuration
+//! through [`PrometheusBackend`], the fixtures of `BACKEND=fixture`, or any
+//! other source of Prometheus API responses. `examples/fixture_table.rs`
+//! prints a table from the fixtures. Everything else is internal to the
+//! server and may change in any release.
+//!
+//! ```
+//! use chrono::NaiveDate;
+//! use power_usage::{Config, Settings, UsageCalculator};
+//!
+//! # #[tokio::main(flavor = "current_thread")]
+//! # async fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let settings = Settings {
+//!     backend: "fixture".to_string(),
+//!     fixture_dir: Some(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/golden/fixtures").into()),
+//!     ..Settings::default()
+//! };
+//! let config = Config::from_settings(&settings).map_err(|errors| errors.join("; "))?;
+//! let calculator = UsageCalculator::new(config)?;
+//!
+//! let at = NaiveDate::from_ymd_opt(2025, 8, 1).unwrap().and_hms_opt(0, 0, 0).unwrap();
+//! for meter in calculator.daily(".*", at, chrono_tz::Asia::Jakarta).await? {
+//!     println!("{} {}: {:?} kWh", meter.instance, meter.address, meter.daily_kwh);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+mod aliases;
+mod anonymize;
+mod api;
+mod api_keys;
+mod archive;
+mod atomic_file;
+mod audit;
+mod cache;
+mod calculator;
+mod changeover;
+mod cli;
+mod client_ip;
+mod config;
+mod corrections;
+mod deadline;
+mod disk_cache;
+mod error;
+mod estimate;
+mod fixture;
+mod flags;
+mod jobs;
+mod logs;
+mod mailer;
+mod metrics;
+mod natural;
+mod object_store;
+mod period;
+mod probe;
+mod prometheus;
+mod range;
+mod reload;
+mod remote_write;
+mod reports;
+mod request_id;
+mod rounding;
+mod selector;
+mod server;
+mod shadow;
+mod state;
+mod stats;
+mod status;
+mod tariff;
+mod textfile;
+mod thresholds;
+mod timezones;
+mod usage;
+mod usage_metrics;
+mod version;
+mod warmup;
+
+use axum::{
+    middleware,
+    routing::{delete, get, post, MethodRouter},
+    Router,
+};
+use clap::Parser;
+use std::process::ExitCode;
+use tower_http::catch_panic::CatchPanicLayer;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+use cli::{Cli, Command};
+use state::AppState;
+
+pub use calculator::{
+    BackendFuture, DailyUsage, DayUsage, Error, MeterDays, MetricsBackend, PrometheusBackend, UsageCalculator,
+};
+pub use config::{Config, Settings};
+
+fn routes() -> Vec<(&'static str, MethodRouter<AppState>)> {
+    let routes = vec![
+        ("/api/v1/power-usage", get(api::v1::power_usage_handler)),
+        ("/api/v1/power-usage/latest", get(api::latest::latest_handler)),
+        ("/api/v1/power-usage/range", get(api::range::range_handler)),
+        ("/api/v1/power-usage/weekly", get(api::range::weekly_handler)),
+        ("/api/v1/power-usage/monthly", get(api::range::monthly_handler)),
+        ("/api/v1/power-usage/histogram", get(api::histogram::histogram_handler)),
+        ("/api/v1/power-usage/alerts", get(api::alerts::alerts_handler)),
+        ("/api/v1/power-usage/compare", get(api::compare::compare_handler)),
+        ("/api/v1/power-usage/max-demand", get(api::demand::max_demand_handler)),
+        ("/api/v1/power-usage/profile", get(api::profile::profile_handler)),
+        ("/api/v1/power-usage/reconcile", get(api::reconcile::reconcile_handler)),
+        ("/api/v1/power-usage/rolling", get(api::rolling::rolling_handler)),
+        ("/api/v2/power-usage", get(api::v2::power_usage_handler)),
+        ("/api/v1/jobs", post(api::jobs::submit_handler)),
+        (
+            "/api/v1/jobs/{id}",
+            get(api::jobs::job_handler).delete(api::jobs::cancel_handler),
+        ),
+        ("/api/v1/jobs/{id}/result", get(api::jobs::result_handler)),
+        (
+            "/api/v1/corrections",
+            get(api::corrections::list_handler).post(api::corrections::create_handler),
+        ),
+        ("/api/v1/corrections/{id}", delete(api::corrections::delete_handler)),
+        ("/api/v1/targets", get(api::targets::targets_handler)),
+        ("/api/v1/labels/{label}/values", get(api::labels::label_values_handler)),
+        ("/api/v1/electrical", get(api::electrical::electrical_handler)),
+        ("/annotations", post(api::annotations::annotations_handler)),
+        (
+            "/graphql",
+            get(api::graphql::graphiql_handler).post(api::graphql::graphql_handler),
+        ),
+        ("/metrics", get(metrics::metrics_handler)),
+        ("/version", get(version::version_handler)),
+        ("/metrics/usage", get(usage_metrics::usage_metrics_handler)),
+        ("/probe", get(probe::probe_handler)),
+        ("/admin/status", get(api::admin::status_handler)),
+        ("/admin/reload", post(api::admin::reload_handler)),
+        ("/admin/cache", get(api::admin::cache_handler)),
+        ("/admin/support-bundle", get(api::support::support_bundle_handler)),
+        ("/admin/keys/usage", get(api::admin::keys_usage_handler)),
+        ("/admin/anonymize/reveal", get(api::admin::reveal_handler)),
+        ("/admin/reports/send-test", post(api::admin::send_test_handler)),
+        ("/admin/sinks/test", post(api::admin::sinks_test_handler)),
+    ];
+    #[cfg(feature = "debug-routes")]
+    let routes = {
+        let mut routes = routes;
+        routes.push(("/debug/panic", get(debug_panic_handler)));
+        routes
+    };
+    routes
+}
+
+#[cfg(feature = "debug-routes")]
+async fn debug_panic_handler() -> &'static str {
+    panic!("deliberate panic from /debug/panic")
+}
+
+/// Every route under `BASE_PATH`, behind the middleware stack.
+fn app(state: &AppState) -> Router {
+    let router = routes()
+        .into_iter()
+        .fold(Router::new(), |router, (path, handler)| {
+            let handler = match path.starts_with("/admin/") || path.starts_with("/debug/") {
+                true => handler.layer(middleware::map_response(api::no_store)),
+                false => handler,
+            };
+            router.route(path, handler)
+        });
+    let base_path = &state.config.base_path;
+    let app = if base_path.is_empty() {
+        router
+    } else {
+        Router::new().nest(base_path, router)
+    };
+    app.layer(CatchPanicLayer::custom(error::panic_response))
+        .layer(middleware::from_fn_with_state(state.clone(), deadline::deadline_middleware))
+        .layer(middleware::from_fn(version::version_header_middleware))
+        .layer(middleware::from_fn_with_state(state.clone(), reload::snapshot_middleware))
+        .layer(middleware::from_fn_with_state(state.clone(), api_keys::api_key_middleware))
+        .layer(middleware::from_fn_with_state(state.clone(), audit::audit_middleware))
+        .layer(middleware::from_fn(request_id::request_id_middleware))
+        .layer(middleware::from_fn_with_state(state.clone(), client_ip::client_ip_middleware))
+        .with_state(state.clone())
+}
+
+/// The `power-usage` command line, which the binary runs.
+#[doc(hidden)]
+pub async fn run() -> ExitCode {
+    let cli = Cli::parse();
+    let settings = Settings::load(cli.config.as_deref());
+
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => match cli::load_config(settings.clone()) {
+            Ok(config) => serve(settings.unwrap_or_default(), config).await,
+            Err(code) => code,
+        },
+        Command::CheckConfig => cli::check_config(settings).await,
+        Command::Query {
+            target,
+            date,
+            time,
+            csv,
+        } => match cli::load_config(settings) {
+            Ok(config) => cli::query(config, target, date, time, csv).await,
+            Err(code) => code,
+        },
+        Command::Verify { fixtures, update } => cli::verify(&fixtures, update).await,
+    }
+}
+
+async fn serve(settings: Settings, config: Config) -> ExitCode {
+    tracing_subscriber::registry()
+        .with(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+        .with(tracing_subscriber::fmt::layer())
+        .with(logs::RingLayer)
+        .init();
+    metrics::install();
+
+    match toml::to_string(&settings.redacted()) {
+        Ok(effective) => tracing::info!("Effective configuration:\n{}", effective),
+        Err(e) => tracing::warn!("Failed to render configuration: {}", e),
+    }
+
+    let state = match AppState::new(config) {
+        Ok(state) => state,
+        Err(e) => {
+            tracing::error!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let config = state.config.clone();
+    tokio::spawn(config.aliases.clone().watch());
+    tokio::spawn(reload::reload_on_sighup(config.clone()));
+    tokio::spawn(usage_metrics::refresh_loop(state.clone()));
+    tokio::spawn(api::targets::refresh_loop(state.clone()));
+    tokio::spawn(reports::schedule_loop(state.clone()));
+    tokio::spawn(api_keys::persist_loop(state.clone()));
+    tokio::spawn(disk_cache::flush_loop(state.clone()));
+    tokio::spawn(jobs::cleanup_loop(state.clone()));
+    let (warmed, warming) = tokio::sync::oneshot::channel();
+    tokio::spawn(warmup::warm_loop(state.clone(), warmed));
+    if let Some(addr) = config.grpc_bind_addr {
+        tokio::spawn(api::grpc::serve(addr, state.clone()));
+    }
+
+    let paths: Vec<&str> = routes().iter().map(|(path, _)| *path).collect();
+    let app = app(&state);
+
+    // The first warmup may hold up listening for at most `WARM_BUDGET`.
+    if !config.warm_targets.is_empty() && tokio::time::timeout(config.warm_budget, warming).await.is_err() {
+        tracing::warn!("Cache warmup still running after WARM_BUDGET, serving without it");
+    }
+
+    let listeners = match server::bind(&config.bind_addrs, config.tls.as_ref()).await {
+        Ok(listeners) => listeners,
+        Err(e) => {
+            tracing::error!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let urls: Vec<String> = listeners.iter().map(|listener| listener.url(config.tls.as_ref())).collect();
+    let built_at = version::built_at().map_or("an unknown time".to_string(), |t| t.to_rfc3339());
+    let version = version::full_version();
+    tracing::info!("Server running on {} (power-usage {}, built {})", urls.join(", "), version, built_at);
+    for path in paths {
+        tracing::info!("  {}{}{}", urls[0], config.base_path, path);
+    }
+
+    if let Err(e) = server::serve(listeners, config.tls.as_ref(), state.prometheus.clone(), app).await {
+        tracing::error!("{}", e);
+        return ExitCode::FAILURE;
+    }
+    if let Some(disk_cache) = &state.disk_cache {
+        disk_cache.flush().await;
+    }
+    ExitCode::SUCCESS
+}
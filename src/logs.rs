@@ -0,0 +1,89 @@
+use std::{
+    collections::VecDeque,
+    fmt::Write,
+    sync::{Mutex, OnceLock},
+};
+use tracing::{
+    field::{Field, Visit},
+    Event, Level, Subscriber,
+};
+use tracing_subscriber::{layer::Context, Layer};
+
+use crate::request_id;
+
+/// Log lines kept in memory for support bundles.
+const KEPT: usize = 1000;
+
+struct Line {
+    request_id: Option<String>,
+    level: Level,
+    text: String,
+}
+
+static LINES: OnceLock<Mutex<VecDeque<Line>>> = OnceLock::new();
+
+fn lines() -> &'static Mutex<VecDeque<Line>> {
+    LINES.get_or_init(|| Mutex::new(VecDeque::with_capacity(KEPT)))
+}
+
+/// Keeps the last `KEPT` log lines, formatted as the console has them,
+/// with the request that logged each.
+pub struct RingLayer;
+
+impl<S: Subscriber> Layer<S> for RingLayer {
+    fn on_event(&self, event: &Event<'_>, _: Context<'_, S>) {
+        let meta = event.metadata();
+        let mut text = format!(
+            "{} {:>5} {}:",
+            chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Micros, true),
+            meta.level(),
+            meta.target()
+        );
+        event.record(&mut Fields(&mut text));
+        let line = Line {
+            request_id: request_id::current(),
+            level: *meta.level(),
+            text,
+        };
+        let mut lines = lines().lock().unwrap();
+        if lines.len() == KEPT {
+            lines.pop_front();
+        }
+        lines.push_back(line);
+    }
+}
+
+struct Fields<'a>(&'a mut String);
+
+impl Visit for Fields<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        match field.name() {
+            "message" => write!(self.0, " {:?}", value),
+            name => write!(self.0, " {}={:?}", name, value),
+        }
+        .ok();
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "message" => write!(self.0, " {}", value),
+            name => write!(self.0, " {}={:?}", name, value),
+        }
+        .ok();
+    }
+}
+
+/// The last `limit` lines relevant to `request_id`: its own, and every
+/// warning or error from anywhere, oldest first.
+pub fn recent(request_id: &str, limit: usize) -> Vec<String> {
+    let lines = lines().lock().unwrap();
+    let mut relevant: Vec<String> = lines
+        .iter()
+        .rev()
+        .filter(|line| line.level <= Level::WARN || line.request_id.as_deref() == Some(request_id))
+        .take(limit)
+        .map(|line| line.text.clone())
+        .collect();
+    relevant.reverse();
+    relevant
+}
@@ -0,0 +1,139 @@
+use lettre::{
+    message::{header::ContentType, Attachment, Mailbox, MultiPart, SinglePart},
+    transport::smtp::authentication::Credentials,
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use std::time::Duration;
+
+use crate::{config::Settings, status::LastOutcome};
+
+pub const EMAILS_SENT_TOTAL: &str = "report_emails_sent_total";
+pub const EMAILS_FAILED_TOTAL: &str = "report_emails_failed_total";
+
+/// Attempts per email, with the delay doubling from `RETRY_DELAY` between them.
+const ATTEMPTS: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A file attached to a report email.
+pub struct MailAttachment {
+    pub filename: String,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+/// Sends report emails through `SMTP_HOST`.
+pub struct Mailer {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+    pub last_send: LastOutcome,
+}
+
+/// Parses an email address, recording `setting` in the error.
+pub fn parse_mailbox(setting: &str, address: &str) -> Result<Mailbox, String> {
+    address
+        .parse()
+        .map_err(|e| format!("`{}` has an invalid email address {:?}: {}", setting, address, e))
+}
+
+impl Mailer {
+    pub fn from_settings(settings: &Settings) -> Result<Option<Self>, String> {
+        if settings.smtp_host.is_empty() {
+            return Ok(None);
+        }
+        if settings.smtp_from.is_empty() {
+            return Err("`SMTP_FROM` must be set when `SMTP_HOST` is".to_string());
+        }
+        let from = parse_mailbox("SMTP_FROM", &settings.smtp_from)?;
+        let host = settings.smtp_host.as_str();
+
+        // STARTTLS upgrades a plain connection, usually on 587; `tls` speaks
+        // TLS from the first byte, usually on 465.
+        let builder = match settings.smtp_tls.as_str() {
+            "starttls" => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host),
+            "tls" => AsyncSmtpTransport::<Tokio1Executor>::relay(host),
+            "none" => Ok(AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host)),
+            other => return Err(format!("`SMTP_TLS` must be `starttls`, `tls` or `none`, got {:?}", other)),
+        }
+        .map_err(|e| format!("`SMTP_HOST` is not usable: {}", e))?;
+        let mut builder = builder.timeout(Some(TIMEOUT));
+        if !settings.smtp_port.is_empty() {
+            let port = settings
+                .smtp_port
+                .trim()
+                .parse::<u16>()
+                .map_err(|_| format!("`SMTP_PORT` must be a port number, got {:?}", settings.smtp_port))?;
+            builder = builder.port(port);
+        }
+        if !settings.smtp_username.is_empty() {
+            builder = builder.credentials(Credentials::new(
+                settings.smtp_username.clone(),
+                settings.smtp_password.clone(),
+            ));
+        }
+        Ok(Some(Self {
+            transport: builder.build(),
+            from,
+            last_send: LastOutcome::default(),
+        }))
+    }
+
+    /// Sends one email to all `recipients`, retrying with backoff unless the
+    /// server refuses it permanently. The last error is returned once the
+    /// attempts run out.
+    pub async fn send(
+        &self,
+        recipients: &[Mailbox],
+        subject: &str,
+        text: String,
+        attachment: Option<MailAttachment>,
+    ) -> Result<(), String> {
+        let result = self.try_send(recipients, subject, text, attachment).await;
+        match &result {
+            Ok(()) => metrics::counter!(EMAILS_SENT_TOTAL).increment(1),
+            Err(e) => {
+                tracing::error!(subject, "Report email failed: {}", e);
+                metrics::counter!(EMAILS_FAILED_TOTAL).increment(1);
+            }
+        }
+        self.last_send.record(&result);
+        result
+    }
+
+    /// `send` without counting or recording the outcome, for
+    /// `/admin/sinks/test`.
+    pub async fn try_send(
+        &self,
+        recipients: &[Mailbox],
+        subject: &str,
+        text: String,
+        attachment: Option<MailAttachment>,
+    ) -> Result<(), String> {
+        let mut builder = Message::builder().from(self.from.clone()).subject(subject);
+        for recipient in recipients {
+            builder = builder.to(recipient.clone());
+        }
+        let text = SinglePart::plain(text);
+        let message = match attachment {
+            Some(file) => {
+                let content_type = ContentType::parse(file.content_type).map_err(|e| e.to_string())?;
+                let file = Attachment::new(file.filename).body(file.body, content_type);
+                builder.multipart(MultiPart::mixed().singlepart(text).singlepart(file))
+            }
+            None => builder.singlepart(text),
+        }
+        .map_err(|e| format!("failed to build email: {}", e))?;
+
+        let (mut attempt, mut delay) = (1, RETRY_DELAY);
+        loop {
+            match self.transport.send(message.clone()).await {
+                Ok(_) => return Ok(()),
+                Err(e) if e.is_permanent() || attempt == ATTEMPTS => return Err(e.to_string()),
+                Err(e) => tracing::warn!(attempt, "Sending report email failed: {}", e),
+            }
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+            delay *= 2;
+        }
+    }
+}
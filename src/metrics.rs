@@ -0,0 +1,108 @@
+use axum::{http::header, response::IntoResponse};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use std::sync::OnceLock;
+
+use crate::{audit, mailer, object_store, prometheus, reload, remote_write, shadow, usage, version};
+
+static HANDLE: OnceLock<PrometheusHandle> = OnceLock::new();
+
+pub const PANICS_TOTAL: &str = "panics_total";
+
+/// Installs the global recorder backing the self-metrics endpoint.
+pub fn install() {
+    let sample_age = Matcher::Full(usage::SAMPLE_AGE_SECONDS.to_string());
+    let handle = PrometheusBuilder::new()
+        .set_buckets_for_metric(sample_age, &usage::SAMPLE_AGE_BUCKETS)
+        .expect("sample age buckets are not empty")
+        .install_recorder()
+        .expect("failed to install metrics recorder");
+    metrics::describe_counter!(PANICS_TOTAL, "Handler panics converted into 500 responses");
+    metrics::describe_counter!(
+        prometheus::PROMETHEUS_REQUESTS_TOTAL,
+        "Prometheus API calls by outcome: ok, or the error_kind of the failure"
+    );
+    metrics::describe_counter!(
+        prometheus::PROMETHEUS_QUERY_CHUNKS_TOTAL,
+        "Chunks run for usage queries split by CHUNK_SIZE"
+    );
+    metrics::describe_counter!(
+        prometheus::PROMETHEUS_CONNECTIONS_TOTAL,
+        "Connections opened to Prometheus by outcome: ok or error; one per request means no reuse"
+    );
+    metrics::describe_counter!(
+        prometheus::SKIPPED_SERIES_TOTAL,
+        "Series left out because their value was not a number, by reason: nan, parse_error or missing_value"
+    );
+    metrics::describe_counter!(
+        shadow::SHADOW_COMPARISONS_TOTAL,
+        "Usage requests compared with SHADOW_PROMETHEUS_HOST by outcome: match, mismatch, error or skipped"
+    );
+    metrics::describe_counter!(
+        shadow::SHADOW_MISMATCHED_ENTRIES_TOTAL,
+        "Meters whose daily_kwh differed between the primary and shadow backends"
+    );
+    metrics::describe_counter!(
+        remote_write::SAMPLES_PUSHED_TOTAL,
+        "Daily usage samples accepted by the remote-write endpoint"
+    );
+    metrics::describe_counter!(
+        remote_write::SAMPLES_FAILED_TOTAL,
+        "Daily usage samples dropped after remote-write failures"
+    );
+    metrics::describe_counter!(mailer::EMAILS_SENT_TOTAL, "Report emails accepted by the SMTP server");
+    metrics::describe_counter!(
+        mailer::EMAILS_FAILED_TOTAL,
+        "Report emails given up on after retries or a permanent SMTP error"
+    );
+    metrics::describe_counter!(object_store::UPLOADS_TOTAL, "Reports stored in S3_BUCKET");
+    metrics::describe_counter!(
+        object_store::UPLOAD_FAILURES_TOTAL,
+        "Report uploads given up on after retries or a refused request"
+    );
+    metrics::describe_counter!(audit::AUDIT_ENTRIES_TOTAL, "Entries appended to AUDIT_LOG_PATH");
+    metrics::describe_counter!(
+        audit::AUDIT_FAILURES_TOTAL,
+        "Audit log entries lost, by reason: dropped while the writer was behind, or write"
+    );
+    metrics::describe_counter!(
+        usage::USAGE_CACHE_REQUESTS_TOTAL,
+        "Usage cache lookups by result: hit, stale or miss"
+    );
+    metrics::describe_counter!(
+        usage::USAGE_REQUESTS_COALESCED_TOTAL,
+        "Usage requests answered by an identical one already in flight"
+    );
+    metrics::describe_counter!(
+        usage::DEGRADED_RESPONSES_TOTAL,
+        "Usage results served from the last known ones with fallback=last_known because Prometheus was down"
+    );
+    metrics::describe_counter!(
+        usage::IMPLAUSIBLE_READINGS_TOTAL,
+        "Meter days found beyond MAX_DAILY_KWH or MAX_READING_KWH, each time usage is computed"
+    );
+    metrics::describe_histogram!(
+        usage::SAMPLE_AGE_SECONDS,
+        metrics::Unit::Seconds,
+        "Time between the requested instant and the scrape read for it, per reading, by target"
+    );
+    metrics::describe_counter!(
+        reload::CONFIG_RELOADS_TOTAL,
+        "Configuration reloads by outcome: ok, or error when the previous one was kept"
+    );
+    metrics::describe_gauge!(version::BUILD_INFO, "Always 1, labelled with the running build");
+    metrics::gauge!(
+        version::BUILD_INFO,
+        "version" => version::VERSION,
+        "commit" => version::GIT_COMMIT,
+    )
+    .set(1.0);
+    HANDLE.set(handle).ok();
+}
+
+pub async fn metrics_handler() -> impl IntoResponse {
+    let body = HANDLE.get().map(|h| h.render()).unwrap_or_default();
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4; charset=utf-8")],
+        body,
+    )
+}
@@ -0,0 +1,117 @@
+use std::cmp::Ordering;
+
+/// An address as meters are listed by, in `natural` order, for
+/// `sort_by_key` and the keys of ordered maps.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Natural(pub String);
+
+impl Ord for Natural {
+    fn cmp(&self, other: &Self) -> Ordering {
+        natural(&self.0, &other.0)
+    }
+}
+
+impl PartialOrd for Natural {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Orders labels such as Modbus addresses as people number them: runs of
+/// digits by their value, anything else by its characters, and a number
+/// before anything else, so an empty label < `1` < `2` < `10` < `A1` <
+/// `A2` < `A10` < `B2`. Labels that only differ in leading zeros, `01` and
+/// `1`, are told apart by their characters, so no two labels are ever
+/// equal and rows come out in the same order on every request.
+pub fn natural(a: &str, b: &str) -> Ordering {
+    let (mut a_runs, mut b_runs) = (runs(a), runs(b));
+    loop {
+        let order = match (a_runs.next(), b_runs.next()) {
+            (None, None) => return a.cmp(b),
+            (None, Some(_)) => Ordering::Less,
+            (Some(_), None) => Ordering::Greater,
+            (Some(x), Some(y)) => match (number(x), number(y)) {
+                (Some(x), Some(y)) => x.len().cmp(&y.len()).then_with(|| x.cmp(y)),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => x.cmp(y),
+            },
+        };
+        if order != Ordering::Equal {
+            return order;
+        }
+    }
+}
+
+/// `label` split into runs of ASCII digits and runs of anything else.
+fn runs(label: &str) -> impl Iterator<Item = &str> {
+    let mut rest = label;
+    std::iter::from_fn(move || {
+        let digits = rest.chars().next()?.is_ascii_digit();
+        let end = rest.find(|c: char| c.is_ascii_digit() != digits).unwrap_or(rest.len());
+        let (run, tail) = rest.split_at(end);
+        rest = tail;
+        Some(run)
+    })
+}
+
+/// A run of digits without its leading zeros, which orders by value once
+/// shorter runs come first, however long it is.
+fn number(run: &str) -> Option<&str> {
+    run.starts_with(|c: char| c.is_ascii_digit()).then(|| run.trim_start_matches('0'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted(labels: &[&str]) -> Vec<String> {
+        let mut labels: Vec<Natural> = labels.iter().map(|label| Natural(label.to_string())).collect();
+        labels.sort();
+        labels.into_iter().map(|label| label.0).collect()
+    }
+
+    #[test]
+    fn numbers_by_value() {
+        assert_eq!(sorted(&["10", "2", "1"]), ["1", "2", "10"]);
+        assert_eq!(natural("99999999999999999999999", "100000000000000000000000"), Ordering::Less);
+    }
+
+    #[test]
+    fn digit_runs_within_text_by_value() {
+        assert_eq!(sorted(&["A10", "A2", "A1"]), ["A1", "A2", "A10"]);
+        assert_eq!(sorted(&["B2", "A10", "10", "A1", "2"]), ["2", "10", "A1", "A10", "B2"]);
+    }
+
+    #[test]
+    fn leading_zeros_are_told_apart() {
+        assert_eq!(sorted(&["2", "1", "01", "001"]), ["001", "01", "1", "2"]);
+        assert_ne!(natural("01", "1"), Ordering::Equal);
+        assert_eq!(natural("01", "1"), natural("01", "1"));
+    }
+
+    #[test]
+    fn empty_first() {
+        assert_eq!(sorted(&["1", "", "A1"]), ["", "1", "A1"]);
+        assert_eq!(natural("", ""), Ordering::Equal);
+    }
+
+    #[test]
+    fn total_and_stable() {
+        let labels = ["1", "10", "2", "A1", "", "01", "A01", "a1", "A", "A1B", "1A", "-1", "１"];
+        for a in labels {
+            for b in labels {
+                assert_eq!(natural(a, b), natural(b, a).reverse(), "{:?} {:?}", a, b);
+                assert_eq!(natural(a, b) == Ordering::Equal, a == b, "{:?} {:?}", a, b);
+                for c in labels {
+                    if natural(a, b).is_le() && natural(b, c).is_le() {
+                        assert!(natural(a, c).is_le(), "{:?} {:?} {:?}", a, b, c);
+                    }
+                }
+            }
+        }
+        let mut reversed = labels;
+        reversed.reverse();
+        assert_eq!(sorted(&labels), sorted(&reversed));
+    }
+}
@@ -0,0 +1,234 @@
+use chrono::{DateTime, Datelike, NaiveDate, Utc};
+use reqwest::{Method, RequestBuilder, StatusCode, Url};
+use rusty_s3::{
+    actions::{
+        AbortMultipartUpload, CompleteMultipartUpload, CreateMultipartUpload, DeleteObject, GetObject, PutObject,
+        UploadPart,
+    },
+    Bucket, Credentials, S3Action, UrlStyle,
+};
+use serde::Serialize;
+use std::{collections::BTreeMap, sync::Mutex, time::Duration};
+
+use crate::{config::Settings, status::LastOutcome};
+
+pub const UPLOADS_TOTAL: &str = "s3_uploads_total";
+pub const UPLOAD_FAILURES_TOTAL: &str = "s3_upload_failures_total";
+
+/// Attempts per request, with the delay doubling from `RETRY_DELAY` between them.
+const ATTEMPTS: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+/// How long a signed request URL stays valid.
+const SIGNATURE_TTL: Duration = Duration::from_secs(15 * 60);
+/// Files larger than this are sent in `PART_SIZE` parts; S3 needs every
+/// part but the last to be at least 5 MiB.
+const MULTIPART_THRESHOLD: usize = 16 * 1024 * 1024;
+const PART_SIZE: usize = 8 * 1024 * 1024;
+
+/// The most recent successful upload for one target.
+#[derive(Clone, Serialize)]
+pub struct Upload {
+    pub key: String,
+    pub at: DateTime<Utc>,
+}
+
+/// Stores generated reports in the S3-compatible bucket `S3_BUCKET`.
+pub struct ObjectStore {
+    client: reqwest::Client,
+    bucket: Bucket,
+    credentials: Credentials,
+    key_template: String,
+    last_uploads: Mutex<BTreeMap<String, Upload>>,
+    /// The latest upload for any target, failed or not.
+    pub last_upload: LastOutcome,
+}
+
+/// Keeps target patterns like `meter-a.*` usable in object keys.
+fn key_segment(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || "-_.".contains(c) { c } else { '_' })
+        .collect()
+}
+
+impl ObjectStore {
+    pub fn from_settings(settings: &Settings) -> Result<Option<Self>, String> {
+        if settings.s3_endpoint.is_empty() {
+            return Ok(None);
+        }
+        let endpoint = Url::parse(&settings.s3_endpoint)
+            .map_err(|e| format!("`S3_ENDPOINT` is not a valid URL: {}", e))?;
+        if settings.s3_bucket.is_empty() {
+            return Err("`S3_BUCKET` must be set when `S3_ENDPOINT` is".to_string());
+        }
+        // Path-style addressing works with MinIO and AWS alike.
+        let bucket = Bucket::new(
+            endpoint,
+            UrlStyle::Path,
+            settings.s3_bucket.clone(),
+            settings.s3_region.clone(),
+        )
+        .map_err(|e| format!("`S3_ENDPOINT` is not usable: {}", e))?;
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(120))
+            .build()
+            .map_err(|e| format!("failed to build HTTP client: {}", e))?;
+        Ok(Some(Self {
+            client,
+            bucket,
+            credentials: Credentials::new(settings.s3_access_key.clone(), settings.s3_secret_key.clone()),
+            key_template: settings.s3_key_template.clone(),
+            last_uploads: Mutex::default(),
+            last_upload: LastOutcome::default(),
+        }))
+    }
+
+    /// Fills `S3_KEY_TEMPLATE` for a report on `target` covering `date`.
+    pub fn key(&self, target: &str, report: &str, date: NaiveDate) -> String {
+        self.key_template
+            .replace("{target}", &key_segment(target))
+            .replace("{report}", &key_segment(report))
+            .replace("{year}", &format!("{:04}", date.year()))
+            .replace("{month}", &format!("{:02}", date.month()))
+            .replace("{day}", &format!("{:02}", date.day()))
+            .replace("{date}", &date.to_string())
+    }
+
+    /// Uploads `body` to `key`, in parts when it is large. The outcome is
+    /// counted, and a success recorded as `target`'s latest upload.
+    pub async fn upload(
+        &self,
+        target: &str,
+        key: &str,
+        body: Vec<u8>,
+        content_type: &str,
+    ) -> Result<(), String> {
+        let result = self.try_upload(key, body, content_type).await;
+        match &result {
+            Ok(()) => {
+                metrics::counter!(UPLOADS_TOTAL).increment(1);
+                let upload = Upload { key: key.to_string(), at: Utc::now() };
+                self.last_uploads.lock().unwrap().insert(target.to_string(), upload);
+            }
+            Err(e) => {
+                tracing::error!(key, "S3 upload failed: {}", e);
+                metrics::counter!(UPLOAD_FAILURES_TOTAL).increment(1);
+            }
+        }
+        self.last_upload.record(&result);
+        result
+    }
+
+    /// `upload` without counting or recording the outcome, for
+    /// `/admin/sinks/test`.
+    pub async fn try_upload(&self, key: &str, body: Vec<u8>, content_type: &str) -> Result<(), String> {
+        if body.len() > MULTIPART_THRESHOLD {
+            self.multipart(key, &body, content_type).await
+        } else {
+            self.put(key, body, content_type).await
+        }
+    }
+
+    /// Reads back the object at `key`.
+    pub async fn get(&self, key: &str) -> Result<Vec<u8>, String> {
+        let url = GetObject::new(&self.bucket, Some(&self.credentials), key).sign(SIGNATURE_TTL);
+        let response = self.send(|| self.client.get(url.clone())).await?;
+        response.bytes().await.map(|body| body.to_vec()).map_err(|e| e.to_string())
+    }
+
+    pub async fn delete(&self, key: &str) -> Result<(), String> {
+        let url = DeleteObject::new(&self.bucket, Some(&self.credentials), key).sign(SIGNATURE_TTL);
+        self.send(|| self.client.delete(url.clone())).await.map(drop)
+    }
+
+    pub fn last_uploads(&self) -> BTreeMap<String, Upload> {
+        self.last_uploads.lock().unwrap().clone()
+    }
+
+    async fn put(&self, key: &str, body: Vec<u8>, content_type: &str) -> Result<(), String> {
+        let url = PutObject::new(&self.bucket, Some(&self.credentials), key).sign(SIGNATURE_TTL);
+        self.send(|| {
+            self.client
+                .put(url.clone())
+                .header("Content-Type", content_type)
+                .body(body.clone())
+        })
+        .await
+        .map(drop)
+    }
+
+    async fn multipart(&self, key: &str, body: &[u8], content_type: &str) -> Result<(), String> {
+        let url = CreateMultipartUpload::new(&self.bucket, Some(&self.credentials), key).sign(SIGNATURE_TTL);
+        let created = self
+            .send(|| self.client.post(url.clone()).header("Content-Type", content_type))
+            .await?
+            .text()
+            .await
+            .map_err(|e| e.to_string())?;
+        let created = CreateMultipartUpload::parse_response(&created).map_err(|e| e.to_string())?;
+        let upload_id = created.upload_id();
+
+        let result = self.upload_parts(key, upload_id, body).await;
+        if result.is_err() {
+            // Parts of an unfinished upload are billed until aborted.
+            let url = AbortMultipartUpload::new(&self.bucket, Some(&self.credentials), key, upload_id)
+                .sign(SIGNATURE_TTL);
+            if let Err(e) = self.send(|| self.client.request(Method::DELETE, url.clone())).await {
+                tracing::warn!(key, "Aborting S3 multipart upload failed: {}", e);
+            }
+        }
+        result
+    }
+
+    async fn upload_parts(&self, key: &str, upload_id: &str, body: &[u8]) -> Result<(), String> {
+        let mut etags = Vec::new();
+        for (i, part) in body.chunks(PART_SIZE).enumerate() {
+            let number = u16::try_from(i + 1).map_err(|_| "too many parts".to_string())?;
+            let url = UploadPart::new(&self.bucket, Some(&self.credentials), key, number, upload_id)
+                .sign(SIGNATURE_TTL);
+            let response = self.send(|| self.client.put(url.clone()).body(part.to_vec())).await?;
+            let etag = response
+                .headers()
+                .get("ETag")
+                .and_then(|v| v.to_str().ok())
+                .ok_or("part upload returned no ETag")?;
+            etags.push(etag.to_string());
+        }
+
+        let complete = CompleteMultipartUpload::new(
+            &self.bucket,
+            Some(&self.credentials),
+            key,
+            upload_id,
+            etags.iter().map(String::as_str),
+        );
+        let url = complete.sign(SIGNATURE_TTL);
+        let body = complete.body();
+        self.send(|| self.client.post(url.clone()).body(body.clone()))
+            .await
+            .map(drop)
+    }
+
+    /// Sends the request built by `request`, retrying with backoff on
+    /// network errors, 429 and 5xx.
+    async fn send(&self, request: impl Fn() -> RequestBuilder) -> Result<reqwest::Response, String> {
+        let (mut attempt, mut delay) = (1, RETRY_DELAY);
+        loop {
+            let error = match request().send().await {
+                Ok(res) if res.status().is_success() => return Ok(res),
+                Ok(res) if res.status().is_client_error() && res.status() != StatusCode::TOO_MANY_REQUESTS => {
+                    return Err(format!("refused: {}", res.status()));
+                }
+                Ok(res) => res.status().to_string(),
+                Err(e) => e.to_string(),
+            };
+            if attempt == ATTEMPTS {
+                return Err(error);
+            }
+            tracing::warn!(attempt, "S3 request failed: {}", error);
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+            delay *= 2;
+        }
+    }
+}
@@ -0,0 +1,180 @@
+// Calendar arithmetic shared by the report endpoints. Days are local
+// calendar dates; they become UTC instants only through `resolve_local`
+// and `local_midnight`, as the last step.
+
+use chrono::{DateTime, Datelike, Duration, LocalResult, Months, NaiveDate, NaiveDateTime, Utc, Weekday};
+use chrono_tz::Tz;
+
+/// Which instant an ambiguous local time, repeated when clocks go back,
+/// resolves to.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Dst {
+    Early,
+    Late,
+}
+
+/// Resolves a local wall-clock time in `tz`. A time skipped when clocks go
+/// forward moves to the first valid instant after the gap, and a repeated
+/// one takes the earlier or later offset as `dst` says.
+pub fn resolve_local(naive: NaiveDateTime, tz: Tz, dst: Dst) -> Option<DateTime<Tz>> {
+    // Gaps are at most a few hours; step through them a minute at a time.
+    (0..=24 * 60).find_map(|minutes| {
+        match (naive + Duration::minutes(minutes)).and_local_timezone(tz) {
+            LocalResult::Single(dt) => Some(dt),
+            LocalResult::Ambiguous(early, late) => Some(if dst == Dst::Late { late } else { early }),
+            LocalResult::None => None,
+        }
+    })
+}
+
+/// Local midnight at the start of `date`, as UTC. Where clocks skip
+/// midnight the day starts at the end of the gap.
+pub fn local_midnight(date: NaiveDate, tz: Tz) -> Option<DateTime<Utc>> {
+    resolve_local(date.and_hms_opt(0, 0, 0)?, tz, Dst::Early).map(|dt| dt.with_timezone(&Utc))
+}
+
+/// The same wall-clock time `days` calendar days earlier. Being naive, this
+/// is unaffected by DST; resolve the result with `resolve_local`.
+pub fn days_before(naive: NaiveDateTime, days: u64) -> Option<NaiveDateTime> {
+    naive.checked_sub_days(chrono::Days::new(days))
+}
+
+/// The calendar day before `date`, across month and year boundaries.
+pub fn previous_day(date: NaiveDate) -> Option<NaiveDate> {
+    date.pred_opt()
+}
+
+/// `days` consecutive dates from `first`.
+pub fn dates(first: NaiveDate, days: u32) -> impl Iterator<Item = NaiveDate> {
+    first.iter_days().take(days as usize)
+}
+
+/// The last of `days` consecutive dates from `first`.
+pub fn last_date(first: NaiveDate, days: u32) -> Option<NaiveDate> {
+    first.checked_add_days(chrono::Days::new(u64::from(days.checked_sub(1)?)))
+}
+
+/// Number of dates from `start` through `end`, both included.
+pub fn days_inclusive(start: NaiveDate, end: NaiveDate) -> Option<u32> {
+    u32::try_from((end - start).num_days() + 1).ok()
+}
+
+pub fn days_in_month(year: i32, month: u32) -> Option<u32> {
+    let first = NaiveDate::from_ymd_opt(year, month, 1)?;
+    let next = first.checked_add_months(Months::new(1))?;
+    Some((next - first).num_days() as u32)
+}
+
+/// `day` of the given month, clamped to its last day, so a billing day of
+/// 31 falls on the 30th in April and on the 28th or 29th in February.
+pub fn clamped_day(year: i32, month: u32, day: u32) -> Option<NaiveDate> {
+    let last = days_in_month(year, month)?;
+    NaiveDate::from_ymd_opt(year, month, day.clamp(1, last))
+}
+
+/// Parses `YYYY-MM` into the first day of that month and its length in days.
+pub fn parse_month(value: &str) -> Option<(NaiveDate, u32)> {
+    let (year, month) = value.split_once('-')?;
+    let (year, month) = (year.parse().ok()?, month.parse().ok()?);
+    Some((NaiveDate::from_ymd_opt(year, month, 1)?, days_in_month(year, month)?))
+}
+
+/// Parses `YYYY-Www` into the Monday of that ISO week.
+pub fn parse_week(value: &str) -> Option<NaiveDate> {
+    let (year, week) = value.split_once("-W")?;
+    NaiveDate::from_isoywd_opt(year.parse().ok()?, week.parse().ok()?, Weekday::Mon)
+}
+
+/// The billing period that starts in the month of `month` on `billing_day`,
+/// as its first date and length in days. It runs up to the day before the
+/// next month's billing day, both ends clamped to the length of their month.
+pub fn billing_period(month: NaiveDate, billing_day: u32) -> Option<(NaiveDate, u32)> {
+    let start = clamped_day(month.year(), month.month(), billing_day)?;
+    let next = month.with_day(1)?.checked_add_months(Months::new(1))?;
+    let end = clamped_day(next.year(), next.month(), billing_day)?;
+    Some((start, (end - start).num_days() as u32))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(value: &str) -> NaiveDate {
+        value.parse().unwrap()
+    }
+
+    fn utc(value: &str) -> DateTime<Utc> {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn clamped_day_to_the_end_of_the_month() {
+        assert_eq!(clamped_day(2024, 2, 31), Some(date("2024-02-29")));
+        assert_eq!(clamped_day(2023, 2, 31), Some(date("2023-02-28")));
+        assert_eq!(clamped_day(2024, 4, 31), Some(date("2024-04-30")));
+        assert_eq!(clamped_day(2024, 12, 31), Some(date("2024-12-31")));
+        assert_eq!(clamped_day(2024, 13, 1), None);
+    }
+
+    #[test]
+    fn billing_day_31_in_short_months() {
+        assert_eq!(billing_period(date("2024-01-01"), 31), Some((date("2024-01-31"), 29)));
+        assert_eq!(billing_period(date("2024-02-01"), 31), Some((date("2024-02-29"), 31)));
+        assert_eq!(billing_period(date("2023-02-01"), 31), Some((date("2023-02-28"), 31)));
+        assert_eq!(billing_period(date("2024-03-01"), 31), Some((date("2024-03-31"), 30)));
+        assert_eq!(billing_period(date("2024-04-01"), 31), Some((date("2024-04-30"), 31)));
+    }
+
+    #[test]
+    fn billing_period_across_the_year() {
+        assert_eq!(billing_period(date("2024-12-01"), 15), Some((date("2024-12-15"), 31)));
+        assert_eq!(billing_period(date("2024-12-20"), 31), Some((date("2024-12-31"), 31)));
+        assert_eq!(billing_period(date("2025-01-01"), 29), Some((date("2025-01-29"), 30)));
+    }
+
+    #[test]
+    fn last_date_across_feb_29_and_the_year() {
+        assert_eq!(last_date(date("2024-02-28"), 2), Some(date("2024-02-29")));
+        assert_eq!(last_date(date("2023-02-28"), 2), Some(date("2023-03-01")));
+        assert_eq!(last_date(date("2024-12-31"), 2), Some(date("2025-01-01")));
+        assert_eq!(last_date(date("2024-02-01"), 29), Some(date("2024-02-29")));
+        assert_eq!(last_date(date("2024-02-01"), 1), Some(date("2024-02-01")));
+        assert_eq!(last_date(date("2024-02-01"), 0), None);
+    }
+
+    #[test]
+    fn local_midnight_in_utc() {
+        let jakarta = chrono_tz::Asia::Jakarta;
+        assert_eq!(local_midnight(date("2025-01-01"), jakarta), Some(utc("2024-12-31T17:00:00Z")));
+        assert_eq!(local_midnight(date("2024-02-29"), jakarta), Some(utc("2024-02-28T17:00:00Z")));
+        let berlin = chrono_tz::Europe::Berlin;
+        assert_eq!(local_midnight(date("2024-03-31"), berlin), Some(utc("2024-03-30T23:00:00Z")));
+        assert_eq!(local_midnight(date("2024-04-01"), berlin), Some(utc("2024-03-31T22:00:00Z")));
+        // Clocks went from midnight straight to 01:00.
+        let santiago = chrono_tz::America::Santiago;
+        assert_eq!(local_midnight(date("2024-09-08"), santiago), Some(utc("2024-09-08T04:00:00Z")));
+    }
+
+    fn berlin(value: &str, dst: Dst) -> Option<DateTime<Utc>> {
+        let naive = value.parse().unwrap();
+        resolve_local(naive, chrono_tz::Europe::Berlin, dst).map(|dt| dt.with_timezone(&Utc))
+    }
+
+    #[test]
+    fn skipped_time_moves_past_the_gap() {
+        // 02:00 to 03:00 does not exist on 2024-03-31.
+        for dst in [Dst::Early, Dst::Late] {
+            assert_eq!(berlin("2024-03-31T02:30:00", dst), Some(utc("2024-03-31T01:00:00Z")));
+        }
+        assert_eq!(berlin("2024-03-31T01:59:00", Dst::Early), Some(utc("2024-03-31T00:59:00Z")));
+        assert_eq!(berlin("2024-03-31T03:00:00", Dst::Early), Some(utc("2024-03-31T01:00:00Z")));
+    }
+
+    #[test]
+    fn repeated_time_takes_the_offset_dst_asks_for() {
+        // 02:00 to 03:00 happens twice on 2024-10-27, first at +02:00.
+        assert_eq!(berlin("2024-10-27T02:30:00", Dst::Early), Some(utc("2024-10-27T00:30:00Z")));
+        assert_eq!(berlin("2024-10-27T02:30:00", Dst::Late), Some(utc("2024-10-27T01:30:00Z")));
+        assert_eq!(berlin("2024-10-27T03:00:00", Dst::Late), Some(utc("2024-10-27T02:00:00Z")));
+    }
+}
@@ -0,0 +1,89 @@
+use axum::{
+    extract::{Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use chrono::Utc;
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    api::targets::validate_target,
+    cache::TtlCache,
+    error::ApiError,
+    period::previous_day,
+    range::daily_usage,
+    state::AppState,
+    usage::{resolve_selector, resolve_target},
+    usage_metrics::write_gauges,
+};
+
+/// How long a probe's figures are reused, well above typical scrape
+/// intervals; the day they are for only changes at midnight.
+pub const PROBE_CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// Each meter's `(instance, address, daily_kwh)` for the previous day, by
+/// target selector and date.
+pub type ProbeCache = TtlCache<String, Arc<Vec<(String, String, f64)>>>;
+
+pub async fn probe_handler(
+    State(state): State<AppState>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Response {
+    match probe(&state, &params).await {
+        Ok(body) => {
+            ([(header::CONTENT_TYPE, "text/plain; version=0.0.4; charset=utf-8")], body).into_response()
+        }
+        Err(error) => error.into_response(),
+    }
+}
+
+/// `GET /probe?target=...`, for Prometheus to scrape in the manner of the
+/// blackbox exporter: the `/metrics/usage` gauges for the previous completed
+/// local day of the scraped target, and `probe_success` with whether the
+/// backend answered. The target is checked as on `/api/v1/power-usage`, and
+/// a bad one fails the scrape instead.
+async fn probe(state: &AppState, params: &HashMap<String, String>) -> Result<String, ApiError> {
+    let (target, address) = resolve_target(params, state)?;
+    validate_target(state, params)?;
+    let selector = resolve_selector(params, &target)?;
+    let today = Utc::now().with_timezone(&state.config.timezone).date_naive();
+    let yesterday = previous_day(today).ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let started = Instant::now();
+    let key = format!("{}\n{}", selector, yesterday);
+    let meters = match state.probe_cache.get(&key) {
+        Some(meters) => Ok(meters),
+        None => daily_usage(state, &selector, yesterday, 1).await.map(|series| {
+            let meters: Vec<(String, String, f64)> = series
+                .into_iter()
+                .filter_map(|meter| Some((meter.instance, meter.address, meter.days.first()?.1?)))
+                .collect();
+            let meters = Arc::new(meters);
+            state.probe_cache.insert(key, meters.clone());
+            meters
+        }),
+    };
+    if let Err(code) = &meters {
+        tracing::warn!(target = %target, "Probe failed: {}", code);
+    }
+
+    let mut body = String::new();
+    writeln!(body, "# HELP probe_success Whether the backend answered for the target").ok();
+    writeln!(body, "# TYPE probe_success gauge").ok();
+    writeln!(body, "probe_success {}", u8::from(meters.is_ok())).ok();
+    writeln!(body, "# HELP probe_duration_seconds How long the probe took").ok();
+    writeln!(body, "# TYPE probe_duration_seconds gauge").ok();
+    writeln!(body, "probe_duration_seconds {}", started.elapsed().as_secs_f64()).ok();
+    let meters = meters.unwrap_or_default();
+    let kept = meters
+        .iter()
+        .filter(|(_, a, _)| address.as_ref().is_none_or(|only| only == a))
+        .map(|(i, a, kwh)| (i.as_str(), a.as_str(), *kwh));
+    write_gauges(&mut body, kept);
+    Ok(body)
+}
@@ -0,0 +1,1392 @@
+use axum::http::{header, StatusCode};
+use chrono::{DateTime, Utc};
+use futures_util::{stream, StreamExt, TryStreamExt};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    borrow::Cow,
+    collections::{hash_map::Entry, BTreeMap, HashMap},
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+use tower::{Layer, Service};
+
+use crate::{
+    aliases::literal_pattern,
+    calculator::MetricsBackend,
+    config::{parse_duration, Config, Settings},
+    deadline,
+    error::Error,
+    fixture::Fixtures,
+    natural::natural,
+    reload::Live,
+    request_id::{self, X_REQUEST_ID},
+    selector,
+    usage::PHASE_LABEL,
+};
+
+pub const PROMETHEUS_REQUESTS_TOTAL: &str = "prometheus_requests_total";
+pub const PROMETHEUS_QUERY_CHUNKS_TOTAL: &str = "prometheus_query_chunks_total";
+pub const PROMETHEUS_CONNECTIONS_TOTAL: &str = "prometheus_connections_total";
+pub const SKIPPED_SERIES_TOTAL: &str = "skipped_series_total";
+
+/// Chunks of a split usage query that run at the same time.
+const CHUNK_CONCURRENCY: usize = 4;
+
+/// Share of `PROMETHEUS_TIMEOUT` that Prometheus is given to evaluate a query.
+const SERVER_TIMEOUT_SHARE: f64 = 0.9;
+
+/// Bytes of an unexpected response body that are logged.
+const EXCERPT_BYTES: usize = 200;
+
+/// Why a Prometheus call failed: `error_kind` in the error body, and the
+/// `outcome` label of `PROMETHEUS_REQUESTS_TOTAL`.
+#[derive(Clone, Copy, PartialEq)]
+pub enum ErrorKind {
+    /// Connection refused, DNS failure and the like.
+    Unreachable,
+    Timeout,
+    /// Prometheus rejected the expression, usually a bug in how it was built.
+    BadQuery,
+    /// A 429 or 503 from Prometheus.
+    Overloaded,
+    /// Any other error Prometheus reported.
+    Upstream,
+    /// A body that is not the JSON the API documents.
+    InvalidResponse,
+}
+
+impl ErrorKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Unreachable => "unreachable",
+            Self::Timeout => "timeout",
+            Self::BadQuery => "bad_query",
+            Self::Overloaded => "overloaded",
+            Self::Upstream => "upstream_error",
+            Self::InvalidResponse => "invalid_response",
+        }
+    }
+
+    /// Whether Prometheus itself is down or struggling, rather than
+    /// answering badly, so that `fallback=last_known` applies.
+    pub fn is_outage(self) -> bool {
+        matches!(self, Self::Unreachable | Self::Timeout | Self::Overloaded)
+    }
+
+    pub fn status(self) -> StatusCode {
+        match self {
+            Self::Unreachable | Self::Upstream | Self::InvalidResponse => StatusCode::BAD_GATEWAY,
+            Self::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            Self::BadQuery => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::Overloaded => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    pub fn message(self) -> &'static str {
+        match self {
+            Self::Unreachable => "Prometheus is unreachable",
+            Self::Timeout => "Prometheus query timed out",
+            Self::BadQuery => "Prometheus rejected the generated query",
+            Self::Overloaded => "Prometheus is overloaded; retry later",
+            Self::Upstream => "Prometheus query failed",
+            Self::InvalidResponse => "Prometheus returned an unexpected response",
+        }
+    }
+}
+
+/// A failed Prometheus call, returned as `Error::Prometheus` for the error
+/// response of the request that made it.
+#[derive(Clone)]
+pub struct Failure {
+    pub kind: ErrorKind,
+    /// The expression, when Prometheus rejected it.
+    pub expr: Option<String>,
+    /// Prometheus' `Retry-After`, when it sent one.
+    pub retry_after: Option<String>,
+    /// The `error` of Prometheus' own error body, when it answered with one.
+    pub upstream: Option<String>,
+}
+
+fn fail(failure: Failure) -> Error {
+    metrics::counter!(PROMETHEUS_REQUESTS_TOTAL, "outcome" => failure.kind.name()).increment(1);
+    Error::Prometheus(failure)
+}
+
+fn fail_with(kind: ErrorKind) -> Error {
+    fail(Failure {
+        kind,
+        expr: None,
+        retry_after: None,
+        upstream: None,
+    })
+}
+
+fn excerpt(body: &[u8]) -> String {
+    String::from_utf8_lossy(&body[..body.len().min(EXCERPT_BYTES)]).into_owned()
+}
+
+/// The body of an instant query, as the HTTP API documents it.
+#[derive(Deserialize)]
+struct InstantResponse {
+    status: String,
+    data: InstantData,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct InstantData {
+    result_type: String,
+    /// Read as a `Vec<VectorSeries>` once `result_type` is known to be `vector`.
+    result: Value,
+}
+
+/// One series of an instant query's `vector` result.
+#[derive(Deserialize)]
+pub struct VectorSeries {
+    pub metric: HashMap<String, String>,
+    /// The evaluation time in seconds, and the value as Prometheus formats it.
+    #[serde(default)]
+    pub value: Option<(f64, String)>,
+}
+
+impl VectorSeries {
+    fn label(&self, name: &str) -> Option<&str> {
+        self.metric.get(name).map(String::as_str)
+    }
+
+    fn number(&self) -> Result<f64, SkipReason> {
+        let (_, raw) = self.value.as_ref().ok_or(SkipReason::MissingValue)?;
+        parse_value(raw)
+    }
+}
+
+/// Why a series was left out rather than read: the `reason` label of
+/// `SKIPPED_SERIES_TOTAL`.
+#[derive(Clone, Copy, PartialEq)]
+pub enum SkipReason {
+    /// `NaN` or an infinity, which a meter may export while it boots; read
+    /// as a missing reading so it never reaches a delta.
+    Nan,
+    ParseError,
+    /// No value at all.
+    MissingValue,
+}
+
+impl SkipReason {
+    pub fn name(self) -> &'static str {
+        match self {
+            SkipReason::Nan => "nan",
+            SkipReason::ParseError => "parse_error",
+            SkipReason::MissingValue => "missing_value",
+        }
+    }
+}
+
+/// A sample value as Prometheus formats it, when it is a finite number.
+pub fn parse_value(raw: &str) -> Result<f64, SkipReason> {
+    match raw.parse::<f64>() {
+        Ok(value) if value.is_finite() => Ok(value),
+        Ok(_) => Err(SkipReason::Nan),
+        Err(_) => Err(SkipReason::ParseError),
+    }
+}
+
+/// A series `parse_samples` left out, and the value it had.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct SkippedSeries {
+    pub instance: String,
+    pub address: String,
+    pub reason: String,
+    /// As Prometheus sent it, when it sent one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+}
+
+/// `skipped` sorted, each series once.
+pub fn dedup_skipped(skipped: &mut Vec<SkippedSeries>) {
+    let key = |s: &SkippedSeries| (s.instance.clone(), s.address.clone(), s.reason.clone());
+    skipped.sort_by_key(key);
+    skipped.dedup_by_key(|s| key(s));
+}
+
+/// Logs and counts a series left out for `reason`.
+fn skip(item: &VectorSeries, reason: SkipReason) -> SkippedSeries {
+    let instance = item.label("instance").unwrap_or("unknown");
+    let address = item.label("address").unwrap_or_default();
+    let value = item.value.as_ref().map(|(_, raw)| raw.clone());
+    tracing::warn!(
+        instance,
+        address,
+        value = value.as_deref().unwrap_or("none"),
+        reason = reason.name(),
+        "Series skipped, its value is not a number"
+    );
+    metrics::counter!(SKIPPED_SERIES_TOTAL, "reason" => reason.name()).increment(1);
+    SkippedSeries {
+        instance: instance.to_string(),
+        address: address.to_string(),
+        reason: reason.name().to_string(),
+        value,
+    }
+}
+
+
+pub struct Sample {
+    pub address: String,
+    pub value: f64,
+    pub timestamp: Option<DateTime<Utc>>,
+    /// Every label on the series, for grouping by arbitrary labels.
+    pub labels: HashMap<String, String>,
+}
+
+/// Readings by instance, as `parse_samples` groups them.
+pub type Readings = HashMap<String, Vec<Sample>>;
+
+/// The readings of a query, and the series left out of them for their value.
+#[derive(Default)]
+pub struct Parsed {
+    pub data: Readings,
+    pub skipped: Vec<SkippedSeries>,
+}
+
+impl Parsed {
+    fn extend(&mut self, other: Parsed) {
+        self.data.extend(other.data);
+        self.skipped.extend(other.skipped);
+    }
+}
+
+/// The current readings `get_current` read, by instance, and what
+/// `get_previous` needs to read the previous ones.
+pub struct Current {
+    pub data: Readings,
+    skipped: Vec<SkippedSeries>,
+    /// Each selector queried, with the previous readings the same query
+    /// answered with, if any.
+    chunks: Vec<(String, Option<Parsed>)>,
+    prev: DateTime<Utc>,
+}
+
+/// Both readings of `get_pair`, and the series left out of either.
+pub struct Pair {
+    pub curr: Readings,
+    pub prev: Readings,
+    pub skipped: Vec<SkippedSeries>,
+}
+
+/// How `get_pair` fetches the two readings of a usage query.
+#[derive(Clone, Copy, PartialEq)]
+pub enum QueryStrategy {
+    /// One instant query per reading.
+    Separate,
+    /// Both readings in one query, the earlier one through `offset`.
+    Offset,
+}
+
+impl QueryStrategy {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.trim() {
+            "separate" => Ok(Self::Separate),
+            "offset" => Ok(Self::Offset),
+            other => Err(format!("`QUERY_STRATEGY` must be `separate` or `offset`, got {:?}", other)),
+        }
+    }
+}
+
+/// One Prometheus API call: the path and the parameters selecting the data,
+/// plus the evaluation options `timeout=` and `stats=all`, which do not.
+/// Fixtures are keyed on the parameters alone, so they replay whatever the
+/// options.
+#[derive(Clone, Debug)]
+pub struct ApiCall {
+    path: Cow<'static, str>,
+    params: Vec<(&'static str, String)>,
+    /// Whether the endpoint evaluates PromQL, and so takes the options.
+    evaluates: bool,
+    timeout: Option<Duration>,
+    stats: bool,
+}
+
+fn api_time(t: DateTime<Utc>) -> String {
+    t.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+}
+
+impl ApiCall {
+    pub fn new(path: impl Into<Cow<'static, str>>) -> Self {
+        Self {
+            path: path.into(),
+            params: Vec::new(),
+            evaluates: false,
+            timeout: None,
+            stats: false,
+        }
+    }
+
+    /// An instant query of `expr`, at `time` or else now.
+    pub fn query(expr: &str, time: Option<DateTime<Utc>>) -> Self {
+        let call = Self {
+            evaluates: true,
+            ..Self::new("api/v1/query")
+        };
+        let call = call.param("query", expr);
+        match time {
+            Some(time) => call.param("time", api_time(time)),
+            None => call,
+        }
+    }
+
+    /// A range query of `expr` every `step` from `start` through `end`.
+    pub fn query_range(expr: &str, start: DateTime<Utc>, end: DateTime<Utc>, step: Duration) -> Self {
+        let call = Self {
+            evaluates: true,
+            ..Self::new("api/v1/query_range")
+        };
+        call.param("query", expr)
+            .param("start", api_time(start))
+            .param("end", api_time(end))
+            .param("step", format!("{}s", step.as_secs()))
+    }
+
+    pub fn param(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.params.push((name, value.into()));
+        self
+    }
+
+    /// Lets Prometheus cancel the evaluation itself after `timeout`.
+    /// Ignored by endpoints that evaluate nothing.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = self.evaluates.then_some(timeout);
+        self
+    }
+
+    /// Asks for the `stats` block of the evaluation.
+    pub fn stats(mut self, stats: bool) -> Self {
+        self.stats = stats && self.evaluates;
+        self
+    }
+
+    /// The parameters selecting the data, without the evaluation options.
+    pub fn params(&self) -> &[(&'static str, String)] {
+        &self.params
+    }
+
+    /// Everything sent in the query string.
+    pub fn query_string(&self) -> Vec<(&'static str, String)> {
+        let mut query = self.params.clone();
+        if let Some(timeout) = self.timeout {
+            query.push(("timeout", format!("{}ms", timeout.as_millis())));
+        }
+        if self.stats {
+            query.push(("stats", "all".to_string()));
+        }
+        query
+    }
+
+    fn expr(&self) -> Option<String> {
+        self.params.iter().find(|(name, _)| *name == "query").map(|(_, expr)| expr.clone())
+    }
+}
+
+/// The `stats` block Prometheus returned for one call, with `debug=true`.
+#[derive(Serialize)]
+pub struct QueryStats {
+    pub path: Cow<'static, str>,
+    pub params: BTreeMap<&'static str, String>,
+    /// As Prometheus sent it: `timings.execTotalTime` is the evaluation time
+    /// in seconds, and `samples.totalQueryableSamples` the samples loaded.
+    pub stats: Value,
+}
+
+/// The stats of every query made by a client from `Prometheus::with_stats`.
+pub type StatsLog = Arc<Mutex<Vec<QueryStats>>>;
+
+/// One call and what came back, for support bundles.
+#[derive(Serialize)]
+pub struct Exchange {
+    pub path: Cow<'static, str>,
+    pub params: BTreeMap<&'static str, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    /// The body as JSON, or as text when it is not JSON.
+    #[serde(skip_serializing_if = "Value::is_null")]
+    pub response: Value,
+    /// The `error_kind` when no response came back.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<&'static str>,
+}
+
+/// Every call made by a client from `Prometheus::with_exchanges`, in order.
+pub type ExchangeLog = Arc<Mutex<Vec<Exchange>>>;
+
+fn exchange(call: &ApiCall, fetched: &Result<(StatusCode, Vec<u8>), Error>) -> Exchange {
+    let (status, response, error) = match fetched {
+        Ok((status, body)) => {
+            let response = serde_json::from_slice(body)
+                .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(body).into_owned()));
+            (Some(status.as_u16()), response, None)
+        }
+        Err(error) => {
+            let kind = error.failure().map_or(ErrorKind::Upstream, |failure| failure.kind);
+            (None, Value::Null, Some(kind.name()))
+        }
+    };
+    Exchange {
+        path: call.path.clone(),
+        params: call.params.iter().cloned().collect(),
+        status,
+        response,
+        error,
+    }
+}
+
+/// Connection handling of the Prometheus client. The defaults are reqwest's
+/// own: HTTP/1.1, idle connections kept for 90 seconds without a limit per
+/// host, and no TCP keep-alive.
+#[derive(Clone, Copy)]
+pub struct ClientTuning {
+    /// Speak HTTP/2 from the first byte, with `PROMETHEUS_HTTP2=true`.
+    http2: bool,
+    pool_idle_timeout: Duration,
+    pool_max_idle_per_host: Option<usize>,
+    tcp_keepalive: Option<Duration>,
+}
+
+impl ClientTuning {
+    pub fn from_settings(settings: &Settings) -> Result<Self, String> {
+        let http2 = match settings.prometheus_http2.trim() {
+            "true" => true,
+            "false" => false,
+            other => return Err(format!("`PROMETHEUS_HTTP2` must be `true` or `false`, got {:?}", other)),
+        };
+        let idle = &settings.pool_idle_timeout;
+        let pool_idle_timeout = parse_duration(idle)
+            .ok_or_else(|| format!("`POOL_IDLE_TIMEOUT` must be a duration like `90s`, got {:?}", idle))?;
+        let max_idle = settings.pool_max_idle_per_host.trim();
+        let pool_max_idle_per_host = match max_idle {
+            "" => None,
+            max => Some(max.parse::<usize>().map_err(|_| {
+                format!("`POOL_MAX_IDLE_PER_HOST` must be a non-negative integer, got {:?}", max)
+            })?),
+        };
+        let keepalive = settings.tcp_keepalive.trim();
+        let tcp_keepalive = match keepalive {
+            "" => None,
+            value => Some(parse_duration(value).filter(|d| !d.is_zero()).ok_or_else(|| {
+                format!("`TCP_KEEPALIVE` must be a positive duration like `30s`, got {:?}", value)
+            })?),
+        };
+        Ok(Self {
+            http2,
+            pool_idle_timeout,
+            pool_max_idle_per_host,
+            tcp_keepalive,
+        })
+    }
+
+    fn apply(&self, mut builder: reqwest::ClientBuilder) -> reqwest::ClientBuilder {
+        if self.http2 {
+            builder = builder.http2_prior_knowledge();
+        }
+        builder = builder.pool_idle_timeout(self.pool_idle_timeout);
+        if let Some(max) = self.pool_max_idle_per_host {
+            builder = builder.pool_max_idle_per_host(max);
+        }
+        builder
+            .tcp_keepalive(self.tcp_keepalive)
+            .connector_layer(CountConnections)
+    }
+}
+
+/// Counts the connections the client opens in `prometheus_connections_total`,
+/// so churn in the pool shows on `/metrics`.
+#[derive(Clone)]
+struct CountConnections;
+
+impl<S> Layer<S> for CountConnections {
+    type Service = Counted<S>;
+
+    fn layer(&self, inner: S) -> Counted<S> {
+        Counted(inner)
+    }
+}
+
+#[derive(Clone)]
+struct Counted<S>(S);
+
+impl<S, R> Service<R> for Counted<S>
+where
+    S: Service<R>,
+    S::Future: Send + 'static,
+    S::Response: Send + 'static,
+    S::Error: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<S::Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        self.0.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: R) -> Self::Future {
+        let connecting = self.0.call(request);
+        Box::pin(async move {
+            let connection = connecting.await;
+            let outcome = if connection.is_ok() { "ok" } else { "error" };
+            metrics::counter!(PROMETHEUS_CONNECTIONS_TOTAL, "outcome" => outcome).increment(1);
+            connection
+        })
+    }
+}
+
+/// Client for the Prometheus HTTP API, sharing one connection pool.
+#[derive(Clone)]
+pub struct Prometheus {
+    client: reqwest::Client,
+    base_url: Url,
+    /// Where the lookback is read from, so a reload applies to every client.
+    live: Arc<Live>,
+    strategy: QueryStrategy,
+    fixtures: Option<Fixtures>,
+    /// Answers every call instead of HTTP, for `UsageCalculator::with_backend`.
+    source: Option<Arc<dyn MetricsBackend>>,
+    /// Most instances one usage query may cover, from `CHUNK_SIZE`.
+    chunk_size: Option<usize>,
+    /// Passed as `timeout=`, a little under `PROMETHEUS_TIMEOUT` so that
+    /// Prometheus gives up before the client does.
+    server_timeout: Duration,
+    /// Where the stats go when every query asks for `stats=all`.
+    stats: Option<StatsLog>,
+    /// Where every call goes, with its raw response, for support bundles.
+    exchanges: Option<ExchangeLog>,
+    /// Counts of completed calls this client adds to, from `counting`.
+    completed: Vec<Arc<AtomicUsize>>,
+}
+
+impl Prometheus {
+    /// A client for the Prometheus at `base_url`, with the timeout, connection
+    /// tuning, lookback and strategy from `config`.
+    pub fn new(config: &Config, base_url: Url) -> Result<Self, String> {
+        let client = config
+            .prometheus_client
+            .apply(reqwest::Client::builder().timeout(config.prometheus_timeout))
+            .build()
+            .map_err(|e| format!("failed to build HTTP client: {}", e))?;
+
+        Ok(Self {
+            client,
+            base_url,
+            live: config.live.clone(),
+            strategy: config.query_strategy,
+            fixtures: config.fixtures.clone(),
+            source: None,
+            chunk_size: config.chunk_size,
+            server_timeout: config.prometheus_timeout.mul_f64(SERVER_TIMEOUT_SHARE),
+            stats: None,
+            exchanges: None,
+            completed: Vec::new(),
+        })
+    }
+
+    /// `LOOKBACK` as of the start of the current request.
+    pub fn lookback(&self) -> String {
+        self.live.current().lookback.clone()
+    }
+
+    /// `FALLBACK_LOOKBACK` as of the start of the current request.
+    pub fn fallback_lookback(&self) -> Option<String> {
+        self.live.current().fallback_lookback.clone()
+    }
+
+    /// The same client with every call answered by `source`.
+    pub fn with_source(self, source: Arc<dyn MetricsBackend>) -> Self {
+        Self {
+            source: Some(source),
+            ..self
+        }
+    }
+
+    /// The same client with `stats=all` on every query, whose stats are
+    /// kept in `log`.
+    pub fn with_stats(self, log: StatsLog) -> Self {
+        Self {
+            stats: Some(log),
+            ..self
+        }
+    }
+
+    /// The same client keeping every call it makes, with the raw
+    /// response, in `log`.
+    pub fn with_exchanges(self, log: ExchangeLog) -> Self {
+        Self {
+            exchanges: Some(log),
+            ..self
+        }
+    }
+
+    /// The same client adding each call it completes to `completed` too,
+    /// whichever task makes it.
+    pub fn counting(mut self, completed: Arc<AtomicUsize>) -> Self {
+        self.completed.push(completed);
+        self
+    }
+
+    /// Credits `queries` calls completed on this client's behalf, such as
+    /// by the identical request a usage query was coalesced with.
+    pub fn note_completed(&self, queries: usize) {
+        for completed in &self.completed {
+            completed.fetch_add(queries, Ordering::Relaxed);
+        }
+        deadline::note_queries(queries);
+    }
+
+    /// Counts a successful call.
+    fn succeeded(&self) {
+        metrics::counter!(PROMETHEUS_REQUESTS_TOTAL, "outcome" => "ok").increment(1);
+        self.note_completed(1);
+    }
+
+    /// The base URL with any credentials stripped, for logs and metadata.
+    pub fn display_url(&self) -> String {
+        let mut url = self.base_url.clone();
+        url.set_username("").ok();
+        url.set_password(None).ok();
+        url.to_string()
+    }
+
+    fn request(&self, path: &str) -> Result<reqwest::RequestBuilder, Error> {
+        let url = self
+            .base_url
+            .join(path)
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        let mut request = self.client.get(url);
+        if let Some(id) = request_id::current() {
+            request = request.header(&X_REQUEST_ID, id);
+        }
+        Ok(request)
+    }
+
+    /// Runs an instant query and returns the `data.result` series.
+    pub async fn query(&self, expr: &str, datetime: DateTime<Utc>) -> Result<Vec<VectorSeries>, Error> {
+        self.vector(ApiCall::query(expr, Some(datetime))).await
+    }
+
+    /// Runs a range query evaluated every `step` from `start` through `end`
+    /// and returns the raw `data.result` matrix.
+    pub async fn query_range(
+        &self,
+        expr: &str,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        step: Duration,
+    ) -> Result<Vec<Value>, Error> {
+        self.result(ApiCall::query_range(expr, start, end, step)).await
+    }
+
+    /// The HTTP status and body of `GET <path>?<params>`, as `fetch` gets them.
+    pub async fn get(
+        &self,
+        path: &str,
+        params: &[(&'static str, String)],
+    ) -> Result<(StatusCode, Vec<u8>), Error> {
+        let call = params
+            .iter()
+            .fold(ApiCall::new(path.to_string()), |call, (name, value)| call.param(name, value.clone()));
+        self.fetch(&call).await
+    }
+
+    /// The HTTP status and body of one API call, or with `BACKEND=fixture`
+    /// the recorded body.
+    async fn fetch(&self, call: &ApiCall) -> Result<(StatusCode, Vec<u8>), Error> {
+        if let Some(source) = &self.source {
+            return match source.get(&call.path, call.params()).await {
+                Ok(body) => Ok((StatusCode::OK, body)),
+                Err(e) => {
+                    tracing::warn!("Metrics backend failed: {}", e);
+                    Err(fail_with(ErrorKind::Unreachable))
+                }
+            };
+        }
+        if let Some(fixtures @ Fixtures::Replay { .. }) = &self.fixtures {
+            let replayed = fixtures.replay(&call.path, call.params()).await;
+            let body = replayed.ok_or_else(|| fail_with(ErrorKind::Upstream))?;
+            return Ok((StatusCode::OK, body));
+        }
+        let transport = |e: reqwest::Error| {
+            fail_with(if e.is_timeout() { ErrorKind::Timeout } else { ErrorKind::Unreachable })
+        };
+        let request = self.request(&call.path)?.query(&call.query_string());
+        let response = request.send().await.map_err(transport)?;
+        let status = response.status();
+        if status == StatusCode::TOO_MANY_REQUESTS || status == StatusCode::SERVICE_UNAVAILABLE {
+            let retry_after = response.headers().get(header::RETRY_AFTER).and_then(|v| v.to_str().ok());
+            return Err(fail(Failure {
+                kind: ErrorKind::Overloaded,
+                expr: None,
+                retry_after: retry_after.map(str::to_string),
+                upstream: None,
+            }));
+        }
+        Ok((status, response.bytes().await.map_err(transport)?.to_vec()))
+    }
+
+    /// Runs one API call and returns `data.result`.
+    async fn result(&self, call: ApiCall) -> Result<Vec<Value>, Error> {
+        self.array(call, "/data/result").await
+    }
+
+    /// Runs one API call and returns the array at `pointer` in its body.
+    async fn array(&self, call: ApiCall, pointer: &str) -> Result<Vec<Value>, Error> {
+        let (mut res, body) = self.body(call).await?;
+        match res.pointer_mut(pointer).map(Value::take) {
+            Some(Value::Array(result)) => {
+                self.succeeded();
+                Ok(result)
+            }
+            _ => {
+                tracing::warn!("Prometheus response has no result array: {}", excerpt(&body));
+                Err(fail_with(ErrorKind::InvalidResponse))
+            }
+        }
+    }
+
+    /// Runs an instant query that must answer with a `vector`. Anything
+    /// else, such as a proxy wrapping the response its own way, fails
+    /// rather than reading as no series.
+    async fn vector(&self, call: ApiCall) -> Result<Vec<VectorSeries>, Error> {
+        let (res, body) = self.body(call).await?;
+        let invalid = |reason: String| {
+            let excerpt = excerpt(&body);
+            tracing::warn!("Prometheus response is not an instant query result, {}: {}", reason, excerpt);
+            fail_with(ErrorKind::InvalidResponse)
+        };
+        let response = serde_json::from_value::<InstantResponse>(res).map_err(|e| invalid(e.to_string()))?;
+        if response.status != "success" {
+            return Err(invalid(format!("status {:?}", response.status)));
+        }
+        if response.data.result_type != "vector" {
+            return Err(invalid(format!("resultType {:?} instead of \"vector\"", response.data.result_type)));
+        }
+        let result = serde_json::from_value(response.data.result).map_err(|e| invalid(e.to_string()))?;
+        self.succeeded();
+        Ok(result)
+    }
+
+    /// `call` with the evaluation options of this client.
+    fn options(&self, call: ApiCall) -> ApiCall {
+        call.timeout(self.server_timeout).stats(self.stats.is_some())
+    }
+
+    /// Runs one API call, with `timeout=` and, from `with_stats`,
+    /// `stats=all`, and returns its body, parsed and raw. Failures are
+    /// counted by kind and returned with what Prometheus said about them.
+    async fn body(&self, call: ApiCall) -> Result<(Value, Vec<u8>), Error> {
+        let call = self.options(call);
+        let fetched = self.fetch(&call).await;
+        if let Some(log) = &self.exchanges {
+            log.lock().unwrap().push(exchange(&call, &fetched));
+        }
+        let (status, body) = fetched?;
+        let Ok(mut res) = serde_json::from_slice::<Value>(&body) else {
+            tracing::warn!(status = status.as_u16(), "Prometheus returned a non-JSON body: {}", excerpt(&body));
+            return Err(fail_with(ErrorKind::InvalidResponse));
+        };
+        if res["status"] == "error" {
+            let (error_type, error) = (res["errorType"].as_str(), res["error"].as_str());
+            tracing::warn!("Prometheus error {:?}: {}", error_type, error.unwrap_or_default());
+            let kind = match error_type {
+                Some("bad_data") => ErrorKind::BadQuery,
+                Some("timeout") => ErrorKind::Timeout,
+                _ => ErrorKind::Upstream,
+            };
+            return Err(fail(Failure {
+                kind,
+                expr: call.expr().filter(|_| kind == ErrorKind::BadQuery),
+                retry_after: None,
+                upstream: error.map(str::to_string),
+            }));
+        }
+        if let Some(fixtures) = &self.fixtures {
+            fixtures.record(&call.path, call.params(), &res).await;
+        }
+        if let Some(log) = self.stats.as_ref().filter(|_| call.stats) {
+            let stats = QueryStats {
+                path: call.path.clone(),
+                params: call.params.iter().cloned().collect(),
+                stats: res["data"]["stats"].take(),
+            };
+            log.lock().unwrap().push(stats);
+        }
+        Ok((res, body))
+    }
+
+    /// Latest reading per series matching `selector`, built with
+    /// `selector::energy`, within the configured lookback.
+    pub async fn get_data(
+        &self,
+        selector: &str,
+        datetime: DateTime<Utc>,
+    ) -> Result<Parsed, Error> {
+        self.get_data_within(selector, datetime, &self.lookback()).await
+    }
+
+    /// The expression `get_data_within` runs, for explain output.
+    pub fn last_over_time_expr(selector: &str, window: &str) -> String {
+        format!("last_over_time({}[{}])", selector, window)
+    }
+
+    /// Like `get_data`, but takes the latest reading within `window` instead
+    /// of the configured lookback.
+    pub async fn get_data_within(
+        &self,
+        selector: &str,
+        datetime: DateTime<Utc>,
+        window: &str,
+    ) -> Result<Parsed, Error> {
+        let expr = Self::last_over_time_expr(selector, window);
+        Ok(parse_samples(self.query(&expr, datetime).await?))
+    }
+
+    /// Latest reading per (instance, address) at every `step` from `start`
+    /// through `end`, oldest first. Steps without a reading are absent, so
+    /// consecutive points are not necessarily one step apart. The phases of
+    /// a meter are summed, keeping only the steps every phase has a reading
+    /// at.
+    pub async fn get_data_range(
+        &self,
+        selector: &str,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+        step: Duration,
+    ) -> Result<HashMap<(String, String), Vec<(DateTime<Utc>, f64)>>, Error> {
+        let expr = Self::last_over_time_expr(selector, &format!("{}s", step.as_secs()));
+        let series = self.query_range(&expr, start, end, step).await?;
+
+        let mut by_series = BTreeMap::new();
+        for item in series {
+            let instance = item["metric"]["instance"].as_str().unwrap_or("unknown");
+            let address = item["metric"]["address"].as_str().unwrap_or_default();
+            let phase = item["metric"][PHASE_LABEL].as_str().map(str::to_string);
+            let points: Vec<(DateTime<Utc>, f64)> = item["values"]
+                .as_array()
+                .map(|values| {
+                    values
+                        .iter()
+                        .filter_map(|point| {
+                            let time = DateTime::from_timestamp_millis((point[0].as_f64()? * 1000.0) as i64)?;
+                            Some((time, parse_value(point[1].as_str()?).ok()?))
+                        })
+                        .collect()
+                })
+                .unwrap_or_default();
+            by_series.insert((instance.to_string(), address.to_string(), phase), points);
+        }
+
+        let mut result = HashMap::new();
+        for ((instance, address, _), points) in by_series {
+            match result.entry((instance, address)) {
+                Entry::Vacant(entry) => {
+                    entry.insert(points);
+                }
+                Entry::Occupied(mut entry) => {
+                    let phase: HashMap<DateTime<Utc>, f64> = points.into_iter().collect();
+                    entry.get_mut().retain_mut(|(time, kwh)| match phase.get(time) {
+                        Some(phase_kwh) => {
+                            *kwh += phase_kwh;
+                            true
+                        }
+                        None => false,
+                    });
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    /// Scrape time of the newest raw sample per series within `window`.
+    fn sample_times_expr(selector: &str, window: &str, offset: &str) -> String {
+        format!("max_over_time(timestamp({})[{}:1m]{})", selector, window, offset)
+    }
+
+    /// The latest reading of each series of `selector` within `window`,
+    /// and the scrape time behind it, told apart by `PART_LABEL`.
+    fn sampled_expr(selector: &str, window: &str, offset: &str) -> String {
+        let readings = format!("last_over_time({}[{}]{})", selector, window, offset);
+        let times = Self::sample_times_expr(selector, window, offset);
+        format!("{} or {}", mark(&readings, PART_LABEL, "reading"), mark(&times, PART_LABEL, "scraped"))
+    }
+
+    /// The queries `get_pair` runs, as (expression, evaluation time): one
+    /// per reading, or with `QUERY_STRATEGY=offset` one for both.
+    pub fn pair_queries(
+        &self,
+        selector: &str,
+        curr: DateTime<Utc>,
+        prev: DateTime<Utc>,
+    ) -> Vec<(String, DateTime<Utc>)> {
+        let lookback = &self.lookback();
+        match self.strategy {
+            QueryStrategy::Separate => vec![
+                (Self::sampled_expr(selector, lookback, ""), curr),
+                (Self::sampled_expr(selector, lookback, ""), prev),
+            ],
+            QueryStrategy::Offset => {
+                let offset = format!(" offset {}s", (curr - prev).num_seconds());
+                let combined = format!(
+                    "{} or {}",
+                    mark(&Self::sampled_expr(selector, lookback, ""), READING_LABEL, "curr"),
+                    mark(&Self::sampled_expr(selector, lookback, &offset), READING_LABEL, "prev")
+                );
+                vec![(combined, curr)]
+            }
+        }
+    }
+
+    /// Instances with a series matching `selector` between `start` and `end`.
+    pub async fn instances(
+        &self,
+        selector: &str,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<String>, Error> {
+        self.label_values("instance", selector, start, end).await
+    }
+
+    /// Values of `label` on the series matching `selector` between `start`
+    /// and `end`. `label` must be a valid label name.
+    pub async fn label_values(
+        &self,
+        label: &str,
+        selector: &str,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<String>, Error> {
+        let call = ApiCall::new(format!("api/v1/label/{}/values", label))
+            .param("match[]", selector)
+            .param("start", api_time(start))
+            .param("end", api_time(end));
+        let values = self.array(call, "/data").await?;
+        Ok(values.into_iter().filter_map(|v| v.as_str().map(str::to_string)).collect())
+    }
+
+    /// The label sets of the series matching `selector` between `start`
+    /// and `end`, by instance, without reading any of their samples.
+    pub async fn series(
+        &self,
+        selector: &str,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<HashMap<String, Vec<HashMap<String, String>>>, Error> {
+        let call = ApiCall::new("api/v1/series")
+            .param("match[]", selector)
+            .param("start", api_time(start))
+            .param("end", api_time(end));
+        let mut series: HashMap<String, Vec<HashMap<String, String>>> = HashMap::new();
+        for labels in self.array(call, "/data").await? {
+            let Ok(labels) = serde_json::from_value::<HashMap<String, String>>(labels) else {
+                continue;
+            };
+            let instance = labels.get("instance").cloned().unwrap_or_else(|| "unknown".to_string());
+            series.entry(instance).or_default().push(labels);
+        }
+        Ok(series)
+    }
+
+    /// The selectors `get_pair` queries: `selector` itself, or with
+    /// `CHUNK_SIZE` set and more instances matching than that, one per chunk
+    /// of at most that many instances, each narrowed to its own with an extra
+    /// `instance=~"a|b|..."` matcher. Deciding takes one label values call.
+    pub async fn pair_chunks(
+        &self,
+        selector: &str,
+        curr: DateTime<Utc>,
+        prev: DateTime<Utc>,
+    ) -> Result<Vec<String>, Error> {
+        let Some(chunk_size) = self.chunk_size else {
+            return Ok(vec![selector.to_string()]);
+        };
+        let lookback = parse_duration(&self.lookback()).unwrap_or_default();
+        let start = prev.min(curr) - chrono::Duration::from_std(lookback).unwrap_or_default();
+        let mut instances = self.instances(selector, start, prev.max(curr)).await?;
+        if instances.len() <= chunk_size {
+            return Ok(vec![selector.to_string()]);
+        }
+        instances.sort();
+        Ok(instances
+            .chunks(chunk_size)
+            .map(|chunk| {
+                let alternatives: Vec<String> = chunk.iter().map(|i| literal_pattern(i)).collect();
+                let matcher = format!("instance=~\"{}\"", selector::quote(&alternatives.join("|")));
+                selector::with_matchers(selector, &matcher)
+            })
+            .collect())
+    }
+
+    /// `get_data` at `curr` and at `prev`, with each sample's timestamp set
+    /// to when it was actually scraped. A broad selector is split as
+    /// `pair_chunks` decides, and the chunks' results are merged, so the
+    /// caller cannot tell the difference.
+    pub async fn get_pair(
+        &self,
+        selector: &str,
+        curr: DateTime<Utc>,
+        prev: DateTime<Utc>,
+    ) -> Result<Pair, Error> {
+        let current = self.get_current(selector, curr, prev).await?;
+        self.get_previous(current).await
+    }
+
+    /// The first half of `get_pair`: the current readings, so the caller can
+    /// look at the series matched before the previous ones are asked for.
+    /// With `QUERY_STRATEGY=offset` those come in the same query and are
+    /// kept until `get_previous`.
+    pub async fn get_current(
+        &self,
+        selector: &str,
+        curr: DateTime<Utc>,
+        prev: DateTime<Utc>,
+    ) -> Result<Current, Error> {
+        let chunks = self.pair_chunks(selector, curr, prev).await?;
+        if chunks.len() > 1 {
+            metrics::counter!(PROMETHEUS_QUERY_CHUNKS_TOTAL).increment(chunks.len() as u64);
+        }
+        let current = Current {
+            data: HashMap::new(),
+            skipped: Vec::new(),
+            chunks: Vec::new(),
+            prev,
+        };
+        stream::iter(chunks)
+            .map(|chunk| async move {
+                let (data, prev_data) = self.get_chunk(&chunk, curr, prev).await?;
+                Ok::<_, Error>((chunk, data, prev_data))
+            })
+            .buffer_unordered(CHUNK_CONCURRENCY)
+            .try_fold(current, |mut current, (chunk, data, prev_data)| {
+                current.data.extend(data.data);
+                current.skipped.extend(data.skipped);
+                current.chunks.push((chunk, prev_data));
+                async move { Ok(current) }
+            })
+            .await
+    }
+
+    /// The rest of `get_pair` after `get_current`: the current readings
+    /// it kept, and the previous ones.
+    pub async fn get_previous(&self, current: Current) -> Result<Pair, Error> {
+        let Current { data, skipped, chunks, prev } = current;
+        let lookback = &self.lookback();
+        let prev_data = stream::iter(chunks)
+            .map(|(chunk, prev_data)| async move {
+                match prev_data {
+                    Some(prev_data) => Ok(prev_data),
+                    None => {
+                        let series = self.query(&Self::sampled_expr(&chunk, lookback, ""), prev).await?;
+                        self.widened(&chunk, prev, series).await
+                    }
+                }
+            })
+            .buffer_unordered(CHUNK_CONCURRENCY)
+            .try_fold(Parsed::default(), |mut prev_all, prev| {
+                prev_all.extend(prev);
+                async move { Ok(prev_all) }
+            })
+            .await?;
+        Ok(Pair {
+            curr: data,
+            prev: prev_data.data,
+            skipped: skipped.into_iter().chain(prev_data.skipped).collect(),
+        })
+    }
+
+    /// The current readings of one selector, and with `QUERY_STRATEGY=offset`
+    /// the previous ones, which come from the same query, the earlier
+    /// through `offset`, and are split apart again by a marker label; the
+    /// result is the same either way.
+    async fn get_chunk(
+        &self,
+        selector: &str,
+        curr: DateTime<Utc>,
+        prev: DateTime<Utc>,
+    ) -> Result<(Parsed, Option<Parsed>), Error> {
+        let (query, at) = self.pair_queries(selector, curr, prev).swap_remove(0);
+        let series = self.query(&query, at).await?;
+        if self.strategy == QueryStrategy::Separate {
+            return Ok((self.widened(selector, curr, series).await?, None));
+        }
+        let is_curr = |item: &VectorSeries| item.label(READING_LABEL) == Some("curr");
+        let (curr_series, mut prev_series): (Vec<_>, Vec<_>) = series.into_iter().partition(is_curr);
+        // Offset results are stamped with the evaluation time, not the
+        // offset instant a separate query would report.
+        let shift = (curr - prev).num_milliseconds() as f64 / 1000.0;
+        for (time, _) in prev_series.iter_mut().filter_map(|item| item.value.as_mut()) {
+            *time -= shift;
+        }
+        let (curr_data, prev_data) = tokio::try_join!(
+            self.widened(selector, curr, curr_series),
+            self.widened(selector, prev, prev_series),
+        )?;
+        Ok((curr_data, Some(prev_data)))
+    }
+
+    /// The readings in `series`, the answer for the instant `at`, with their
+    /// scrape times. When it has none and `FALLBACK_LOOKBACK` is set, they
+    /// are queried again over that wider window; otherwise it takes no
+    /// extra query.
+    async fn widened(
+        &self,
+        selector: &str,
+        at: DateTime<Utc>,
+        mut series: Vec<VectorSeries>,
+    ) -> Result<Parsed, Error> {
+        let is_reading = |item: &VectorSeries| item.label(PART_LABEL) == Some("reading");
+        let wider = self.fallback_lookback().filter(|_| !series.iter().any(is_reading));
+        if let Some(window) = wider {
+            series = self.query(&Self::sampled_expr(selector, &window, ""), at).await?;
+        }
+        let (readings, times): (Vec<_>, Vec<_>) = series.into_iter().partition(is_reading);
+        let mut parsed = parse_samples(readings);
+        apply_times(&mut parsed.data, &series_times(times));
+        Ok(parsed)
+    }
+
+    /// Returns the timestamp of the newest raw sample per (instance, address)
+    /// within `window` before `datetime`. Unlike the evaluation time reported
+    /// by `last_over_time`, this is when the meter was actually scraped.
+    pub async fn get_sample_times(
+        &self,
+        selector: &str,
+        datetime: DateTime<Utc>,
+        window: &str,
+    ) -> Result<HashMap<(String, String), DateTime<Utc>>, Error> {
+        let expr = Self::sample_times_expr(selector, window, "");
+        let series = self.query(&expr, datetime).await?;
+
+        let mut times = HashMap::new();
+        for item in series {
+            let instance = item.label("instance").unwrap_or("unknown");
+            let address = item.label("address").unwrap_or_default();
+            if let Some(time) = sample_time(&item) {
+                times.insert((instance.to_string(), address.to_string()), time);
+            }
+        }
+        Ok(times)
+    }
+
+    /// The average of each series of `selector` over `window` up to `at`.
+    pub async fn get_average(
+        &self,
+        selector: &str,
+        at: DateTime<Utc>,
+        window: Duration,
+    ) -> Result<Parsed, Error> {
+        let expr = format!("avg_over_time({}[{}s])", selector, window.as_secs());
+        Ok(parse_samples(self.query(&expr, at).await?))
+    }
+
+    /// Issues a trivial query to confirm Prometheus is reachable and answering.
+    pub async fn probe(&self) -> Result<(), Error> {
+        self.vector(ApiCall::query("vector(1)", None)).await.map(|_| ())
+    }
+}
+
+/// Tags the series of one half of an offset query so they can be told apart.
+const READING_LABEL: &str = "power_usage_reading";
+/// Tags the readings and the scrape times a usage query answers with.
+const PART_LABEL: &str = "power_usage_part";
+
+/// `expr` with `label` set to `value` on every series.
+fn mark(expr: &str, label: &str, value: &str) -> String {
+    format!("label_replace({}, \"{}\", \"{}\", \"\", \"\")", expr, label, value)
+}
+
+/// A series' full label set, minus the markers, to match readings with
+/// their scrape times.
+type SeriesKey = Vec<(String, String)>;
+
+fn series_key(labels: &HashMap<String, String>) -> SeriesKey {
+    let mut key: SeriesKey = labels
+        .iter()
+        .filter(|(name, _)| ![READING_LABEL, PART_LABEL, "__name__"].contains(&name.as_str()))
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect();
+    key.sort();
+    key
+}
+
+/// Reads a `timestamp()` result value, in seconds, as an instant.
+fn sample_time(item: &VectorSeries) -> Option<DateTime<Utc>> {
+    instant(item.number().ok()?)
+}
+
+fn instant(seconds: f64) -> Option<DateTime<Utc>> {
+    DateTime::from_timestamp_millis((seconds * 1000.0) as i64)
+}
+
+fn series_times(series: Vec<VectorSeries>) -> HashMap<SeriesKey, DateTime<Utc>> {
+    series
+        .iter()
+        .filter_map(|item| Some((series_key(&item.metric), sample_time(item)?)))
+        .collect()
+}
+
+/// Replaces evaluation timestamps with scrape times where one is known.
+fn apply_times(data: &mut HashMap<String, Vec<Sample>>, times: &HashMap<SeriesKey, DateTime<Utc>>) {
+    for sample in data.values_mut().flatten() {
+        if let Some(time) = times.get(&series_key(&sample.labels)) {
+            sample.timestamp = Some(*time);
+        }
+    }
+}
+
+/// Groups `last_over_time` series by instance, sorted by address in
+/// `natural` order, leaving out those whose value is not a number.
+fn parse_samples(series: Vec<VectorSeries>) -> Parsed {
+    let mut result_map: HashMap<String, Vec<Sample>> = HashMap::new();
+    let mut skipped = Vec::new();
+
+    for item in series {
+        let value = match item.number() {
+            Ok(value) => value,
+            Err(reason) => {
+                skipped.push(skip(&item, reason));
+                continue;
+            }
+        };
+        let instance = item.label("instance").unwrap_or("unknown").to_string();
+        let address = item.label("address").unwrap_or_default().to_string();
+        let timestamp = item.value.as_ref().and_then(|(time, _)| instant(*time));
+        let mut labels = item.metric;
+        labels.remove(READING_LABEL);
+        labels.remove(PART_LABEL);
+        result_map.entry(instance).or_default().push(Sample {
+            address,
+            value,
+            timestamp,
+            labels,
+        });
+    }
+    for samples in result_map.values_mut() {
+        // Phases and other series of one address by their labels, so the
+        // order never depends on the order Prometheus answers in.
+        samples.sort_by(|a, b| {
+            natural(&a.address, &b.address).then_with(|| sorted_labels(a).cmp(&sorted_labels(b)))
+        });
+    }
+
+    Parsed {
+        data: result_map,
+        skipped,
+    }
+}
+
+/// The labels of `sample` by name.
+fn sorted_labels(sample: &Sample) -> BTreeMap<&str, &str> {
+    sample.labels.iter().map(|(name, value)| (name.as_str(), value.as_str())).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        let settings = Settings {
+            prometheus_host: "http://localhost:9090".to_string(),
+            ..Settings::default()
+        };
+        Config::from_settings(&settings).unwrap()
+    }
+
+    fn client() -> Prometheus {
+        Prometheus::new(&config(), Url::parse("http://localhost:9090").unwrap()).unwrap()
+    }
+
+    fn at(value: &str) -> DateTime<Utc> {
+        value.parse().unwrap()
+    }
+
+    fn pairs(query: &[(&'static str, &str)]) -> Vec<(&'static str, String)> {
+        query.iter().map(|(name, value)| (*name, value.to_string())).collect()
+    }
+
+    #[test]
+    fn options_follow_the_parameters() {
+        let call = ApiCall::query("up", Some(at("2025-08-01T00:00:00Z")))
+            .timeout(Duration::from_millis(4500))
+            .stats(true);
+        let selecting = pairs(&[("query", "up"), ("time", "2025-08-01T00:00:00Z")]);
+        assert_eq!(call.params(), selecting.as_slice());
+        let sent = pairs(&[("query", "up"), ("time", "2025-08-01T00:00:00Z"), ("timeout", "4500ms"), ("stats", "all")]);
+        assert_eq!(call.query_string(), sent);
+    }
+
+    #[test]
+    fn no_options_where_nothing_is_evaluated() {
+        let call = ApiCall::new("api/v1/series")
+            .param("match[]", "up")
+            .timeout(Duration::from_millis(4500))
+            .stats(true);
+        assert_eq!(call.query_string(), pairs(&[("match[]", "up")]));
+    }
+
+    #[test]
+    fn server_timeout_is_under_the_client_timeout() {
+        let prometheus = client();
+        let call = prometheus.options(ApiCall::query("up", None));
+        let timeout = call.query_string().into_iter().find(|(name, _)| *name == "timeout").unwrap().1;
+        let millis: u128 = timeout.strip_suffix("ms").unwrap().parse().unwrap();
+        assert!(millis < config().prometheus_timeout.as_millis());
+        assert_eq!(timeout, "4500ms");
+    }
+
+    #[test]
+    fn stats_only_from_with_stats() {
+        let stats = |prometheus: &Prometheus| {
+            let call = prometheus.options(ApiCall::query("up", None));
+            call.query_string().iter().any(|(name, value)| *name == "stats" && value == "all")
+        };
+        assert!(!stats(&client()));
+        assert!(stats(&client().with_stats(StatsLog::default())));
+    }
+
+    struct Up;
+
+    impl MetricsBackend for Up {
+        fn get<'a>(&'a self, _path: &'a str, _params: &'a [(&'static str, String)]) -> crate::BackendFuture<'a> {
+            Box::pin(async { Ok(br#"{"status":"success","data":{"resultType":"vector","result":[]}}"#.to_vec()) })
+        }
+    }
+
+    #[tokio::test]
+    async fn exchanges_are_kept_from_any_task() {
+        let log = ExchangeLog::default();
+        let prometheus = client().with_source(Arc::new(Up)).with_exchanges(log.clone());
+        let query = tokio::spawn(async move { prometheus.query("up", at("2025-08-01T00:00:00Z")).await });
+        assert!(query.await.unwrap().is_ok_and(|result| result.is_empty()));
+        let exchanges = log.lock().unwrap();
+        assert_eq!(exchanges.len(), 1);
+        assert_eq!(exchanges[0].params["query"], "up");
+        assert_eq!(exchanges[0].status, Some(200));
+        assert!(client().exchanges.is_none());
+    }
+
+    #[tokio::test]
+    async fn calls_are_counted_from_any_task() {
+        let completed = Arc::new(AtomicUsize::new(0));
+        let prometheus = client().with_source(Arc::new(Up)).counting(completed.clone());
+        let counted = prometheus.clone();
+        let query = tokio::spawn(async move { counted.query("up", at("2025-08-01T00:00:00Z")).await });
+        assert!(query.await.unwrap().is_ok());
+        assert_eq!(completed.load(Ordering::Relaxed), 1);
+        prometheus.note_completed(3);
+        assert_eq!(completed.load(Ordering::Relaxed), 4);
+    }
+}
@@ -0,0 +1,464 @@
+use axum::http::StatusCode;
+use chrono::{DateTime, Datelike, NaiveDate, Utc};
+use chrono_tz::Tz;
+use futures_util::{future, stream, Stream, StreamExt, TryStreamExt};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use crate::{
+    changeover::Stitch,
+    config::{parse_duration, Tunables},
+    corrections::AppliedCorrection,
+    natural::Natural,
+    period::{self, local_midnight},
+    error::Error,
+    prometheus::ErrorKind,
+    reload,
+    state::AppState,
+    usage::{
+        dedupe_series, is_implausible, is_implausible_reading, is_preferred_job, note_implausible,
+        ResponseSize, PHASE_LABEL,
+    },
+};
+
+/// What a range does when some of its readings cannot be fetched, from
+/// `on_error=`.
+#[derive(Clone, Copy, PartialEq)]
+pub enum FailurePolicy {
+    /// The first failed query fails the whole range.
+    FailFast,
+    /// The days around a failed reading are left empty and marked with its
+    /// `error_kind`, until more than `RANGE_MAX_FAILED_FRACTION` of the
+    /// days are.
+    BestEffort,
+}
+
+impl FailurePolicy {
+    pub fn from_params(params: &HashMap<String, String>) -> Result<Self, StatusCode> {
+        match params.get("on_error").map(String::as_str) {
+            None | Some("best_effort") => Ok(Self::BestEffort),
+            Some("fail_fast") => Ok(Self::FailFast),
+            Some(_) => Err(StatusCode::BAD_REQUEST),
+        }
+    }
+}
+
+/// One meter's consumption per local calendar day.
+pub struct DailySeries {
+    pub instance: String,
+    pub address: String,
+    pub name: Option<String>,
+    /// `None` where either boundary reading is missing.
+    pub days: Vec<(NaiveDate, Option<f64>)>,
+    /// Counter readings at every local midnight, from the start of the
+    /// first day to the end of the last, one more than `days`.
+    pub counters: Vec<Option<f64>>,
+    /// Days whose counter went backwards or rose beyond `MAX_DAILY_KWH`, or
+    /// with an implausible reading at either end. Their values, and the
+    /// implausible readings, are left out of `days` and `counters` unless
+    /// kept on request.
+    pub implausible: Vec<NaiveDate>,
+    /// Days a boundary reading could not be fetched for, with the
+    /// `error_kind` of the failure; their values are missing.
+    pub failed: Vec<(NaiveDate, &'static str)>,
+    /// Days a meter was replaced on, per `CHANGEOVERS_FILE`. The series is
+    /// the old meter's before each and the new one's after it, reported as
+    /// the latest, and `counters` continue across the swap.
+    pub changeovers: Vec<NaiveDate>,
+    /// Days whose value in `days` is a correction, by date.
+    pub corrected: Vec<AppliedCorrection>,
+}
+
+/// The sum of `kwh`, 0 when there is none, where `Iterator::sum` on
+/// floats would give -0.
+pub fn sum_kwh(kwh: impl IntoIterator<Item = f64>) -> f64 {
+    kwh.into_iter().fold(0.0, |total, kwh| total + kwh)
+}
+
+impl DailySeries {
+    /// The consumption of the days with both readings.
+    pub fn total_kwh(&self) -> f64 {
+        sum_kwh(self.days.iter().filter_map(|(_, kwh)| *kwh))
+    }
+
+    /// Share of the days with both boundary readings, as a percentage
+    /// rounded to two decimals.
+    pub fn completeness_percent(&self) -> f64 {
+        if self.days.is_empty() {
+            return 0.0;
+        }
+        let present = self.days.iter().filter(|(_, kwh)| kwh.is_some()).count();
+        (present as f64 / self.days.len() as f64 * 10000.0).round() / 100.0
+    }
+
+    /// The daily values with gaps of up to `max_gap` days filled in, each
+    /// day of a gap getting an equal share of the counter difference across
+    /// it, and whether the value was filled. Gaps at either end of the range,
+    /// and ones where the counter went backwards, stay empty, as do
+    /// implausible days.
+    pub fn interpolated(&self, max_gap: usize) -> Vec<(Option<f64>, bool)> {
+        let mut values: Vec<(Option<f64>, bool)> = self.days.iter().map(|(_, kwh)| (*kwh, false)).collect();
+        let is_gap = |i: usize| self.days[i].1.is_none() && !self.implausible.contains(&self.days[i].0);
+        let mut day = 0;
+        while day < self.days.len() {
+            if !is_gap(day) {
+                day += 1;
+                continue;
+            }
+            let end = (day..self.days.len()).find(|i| !is_gap(*i)).unwrap_or(self.days.len());
+            let len = end - day;
+            let span = self.counters.get(day).copied().flatten().zip(self.counters.get(end).copied().flatten());
+            if let Some((from, to)) = span.filter(|(from, to)| len <= max_gap && to >= from) {
+                for value in &mut values[day..end] {
+                    *value = (Some((to - from) / len as f64), true);
+                }
+            }
+            day = end;
+        }
+        values
+    }
+
+    /// Days without a value, which every total silently leaves out.
+    pub fn missing_dates(&self) -> Vec<NaiveDate> {
+        self.days.iter().filter(|(_, kwh)| kwh.is_none()).map(|(date, _)| *date).collect()
+    }
+}
+
+/// A meter, by (instance, address).
+type Meter = (String, String);
+
+/// Counter readings at one instant, keyed by meter. A meter with a series
+/// per `phase` reads as the sum of its phases.
+#[derive(Default)]
+struct Snapshot {
+    counters: HashMap<Meter, f64>,
+    /// The phases summed into the reading of each meter that has them.
+    phases: HashMap<Meter, BTreeSet<String>>,
+}
+
+impl Snapshot {
+    fn get(&self, meter: &Meter) -> Option<f64> {
+        self.counters.get(meter).copied()
+    }
+}
+
+/// The readings of `meter` at the start and end of a day. When the two
+/// were summed over different phases, as when one phase was not scraped,
+/// they do not compare, and the end reading is left out.
+fn day_readings(start: Option<&Snapshot>, end: Option<&Snapshot>, meter: &Meter) -> (Option<f64>, Option<f64>) {
+    let reading = |snapshot: Option<&Snapshot>| snapshot.and_then(|s| s.get(meter));
+    let phases = |snapshot: Option<&Snapshot>| snapshot.and_then(|s| s.phases.get(meter)).cloned();
+    let (start_kwh, end_kwh) = (reading(start), reading(end));
+    if start_kwh.is_some() && end_kwh.is_some() && phases(start) != phases(end) {
+        return (start_kwh, None);
+    }
+    (start_kwh, end_kwh)
+}
+
+/// A boundary's snapshot, or the `error_kind` of the query that failed.
+type Reading = Result<Snapshot, &'static str>;
+
+/// Duplicate series of a meter are dropped as for a single day: the
+/// readings are stamped with `dt`, so `PREFER_JOB` or else the `job` name
+/// picks the same one every day. The phases of a meter are summed, so the
+/// reports built on it cover the whole meter and not whichever phase was
+/// read last.
+async fn snapshot(state: &AppState, selector: &str, dt: DateTime<Utc>) -> Result<Snapshot, Error> {
+    let mut data = state.prometheus.get_data(selector, dt).await?.data;
+    let tunables = state.config.tunables();
+    dedupe_series(&mut data, |_, s| is_preferred_job(&tunables, s));
+    let mut snapshot = Snapshot::default();
+    for (instance, samples) in data {
+        for s in samples {
+            let meter = (instance.clone(), s.address);
+            *snapshot.counters.entry(meter.clone()).or_default() += s.value;
+            if let Some(phase) = s.labels.get(PHASE_LABEL) {
+                snapshot.phases.entry(meter).or_default().insert(phase.clone());
+            }
+        }
+    }
+    Ok(snapshot)
+}
+
+/// Reads the counters at each boundary, dated, at most `RANGE_CONCURRENCY`
+/// at a time and yielded in date order whatever order they complete in.
+/// Under `FailFast` the first failure ends the stream with its error.
+/// Under `BestEffort` a failed reading is yielded as its `error_kind`, until
+/// the days it leaves without both readings are more than
+/// `RANGE_MAX_FAILED_FRACTION` of the range. Each snapshot is rewritten by
+/// `stitch`. The stream carries the current request's tunables, since
+/// callers may poll it after their handler returns.
+fn readings(
+    state: AppState,
+    selector: String,
+    dates: Vec<NaiveDate>,
+    boundaries: Vec<DateTime<Utc>>,
+    policy: FailurePolicy,
+    stitch: Stitch,
+) -> impl Stream<Item = Result<(NaiveDate, Reading), Error>> + Send + 'static {
+    let days = boundaries.len().saturating_sub(1);
+    let allowed = match policy {
+        FailurePolicy::FailFast => 0,
+        FailurePolicy::BestEffort => (state.config.range_max_failed_fraction * days as f64).floor() as usize,
+    };
+    let concurrency = state.config.range_concurrency;
+    let tunables = state.config.tunables();
+    let mut failed_days = BTreeSet::new();
+    stream::iter(dates.into_iter().zip(boundaries).enumerate())
+        .map(move |(i, (date, dt))| {
+            let (state, selector) = (state.clone(), selector.clone());
+            let read = async move { (i, date, snapshot(&state, &selector, dt).await) };
+            reload::with_tunables(tunables.clone(), read)
+        })
+        .buffered(concurrency)
+        .map(move |(i, date, reading)| {
+            let error = match reading {
+                Ok(mut snapshot) => {
+                    stitch.apply(i, &mut snapshot.counters);
+                    return Ok((date, Ok(snapshot)));
+                }
+                Err(error) => error,
+            };
+            // The boundary ends the day before it and starts its own.
+            failed_days.extend((i.saturating_sub(1)..=i).filter(|day| *day < days));
+            if failed_days.len() > allowed {
+                return Err(error);
+            }
+            let kind = error.failure().map_or(ErrorKind::Upstream.name(), |failure| failure.kind.name());
+            tracing::warn!(%date, error_kind = kind, "Range reading failed, leaving its days empty");
+            Ok((date, Err(kind)))
+        })
+}
+
+fn boundaries(tz: Tz, dates: &[NaiveDate]) -> Result<Vec<DateTime<Utc>>, StatusCode> {
+    dates
+        .iter()
+        .map(|date| local_midnight(*date, tz).ok_or(StatusCode::BAD_REQUEST))
+        .collect()
+}
+
+/// One meter's consumption on one day, as produced by `daily_rows`.
+pub struct DayRow {
+    pub instance: String,
+    pub address: String,
+    pub date: NaiveDate,
+    pub daily_kwh: Option<f64>,
+    /// As in `DailySeries::implausible`.
+    pub implausible: bool,
+    /// As in `DailySeries::failed`.
+    pub error: Option<&'static str>,
+    /// Whether the meter was replaced that day, as in
+    /// `DailySeries::changeovers`.
+    pub changeover: bool,
+}
+
+/// Like `daily_usage`, but yields each day's rows as soon as both of its
+/// boundary readings are in, so callers can stream long ranges. A meter
+/// appears on a day when it has a reading at either boundary, so a day
+/// both of whose readings failed under `BestEffort` has no rows.
+pub fn daily_rows(
+    state: AppState,
+    selector: String,
+    first: NaiveDate,
+    days: u32,
+    include_implausible: bool,
+    policy: FailurePolicy,
+) -> Result<impl Stream<Item = Result<Vec<DayRow>, Error>> + Send + 'static, StatusCode> {
+    let dates: Vec<NaiveDate> = period::dates(first, days + 1).collect();
+    let boundaries = boundaries(state.config.timezone, &dates)?;
+    let tunables = state.config.tunables();
+    let stitch = Stitch::new(&tunables.changeovers, &dates);
+    let at: HashMap<NaiveDate, DateTime<Utc>> = dates.iter().copied().zip(boundaries.clone()).collect();
+    let readings = readings(state.clone(), selector, dates, boundaries, policy, stitch.clone());
+
+    let mut previous: Option<(NaiveDate, Reading)> = None;
+    Ok(readings.try_filter_map(move |(date, curr)| {
+        let rows = previous.take().map(|(day, prev)| {
+            let period = (at[&day], at[&date]);
+            let mut rows = rows_between(&state, &tunables, (&prev, &curr), day, period, include_implausible);
+            for row in &mut rows {
+                let meter = (row.instance.clone(), row.address.clone());
+                row.changeover = stitch.days_of(&meter).contains(&day);
+            }
+            rows
+        });
+        previous = Some((date, curr));
+        future::ready(Ok(rows))
+    }))
+}
+
+/// The rows of day `date`, between the instants of `period`, from its
+/// boundary readings.
+fn rows_between(
+    state: &AppState,
+    tunables: &Tunables,
+    (prev, curr): (&Reading, &Reading),
+    date: NaiveDate,
+    period: (DateTime<Utc>, DateTime<Utc>),
+    include_implausible: bool,
+) -> Vec<DayRow> {
+    let error = prev.as_ref().err().or(curr.as_ref().err()).copied();
+    let (prev, curr) = (prev.as_ref().ok(), curr.as_ref().ok());
+    let meters: BTreeSet<&Meter> = prev.into_iter().chain(curr).flat_map(|s| s.counters.keys()).collect();
+    let mut rows: Vec<DayRow> = meters
+        .into_iter()
+        .map(|key| {
+            let (start, end) = day_readings(prev, curr, key);
+            let implausible = is_implausible(tunables, start, end);
+            if implausible {
+                note_implausible(state, (&key.0, &key.1), period, start, end);
+            }
+            let daily_kwh = start.zip(end).map(|(start, end)| end - start);
+            DayRow {
+                instance: key.0.clone(),
+                address: key.1.clone(),
+                date,
+                daily_kwh: daily_kwh.filter(|_| include_implausible || !implausible),
+                implausible,
+                error,
+                changeover: false,
+            }
+        })
+        .collect();
+    rows.sort_by_key(|r| (r.instance.clone(), Natural(r.address.clone())));
+    rows
+}
+
+/// Reads the counters at every local midnight from `first` through the end
+/// of day `first + days - 1`, and turns consecutive readings into daily
+/// deltas per instance/address. Readings are keyed by address rather than
+/// position, since meters may come and go within a long range. Corrected
+/// days are reported as corrected.
+pub async fn daily_usage(
+    state: &AppState,
+    selector: &str,
+    first: NaiveDate,
+    days: u32,
+) -> Result<Vec<DailySeries>, Error> {
+    let (timezone, policy) = (state.config.timezone, FailurePolicy::FailFast);
+    let mut series = daily_usage_in(state, timezone, selector, first, days, false, policy).await?;
+    state.config.corrections.apply_to_series(&mut series);
+    Ok(series)
+}
+
+/// Like `daily_usage`, with the days at the midnights of `tz` instead of
+/// `TIMEZONE`, with `include_implausible` keeping implausible values, and
+/// with failed readings handled as `policy` says.
+pub async fn daily_usage_in(
+    state: &AppState,
+    tz: Tz,
+    selector: &str,
+    first: NaiveDate,
+    days: u32,
+    include_implausible: bool,
+    policy: FailurePolicy,
+) -> Result<Vec<DailySeries>, Error> {
+    let dates: Vec<NaiveDate> = period::dates(first, days + 1).collect();
+    let boundaries = boundaries(tz, &dates)?;
+    let tunables = state.config.tunables();
+    let stitch = Stitch::new(&tunables.changeovers, &dates);
+
+    let periods: Vec<(DateTime<Utc>, DateTime<Utc>)> = boundaries.windows(2).map(|w| (w[0], w[1])).collect();
+    let readings = readings(state.clone(), selector.to_string(), dates, boundaries, policy, stitch.clone());
+    let by_date: BTreeMap<NaiveDate, Reading> = readings.try_collect().await?;
+    let dates: Vec<NaiveDate> = by_date.keys().copied().collect();
+    let readings: Vec<Reading> = by_date.into_values().collect();
+
+    let meters: BTreeSet<Meter> = readings.iter().flatten().flat_map(|s| s.counters.keys().cloned()).collect();
+
+    let aliases = state.config.aliases.current();
+    let mut series: Vec<DailySeries> = meters
+        .into_iter()
+        .map(|key| {
+            let (mut implausible, mut failed) = (Vec::new(), Vec::new());
+            let days = readings
+                .windows(2)
+                .zip(dates.iter().zip(&periods))
+                .map(|(pair, (date, period))| {
+                    let (start, end) = match pair {
+                        [Ok(start), Ok(end)] => day_readings(Some(start), Some(end), &key),
+                        [Err(kind), _] | [_, Err(kind)] => {
+                            failed.push((*date, *kind));
+                            return (*date, None);
+                        }
+                        _ => unreachable!("windows of two"),
+                    };
+                    let delta = start.zip(end).map(|(start, end)| end - start);
+                    if !is_implausible(&tunables, start, end) {
+                        return (*date, delta);
+                    }
+                    note_implausible(state, (&key.0, &key.1), *period, start, end);
+                    implausible.push(*date);
+                    (*date, delta.filter(|_| include_implausible))
+                })
+                .collect();
+            let counters = readings
+                .iter()
+                .map(|reading| reading.as_ref().ok().and_then(|s| s.get(&key)))
+                .map(|kwh| {
+                    kwh.filter(|kwh| include_implausible || !is_implausible_reading(&tunables, *kwh))
+                })
+                .collect();
+            DailySeries {
+                changeovers: stitch.days_of(&key),
+                corrected: Vec::new(),
+                name: aliases.name(&key.0, &key.1).map(str::to_string),
+                instance: key.0,
+                address: key.1,
+                days,
+                counters,
+                implausible,
+                failed,
+            }
+        })
+        .collect();
+    series.sort_by_key(|s| (s.instance.clone(), Natural(s.address.clone())));
+    Ok(series)
+}
+
+/// The series discovery behind `count_only=true` on the range reports: one
+/// row per series matching `selector` from the lookback before the first
+/// midnight through the last, and day, without reading any counter.
+pub async fn range_size(
+    state: &AppState,
+    selector: &str,
+    first: NaiveDate,
+    days: u32,
+) -> Result<ResponseSize, Error> {
+    let dates: Vec<NaiveDate> = period::dates(first, days + 1).collect();
+    let boundaries = boundaries(state.config.timezone, &dates)?;
+    let lookback = parse_duration(&state.prometheus.lookback())
+        .and_then(|lookback| chrono::Duration::from_std(lookback).ok())
+        .unwrap_or_default();
+    let (start, end) = (boundaries[0] - lookback, boundaries[boundaries.len() - 1]);
+    let series = state.prometheus.series(selector, start, end).await?;
+    Ok(ResponseSize::of(&series, days as usize))
+}
+
+/// The size of the report on `series`, as `range_size` estimates it.
+pub fn series_size(series: &[DailySeries]) -> ResponseSize {
+    let instances: HashSet<&str> = series.iter().map(|meter| meter.instance.as_str()).collect();
+    let rows = series.iter().map(|meter| meter.days.len()).sum();
+    ResponseSize { instances: instances.len(), rows }
+}
+
+/// Sums the meters of each instance per day. A day counts only when at
+/// least one of the instance's meters has both readings.
+pub fn instance_totals(series: &[DailySeries]) -> BTreeMap<String, Vec<(NaiveDate, Option<f64>)>> {
+    let mut totals: BTreeMap<String, Vec<(NaiveDate, Option<f64>)>> = BTreeMap::new();
+    for meter in series {
+        let days = totals
+            .entry(meter.instance.clone())
+            .or_insert_with(|| meter.days.iter().map(|(date, _)| (*date, None)).collect());
+        for ((_, total), (_, kwh)) in days.iter_mut().zip(&meter.days) {
+            if let Some(kwh) = kwh {
+                *total = Some(total.unwrap_or(0.0) + kwh);
+            }
+        }
+    }
+    totals
+}
+
+/// Saturdays, Sundays and any configured holiday.
+pub fn is_weekend(date: NaiveDate, holidays: &HashSet<NaiveDate>) -> bool {
+    date.weekday().number_from_monday() >= 6 || holidays.contains(&date)
+}
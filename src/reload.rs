@@ -0,0 +1,148 @@
+use arc_swap::ArcSwap;
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use serde_json::Value;
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+use crate::{
+    config::{Config, Settings, Tunables, RELOADABLE},
+    state::AppState,
+};
+
+pub const CONFIG_RELOADS_TOTAL: &str = "config_reloads_total";
+
+tokio::task_local! {
+    /// The tunables a request started with.
+    static SNAPSHOT: Arc<Tunables>;
+}
+
+/// The reloadable part of the configuration. Everything else in `Config`,
+/// such as the listeners, TLS and backends, is fixed at startup.
+pub struct Live {
+    current: ArcSwap<Tunables>,
+    /// As read at startup, to tell which changes need a restart.
+    startup: Settings,
+    /// As last applied, to tell what a reload changes. Held for the whole
+    /// of a reload, so two never interleave.
+    applied: Mutex<Settings>,
+}
+
+/// What a successful reload changed, as `KEY: old -> new` lines.
+pub struct Reloaded {
+    pub changed: Vec<String>,
+    /// Settings that differ from startup but only apply after a restart.
+    pub restart_required: Vec<String>,
+}
+
+impl Live {
+    pub fn new(tunables: Tunables, settings: &Settings) -> Self {
+        Self {
+            current: ArcSwap::from_pointee(tunables),
+            startup: settings.clone(),
+            applied: Mutex::new(settings.clone()),
+        }
+    }
+
+    /// The tunables the current request started with, or the latest outside
+    /// of one.
+    pub fn current(&self) -> Arc<Tunables> {
+        SNAPSHOT.try_with(Arc::clone).unwrap_or_else(|_| self.current.load_full())
+    }
+
+    /// The settings as last applied, at startup or by a reload.
+    pub fn settings(&self) -> Settings {
+        self.applied.lock().unwrap().clone()
+    }
+}
+
+/// Runs `future` with `tunables` as its snapshot, as a request has. Work a
+/// request hands to a spawned task or streams after returning keeps its
+/// snapshot this way.
+pub async fn with_tunables<F: Future>(tunables: Arc<Tunables>, future: F) -> F::Output {
+    SNAPSHOT.scope(tunables, future).await
+}
+
+/// Pins the tunables for the rest of the request, so a reload mid-request
+/// does not mix old and new settings in one answer.
+pub async fn snapshot_middleware(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let tunables = state.config.live.current.load_full();
+    with_tunables(tunables, next.run(req)).await
+}
+
+/// Re-reads the configuration file and environment, and swaps in the
+/// reloadable settings if all of them are valid. `ALIASES_FILE` is re-read
+/// as well. On any error nothing changes and every error is returned.
+pub fn reload(config: &Config) -> Result<Reloaded, Vec<String>> {
+    let live = &config.live;
+    let mut applied = live.applied.lock().unwrap();
+    let result = Settings::load(live.startup.config_file.as_deref())
+        .map_err(|e| vec![e])
+        .and_then(|settings| Ok((Tunables::from_settings(&settings)?, settings)))
+        .and_then(|loaded| config.aliases.reload().map(|()| loaded).map_err(|e| vec![e]));
+    let (mut tunables, settings) = match result {
+        Ok(loaded) => loaded,
+        Err(errors) => {
+            metrics::counter!(CONFIG_RELOADS_TOTAL, "outcome" => "error").increment(1);
+            for error in &errors {
+                tracing::error!("Keeping the previous configuration: {}", error);
+            }
+            return Err(errors);
+        }
+    };
+
+    let changed = differences(&applied, &settings, |key| RELOADABLE.contains(&key));
+    let restart_required = differences(&live.startup, &settings, |key| !RELOADABLE.contains(&key));
+    tunables.generation = live.current.load().generation + 1;
+    live.current.store(Arc::new(tunables));
+    *applied = settings;
+
+    metrics::counter!(CONFIG_RELOADS_TOTAL, "outcome" => "ok").increment(1);
+    if changed.is_empty() {
+        tracing::info!("Reloaded configuration, no settings changed");
+    }
+    for change in &changed {
+        tracing::info!("Reloaded configuration: {}", change);
+    }
+    for change in &restart_required {
+        tracing::warn!("Not reloadable, restart to apply: {}", change);
+    }
+    Ok(Reloaded {
+        changed,
+        restart_required,
+    })
+}
+
+/// `KEY: old -> new` for each setting picked by `select` that differs, with
+/// secrets masked as in the startup log.
+fn differences(old: &Settings, new: &Settings, select: impl Fn(&str) -> bool) -> Vec<String> {
+    let as_map = |settings: &Settings| match serde_json::to_value(settings.redacted()) {
+        Ok(Value::Object(map)) => map,
+        _ => serde_json::Map::new(),
+    };
+    let (old, new) = (as_map(old), as_map(new));
+    new.iter()
+        .filter(|(key, value)| select(key) && old.get(*key) != Some(value))
+        .map(|(key, value)| {
+            let before = old.get(key).unwrap_or(&Value::Null);
+            format!("{}: {} -> {}", key.to_uppercase(), before, value)
+        })
+        .collect()
+}
+
+/// Reloads on every SIGHUP, alongside the TLS certificate reload.
+pub async fn reload_on_sighup(config: Arc<Config>) {
+    let Ok(mut hangup) = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+    else {
+        return;
+    };
+
+    while hangup.recv().await.is_some() {
+        reload(&config).ok();
+    }
+}
@@ -0,0 +1,236 @@
+use chrono::{DateTime, Utc};
+use prost::Message;
+use reqwest::{StatusCode, Url};
+use std::time::Duration;
+
+use crate::{config::Settings, status::LastOutcome};
+
+pub const SAMPLES_PUSHED_TOTAL: &str = "remote_write_samples_pushed_total";
+pub const SAMPLES_FAILED_TOTAL: &str = "remote_write_samples_failed_total";
+
+/// Attempts per push, with the delay doubling from `RETRY_DELAY` between them.
+const ATTEMPTS: u32 = 4;
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+// The subset of the remote-write protobuf (`prometheus.WriteRequest`) needed
+// for float samples, declared by hand so no `protoc` is required.
+#[derive(Clone, PartialEq, Message)]
+struct WriteRequest {
+    #[prost(message, repeated, tag = "1")]
+    timeseries: Vec<TimeSeries>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct TimeSeries {
+    #[prost(message, repeated, tag = "1")]
+    labels: Vec<Label>,
+    #[prost(message, repeated, tag = "2")]
+    samples: Vec<Sample>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Label {
+    #[prost(string, tag = "1")]
+    name: String,
+    #[prost(string, tag = "2")]
+    value: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Sample {
+    #[prost(double, tag = "1")]
+    value: f64,
+    #[prost(int64, tag = "2")]
+    timestamp: i64,
+}
+
+/// One `power_usage_daily_kwh` sample to push.
+pub struct DailySample {
+    pub instance: String,
+    pub address: String,
+    pub daily_kwh: f64,
+    pub timestamp: DateTime<Utc>,
+}
+
+enum Auth {
+    None,
+    Basic { username: String, password: String },
+    Bearer(String),
+}
+
+/// Pushes derived samples to `REMOTE_WRITE_URL`.
+pub struct RemoteWrite {
+    client: reqwest::Client,
+    url: Url,
+    auth: Auth,
+    pub last_push: LastOutcome,
+}
+
+impl RemoteWrite {
+    pub fn from_settings(settings: &Settings) -> Result<Option<Self>, String> {
+        if settings.remote_write_url.is_empty() {
+            return Ok(None);
+        }
+        let url = Url::parse(&settings.remote_write_url)
+            .map_err(|e| format!("`REMOTE_WRITE_URL` is not a valid URL: {}", e))?;
+        let auth = match (
+            settings.remote_write_username.is_empty(),
+            settings.remote_write_bearer_token.is_empty(),
+        ) {
+            (true, true) => Auth::None,
+            (false, true) => Auth::Basic {
+                username: settings.remote_write_username.clone(),
+                password: settings.remote_write_password.clone(),
+            },
+            (true, false) => Auth::Bearer(settings.remote_write_bearer_token.clone()),
+            (false, false) => {
+                return Err(
+                    "`REMOTE_WRITE_USERNAME` and `REMOTE_WRITE_BEARER_TOKEN` are mutually exclusive"
+                        .to_string(),
+                );
+            }
+        };
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(30))
+            .build()
+            .map_err(|e| format!("failed to build HTTP client: {}", e))?;
+        Ok(Some(Self {
+            client,
+            url,
+            auth,
+            last_push: LastOutcome::default(),
+        }))
+    }
+
+    /// Sends the samples in one request, retrying with backoff on network
+    /// errors and 5xx. A 4xx means the payload itself was refused, so it is
+    /// dropped and logged instead.
+    pub async fn push(&self, samples: &[DailySample]) -> bool {
+        if samples.is_empty() {
+            return true;
+        }
+        let count = samples.len() as u64;
+        let result = self.try_push(samples).await;
+        match &result {
+            Ok(()) => metrics::counter!(SAMPLES_PUSHED_TOTAL).increment(count),
+            Err(_) => metrics::counter!(SAMPLES_FAILED_TOTAL).increment(count),
+        }
+        self.last_push.record(&result);
+        result.is_ok()
+    }
+
+    /// `push` without counting or recording the outcome, for
+    /// `/admin/sinks/test`.
+    pub async fn try_push(&self, samples: &[DailySample]) -> Result<(), String> {
+        let body = encode(samples).map_err(|e| {
+            tracing::error!("Failed to encode remote-write request: {}", e);
+            format!("failed to encode the request: {}", e)
+        })?;
+
+        let (mut attempt, mut delay) = (1, RETRY_DELAY);
+        loop {
+            let mut request = self
+                .client
+                .post(self.url.clone())
+                .header("Content-Encoding", "snappy")
+                .header("Content-Type", "application/x-protobuf")
+                .header("X-Prometheus-Remote-Write-Version", "0.1.0")
+                .body(body.clone());
+            request = match &self.auth {
+                Auth::None => request,
+                Auth::Basic { username, password } => request.basic_auth(username, Some(password)),
+                Auth::Bearer(token) => request.bearer_auth(token),
+            };
+
+            let error = match request.send().await {
+                Ok(res) if res.status().is_success() => return Ok(()),
+                Ok(res) if res.status().is_client_error() && res.status() != StatusCode::TOO_MANY_REQUESTS => {
+                    tracing::error!("Remote write rejected {} samples: {}", samples.len(), res.status());
+                    return Err(format!("rejected: {}", res.status()));
+                }
+                Ok(res) => res.status().to_string(),
+                Err(e) => e.to_string(),
+            };
+            tracing::warn!(attempt, "Remote write failed: {}", error);
+            if attempt == ATTEMPTS {
+                return Err(error);
+            }
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+            delay *= 2;
+        }
+    }
+}
+
+fn encode(samples: &[DailySample]) -> Result<Vec<u8>, snap::Error> {
+    let request = WriteRequest {
+        timeseries: samples
+            .iter()
+            .map(|s| TimeSeries {
+                // Labels must be sorted by name.
+                labels: [
+                    ("__name__", "power_usage_daily_kwh"),
+                    ("address", s.address.as_str()),
+                    ("instance", s.instance.as_str()),
+                ]
+                .into_iter()
+                .map(|(name, value)| Label {
+                    name: name.to_string(),
+                    value: value.to_string(),
+                })
+                .collect(),
+                samples: vec![Sample {
+                    value: s.daily_kwh,
+                    timestamp: s.timestamp.timestamp_millis(),
+                }],
+            })
+            .collect(),
+    };
+    snap::raw::Encoder::new().compress_vec(&request.encode_to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(instance: &str, address: &str, daily_kwh: f64, timestamp: &str) -> DailySample {
+        DailySample {
+            instance: instance.to_string(),
+            address: address.to_string(),
+            daily_kwh,
+            timestamp: timestamp.parse().unwrap(),
+        }
+    }
+
+    fn decode(body: &[u8]) -> WriteRequest {
+        let raw = snap::raw::Decoder::new().decompress_vec(body).unwrap();
+        WriteRequest::decode(raw.as_slice()).unwrap()
+    }
+
+    #[test]
+    fn body_decodes_to_the_samples() {
+        let samples = [
+            sample("meter-a:8899", "1", 12.5, "2025-07-31T17:00:00Z"),
+            sample("meter-b:9100", "10", 0.0, "2025-08-01T17:00:00Z"),
+        ];
+        let request = decode(&encode(&samples).unwrap());
+        let labels: Vec<Vec<(&str, &str)>> = request
+            .timeseries
+            .iter()
+            .map(|series| series.labels.iter().map(|l| (l.name.as_str(), l.value.as_str())).collect())
+            .collect();
+        let points: Vec<Vec<(f64, i64)>> = request
+            .timeseries
+            .iter()
+            .map(|series| series.samples.iter().map(|s| (s.value, s.timestamp)).collect())
+            .collect();
+        assert_eq!(
+            labels,
+            [
+                [("__name__", "power_usage_daily_kwh"), ("address", "1"), ("instance", "meter-a:8899")],
+                [("__name__", "power_usage_daily_kwh"), ("address", "10"), ("instance", "meter-b:9100")],
+            ]
+        );
+        assert_eq!(points, [[(12.5, 1753981200000)], [(0.0, 1754067600000)]]);
+    }
+}
@@ -0,0 +1,273 @@
+use axum::http::StatusCode;
+use chrono::{Datelike, Months, NaiveDate, Utc};
+use lettre::message::Mailbox;
+use serde::Deserialize;
+use std::{collections::HashSet, path::Path, time::Duration};
+
+use crate::{
+    api::table::{check_columns, Cell, HeaderLang, Table},
+    error::Error,
+    mailer::{parse_mailbox, MailAttachment},
+    period::{days_in_month, last_date, local_midnight, previous_day},
+    range::{daily_usage, sum_kwh, DailySeries},
+    selector,
+    state::AppState,
+    status::LastOutcome,
+};
+
+/// How long after local midnight the scheduled reports run, so the closing
+/// readings have been scraped.
+const RUN_DELAY: Duration = Duration::from_secs(5 * 60);
+
+/// Columns a report's `columns` can pick from.
+const COLUMNS: [&str; 5] = ["Target", "Address", "Date", "Daily_KWh", "Name"];
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Period {
+    /// The previous local day, sent every night.
+    Daily,
+    /// The previous calendar month, sent on the first of the month.
+    Monthly,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ReportEntry {
+    name: String,
+    target: String,
+    period: Period,
+    #[serde(default)]
+    recipients: Vec<String>,
+    columns: Option<Vec<String>>,
+    header_lang: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ReportsFile {
+    #[serde(default)]
+    report: Vec<ReportEntry>,
+}
+
+/// A report from `REPORTS_FILE`, generated on its schedule:
+///
+/// ```toml
+/// [[report]]
+/// name = "main-building"
+/// target = "meter-a.*"
+/// period = "monthly"
+/// recipients = ["facilities@example.com"]
+/// columns = ["Target", "Date", "Daily_KWh"]
+/// header_lang = "id"
+/// ```
+pub struct Report {
+    pub name: String,
+    pub target: String,
+    pub period: Period,
+    pub recipients: Vec<Mailbox>,
+    /// CSV columns in order; the usual set when not given.
+    pub columns: Option<Vec<String>>,
+    /// The language of the CSV header, English when not given.
+    pub header_lang: HeaderLang,
+    /// The latest scheduled run, for `/admin/status`.
+    pub last_run: LastOutcome,
+}
+
+pub fn load_reports(path: &Path) -> Result<Vec<Report>, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+    let file: ReportsFile =
+        toml::from_str(&contents).map_err(|e| format!("failed to parse {}: {}", path.display(), e))?;
+
+    let mut names = HashSet::new();
+    file.report
+        .into_iter()
+        .map(|entry| {
+            if !names.insert(entry.name.clone()) {
+                return Err(format!("`REPORTS_FILE` has more than one report named {:?}", entry.name));
+            }
+            let recipients = entry
+                .recipients
+                .iter()
+                .map(|address| parse_mailbox("REPORTS_FILE", address))
+                .collect::<Result<_, _>>()?;
+            if let Some(columns) = &entry.columns {
+                check_columns(&COLUMNS, columns).map_err(|e| format!("report {:?}: {}", entry.name, e))?;
+            }
+            let header_lang = match &entry.header_lang {
+                None => HeaderLang::default(),
+                Some(code) => HeaderLang::named(code)
+                    .ok_or_else(|| format!("report {:?}: unknown `header_lang` {:?}", entry.name, code))?,
+            };
+            Ok(Report {
+                name: entry.name,
+                target: entry.target,
+                period: entry.period,
+                recipients,
+                columns: entry.columns,
+                header_lang,
+                last_run: LastOutcome::default(),
+            })
+        })
+        .collect()
+}
+
+/// A report's figures, ready to be delivered.
+pub struct Generated {
+    pub first: NaiveDate,
+    pub last: NaiveDate,
+    pub meters: usize,
+    pub total_kwh: f64,
+    pub filename: String,
+    pub csv: String,
+}
+
+impl Generated {
+    /// The plain-text email body: what the attachment covers and its totals.
+    pub fn summary(&self, report: &Report) -> String {
+        let period = if self.first == self.last {
+            self.first.to_string()
+        } else {
+            format!("{} to {}", self.first, self.last)
+        };
+        format!(
+            "Power usage report {:?} for {}\n\nTarget: {}\nMeters: {}\nTotal: {:.2} kWh\n\n\
+             Daily figures per meter are attached.\n",
+            report.name, period, report.target, self.meters, self.total_kwh
+        )
+    }
+}
+
+impl Report {
+    /// Whether the report is sent on the night `today` begins.
+    pub fn is_due(&self, today: NaiveDate) -> bool {
+        match self.period {
+            Period::Daily => true,
+            Period::Monthly => today.day() == 1,
+        }
+    }
+
+    /// The most recent complete period before `today`, as its first date
+    /// and length in days.
+    fn covering(&self, today: NaiveDate) -> Option<(NaiveDate, u32)> {
+        match self.period {
+            Period::Daily => Some((previous_day(today)?, 1)),
+            Period::Monthly => {
+                let month = today.with_day(1)?.checked_sub_months(Months::new(1))?;
+                Some((month, days_in_month(month.year(), month.month())?))
+            }
+        }
+    }
+
+    /// Reads the report's period before `today` and lays it out as CSV, one
+    /// row per meter and day.
+    pub async fn generate(&self, state: &AppState, today: NaiveDate) -> Result<Generated, Error> {
+        let (first, days) = self.covering(today).ok_or(StatusCode::BAD_REQUEST)?;
+        let series = daily_usage(state, &selector::energy(&self.target, &[]), first, days).await?;
+
+        let mut table = Table::new(COLUMNS.to_vec());
+        if !state.config.aliases.is_configured() {
+            table = table.optional(&["Name"]);
+        }
+        for meter in &series {
+            for (date, kwh) in &meter.days {
+                table.push(vec![
+                    Cell::Text(meter.instance.clone()),
+                    Cell::Text(meter.address.clone()),
+                    Cell::Text(date.to_string()),
+                    kwh.map_or(Cell::Missing, Cell::Num),
+                    Cell::Text(meter.name.clone().unwrap_or_default()),
+                ]);
+            }
+        }
+        let table = table.select(self.columns.as_deref()).map_err(|_| StatusCode::BAD_REQUEST)?;
+
+        Ok(Generated {
+            first,
+            last: last_date(first, days).ok_or(StatusCode::BAD_REQUEST)?,
+            meters: series.len(),
+            total_kwh: sum_kwh(series.iter().map(DailySeries::total_kwh)),
+            filename: format!("{}-{}.csv", self.name, first),
+            csv: table.header_lang(self.header_lang).to_csv(),
+        })
+    }
+
+    /// Emails `generated` to the report's recipients, when SMTP is set up.
+    pub async fn deliver(&self, state: &AppState, generated: &Generated) -> Result<(), String> {
+        if state.config.mailer.is_none() || self.recipients.is_empty() {
+            return Ok(());
+        }
+        self.deliver_to(state, generated, &self.recipients).await
+    }
+
+    pub async fn deliver_to(
+        &self,
+        state: &AppState,
+        generated: &Generated,
+        recipients: &[Mailbox],
+    ) -> Result<(), String> {
+        let Some(mailer) = &state.config.mailer else {
+            return Err("SMTP is not configured".to_string());
+        };
+        let attachment = MailAttachment {
+            filename: generated.filename.clone(),
+            content_type: "text/csv; charset=utf-8",
+            body: generated.csv.clone().into_bytes(),
+        };
+        let subject = format!("Power usage report {} ({})", self.name, generated.first);
+        mailer
+            .send(recipients, &subject, generated.summary(self), Some(attachment))
+            .await
+    }
+
+    /// Uploads `generated` to `S3_BUCKET`, when object storage is set up.
+    pub async fn store(&self, state: &AppState, generated: &Generated) -> Result<(), String> {
+        let Some(store) = &state.config.object_store else {
+            return Ok(());
+        };
+        let key = store.key(&self.target, &self.name, generated.first);
+        store
+            .upload(&self.target, &key, generated.csv.clone().into_bytes(), "text/csv; charset=utf-8")
+            .await
+    }
+}
+
+/// Generates and delivers the due reports shortly after every local
+/// midnight; does nothing when `REPORTS_FILE` lists none.
+pub async fn schedule_loop(state: AppState) {
+    if state.config.reports.is_empty() {
+        return;
+    }
+    loop {
+        let tz = state.config.timezone;
+        let today = Utc::now().with_timezone(&tz).date_naive();
+        let Some(next) = today.succ_opt().and_then(|date| Some((date, local_midnight(date, tz)?))) else {
+            return;
+        };
+        let wait = (next.1 - Utc::now()).to_std().unwrap_or_default() + RUN_DELAY;
+        tokio::time::sleep(wait).await;
+
+        for report in state.config.reports.iter().filter(|r| r.is_due(next.0)) {
+            let mut errors = Vec::new();
+            match report.generate(&state, next.0).await {
+                Ok(generated) => {
+                    if let Err(e) = report.deliver(&state, &generated).await {
+                        tracing::error!(report = %report.name, "Report delivery failed: {}", e);
+                        errors.push(format!("delivery failed: {}", e));
+                    }
+                    if let Err(e) = report.store(&state, &generated).await {
+                        tracing::error!(report = %report.name, "Report upload failed: {}", e);
+                        errors.push(format!("upload failed: {}", e));
+                    }
+                }
+                Err(code) => {
+                    tracing::error!(report = %report.name, "Report generation failed: {}", code);
+                    errors.push(format!("generation failed: {}", code));
+                }
+            }
+            let result = if errors.is_empty() { Ok(()) } else { Err(errors.join("; ")) };
+            report.last_run.record_for(Some(next.0), &result);
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use axum::http::StatusCode;
+use std::collections::HashMap;
+
+use crate::config::{Settings, Tunables};
+
+/// Decimals a figure is held to before it is rounded. Finer digits are the
+/// noise of subtracting one f64 counter reading from another, which would
+/// otherwise turn a 10.4 kWh day into 10.399999999999977 and a tie into
+/// no tie at all.
+const SCALE: u32 = 9;
+/// Most decimals `ROUNDING_DECIMALS` may ask for.
+const MAX_DECIMALS: u32 = 6;
+
+/// Where `rounding=utility` rounds.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Stage {
+    /// Every meter's day, before anything is summed.
+    Entry,
+    /// Only the sums, of the exact figures.
+    Total,
+}
+
+/// How a tie, a figure exactly halfway between two, is rounded.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Ties {
+    /// Away from zero: 2.5 to 3.
+    Up,
+    /// Towards zero: 2.5 to 2.
+    Down,
+    /// To the even neighbour: 2.5 to 2, 3.5 to 4.
+    Even,
+}
+
+/// The utility's billing rule, from `ROUNDING_STAGE`, `ROUNDING_DECIMALS`
+/// and `ROUNDING_TIES`, applied with `rounding=utility`. Figures are
+/// rounded and summed as integers of their last decimal, so a sum never
+/// picks up f64 error of its own.
+#[derive(Clone, Copy)]
+pub struct RoundingPolicy {
+    pub stage: Stage,
+    pub decimals: u32,
+    pub ties: Ties,
+}
+
+impl RoundingPolicy {
+    pub fn from_settings(settings: &Settings) -> Result<Self, String> {
+        let stage = match settings.rounding_stage.trim() {
+            "entry" => Stage::Entry,
+            "total" => Stage::Total,
+            other => return Err(format!("`ROUNDING_STAGE` must be `entry` or `total`, got {:?}", other)),
+        };
+        let decimals = settings
+            .rounding_decimals
+            .trim()
+            .parse::<u32>()
+            .ok()
+            .filter(|decimals| *decimals <= MAX_DECIMALS)
+            .ok_or_else(|| {
+                format!(
+                    "`ROUNDING_DECIMALS` must be an integer from 0 to {}, got {:?}",
+                    MAX_DECIMALS, settings.rounding_decimals
+                )
+            })?;
+        let ties = match settings.rounding_ties.trim() {
+            "half_up" => Ties::Up,
+            "half_down" => Ties::Down,
+            "half_even" => Ties::Even,
+            other => {
+                return Err(format!(
+                    "`ROUNDING_TIES` must be `half_up`, `half_down` or `half_even`, got {:?}",
+                    other
+                ))
+            }
+        };
+        Ok(Self { stage, decimals, ties })
+    }
+
+    /// One meter's day as the utility bills it: rounded at the entry stage,
+    /// and as computed at the total stage.
+    pub fn entry(&self, kwh: f64) -> f64 {
+        match (self.stage, to_units(kwh)) {
+            (Stage::Entry, Some(units)) => from_units(self.round_units(units)),
+            _ => kwh,
+        }
+    }
+
+    /// The sum of `kwh`, each figure as `entry` reports it, rounded once
+    /// more at the total stage.
+    pub fn total(&self, kwh: impl IntoIterator<Item = f64>) -> f64 {
+        let sum: i128 = kwh
+            .into_iter()
+            .filter_map(to_units)
+            .map(|units| match self.stage {
+                Stage::Entry => self.round_units(units),
+                Stage::Total => units,
+            })
+            .sum();
+        from_units(self.round_units(sum))
+    }
+
+    /// `units` rounded to `decimals`, still in units of `SCALE`.
+    fn round_units(&self, units: i128) -> i128 {
+        let step = 10i128.pow(SCALE - self.decimals);
+        let (kept, rest) = (units / step, units % step);
+        let away = match (2 * rest.abs()).cmp(&step) {
+            std::cmp::Ordering::Less => false,
+            std::cmp::Ordering::Greater => true,
+            std::cmp::Ordering::Equal => match self.ties {
+                Ties::Up => true,
+                Ties::Down => false,
+                Ties::Even => kept % 2 != 0,
+            },
+        };
+        (kept + if away { units.signum() } else { 0 }) * step
+    }
+}
+
+/// `rounding=utility`: the configured policy; `rounding=none`, the default,
+/// reports figures as computed.
+pub fn requested(
+    tunables: &Tunables,
+    params: &HashMap<String, String>,
+) -> Result<Option<RoundingPolicy>, StatusCode> {
+    match params.get("rounding").map(String::as_str) {
+        None | Some("none") => Ok(None),
+        Some("utility") => Ok(Some(tunables.rounding)),
+        Some(_) => Err(StatusCode::BAD_REQUEST),
+    }
+}
+
+/// `kwh` in units of 10^-`SCALE` kWh, the decimal digits f64 formatting
+/// gives it; `None` when it is not finite or too large to hold.
+fn to_units(kwh: f64) -> Option<i128> {
+    if !kwh.is_finite() || kwh.abs() >= 1e18 {
+        return None;
+    }
+    format!("{:.*}", SCALE as usize, kwh).replace('.', "").parse().ok()
+}
+
+fn from_units(units: i128) -> f64 {
+    format!("{}e-{}", units, SCALE).parse().unwrap_or(f64::NAN)
+}
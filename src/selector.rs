@@ -0,0 +1,139 @@
+use std::fmt;
+
+/// Labels set by the service itself, which `selector` may not override.
+const RESERVED: [&str; 2] = ["__name__", "instance"];
+
+/// One extra `label=value` or `label=~regex` matcher from the `selector`
+/// query parameter.
+pub struct Matcher {
+    label: String,
+    regex: bool,
+    value: String,
+}
+
+impl fmt::Display for Matcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let op = if self.regex { "=~" } else { "=" };
+        write!(f, "{}{}\"{}\"", self.label, op, self.value)
+    }
+}
+
+/// Prometheus label names: `[a-zA-Z_][a-zA-Z0-9_]*`.
+pub fn is_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Parses a comma-separated list of `label=value` / `label=~regex` pairs.
+/// Values may not contain quotes, backslashes or control characters, so
+/// they can be embedded in PromQL without escaping, and therefore cannot
+/// contain commas either.
+pub fn parse(value: &str) -> Result<Vec<Matcher>, String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (label, rest) = pair
+                .split_once('=')
+                .ok_or_else(|| format!("expected `label=value`, got {:?}", pair))?;
+            let (regex, value) = match rest.strip_prefix('~') {
+                Some(value) => (true, value),
+                None => (false, rest),
+            };
+            let label = label.trim();
+            if !is_label_name(label) {
+                return Err(format!("invalid label name {:?}", label));
+            }
+            if RESERVED.contains(&label) {
+                return Err(format!("label {:?} is set by the service and cannot be overridden", label));
+            }
+            if value.chars().any(|c| c == '"' || c == '\\' || c.is_control()) {
+                return Err(format!("invalid characters in value for {:?}", label));
+            }
+            Ok(Matcher {
+                label: label.to_string(),
+                regex,
+                value: value.to_string(),
+            })
+        })
+        .collect()
+}
+
+/// The full series selector for energy counters matching `target` and any
+/// extra matchers, e.g. `{__name__="energy",instance=~"x",site="jkt-01"}`.
+pub fn energy(target: &str, extra: &[Matcher]) -> String {
+    metric("energy", target, extra)
+}
+
+/// Like `energy`, for any metric name. `target` comes from the request as
+/// is, so it is quoted: a `"` in it cannot end the string and add matchers
+/// or operators of its own.
+pub fn metric(name: &str, target: &str, extra: &[Matcher]) -> String {
+    let mut selector = format!("{{__name__=\"{}\",instance=~\"{}\"", name, quote(target));
+    for matcher in extra {
+        selector.push(',');
+        selector.push_str(&matcher.to_string());
+    }
+    selector.push('}');
+    selector
+}
+
+/// `selector` with `matchers`, already in PromQL form, added to it.
+pub fn with_matchers(selector: &str, matchers: &str) -> String {
+    match selector.strip_suffix('}') {
+        Some(inner) => format!("{},{}}}", inner, matchers),
+        None => selector.to_string(),
+    }
+}
+
+/// `selector` for the metric `name` instead of the one it names.
+pub fn with_name(selector: &str, name: &str) -> String {
+    let Some(rest) = selector.strip_prefix("{__name__=\"") else {
+        return selector.to_string();
+    };
+    match rest.split_once('"') {
+        Some((_, rest)) => format!("{{__name__=\"{}\"{}", name, rest),
+        None => selector.to_string(),
+    }
+}
+
+/// `value` as the contents of a PromQL double-quoted string.
+pub fn quote(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quote_in_target_stays_in_the_string() {
+        let target = r#"a"}[10m]) or last_over_time({__name__="energy"}[10m]) or last_over_time({__name__="energy",instance=~"b"#;
+        let selector = energy(target, &[]);
+        assert_eq!(
+            selector,
+            r#"{__name__="energy",instance=~"a\"}[10m]) or last_over_time({__name__=\"energy\"}[10m]) or last_over_time({__name__=\"energy\",instance=~\"b"}"#
+        );
+        let restricted = with_matchers(&selector, r#"instance=~"a.*""#);
+        assert!(restricted.ends_with(r#"\"b",instance=~"a.*"}"#), "{}", restricted);
+    }
+
+    #[test]
+    fn backslash_in_target_is_escaped() {
+        assert_eq!(energy(r"meter\.a", &[]), r#"{__name__="energy",instance=~"meter\\.a"}"#);
+        assert_eq!(energy(r"a\", &[]), r#"{__name__="energy",instance=~"a\\"}"#);
+    }
+
+    #[test]
+    fn extra_matchers_follow_the_target() {
+        let extra = parse("site=jkt-01, phase=~l[12]").unwrap();
+        assert_eq!(
+            metric("power", "x.*", &extra),
+            r#"{__name__="power",instance=~"x.*",site="jkt-01",phase=~"l[12]"}"#
+        );
+        assert!(parse("site=\"x").is_err());
+        assert!(parse("instance=x").is_err());
+    }
+}
@@ -0,0 +1,309 @@
+use axum::Router;
+use axum_server::{tls_rustls::RustlsConfig, Handle};
+use listenfd::ListenFd;
+use sd_notify::NotifyState;
+use std::{
+    io,
+    net::SocketAddr,
+    os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::{
+    net::{TcpListener, UnixListener},
+    sync::watch,
+    task::JoinSet,
+};
+
+use crate::{config::Settings, prometheus::Prometheus};
+
+pub enum BindAddr {
+    Tcp(SocketAddr),
+    Unix { path: PathBuf, mode: u32 },
+}
+
+impl BindAddr {
+    /// `BIND_ADDR` is a comma-separated list of addresses, each listened on;
+    /// values prefixed with `unix:` select a Unix domain socket.
+    pub fn from_settings(settings: &Settings) -> Result<Vec<Self>, String> {
+        let addrs = settings
+            .bind_addr
+            .split(',')
+            .map(str::trim)
+            .filter(|addr| !addr.is_empty())
+            .map(|addr| Self::parse(addr, &settings.socket_mode))
+            .collect::<Result<Vec<_>, _>>()?;
+        if addrs.is_empty() {
+            return Err("`BIND_ADDR` must name at least one address".to_string());
+        }
+        Ok(addrs)
+    }
+
+    fn parse(addr: &str, socket_mode: &str) -> Result<Self, String> {
+        match addr.strip_prefix("unix:") {
+            Some(path) => Ok(BindAddr::Unix {
+                path: PathBuf::from(path),
+                mode: u32::from_str_radix(socket_mode, 8)
+                    .map_err(|_| format!("`SOCKET_MODE` must be an octal mode, got {:?}", socket_mode))?,
+            }),
+            None => addr
+                .parse()
+                .map(BindAddr::Tcp)
+                .map_err(|_| format!("`BIND_ADDR` is not a valid socket address: {:?}", addr)),
+        }
+    }
+
+    pub fn url(&self, tls: Option<&TlsFiles>) -> String {
+        match (self, tls) {
+            (BindAddr::Tcp(addr), None) => format!("http://{}", addr),
+            (BindAddr::Tcp(addr), Some(_)) => format!("https://{}", addr),
+            (BindAddr::Unix { path, .. }, _) => format!("unix:{}", path.display()),
+        }
+    }
+}
+
+/// A socket bound for one of the `BindAddr`s, or inherited from systemd.
+pub enum Listener {
+    Tcp(std::net::TcpListener),
+    /// With the path to remove on exit, unless systemd owns the socket file.
+    Unix(UnixListener, Option<PathBuf>),
+}
+
+impl Listener {
+    /// The address actually bound, so a port of 0 shows the one picked.
+    pub fn url(&self, tls: Option<&TlsFiles>) -> String {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => BindAddr::Tcp(addr).url(tls),
+                Err(_) => "tcp:unknown".to_string(),
+            },
+            Listener::Unix(listener, _) => {
+                let addr = listener.local_addr().ok();
+                match addr.as_ref().and_then(|addr| addr.as_pathname()) {
+                    Some(path) => format!("unix:{}", path.display()),
+                    None => "unix:unknown".to_string(),
+                }
+            }
+        }
+    }
+}
+
+/// Binds every address in `addrs`, taking the inherited systemd socket at
+/// the same position in `LISTEN_FDS` instead when there is one. Fails naming
+/// the first address that cannot be bound.
+pub async fn bind(addrs: &[BindAddr], tls: Option<&TlsFiles>) -> Result<Vec<Listener>, String> {
+    let mut listenfd = ListenFd::from_env();
+    let mut listeners = Vec::new();
+    for (index, addr) in addrs.iter().enumerate() {
+        let failed = |e: std::io::Error| format!("Failed to bind {}: {}", addr.url(tls), e);
+        let listener = match addr {
+            BindAddr::Tcp(socket) => {
+                let listener = match listenfd.take_tcp_listener(index).map_err(failed)? {
+                    Some(listener) => listener,
+                    None => std::net::TcpListener::bind(socket).map_err(failed)?,
+                };
+                listener.set_nonblocking(true).map_err(failed)?;
+                Listener::Tcp(listener)
+            }
+            BindAddr::Unix { .. } if tls.is_some() => {
+                return Err(format!("TLS is only supported on TCP listeners, not {}", addr.url(tls)));
+            }
+            BindAddr::Unix { path, mode } => match listenfd.take_unix_listener(index).map_err(failed)? {
+                Some(listener) => {
+                    listener.set_nonblocking(true).map_err(failed)?;
+                    Listener::Unix(UnixListener::from_std(listener).map_err(failed)?, None)
+                }
+                None => Listener::Unix(bind_unix(path, *mode).map_err(failed)?, Some(path.clone())),
+            },
+        };
+        listeners.push(listener);
+    }
+    Ok(listeners)
+}
+
+/// Binds a Unix socket at `path` with `mode`. It is bound in a directory
+/// only this process can enter and moved into place once it has its mode,
+/// so it is never reachable with the default one. A socket left behind by
+/// an unclean exit is replaced; anything else at `path` is left alone.
+fn bind_unix(path: &Path, mode: u32) -> io::Result<UnixListener> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+        Ok(_) => return Err(io::Error::new(io::ErrorKind::AlreadyExists, "the path exists and is not a socket")),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    let name = path.file_name().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no file name"))?;
+    let private = path.with_file_name(format!(".{}.{}", name.to_string_lossy(), std::process::id()));
+    std::fs::DirBuilder::new().mode(0o700).create(&private)?;
+    let staged = private.join(name);
+    let bound = UnixListener::bind(&staged).and_then(|listener| {
+        std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(mode))?;
+        std::fs::rename(&staged, path)?;
+        Ok(listener)
+    });
+    std::fs::remove_file(&staged).ok();
+    std::fs::remove_dir(&private).ok();
+    bound
+}
+
+#[derive(Clone)]
+pub struct TlsFiles {
+    cert: PathBuf,
+    key: PathBuf,
+}
+
+impl TlsFiles {
+    /// Serving stays plain HTTP when neither certificate nor key is configured.
+    pub fn from_settings(settings: &Settings) -> Result<Option<Self>, String> {
+        match (&settings.tls_cert, &settings.tls_key) {
+            (Some(cert), Some(key)) => Ok(Some(Self {
+                cert: cert.clone(),
+                key: key.clone(),
+            })),
+            (None, None) => Ok(None),
+            _ => Err("`TLS_CERT` and `TLS_KEY` must be set together".to_string()),
+        }
+    }
+
+    /// Reads and parses both PEM files, checking that the key matches the certificate.
+    pub async fn load(&self) -> Result<RustlsConfig, String> {
+        rustls::crypto::ring::default_provider().install_default().ok();
+        RustlsConfig::from_pem_file(&self.cert, &self.key)
+            .await
+            .map_err(|e| format!("failed to load TLS certificate/key: {}", e))
+    }
+}
+
+/// Re-reads the certificate and key on SIGHUP so renewals don't need a restart.
+/// A failed reload keeps serving the previous certificate.
+async fn reload_on_sighup(config: RustlsConfig, tls: TlsFiles) {
+    let Ok(mut hangup) = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+    else {
+        return;
+    };
+
+    while hangup.recv().await.is_some() {
+        match config.reload_from_pem_file(&tls.cert, &tls.key).await {
+            Ok(()) => tracing::info!("Reloaded TLS certificate from {}", tls.cert.display()),
+            Err(e) => tracing::error!("Failed to reload TLS certificate: {}", e),
+        }
+    }
+}
+
+/// Serves `app` on every listener until SIGTERM or Ctrl-C, which shuts them
+/// all down gracefully together, or until one of them fails.
+pub async fn serve(
+    listeners: Vec<Listener>,
+    tls: Option<&TlsFiles>,
+    prometheus: Prometheus,
+    app: Router,
+) -> Result<(), String> {
+    let config = match tls {
+        Some(tls) => {
+            let config = tls.load().await?;
+            tokio::spawn(reload_on_sighup(config.clone(), tls.clone()));
+            Some(config)
+        }
+        None => None,
+    };
+
+    let (stop, stopped) = watch::channel(false);
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        stop.send(true).ok();
+    });
+    let shutdown = move || {
+        let mut stopped = stopped.clone();
+        async move {
+            stopped.wait_for(|stopped| *stopped).await.ok();
+        }
+    };
+
+    let mut servers: JoinSet<_> = listeners
+        .into_iter()
+        .map(|listener| {
+            let url = listener.url(tls);
+            let (app, config, shutdown) = (app.clone(), config.clone(), shutdown());
+            async move {
+                let served = match (listener, config) {
+                    (Listener::Tcp(listener), Some(config)) => {
+                        let handle = Handle::new();
+                        tokio::spawn({
+                            let handle = handle.clone();
+                            async move {
+                                shutdown.await;
+                                handle.graceful_shutdown(None);
+                            }
+                        });
+                        match axum_server::from_tcp_rustls(listener, config) {
+                            Ok(server) => {
+                                server
+                                    .handle(handle)
+                                    .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                                    .await
+                            }
+                            Err(e) => Err(e),
+                        }
+                    }
+                    (Listener::Tcp(listener), None) => match TcpListener::from_std(listener) {
+                        Ok(listener) => {
+                            axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+                                .with_graceful_shutdown(shutdown)
+                                .await
+                        }
+                        Err(e) => Err(e),
+                    },
+                    (Listener::Unix(listener, path), _) => {
+                        let served = axum::serve(listener, app).with_graceful_shutdown(shutdown).await;
+                        if let Some(path) = path {
+                            std::fs::remove_file(path).ok();
+                        }
+                        served
+                    }
+                };
+                served.map_err(|e| format!("Serving {} failed: {}", url, e))
+            }
+        })
+        .collect();
+
+    tokio::spawn(notify_ready(prometheus));
+    // The first listener to fail stops the others, so the process exits
+    // with its error rather than serving on without it.
+    while let Some(served) = servers.join_next().await {
+        if let Err(error) = served.map_err(|e| e.to_string()).and_then(|served| served) {
+            servers.abort_all();
+            return Err(error);
+        }
+    }
+    Ok(())
+}
+
+/// Tells systemd the service is ready once Prometheus has answered a probe.
+/// Without `NOTIFY_SOCKET` the notification is a no-op.
+async fn notify_ready(prometheus: Prometheus) {
+    while prometheus.probe().await.is_err() {
+        tracing::warn!("Prometheus probe failed, retrying");
+        tokio::time::sleep(Duration::from_secs(5)).await;
+    }
+    sd_notify::notify(&[NotifyState::Ready]).ok();
+}
+
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.ok();
+    };
+    let terminate = async {
+        if let Ok(mut signal) =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        {
+            signal.recv().await;
+        }
+    };
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+
+    sd_notify::notify(&[NotifyState::Stopping]).ok();
+}
@@ -0,0 +1,275 @@
+use axum::http::StatusCode;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use reqwest::Url;
+
+use crate::{
+    config::Config,
+    prometheus::Prometheus,
+    reload,
+    state::AppState,
+    usage::{uncached_usage, UsageEntry, UsageRequest, PHASE_LABEL},
+};
+
+pub const SHADOW_COMPARISONS_TOTAL: &str = "shadow_comparisons_total";
+pub const SHADOW_MISMATCHED_ENTRIES_TOTAL: &str = "shadow_mismatched_entries_total";
+
+/// Sampled comparisons running at once; further samples are skipped, so a
+/// slow shadow cannot pile up work behind the requests it mirrors.
+const MAX_RUNNING: usize = 4;
+/// Meters kept in `ShadowStatus::worst`.
+const WORST_KEPT: usize = 10;
+
+/// The `SHADOW_PROMETHEUS_HOST` backend. A sample of usage requests is run
+/// against it as well, after the primary's answer is ready, and the two are
+/// compared meter by meter; clients only ever see the primary's.
+pub struct Shadow {
+    prometheus: Prometheus,
+    sample_rate: f64,
+    tolerance_kwh: f64,
+    timeout: Duration,
+    /// Usage requests seen, to pick every `1 / sample_rate`th.
+    requests: AtomicU64,
+    running: AtomicUsize,
+    status: Mutex<ShadowStatus>,
+}
+
+/// The comparisons so far, for `/admin/status`.
+#[derive(Clone, Default, Serialize)]
+pub struct ShadowStatus {
+    url: String,
+    comparisons: u64,
+    /// Comparisons where any meter differed.
+    mismatches: u64,
+    /// Comparisons the shadow failed or ran out of `SHADOW_TIMEOUT` on.
+    failures: u64,
+    /// Samples dropped because `MAX_RUNNING` comparisons were already running.
+    skipped: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_mismatch: Option<DateTime<Utc>>,
+    /// The meters that differed the most, worst first; a meter only one
+    /// backend has counts as worse than any difference.
+    worst: Vec<EntryDiff>,
+}
+
+/// One meter the backends disagree on.
+#[derive(Clone, Serialize)]
+pub struct EntryDiff {
+    instance: String,
+    address: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    phase: Option<String>,
+    /// The current reading's time of the request it was seen in.
+    curr_time: DateTime<Utc>,
+    /// `null` when that backend has no entry or no figure for the meter.
+    primary_kwh: Option<f64>,
+    shadow_kwh: Option<f64>,
+    /// How far apart the figures are, when both have one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    diff_kwh: Option<f64>,
+}
+
+impl EntryDiff {
+    fn severity(&self) -> f64 {
+        self.diff_kwh.unwrap_or(f64::INFINITY)
+    }
+
+    fn meter(&self) -> (&str, &str, Option<&str>) {
+        (&self.instance, &self.address, self.phase.as_deref())
+    }
+}
+
+/// With `shadow=true`: how the shadow's answer compares with the one served.
+#[derive(Serialize)]
+pub struct ShadowDiff {
+    backend: String,
+    /// Meters either backend returned.
+    compared: usize,
+    mismatched: Vec<EntryDiff>,
+    /// Why there is nothing to compare: the shadow's `error_kind`, or `timeout`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'static str>,
+    elapsed_ms: f64,
+}
+
+impl Shadow {
+    pub fn new(config: &Config, url: Url) -> Result<Self, String> {
+        let prometheus = Prometheus::new(config, url)?;
+        let status = ShadowStatus {
+            url: prometheus.display_url(),
+            ..ShadowStatus::default()
+        };
+        Ok(Self {
+            prometheus,
+            sample_rate: config.shadow_sample_rate,
+            tolerance_kwh: config.shadow_tolerance_kwh,
+            timeout: config.shadow_timeout,
+            requests: AtomicU64::new(0),
+            running: AtomicUsize::new(0),
+            status: Mutex::new(status),
+        })
+    }
+
+    pub fn status(&self) -> ShadowStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// Whether this request is one of the sample, spread evenly over requests.
+    fn sampled(&self) -> bool {
+        let n = self.requests.fetch_add(1, Ordering::Relaxed) as f64;
+        ((n + 1.0) * self.sample_rate).floor() > (n * self.sample_rate).floor()
+    }
+
+    /// Compares `primary`, the entries served for `req`, with what the
+    /// shadow computes for it, waiting at most `SHADOW_TIMEOUT`.
+    pub async fn compare(&self, state: &AppState, req: &UsageRequest, primary: &[UsageEntry]) -> ShadowDiff {
+        let started = Instant::now();
+        let shadow_state = state.with_backend(self.prometheus.clone());
+        let shadow = tokio::time::timeout(self.timeout, uncached_usage(&shadow_state, req));
+        let (entries, error) = match shadow.await {
+            Ok(Ok(usage)) => (Some(usage.entries), None),
+            Ok(Err(error)) => (None, Some(error.failure().map_or("upstream_error", |f| f.kind.name()))),
+            Err(_) => (None, Some("timeout")),
+        };
+        let (compared, mismatched) = match &entries {
+            Some(entries) => self.differences(req, primary, entries),
+            None => (0, Vec::new()),
+        };
+        self.record(error.is_some(), &mismatched);
+        ShadowDiff {
+            backend: self.prometheus.display_url(),
+            compared,
+            mismatched,
+            error,
+            elapsed_ms: started.elapsed().as_secs_f64() * 1000.0,
+        }
+    }
+
+    /// Meters either side has, and those whose `daily_kwh` differ by more
+    /// than `SHADOW_TOLERANCE_KWH` or that only one side has a figure for.
+    fn differences(
+        &self,
+        req: &UsageRequest,
+        primary: &[UsageEntry],
+        shadow: &[UsageEntry],
+    ) -> (usize, Vec<EntryDiff>) {
+        type Meter = (String, String, Option<String>);
+        let mut meters: BTreeMap<Meter, (Option<f64>, Option<f64>)> = BTreeMap::new();
+        let meter = |e: &UsageEntry| {
+            let phase = e.labels.get(PHASE_LABEL).cloned();
+            (e.instance.clone(), e.address.clone(), phase)
+        };
+        for entry in primary {
+            meters.entry(meter(entry)).or_default().0 = entry.daily_kwh;
+        }
+        for entry in shadow {
+            meters.entry(meter(entry)).or_default().1 = entry.daily_kwh;
+        }
+        let compared = meters.len();
+        let mismatched = meters
+            .into_iter()
+            .filter_map(|((instance, address, phase), (primary_kwh, shadow_kwh))| {
+                let diff_kwh = primary_kwh.zip(shadow_kwh).map(|(p, s)| (p - s).abs());
+                let agree = match diff_kwh {
+                    Some(diff) => diff <= self.tolerance_kwh,
+                    None => primary_kwh.is_none() && shadow_kwh.is_none(),
+                };
+                (!agree).then_some(EntryDiff {
+                    instance,
+                    address,
+                    phase,
+                    curr_time: req.curr_dt,
+                    primary_kwh,
+                    shadow_kwh,
+                    diff_kwh,
+                })
+            })
+            .collect();
+        (compared, mismatched)
+    }
+
+    fn record(&self, failed: bool, mismatched: &[EntryDiff]) {
+        let outcome = match (failed, mismatched.is_empty()) {
+            (true, _) => "error",
+            (false, true) => "match",
+            (false, false) => "mismatch",
+        };
+        metrics::counter!(SHADOW_COMPARISONS_TOTAL, "outcome" => outcome).increment(1);
+        metrics::counter!(SHADOW_MISMATCHED_ENTRIES_TOTAL).increment(mismatched.len() as u64);
+        let mut status = self.status.lock().unwrap();
+        status.comparisons += 1;
+        if failed {
+            status.failures += 1;
+            return;
+        }
+        if mismatched.is_empty() {
+            return;
+        }
+        status.mismatches += 1;
+        status.last_mismatch = Some(Utc::now());
+        for diff in mismatched {
+            match status.worst.iter_mut().find(|worst| worst.meter() == diff.meter()) {
+                Some(worst) if worst.severity() < diff.severity() => *worst = diff.clone(),
+                Some(_) => {}
+                None => status.worst.push(diff.clone()),
+            }
+        }
+        status.worst.sort_by(|a, b| b.severity().total_cmp(&a.severity()));
+        status.worst.truncate(WORST_KEPT);
+    }
+}
+
+/// The shadow to compare with before answering, with `shadow=true`. It
+/// needs `SHADOW_PROMETHEUS_HOST`, and mirrors only `PROMETHEUS_HOST`,
+/// so not `prom=`.
+pub fn requested<'a>(
+    state: &'a AppState,
+    params: &HashMap<String, String>,
+) -> Result<Option<&'a Arc<Shadow>>, StatusCode> {
+    match params.get("shadow").map(String::as_str) {
+        None | Some("false") => Ok(None),
+        Some("true") if !params.contains_key("prom") => {
+            state.shadow.as_ref().map(Some).ok_or(StatusCode::BAD_REQUEST)
+        }
+        Some(_) => Err(StatusCode::BAD_REQUEST),
+    }
+}
+
+/// Mirrors a sample of usage requests to the shadow backend, in the
+/// background and after the primary answered `primary` for `req`.
+pub fn mirror(state: &AppState, req: &UsageRequest, primary: &[UsageEntry]) {
+    let Some(shadow) = &state.shadow else {
+        return;
+    };
+    if !shadow.sampled() {
+        return;
+    }
+    if shadow.running.fetch_add(1, Ordering::Relaxed) >= MAX_RUNNING {
+        shadow.running.fetch_sub(1, Ordering::Relaxed);
+        metrics::counter!(SHADOW_COMPARISONS_TOTAL, "outcome" => "skipped").increment(1);
+        shadow.status.lock().unwrap().skipped += 1;
+        return;
+    }
+    let (state, req, primary) = (state.clone(), req.clone(), primary.to_vec());
+    let tunables = state.config.tunables();
+    tokio::spawn(reload::with_tunables(tunables, async move {
+        let Some(shadow) = &state.shadow else {
+            return;
+        };
+        let diff = shadow.compare(&state, &req, &primary).await;
+        if !diff.mismatched.is_empty() {
+            let (mismatched, compared) = (diff.mismatched.len(), diff.compared);
+            let target = &req.target;
+            tracing::warn!(%target, "Shadow backend disagrees on {} of {} meters", mismatched, compared);
+        }
+        shadow.running.fetch_sub(1, Ordering::Relaxed);
+    }));
+}
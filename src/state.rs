@@ -0,0 +1,97 @@
+use std::sync::Arc;
+
+use crate::{
+    api::targets::{KnownInstances, TargetInfo},
+    audit::AuditLog,
+    cache::{SingleFlight, StaleCache, TtlCache},
+    config::Config,
+    disk_cache::DiskCache,
+    jobs::Jobs,
+    probe::{ProbeCache, PROBE_CACHE_TTL},
+    prometheus::Prometheus,
+    shadow::Shadow,
+    usage::{MeterDay, SharedUsage, Usage, IMPLAUSIBLE_NOTED_FOR, LAST_KNOWN_FOR},
+    usage_metrics::UsageMetrics,
+    warmup::Warmup,
+};
+
+/// Shared by every handler through axum's `State` extractor.
+#[derive(Clone)]
+pub struct AppState {
+    pub config: Arc<Config>,
+    pub prometheus: Prometheus,
+    /// Clients for the named `PROMETHEUS_SITES`, for `prom=`.
+    pub sites: Arc<Vec<(String, Prometheus)>>,
+    /// With `SHADOW_PROMETHEUS_HOST`.
+    pub shadow: Option<Arc<Shadow>>,
+    pub targets_cache: Arc<TtlCache<String, Arc<Vec<TargetInfo>>>>,
+    /// `/api/v1/labels/<label>/values` by `<label>:<match>`.
+    pub label_values_cache: Arc<TtlCache<String, Arc<Vec<String>>>>,
+    pub known_instances: Arc<KnownInstances>,
+    /// Usage results by request and backend, with `USAGE_CACHE_TTL`.
+    pub usage_cache: Option<Arc<StaleCache<String, Usage>>>,
+    /// Settled usage results kept across restarts, with `CACHE_DIR`.
+    pub disk_cache: Option<Arc<DiskCache>>,
+    /// Usage queries running now, by the same key, for coalescing duplicates.
+    pub in_flight: Arc<SingleFlight<String, SharedUsage>>,
+    pub usage_metrics: Arc<UsageMetrics>,
+    /// Implausible meter days already counted, for `note_implausible`.
+    pub implausible_days: Arc<TtlCache<MeterDay, ()>>,
+    /// `/probe` answers by target, for `PROBE_CACHE_TTL`.
+    pub probe_cache: Arc<ProbeCache>,
+    pub audit: Option<AuditLog>,
+    pub warmup: Arc<Warmup>,
+    pub jobs: Arc<Jobs>,
+}
+
+impl AppState {
+    pub fn new(config: Config) -> Result<Self, String> {
+        let prometheus = Prometheus::new(&config, config.prometheus_url.clone())?;
+        let sites = config
+            .prometheus_sites
+            .iter()
+            .map(|(name, url)| Ok((name.clone(), Prometheus::new(&config, url.clone())?)))
+            .collect::<Result<Vec<_>, String>>()?;
+        let shadow = match &config.shadow_url {
+            Some(url) => Some(Arc::new(Shadow::new(&config, url.clone())?)),
+            None => None,
+        };
+        let targets_cache = Arc::new(TtlCache::new(config.targets_cache_ttl));
+        let label_values_cache = Arc::new(TtlCache::new(config.targets_cache_ttl));
+        let usage_cache = config
+            .usage_cache_ttl
+            .map(|ttl| Arc::new(StaleCache::new(ttl, config.usage_cache_stale).retaining(LAST_KNOWN_FOR)));
+        let disk_cache = config.cache_dir.clone().map(|dir| Arc::new(DiskCache::new(dir)));
+        if let Some((disk_cache, usage_cache)) = disk_cache.as_ref().zip(usage_cache.as_ref()) {
+            disk_cache.load(usage_cache);
+        }
+        let audit = config.audit.as_ref().map(|audit| AuditLog::start(audit, config.timezone));
+        let jobs = Arc::new(Jobs::new(&config.jobs));
+        Ok(Self {
+            config: Arc::new(config),
+            prometheus,
+            sites: Arc::new(sites),
+            shadow,
+            targets_cache,
+            label_values_cache,
+            known_instances: Arc::default(),
+            usage_cache,
+            disk_cache,
+            in_flight: Arc::default(),
+            usage_metrics: Arc::default(),
+            implausible_days: Arc::new(TtlCache::new(IMPLAUSIBLE_NOTED_FOR)),
+            probe_cache: Arc::new(TtlCache::new(PROBE_CACHE_TTL)),
+            audit,
+            warmup: Arc::default(),
+            jobs,
+        })
+    }
+
+    /// The same state querying `prometheus` instead of `PROMETHEUS_HOST`.
+    pub fn with_backend(&self, prometheus: Prometheus) -> Self {
+        Self {
+            prometheus,
+            ..self.clone()
+        }
+    }
+}
@@ -0,0 +1,35 @@
+/// Median of `values`, which is sorted in place.
+pub fn median(values: &mut [f64]) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_by(f64::total_cmp);
+    let mid = values.len() / 2;
+    Some(if values.len().is_multiple_of(2) {
+        (values[mid - 1] + values[mid]) / 2.0
+    } else {
+        values[mid]
+    })
+}
+
+/// Centered moving average over `window` days (odd). Missing values are
+/// left out of each average. Near the edges the window is cut short on one
+/// side, which the second element reports.
+pub fn moving_average(values: &[Option<f64>], window: usize) -> Vec<(Option<f64>, bool)> {
+    let half = window / 2;
+    (0..values.len())
+        .map(|i| {
+            let start = i.saturating_sub(half);
+            let end = (i + half + 1).min(values.len());
+            let present: Vec<f64> = values[start..end].iter().flatten().copied().collect();
+            let average = (!present.is_empty()).then(|| present.iter().sum::<f64>() / present.len() as f64);
+            (average, end - start < window)
+        })
+        .collect()
+}
+
+/// Median absolute deviation from `median`.
+pub fn mad(values: &[f64], median_value: f64) -> Option<f64> {
+    let mut deviations: Vec<f64> = values.iter().map(|v| (v - median_value).abs()).collect();
+    median(&mut deviations)
+}
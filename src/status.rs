@@ -0,0 +1,38 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::Serialize;
+use std::sync::Mutex;
+
+/// How the most recent attempt at some background work ended.
+#[derive(Clone, Serialize)]
+pub struct Outcome {
+    pub at: DateTime<Utc>,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// The local day a scheduled report covered.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub date: Option<NaiveDate>,
+}
+
+/// Keeps the latest `Outcome` for `/admin/status`.
+#[derive(Default)]
+pub struct LastOutcome(Mutex<Option<Outcome>>);
+
+impl LastOutcome {
+    pub fn record<T>(&self, result: &Result<T, String>) {
+        self.record_for(None, result);
+    }
+
+    pub fn record_for<T>(&self, date: Option<NaiveDate>, result: &Result<T, String>) {
+        *self.0.lock().unwrap() = Some(Outcome {
+            at: Utc::now(),
+            ok: result.is_ok(),
+            error: result.as_ref().err().cloned(),
+            date,
+        });
+    }
+
+    pub fn get(&self) -> Option<Outcome> {
+        self.0.lock().unwrap().clone()
+    }
+}
@@ -0,0 +1,39 @@
+use crate::config::Settings;
+
+/// Decimals costs are rounded to.
+pub const COST_DECIMALS: usize = 2;
+
+/// A flat price per kWh, from `TARIFF_PER_KWH`, in `TARIFF_CURRENCY`.
+pub struct Tariff {
+    pub per_kwh: f64,
+    pub currency: String,
+}
+
+impl Tariff {
+    pub fn from_settings(settings: &Settings) -> Result<Option<Self>, String> {
+        if settings.tariff_per_kwh.is_empty() {
+            return Ok(None);
+        }
+        let per_kwh = settings
+            .tariff_per_kwh
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .filter(|v| v.is_finite() && *v >= 0.0)
+            .ok_or_else(|| {
+                format!("`TARIFF_PER_KWH` must be a non-negative number, got {:?}", settings.tariff_per_kwh)
+            })?;
+        if settings.tariff_currency.trim().is_empty() {
+            return Err("`TARIFF_CURRENCY` must be set when `TARIFF_PER_KWH` is".to_string());
+        }
+        Ok(Some(Self {
+            per_kwh,
+            currency: settings.tariff_currency.trim().to_string(),
+        }))
+    }
+
+    /// The unrounded cost of `kwh`.
+    pub fn cost(&self, kwh: f64) -> f64 {
+        kwh * self.per_kwh
+    }
+}
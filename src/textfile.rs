@@ -0,0 +1,140 @@
+use chrono::{DateTime, Utc};
+use std::{collections::BTreeMap, fmt::Write, path::PathBuf, sync::Mutex, time::Duration};
+
+use crate::{
+    atomic_file::write_atomically,
+    config::{parse_duration, Settings},
+    status::LastOutcome,
+    usage_metrics::escape_label,
+};
+
+/// One meter's figure for the latest day it had one.
+struct Written {
+    daily_kwh: f64,
+    /// Local midnight starting the day `daily_kwh` covers.
+    day: DateTime<Utc>,
+    /// When the meter was last part of a computation.
+    last_seen: DateTime<Utc>,
+}
+
+/// Writes the `/metrics/usage` daily figures to `TEXTFILE_PATH` after every
+/// computation, for node_exporter's textfile collector on hosts that cannot
+/// scrape this service.
+pub struct Textfile {
+    path: PathBuf,
+    stale_after: Duration,
+    series: Mutex<BTreeMap<(String, String), Written>>,
+    pub last_write: LastOutcome,
+}
+
+impl Textfile {
+    pub fn from_settings(settings: &Settings) -> Result<Option<Self>, String> {
+        let Some(path) = &settings.textfile_path else {
+            return Ok(None);
+        };
+        if settings.usage_metrics_targets.is_empty() {
+            return Err("`TEXTFILE_PATH` needs `USAGE_METRICS_TARGETS`".to_string());
+        }
+        let stale = &settings.textfile_stale;
+        let stale_after = parse_duration(stale)
+            .filter(|d| !d.is_zero())
+            .ok_or_else(|| format!("`TEXTFILE_STALE` must be a positive duration like `3d`, got {:?}", stale))?;
+        Ok(Some(Self {
+            path: path.clone(),
+            stale_after,
+            series: Mutex::default(),
+            last_write: LastOutcome::default(),
+        }))
+    }
+
+    /// Records the meters of the latest computation, forgets those missing
+    /// from every one for longer than `TEXTFILE_STALE`, and rewrites the file.
+    pub async fn write(&self, meters: &[(String, String, f64)], day: DateTime<Utc>) {
+        let now = Utc::now();
+        let body = {
+            let mut series = self.series.lock().unwrap();
+            for (instance, address, daily_kwh) in meters {
+                let written = Written {
+                    daily_kwh: *daily_kwh,
+                    day,
+                    last_seen: now,
+                };
+                series.insert((instance.clone(), address.clone()), written);
+            }
+            series.retain(|_, w| (now - w.last_seen).to_std().unwrap_or_default() <= self.stale_after);
+            render(&series)
+        };
+        let result = self.replace(body).await;
+        if let Err(e) = &result {
+            tracing::warn!("Failed to write {}: {}", self.path.display(), e);
+        }
+        self.last_write.record(&result);
+    }
+
+    /// Writes `meters` alone beside `TEXTFILE_PATH`, as `<name>.sinks-test`,
+    /// which the collector ignores, leaving the real file and its meters as
+    /// they are. For `/admin/sinks/test`.
+    pub async fn try_write(
+        &self,
+        meters: &[(String, String, f64)],
+        day: DateTime<Utc>,
+    ) -> Result<String, String> {
+        let series = meters
+            .iter()
+            .map(|(instance, address, daily_kwh)| {
+                let written = Written {
+                    daily_kwh: *daily_kwh,
+                    day,
+                    last_seen: Utc::now(),
+                };
+                ((instance.clone(), address.clone()), written)
+            })
+            .collect();
+        let path = self.path.with_extension("sinks-test");
+        tokio::fs::write(&path, render(&series)).await.map_err(|e| e.to_string())?;
+        Ok(path.display().to_string())
+    }
+
+    /// Replaces the file with `write_atomically`, whose temporary file the
+    /// collector skips for not ending in `.prom`.
+    async fn replace(&self, body: String) -> Result<(), String> {
+        write_atomically(&self.path, body).await.map_err(|e| e.to_string())
+    }
+}
+
+/// The figures in the text format, ending with the OpenMetrics `# EOF`. The
+/// collector refuses files whose samples carry timestamps, so the day each
+/// figure covers is its own gauge.
+fn render(series: &BTreeMap<(String, String), Written>) -> String {
+    type Gauge = (&'static str, &'static str, fn(&Written) -> f64);
+    let gauges: [Gauge; 2] = [
+        (
+            "power_usage_daily_kwh",
+            "Energy used on the most recent completed local day",
+            |w| w.daily_kwh,
+        ),
+        (
+            "power_usage_daily_timestamp_seconds",
+            "Local midnight starting the day power_usage_daily_kwh covers",
+            |w| w.day.timestamp() as f64,
+        ),
+    ];
+    let mut body = String::new();
+    for (name, help, value) in gauges {
+        writeln!(body, "# HELP {} {}", name, help).ok();
+        writeln!(body, "# TYPE {} gauge", name).ok();
+        for ((instance, address), written) in series {
+            writeln!(
+                body,
+                "{}{{instance=\"{}\",address=\"{}\"}} {}",
+                name,
+                escape_label(instance),
+                escape_label(address),
+                value(written)
+            )
+            .ok();
+        }
+    }
+    body.push_str("# EOF\n");
+    body
+}
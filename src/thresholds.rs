@@ -0,0 +1,42 @@
+use std::collections::HashMap;
+
+use crate::config::{load_map, Settings};
+
+/// Per-meter daily kWh limits from `THRESHOLDS_FILE`, keyed by
+/// `instance/address`, `instance`, or an alias from `ALIASES_FILE`:
+///
+/// ```toml
+/// "10.3.7.22:8899" = 40.0
+/// "Chiller 2" = 400.0
+/// ```
+#[derive(Default)]
+pub struct Thresholds {
+    limits: HashMap<String, f64>,
+}
+
+impl Thresholds {
+    pub fn from_settings(settings: &Settings) -> Result<Self, String> {
+        let Some(path) = &settings.thresholds_file else {
+            return Ok(Self::default());
+        };
+        let limits: HashMap<String, f64> = load_map(path)?;
+        if let Some((key, _)) = limits.iter().find(|(_, v)| !v.is_finite() || **v < 0.0) {
+            return Err(format!("`THRESHOLDS_FILE` has an invalid threshold for {:?}", key));
+        }
+        Ok(Self { limits })
+    }
+
+    pub fn is_configured(&self) -> bool {
+        !self.limits.is_empty()
+    }
+
+    /// The most specific limit for a meter: its address, then its alias,
+    /// then its instance.
+    pub fn for_meter(&self, instance: &str, address: &str, name: Option<&str>) -> Option<f64> {
+        self.limits
+            .get(&format!("{}/{}", instance, address))
+            .or_else(|| name.and_then(|name| self.limits.get(name)))
+            .or_else(|| self.limits.get(instance))
+            .copied()
+    }
+}
@@ -0,0 +1,76 @@
+use chrono_tz::Tz;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{
+    config::{load_map, Settings},
+    selector,
+};
+
+/// IANA zones for instances matching a regex, from `TIMEZONES_FILE`, so a
+/// meter's day runs from its own local midnight:
+///
+/// ```toml
+/// "10\\.4\\..*" = "Asia/Makassar"
+/// "papua-.*" = "Asia/Jayapura"
+/// ```
+///
+/// Patterns are anchored like `target`. An instance matching patterns of
+/// several zones belongs to the zone that sorts first.
+#[derive(Default)]
+pub struct Timezones {
+    /// Patterns per zone, ordered by zone name.
+    zones: BTreeMap<String, (Tz, Vec<String>)>,
+}
+
+impl Timezones {
+    pub fn from_settings(settings: &Settings) -> Result<Self, String> {
+        let Some(path) = &settings.timezones_file else {
+            return Ok(Self::default());
+        };
+        let patterns: HashMap<String, String> = load_map(path)?;
+        let mut zones: BTreeMap<String, (Tz, Vec<String>)> = BTreeMap::new();
+        for (pattern, zone) in patterns {
+            if pattern.is_empty() || pattern.chars().any(char::is_control) {
+                return Err(format!("`TIMEZONES_FILE` has an invalid pattern: {:?}", pattern));
+            }
+            let tz = zone
+                .parse::<Tz>()
+                .map_err(|_| format!("`TIMEZONES_FILE` maps {:?} to an unknown timezone {:?}", pattern, zone))?;
+            zones.entry(zone).or_insert_with(|| (tz, Vec::new())).1.push(pattern);
+        }
+        for (_, patterns) in zones.values_mut() {
+            patterns.sort();
+        }
+        Ok(Self { zones })
+    }
+
+    pub fn is_configured(&self) -> bool {
+        !self.zones.is_empty()
+    }
+
+    /// Each zone with the PromQL matchers selecting its instances, then
+    /// `None` with the matchers for instances in no zone. The groups never
+    /// overlap, so each instance is read exactly once.
+    pub fn groups(&self) -> Vec<(Option<Tz>, String)> {
+        let alternation = |patterns: &[&String]| {
+            patterns
+                .iter()
+                .map(|p| format!("({})", selector::quote(p)))
+                .collect::<Vec<_>>()
+                .join("|")
+        };
+        let mut earlier: Vec<&String> = Vec::new();
+        let mut groups = Vec::new();
+        for (tz, patterns) in self.zones.values() {
+            let own: Vec<&String> = patterns.iter().collect();
+            let mut matchers = format!("instance=~\"{}\"", alternation(&own));
+            if !earlier.is_empty() {
+                matchers.push_str(&format!(",instance!~\"{}\"", alternation(&earlier)));
+            }
+            groups.push((Some(*tz), matchers));
+            earlier.extend(own);
+        }
+        groups.push((None, format!("instance!~\"{}\"", alternation(&earlier))));
+        groups
+    }
+}
@@ -0,0 +1,1460 @@
+use axum::http::StatusCode;
+use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Deserializer, Serialize};
+use futures_util::future;
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use crate::{
+    aliases::{literal_pattern, Composite},
+    api_keys::restrict,
+    cache::CacheStatus,
+    config::{parse_duration, Tunables},
+    corrections::{ignores_corrections, AppliedCorrection},
+    error::{ApiError, Error},
+    estimate::{self, Estimate},
+    flags::{self, FlagFilter, Flags},
+    period::{days_before, resolve_local, Dst},
+    prometheus::{self, Current, Pair, Parsed, Sample, SkippedSeries},
+    reload,
+    request_id,
+    rounding::{self, RoundingPolicy},
+    selector::{self, is_label_name},
+    state::AppState,
+};
+
+#[derive(Clone)]
+pub struct UsageRequest {
+    pub target: String,
+    /// Series selector with `target` and any extra `selector` matchers merged in.
+    pub selector: String,
+    /// Restricts results to one address when `target_name` names an address alias.
+    pub address: Option<String>,
+    /// The requested local date and time, before resolving it in a zone.
+    naive: NaiveDateTime,
+    dst: Dst,
+    pub local_dt: DateTime<Tz>,
+    pub curr_dt: DateTime<Utc>,
+    pub prev_dt: DateTime<Utc>,
+    /// Label to aggregate per-meter deltas by, from `group_by`.
+    pub group_by: Option<String>,
+    /// Keep the series of each `phase` separate, from `phase_breakdown=true`.
+    pub phase_breakdown: bool,
+    /// Also average the `power` gauge over the period, from
+    /// `avg_power_source=gauge`.
+    pub power_gauge: bool,
+    /// Daily kWh limit from `threshold_kwh`, for meters without their own
+    /// entry in `THRESHOLDS_FILE`.
+    pub threshold_kwh: Option<f64>,
+    /// The same pair of instants one week earlier (curr, prev), from
+    /// `compare=same_weekday`.
+    pub last_week: Option<(DateTime<Utc>, DateTime<Utc>)>,
+    /// Keep the first `MAX_INSTANCES` instances instead of failing, from
+    /// `truncate=true`.
+    pub truncate: bool,
+    /// Answer with no results instead of a 404 when nothing matches, from
+    /// `empty_ok=true`.
+    pub empty_ok: bool,
+    /// Keep the members of `ALIASES_FILE` composites next to their sum, from
+    /// `expand_composites=true`.
+    pub expand_composites: bool,
+    /// Count `implausible` entries in totals and aggregates, from
+    /// `include_implausible=true`.
+    pub include_implausible: bool,
+    /// Extrapolate meters missing their current reading, from
+    /// `estimate=true`.
+    pub estimate: bool,
+    /// Report the computed values instead of the corrected ones, from
+    /// `corrections=ignore`.
+    pub ignore_corrections: bool,
+    /// The utility's billing rule, from `rounding=utility`.
+    pub rounding: Option<RoundingPolicy>,
+    /// What to answer with while Prometheus is down, from `fallback=` or
+    /// `FALLBACK_MODE`.
+    pub fallback: Fallback,
+    /// The entries to keep by their flags, from `flagged_only=true` and
+    /// `exclude_flags=`.
+    pub flag_filter: FlagFilter,
+}
+
+/// What a usage request is answered with when Prometheus is unreachable.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Fallback {
+    /// The error.
+    None,
+    /// The last result the usage cache or `CACHE_DIR` holds for the same
+    /// request, marked degraded; the error when neither holds one. A 400
+    /// without `USAGE_CACHE_TTL`, which both need.
+    LastKnown,
+}
+
+impl Fallback {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value.trim() {
+            "none" => Ok(Self::None),
+            "last_known" => Ok(Self::LastKnown),
+            other => Err(format!("`FALLBACK_MODE` must be `none` or `last_known`, got {:?}", other)),
+        }
+    }
+}
+
+pub const USAGE_CACHE_REQUESTS_TOTAL: &str = "usage_cache_requests_total";
+pub const USAGE_REQUESTS_COALESCED_TOTAL: &str = "usage_requests_coalesced_total";
+pub const IMPLAUSIBLE_READINGS_TOTAL: &str = "implausible_readings_total";
+pub const DEGRADED_RESPONSES_TOTAL: &str = "degraded_responses_total";
+pub const SAMPLE_AGE_SECONDS: &str = "sample_age_seconds";
+/// Up to an hour, well past the default lookback.
+pub const SAMPLE_AGE_BUCKETS: [f64; 10] = [1.0, 5.0, 15.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1800.0, 3600.0];
+
+/// Usage for readings older than this no longer changes, so cached results
+/// for it are never revalidated.
+const SETTLED_AFTER: chrono::Duration = chrono::Duration::hours(48);
+/// How long settled results stay cached, in memory and in `CACHE_DIR`.
+pub const SETTLED_TTL: std::time::Duration = std::time::Duration::from_secs(24 * 3600);
+/// How long past its TTL and stale window a cached result may still answer
+/// with `fallback=last_known`.
+pub const LAST_KNOWN_FOR: std::time::Duration = std::time::Duration::from_secs(24 * 3600);
+/// How long `note_implausible` remembers a meter day it counted.
+pub const IMPLAUSIBLE_NOTED_FOR: std::time::Duration = std::time::Duration::from_secs(24 * 3600);
+
+/// A usage query's result as coalesced requests share it, so every waiter's
+/// error names the same failed Prometheus call, with the calls it took to
+/// credit to each waiter and the request whose id those calls carried.
+#[derive(Clone)]
+pub struct SharedUsage {
+    usage: Result<Usage, Error>,
+    queries: usize,
+    request_id: Option<String>,
+}
+
+/// Every value `WeekComparison::reason` takes, so it can be read back from
+/// `CACHE_DIR`.
+const REASONS: [&str; 3] = ["missing_last_week", "missing_prev", "zero_last_week"];
+
+/// `UsageEntry::address` of a composite's entry.
+pub const COMPOSITE_ADDRESS: &str = "composite";
+
+fn known_reason(name: &str) -> Result<&'static str, String> {
+    let known = REASONS.iter().find(|reason| **reason == name).copied();
+    known.ok_or_else(|| format!("unknown reason {:?}", name))
+}
+
+/// Result of `compute_usage`.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Usage {
+    pub entries: Vec<UsageEntry>,
+    /// Instances matched before `truncate=true` dropped some.
+    pub truncated_from: Option<usize>,
+    /// Whether either reading found any series; no entries alone may just
+    /// mean the current readings were missing.
+    pub matched: bool,
+    /// Series dropped because another reported the same meter, see
+    /// `dedupe_series`.
+    #[serde(default)]
+    pub discarded_series: usize,
+    /// Series left out because their value was not a number; their meters
+    /// read as missing.
+    #[serde(default)]
+    pub skipped_series: Vec<SkippedSeries>,
+    /// When the entries were read from Prometheus. A stored result without
+    /// it does not load, rather than passing for one read just now.
+    pub fetched_at: DateTime<Utc>,
+    /// Prometheus was down and these are the last entries cached, served
+    /// with `fallback=last_known`.
+    #[serde(skip)]
+    pub degraded: bool,
+    /// How `USAGE_CACHE_TTL` answered, when it is set.
+    #[serde(skip)]
+    pub cache: Option<CacheStatus>,
+}
+
+impl Usage {
+    /// The newest scrape behind the entries, for `Last-Modified`.
+    pub fn last_modified(&self) -> Option<DateTime<Utc>> {
+        self.entries
+            .iter()
+            .flat_map(|entry| [entry.prev_sample_time, entry.curr_sample_time])
+            .flatten()
+            .max()
+    }
+
+    /// Fails with `ApiError::no_series` when nothing matched, unless the
+    /// request has `empty_ok=true`.
+    pub fn require_match(&self, req: &UsageRequest) -> Result<(), ApiError> {
+        if self.matched || req.empty_ok {
+            Ok(())
+        } else {
+            Err(ApiError::no_series(&req.target))
+        }
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct UsageEntry {
+    pub instance: String,
+    pub address: String,
+    pub name: Option<String>,
+    /// The zone the day was computed in, with `TIMEZONES_FILE`.
+    pub timezone: Option<Tz>,
+    pub prev_kwh: Option<f64>,
+    pub curr_kwh: f64,
+    pub daily_kwh: Option<f64>,
+    /// Average power over `period_hours`.
+    pub avg_power_watt: Option<f64>,
+    /// Hours between the two scrapes, or between the requested instants
+    /// when the scrape times are unknown.
+    pub period_hours: Option<f64>,
+    /// Deprecated: `daily_kwh` over a fixed 24 hours, as `avg_power_watt`
+    /// was computed before it used the scrape times.
+    pub avg_power_watt_24h: Option<f64>,
+    pub prev_sample_time: Option<DateTime<Utc>>,
+    pub curr_sample_time: Option<DateTime<Utc>>,
+    pub flags: Flags,
+    pub labels: HashMap<String, String>,
+    /// Effective daily kWh limit for this meter, if any.
+    pub threshold_kwh: Option<f64>,
+    pub comparison: Option<WeekComparison>,
+    /// The `power` gauge's average, with `avg_power_source=gauge` on a
+    /// meter that has one.
+    pub power_gauge: Option<PowerGauge>,
+    /// On a composite's entry, its members as `instance/address`.
+    pub members: Option<Vec<String>>,
+    /// On a member kept by `expand_composites=true`, the composite's name.
+    pub part_of: Option<String>,
+    /// How the current reading was extrapolated, on an entry added by
+    /// `estimate=true`.
+    #[serde(default)]
+    pub estimate: Option<Estimate>,
+    /// How long before each requested instant the meter was scraped.
+    #[serde(default)]
+    pub sample_age_seconds: Option<SampleAge>,
+    /// The correction reported instead of the computed day.
+    #[serde(default)]
+    pub correction: Option<AppliedCorrection>,
+}
+
+/// Seconds between a requested instant and the scrape read for it, where
+/// the scrape time is known.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct SampleAge {
+    pub prev: Option<f64>,
+    pub curr: Option<f64>,
+}
+
+/// The meter's `power` gauge averaged over the same period as
+/// `avg_power_watt`, as a cross-check of the counter.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct PowerGauge {
+    pub avg_power_watt_gauge: f64,
+    /// How far the gauge average is from `avg_power_watt`, relative to the
+    /// latter; missing without a counter average or when it is zero.
+    pub power_diff_percent: Option<f64>,
+}
+
+impl PowerGauge {
+    fn new(avg_power_watt_gauge: f64, avg_power_watt: Option<f64>) -> Self {
+        let diff = avg_power_watt
+            .filter(|watt| *watt != 0.0)
+            .map(|watt| (avg_power_watt_gauge - watt) / watt.abs() * 100.0);
+        Self {
+            avg_power_watt_gauge: (avg_power_watt_gauge * 100.0).round() / 100.0,
+            power_diff_percent: diff.map(|d| (d * 100.0).round() / 100.0),
+        }
+    }
+
+    fn is_mismatch(&self, threshold_percent: f64) -> bool {
+        self.power_diff_percent.is_some_and(|diff| diff.abs() > threshold_percent)
+    }
+}
+
+/// The same meter's consumption seven days earlier, with `compare=same_weekday`.
+/// Fields are `null` with a `reason` when either day cannot be computed.
+#[derive(Clone, Serialize)]
+pub struct WeekComparison {
+    pub last_week_kwh: Option<f64>,
+    pub change_kwh: Option<f64>,
+    pub change_percent: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<&'static str>,
+}
+
+/// `WeekComparison` as read back, before `reason` is matched to a flag.
+#[derive(Deserialize)]
+struct StoredComparison {
+    last_week_kwh: Option<f64>,
+    change_kwh: Option<f64>,
+    change_percent: Option<f64>,
+    #[serde(default)]
+    reason: Option<String>,
+}
+
+// By hand, as the derive would tie `'de` to the `'static` of `reason`.
+impl<'de> Deserialize<'de> for WeekComparison {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let stored = StoredComparison::deserialize(deserializer)?;
+        Ok(Self {
+            last_week_kwh: stored.last_week_kwh,
+            change_kwh: stored.change_kwh,
+            change_percent: stored.change_percent,
+            reason: stored.reason.as_deref().map(known_reason).transpose().map_err(serde::de::Error::custom)?,
+        })
+    }
+}
+
+impl WeekComparison {
+    fn new(daily_kwh: Option<f64>, last_week_kwh: Option<f64>) -> Self {
+        let reason = match (daily_kwh, last_week_kwh) {
+            (_, None) => Some("missing_last_week"),
+            (None, _) => Some("missing_prev"),
+            (_, Some(0.0)) => Some("zero_last_week"),
+            _ => None,
+        };
+        let change_kwh = daily_kwh.zip(last_week_kwh).map(|(curr, last)| curr - last);
+        Self {
+            last_week_kwh,
+            change_kwh,
+            change_percent: change_kwh
+                .zip(last_week_kwh)
+                .filter(|(_, last)| *last != 0.0)
+                .map(|(change, last)| (change / last * 10000.0).round() / 100.0),
+            reason,
+        }
+    }
+}
+
+impl UsageEntry {
+    pub fn over_threshold(&self) -> Option<bool> {
+        Some(self.daily_kwh? > self.threshold_kwh?)
+    }
+
+    pub fn is_implausible(&self) -> bool {
+        self.flags.contains(flags::IMPLAUSIBLE)
+    }
+}
+
+/// Summed usage of all meters sharing one value of the `group_by` label.
+#[derive(Serialize)]
+pub struct GroupUsage {
+    pub group: String,
+    pub daily_kwh: f64,
+    pub avg_power_watt: f64,
+    /// Meters whose delta went into the sum.
+    pub meters: usize,
+    /// Meters in the group skipped for lacking a previous reading.
+    pub missing_prev: usize,
+    /// Meters in the group skipped as `implausible`, without
+    /// `include_implausible=true`.
+    pub implausible: usize,
+    /// The daily kWh of each meter summed, for the totals of
+    /// `rounding=utility`.
+    #[serde(skip)]
+    pub meter_kwh: Vec<f64>,
+}
+
+/// Bucket for series that do not carry the `group_by` label.
+pub const UNLABELLED: &str = "_unlabelled";
+
+pub const PHASE_LABEL: &str = "phase";
+
+impl UsageRequest {
+    pub fn from_params(params: &HashMap<String, String>, state: &AppState) -> Result<Self, StatusCode> {
+        let (target, address) = resolve_target(params, state)?;
+        let selector = resolve_selector(params, &target)?;
+
+        let date = params
+            .get("date")
+            .and_then(|d| {
+                let parts: Vec<u32> = d.split('-').filter_map(|s| s.parse().ok()).collect();
+                if parts.len() == 3 {
+                    Some((parts[0] as i32, parts[1], parts[2]))
+                } else {
+                    None
+                }
+            })
+            .ok_or(StatusCode::BAD_REQUEST)?;
+
+        let time = params
+            .get("time")
+            .and_then(|t| {
+                let parts: Vec<u32> = t.split(':').filter_map(|s| s.parse().ok()).collect();
+                if parts.len() == 2 {
+                    Some((parts[0], parts[1]))
+                } else {
+                    None
+                }
+            })
+            .ok_or(StatusCode::BAD_REQUEST)?;
+
+        let naive_date = NaiveDate::from_ymd_opt(date.0, date.1, date.2)
+            .and_then(|d| d.and_hms_opt(time.0, time.1, 0))
+            .ok_or(StatusCode::BAD_REQUEST)?;
+
+        let timezone = match params.get("tz") {
+            Some(tz) => tz.parse::<Tz>().map_err(|_| StatusCode::BAD_REQUEST)?,
+            None => state.config.timezone,
+        };
+        let dst = match params.get("dst").map(String::as_str) {
+            None | Some("early") => Dst::Early,
+            Some("late") => Dst::Late,
+            Some(_) => return Err(StatusCode::BAD_REQUEST),
+        };
+        let compare = match params.get("compare").map(String::as_str) {
+            None => false,
+            Some("same_weekday") => true,
+            Some(_) => return Err(StatusCode::BAD_REQUEST),
+        };
+        let instants = Instants::new(naive_date, timezone, dst, compare)?;
+
+        let group_by = params.get("group_by").cloned();
+        if group_by.as_deref().is_some_and(|label| !is_label_name(label)) {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+
+        Ok(Self {
+            target,
+            selector,
+            address,
+            naive: naive_date,
+            dst,
+            local_dt: instants.local_dt,
+            curr_dt: instants.curr_dt,
+            prev_dt: instants.prev_dt,
+            group_by,
+            phase_breakdown: params.get("phase_breakdown").is_some_and(|v| v == "true"),
+            power_gauge: match params.get("avg_power_source").map(String::as_str) {
+                None | Some("counter") => false,
+                Some("gauge") => true,
+                Some(_) => return Err(StatusCode::BAD_REQUEST),
+            },
+            threshold_kwh: parse_threshold(params)?,
+            last_week: instants.last_week,
+            truncate: wants_truncate(params),
+            empty_ok: params.get("empty_ok").is_some_and(|v| v == "true"),
+            expand_composites: params.get("expand_composites").is_some_and(|v| v == "true"),
+            include_implausible: wants_implausible(params),
+            estimate: params.get("estimate").is_some_and(|v| v == "true"),
+            ignore_corrections: ignores_corrections(params)?,
+            rounding: rounding::requested(&state.config.tunables(), params)?,
+            fallback: match params.get("fallback") {
+                None => state.config.fallback,
+                Some(v) => Fallback::parse(v).map_err(|_| StatusCode::BAD_REQUEST)?,
+            },
+            flag_filter: FlagFilter::from_params(params)?,
+        })
+    }
+
+    /// Everything `query_usage` depends on, with the backend it runs against.
+    fn cache_key(&self, state: &AppState) -> String {
+        format!(
+            "{}\n{}\n{:?}\n{}\n{}\n{:?}\n{}\n{}\n{}\n{:?}\n{:?}\n{}\n{}\n{}\n{}\n{}",
+            state.prometheus.display_url(),
+            state.prometheus.lookback(),
+            state.prometheus.fallback_lookback(),
+            state.config.tunables().generation,
+            self.selector,
+            self.address,
+            self.naive,
+            self.curr_dt,
+            self.prev_dt,
+            self.last_week,
+            self.threshold_kwh,
+            self.phase_breakdown,
+            self.power_gauge,
+            self.truncate,
+            self.expand_composites,
+            match self.ignore_corrections {
+                true => "ignore".to_string(),
+                false => state.config.corrections.version(),
+            }
+        )
+    }
+
+    /// `Freshness::Historical` when the latest instant read, in any zone of
+    /// `TIMEZONES_FILE`, is more than the lookback before `now`, and
+    /// `Freshness::Recent` otherwise. Earlier instants, the previous and
+    /// last week's readings, are older still.
+    pub fn freshness(&self, state: &AppState, now: DateTime<Utc>) -> Freshness {
+        let Some(lookback) = parse_duration(&state.prometheus.lookback())
+            .and_then(|lookback| chrono::Duration::from_std(lookback).ok())
+        else {
+            return Freshness::Recent;
+        };
+        let timezones = &state.config.timezones;
+        let zones = if timezones.is_configured() { timezones.groups() } else { Vec::new() };
+        let mut latest = self.curr_dt;
+        for (tz, _) in zones {
+            match Instants::new(self.naive, tz.unwrap_or(self.local_dt.timezone()), self.dst, false) {
+                Ok(instants) => latest = latest.max(instants.curr_dt),
+                Err(_) => return Freshness::Recent,
+            }
+        }
+        if latest < now - lookback {
+            Freshness::Historical
+        } else {
+            Freshness::Recent
+        }
+    }
+
+    /// The same request for the instances in `timezone` matched by
+    /// `matchers`, read at the requested wall-clock time in that zone.
+    fn in_zone(&self, timezone: Tz, matchers: &str) -> Result<Self, StatusCode> {
+        let instants = Instants::new(self.naive, timezone, self.dst, self.last_week.is_some())?;
+        Ok(Self {
+            target: self.target.clone(),
+            selector: selector::with_matchers(&self.selector, matchers),
+            address: self.address.clone(),
+            naive: self.naive,
+            dst: self.dst,
+            local_dt: instants.local_dt,
+            curr_dt: instants.curr_dt,
+            prev_dt: instants.prev_dt,
+            group_by: self.group_by.clone(),
+            phase_breakdown: self.phase_breakdown,
+            power_gauge: self.power_gauge,
+            threshold_kwh: self.threshold_kwh,
+            last_week: instants.last_week,
+            truncate: self.truncate,
+            empty_ok: self.empty_ok,
+            expand_composites: self.expand_composites,
+            include_implausible: self.include_implausible,
+            estimate: self.estimate,
+            ignore_corrections: self.ignore_corrections,
+            rounding: self.rounding,
+            fallback: self.fallback,
+            flag_filter: self.flag_filter.clone(),
+        })
+    }
+}
+
+/// How long intermediaries may keep a usage response, for `Cache-Control`.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Freshness {
+    /// Every reading is older than the lookback, so no sample can still
+    /// change it.
+    Historical,
+    /// A reading involves now, and new samples may still arrive.
+    Recent,
+    /// Debug output, or a request with side effects, never served from a
+    /// cache.
+    Private,
+}
+
+impl Freshness {
+    pub fn cache_control(self) -> &'static str {
+        match self {
+            Self::Historical => "public, max-age=86400, immutable",
+            Self::Recent => "public, max-age=30",
+            Self::Private => "no-store",
+        }
+    }
+}
+
+/// The instants a usage request reads the counters at.
+struct Instants {
+    local_dt: DateTime<Tz>,
+    curr_dt: DateTime<Utc>,
+    prev_dt: DateTime<Utc>,
+    last_week: Option<(DateTime<Utc>, DateTime<Utc>)>,
+}
+
+impl Instants {
+    fn new(naive: NaiveDateTime, timezone: Tz, dst: Dst, compare: bool) -> Result<Self, StatusCode> {
+        let local_dt = resolve_local(naive, timezone, dst).ok_or(StatusCode::BAD_REQUEST)?;
+
+        // Earlier readings are at the same wall-clock time on earlier days,
+        // so across a DST change the period is 23 or 25 hours.
+        let days_earlier = |days: u64| {
+            days_before(naive, days)
+                .and_then(|naive| resolve_local(naive, timezone, dst))
+                .map(|dt| dt.with_timezone(&Utc))
+                .ok_or(StatusCode::BAD_REQUEST)
+        };
+        Ok(Self {
+            curr_dt: local_dt.with_timezone(&Utc),
+            prev_dt: days_earlier(1)?,
+            last_week: if compare { Some((days_earlier(7)?, days_earlier(8)?)) } else { None },
+            local_dt,
+        })
+    }
+}
+
+pub fn wants_truncate(params: &HashMap<String, String>) -> bool {
+    params.get("truncate").is_some_and(|v| v == "true")
+}
+
+pub fn wants_implausible(params: &HashMap<String, String>) -> bool {
+    params.get("include_implausible").is_some_and(|v| v == "true")
+}
+
+/// Whether a counter reading is negative, not a number or over
+/// `MAX_READING_KWH`.
+pub fn is_implausible_reading(tunables: &Tunables, kwh: f64) -> bool {
+    !kwh.is_finite() || kwh < 0.0 || tunables.max_reading_kwh.is_some_and(|max| kwh > max)
+}
+
+/// Whether the day between readings `prev` and `curr` of a meter cannot be
+/// real: a reading is negative or over `MAX_READING_KWH`, the counter went
+/// backwards, as it does when it is reset, or the day's consumption is
+/// beyond `MAX_DAILY_KWH`.
+pub fn is_implausible(tunables: &Tunables, prev: Option<f64>, curr: Option<f64>) -> bool {
+    let daily = prev.zip(curr).map(|(prev, curr)| curr - prev);
+    prev.into_iter().chain(curr).any(|kwh| is_implausible_reading(tunables, kwh))
+        || daily.is_some_and(|kwh| kwh < 0.0 || kwh > tunables.max_daily_kwh)
+}
+
+/// A meter's instance and address, and the instants a day of it runs between.
+pub type MeterDay = (String, String, DateTime<Utc>, DateTime<Utc>);
+
+/// Counts and logs an implausible day of `meter` between the instants of
+/// `period`, where it is computed, once in `IMPLAUSIBLE_NOTED_FOR` however
+/// many requests, cache refreshes and range reports compute it again.
+pub fn note_implausible(
+    state: &AppState,
+    meter: (&str, &str),
+    period: (DateTime<Utc>, DateTime<Utc>),
+    prev: Option<f64>,
+    curr: Option<f64>,
+) {
+    let (instance, address) = meter;
+    let day = (instance.to_string(), address.to_string(), period.0, period.1);
+    if !state.implausible_days.insert_new(day, ()) {
+        return;
+    }
+    metrics::counter!(IMPLAUSIBLE_READINGS_TOTAL).increment(1);
+    let (from, to) = period;
+    tracing::warn!(instance, address, %from, %to, ?prev, ?curr, "Implausible energy reading");
+}
+
+/// Roughly what one row of a usage report takes as JSON, with its field
+/// names, and as CSV.
+const JSON_ROW_BYTES: usize = 320;
+const CSV_ROW_BYTES: usize = 90;
+
+/// How large a report on the series a query matched would be, counted
+/// before any per-meter work. `MAX_INSTANCES` and `MAX_RESPONSE_ROWS` are
+/// enforced on it, and `count_only=true` answers with it.
+#[derive(Clone, Copy, Default)]
+pub struct ResponseSize {
+    pub instances: usize,
+    /// One per series, times the days of a range.
+    pub rows: usize,
+}
+
+impl ResponseSize {
+    /// A report over `days` days on `series`, by instance.
+    pub fn of<T>(series: &HashMap<String, Vec<T>>, days: usize) -> Self {
+        let rows = series.values().map(Vec::len).sum::<usize>() * days;
+        Self { instances: series.len(), rows }
+    }
+
+    /// A report on `entries`, one row each.
+    pub fn of_entries(entries: &[UsageEntry]) -> Self {
+        let instances: HashSet<&str> = entries.iter().map(|e| e.instance.as_str()).collect();
+        Self {
+            instances: instances.len(),
+            rows: entries.len(),
+        }
+    }
+
+    pub fn add(self, other: Self) -> Self {
+        Self {
+            instances: self.instances + other.instances,
+            rows: self.rows + other.rows,
+        }
+    }
+
+    pub fn estimated_bytes(self, csv: bool) -> usize {
+        self.rows * if csv { CSV_ROW_BYTES } else { JSON_ROW_BYTES }
+    }
+
+    /// Fails with 413 over `MAX_RESPONSE_ROWS`.
+    pub fn check_rows(self, tunables: &Tunables) -> Result<(), StatusCode> {
+        if self.rows <= tunables.max_response_rows {
+            return Ok(());
+        }
+        Err(StatusCode::PAYLOAD_TOO_LARGE)
+    }
+
+    /// The `count_only=true` response: no body, and the size in headers.
+    pub fn headers(self, csv: bool) -> [(&'static str, String); 3] {
+        [
+            ("x-instance-count", self.instances.to_string()),
+            ("x-row-count", self.rows.to_string()),
+            ("x-estimated-bytes", self.estimated_bytes(csv).to_string()),
+        ]
+    }
+}
+
+/// Whether the request only asks for its size, with `count_only=true`.
+pub fn wants_count_only(params: &HashMap<String, String>) -> bool {
+    params.get("count_only").is_some_and(|v| v == "true")
+}
+
+/// Enforces `MAX_INSTANCES` on freshly parsed query results, before any
+/// per-entry work, the instances of all of `data` counted together as
+/// `ResponseSize::add` counts them. Over the limit this fails with 422
+/// unless `truncate` is set, in which case the instances sorting first by
+/// name are kept and the original count is returned.
+pub fn limit_instances<T>(
+    state: &AppState,
+    data: &mut [&mut HashMap<String, Vec<T>>],
+    truncate: bool,
+) -> Result<Option<usize>, StatusCode> {
+    let sizes = data.iter().map(|data| ResponseSize::of(data, 1));
+    let size = sizes.fold(ResponseSize::default(), ResponseSize::add);
+    let (total, max) = (size.instances, state.config.tunables().max_instances);
+    if total <= max {
+        return Ok(None);
+    }
+    if !truncate {
+        return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+    let mut instances: Vec<String> = data.iter().flat_map(|data| data.keys().cloned()).collect();
+    instances.sort();
+    for instance in &instances[max..] {
+        data.iter_mut().for_each(|data| {
+            data.remove(instance);
+        });
+    }
+    Ok(Some(total))
+}
+
+/// The series discovery behind `count_only=true`: the size of the report
+/// `req` asks for, from the series each zone has within the lookback
+/// before its current reading, without reading or pairing any counter.
+pub async fn response_size(state: &AppState, req: &UsageRequest) -> Result<ResponseSize, Error> {
+    let requests = match state.config.timezones.is_configured() {
+        true => zone_requests(state, req)?,
+        false => vec![req.clone()],
+    };
+    let lookback = parse_duration(&state.prometheus.lookback())
+        .and_then(|lookback| chrono::Duration::from_std(lookback).ok())
+        .unwrap_or_default();
+    let sizes = future::try_join_all(requests.iter().map(|req| async move {
+        let series = state.prometheus.series(&req.selector, req.curr_dt - lookback, req.curr_dt).await?;
+        Ok::<_, Error>(ResponseSize::of(&series, 1))
+    }))
+    .await?;
+    Ok(sizes.into_iter().fold(ResponseSize::default(), ResponseSize::add))
+}
+
+fn parse_threshold(params: &HashMap<String, String>) -> Result<Option<f64>, StatusCode> {
+    params
+        .get("threshold_kwh")
+        .map(|v| {
+            v.parse::<f64>()
+                .ok()
+                .filter(|v| v.is_finite() && *v >= 0.0)
+                .ok_or(StatusCode::BAD_REQUEST)
+        })
+        .transpose()
+}
+
+pub fn avg_power_watt(kwh: f64, hours: f64) -> f64 {
+    (kwh / hours * 100000.0).round() / 100.0
+}
+
+pub fn hours_between(prev: DateTime<Utc>, curr: DateTime<Utc>) -> f64 {
+    (curr - prev).num_milliseconds() as f64 / 3_600_000.0
+}
+
+/// Hours between two scrapes, falling back to the requested period when
+/// either time is missing or they are not in order.
+pub fn period_hours(prev: Option<DateTime<Utc>>, curr: Option<DateTime<Utc>>, nominal: f64) -> f64 {
+    prev.zip(curr)
+        .map(|(prev, curr)| hours_between(prev, curr))
+        .filter(|hours| *hours > 0.0)
+        .unwrap_or(nominal)
+}
+
+/// Takes the instance regex from `target`, or resolves `target_name`
+/// against the aliases file into a literal instance match.
+pub fn resolve_target(
+    params: &HashMap<String, String>,
+    state: &AppState,
+) -> Result<(String, Option<String>), StatusCode> {
+    if let Some(target) = params.get("target") {
+        return Ok((target.to_string(), None));
+    }
+    let name = params.get("target_name").ok_or(StatusCode::BAD_REQUEST)?;
+    let (instance, address) = state
+        .config
+        .aliases
+        .current()
+        .resolve(name)
+        .ok_or(StatusCode::BAD_REQUEST)?;
+    Ok((literal_pattern(&instance), address))
+}
+
+/// Merges the extra `selector=label=value,...` matchers with `target`,
+/// within the `targets` of the request's API key.
+pub fn resolve_selector(params: &HashMap<String, String>, target: &str) -> Result<String, StatusCode> {
+    let extra = match params.get("selector") {
+        Some(value) => selector::parse(value).map_err(|_| StatusCode::BAD_REQUEST)?,
+        None => Vec::new(),
+    };
+    Ok(restrict(selector::energy(target, &extra), params))
+}
+
+pub fn same_series<'a>(samples: Option<&'a Vec<Sample>>, like: &Sample) -> Option<&'a Sample> {
+    samples?.iter().find(|s| is_same_meter(s, like))
+}
+
+/// Whether `a` and `b` read the same address and phase.
+fn is_same_meter(a: &Sample, b: &Sample) -> bool {
+    a.address == b.address && a.labels.get(PHASE_LABEL) == b.labels.get(PHASE_LABEL)
+}
+
+fn job(sample: &Sample) -> Option<&str> {
+    sample.labels.get("job").map(String::as_str)
+}
+
+/// Whether `sample` comes from `PREFER_JOB`.
+pub fn is_preferred_job(tunables: &Tunables, sample: &Sample) -> bool {
+    tunables.prefer_job.is_some() && job(sample) == tunables.prefer_job.as_deref()
+}
+
+/// A meter's series: instance, address and phase.
+type MeterSeries = (String, String, Option<String>);
+
+fn meter_series(instance: &str, address: &str, labels: &HashMap<String, String>) -> MeterSeries {
+    (instance.to_string(), address.to_string(), labels.get(PHASE_LABEL).cloned())
+}
+
+/// Keeps one series per address and phase of each instance where several
+/// report the same meter, as two scrape jobs or a relabelling leave behind:
+/// the one `preferred` picks, else the newest sample, else the first `job`
+/// by name. Returns how many were dropped for each meter. The two days may
+/// still differ in their meters, so readings are paired with `same_series`
+/// rather than by what is left in order.
+pub fn dedupe_series(
+    data: &mut HashMap<String, Vec<Sample>>,
+    preferred: impl Fn(&str, &Sample) -> bool,
+) -> HashMap<MeterSeries, usize> {
+    let mut discarded = HashMap::new();
+    for (instance, samples) in data.iter_mut() {
+        let rank = |s: &Sample| (preferred(instance, s), s.timestamp, Reverse(s.labels.get("job").cloned()));
+        let mut kept: Vec<Sample> = Vec::with_capacity(samples.len());
+        for sample in samples.drain(..) {
+            let Some(i) = kept.iter().position(|k| is_same_meter(k, &sample)) else {
+                kept.push(sample);
+                continue;
+            };
+            *discarded.entry(meter_series(instance, &sample.address, &sample.labels)).or_insert(0) += 1;
+            if rank(&sample) > rank(&kept[i]) {
+                kept[i] = sample;
+            }
+        }
+        *samples = kept;
+    }
+    discarded
+}
+
+/// `cached_usage` with meters missing their current reading estimated, with
+/// `estimate=true`, each meter's day rounded as `rounding=utility` asks, and
+/// only the entries `flagged_only=true` and `exclude_flags=` keep. A 400
+/// for `fallback=last_known` without a usage cache to fall back on.
+pub async fn compute_usage(state: &AppState, req: &UsageRequest) -> Result<Usage, Error> {
+    if req.fallback == Fallback::LastKnown && state.usage_cache.is_none() {
+        return Err(Error::Invalid("fallback=last_known needs USAGE_CACHE_TTL"));
+    }
+    let mut usage = cached_usage(state, req).await?;
+    // A degraded result is all there is while Prometheus is down.
+    if req.estimate && !usage.degraded {
+        estimate::add_estimates(state, req, &mut usage).await?;
+    }
+    finish_entries(req, &mut usage);
+    Ok(usage)
+}
+
+/// Rounds each meter's day per `rounding=utility` and drops the entries the
+/// flag filter leaves out, after the cache, which keeps every entry as
+/// computed.
+fn finish_entries(req: &UsageRequest, usage: &mut Usage) {
+    if let Some(rounding) = req.rounding {
+        for entry in &mut usage.entries {
+            entry.daily_kwh = entry.daily_kwh.map(|kwh| rounding.entry(kwh));
+        }
+    }
+    usage.entries.retain(|entry| req.flag_filter.keeps(&entry.flags));
+}
+
+/// `fetch_usage`, through `USAGE_CACHE_TTL` when it is set. A result past
+/// its TTL but within `USAGE_CACHE_STALE` is returned as is while one
+/// background task per request fetches it again for the next caller.
+/// Results for readings older than `SETTLED_AFTER` are kept for
+/// `SETTLED_TTL` without ever going stale.
+async fn cached_usage(state: &AppState, req: &UsageRequest) -> Result<Usage, Error> {
+    let Some(cache) = &state.usage_cache else {
+        return fetch_usage(state, req).await;
+    };
+    let key = req.cache_key(state);
+    let (usage, status) = match cache.get(&key) {
+        Some((usage, status)) => (usage, status),
+        None => {
+            let usage = match fetch_usage(state, req).await {
+                Ok(usage) => usage,
+                Err(error) => return last_known(state, req, &key, &error).await.ok_or(error),
+            };
+            store_usage(state, req, key.clone(), usage.clone());
+            (usage, CacheStatus::Miss)
+        }
+    };
+    metrics::counter!(USAGE_CACHE_REQUESTS_TOTAL, "result" => status.name()).increment(1);
+    if status == CacheStatus::Stale && cache.begin_refresh(&key) {
+        let (state, req) = (state.clone(), req.clone());
+        let tunables = state.config.tunables();
+        tokio::spawn(reload::with_tunables(tunables, async move {
+            match fetch_usage(&state, &req).await {
+                Ok(usage) => store_usage(&state, &req, key.clone(), usage),
+                Err(error) => tracing::warn!(target = %req.target, "Usage cache refresh failed: {}", error),
+            }
+            if let Some(cache) = &state.usage_cache {
+                cache.end_refresh(&key);
+            }
+        }));
+    }
+    Ok(Usage {
+        cache: Some(status),
+        ..usage
+    })
+}
+
+/// With `fallback=last_known`, when `error` says Prometheus is down: the
+/// last result the usage cache keeps for `key` past its windows, or else
+/// the one `CACHE_DIR` stored, marked degraded.
+async fn last_known(state: &AppState, req: &UsageRequest, key: &String, error: &Error) -> Option<Usage> {
+    let outage = error.failure().is_some_and(|failure| failure.kind.is_outage());
+    if req.fallback != Fallback::LastKnown || !outage {
+        return None;
+    }
+    let usage = match state.usage_cache.as_ref().and_then(|cache| cache.last_known(key)) {
+        Some(usage) => usage,
+        None => state.disk_cache.as_ref()?.last_known(key).await?,
+    };
+    tracing::warn!(
+        target = %req.target,
+        as_of = %usage.fetched_at,
+        "Prometheus is down, serving the last known usage"
+    );
+    metrics::counter!(DEGRADED_RESPONSES_TOTAL).increment(1);
+    Some(Usage {
+        degraded: true,
+        cache: Some(CacheStatus::Stale),
+        ..usage
+    })
+}
+
+/// Fetches `req` into the usage cache regardless of what it holds.
+pub async fn warm_usage(state: &AppState, req: &UsageRequest) -> Result<(), Error> {
+    let usage = fetch_usage(state, req).await?;
+    store_usage(state, req, req.cache_key(state), usage);
+    Ok(())
+}
+
+fn store_usage(state: &AppState, req: &UsageRequest, key: String, usage: Usage) {
+    let Some(cache) = &state.usage_cache else {
+        return;
+    };
+    if req.curr_dt < Utc::now() - SETTLED_AFTER {
+        if let Some(disk_cache) = &state.disk_cache {
+            disk_cache.queue(key.clone(), usage.clone());
+        }
+        cache.insert_for(key, usage, SETTLED_TTL, std::time::Duration::ZERO);
+    } else {
+        cache.insert(key, usage);
+    }
+}
+
+/// `query_usage` on its own, past the cache and any identical request
+/// running, so `debug=true` sees the stats of every query it needs.
+pub async fn uncached_usage(state: &AppState, req: &UsageRequest) -> Result<Usage, Error> {
+    let mut usage = query_usage(state, req).await?;
+    if req.estimate {
+        estimate::add_estimates(state, req, &mut usage).await?;
+    }
+    finish_entries(req, &mut usage);
+    Ok(usage)
+}
+
+/// `query_usage`, shared with any identical request already running so
+/// concurrent duplicates cost one set of Prometheus queries.
+async fn fetch_usage(state: &AppState, req: &UsageRequest) -> Result<Usage, Error> {
+    let lead = || async {
+        let queries = Arc::new(AtomicUsize::new(0));
+        let counted = state.with_backend(state.prometheus.clone().counting(queries.clone()));
+        let usage = query_usage(&counted, req).await;
+        SharedUsage {
+            usage,
+            queries: queries.load(Ordering::Relaxed),
+            request_id: request_id::current(),
+        }
+    };
+    let (flight, shared) = state.in_flight.run(req.cache_key(state), lead).await;
+    if shared {
+        metrics::counter!(USAGE_REQUESTS_COALESCED_TOTAL).increment(1);
+        state.prometheus.note_completed(flight.queries);
+        let leader = flight.request_id.as_deref().unwrap_or("-");
+        tracing::info!(leader_request_id = %leader, "Usage query coalesced with an identical request");
+    }
+    flight.usage
+}
+
+/// `zoned_usage`, with `MAX_RESPONSE_ROWS` enforced on the merged result,
+/// and the corrections and then the `ALIASES_FILE` composites applied.
+async fn query_usage(state: &AppState, req: &UsageRequest) -> Result<Usage, Error> {
+    let mut usage = zoned_usage(state, req).await?;
+    ResponseSize::of_entries(&usage.entries).check_rows(&state.config.tunables())?;
+    if !req.ignore_corrections {
+        correct(state, req, &mut usage.entries);
+    }
+    usage.entries = compose(state, req, usage.entries);
+    Ok(usage)
+}
+
+/// Fetches both readings and pairs them per instance. `MAX_INSTANCES` is
+/// enforced on the current readings, before the previous ones are asked
+/// for. With `TIMEZONES_FILE` the instances of each zone are read at the
+/// requested time in that zone, two queries per zone, all zones count
+/// together towards the limit, and the results are merged, each skipped
+/// series once.
+async fn zoned_usage(state: &AppState, req: &UsageRequest) -> Result<Usage, Error> {
+    let zones: Vec<(UsageRequest, Option<Tz>)> = match state.config.timezones.is_configured() {
+        true => zone_requests(state, req)?
+            .into_iter()
+            .map(|req| {
+                let timezone = req.local_dt.timezone();
+                (req, Some(timezone))
+            })
+            .collect(),
+        false => vec![(req.clone(), None)],
+    };
+    let prometheus = &state.prometheus;
+    let mut currents = future::try_join_all(
+        zones.iter().map(|(req, _)| prometheus.get_current(&req.selector, req.curr_dt, req.prev_dt)),
+    )
+    .await?;
+    let mut data: Vec<_> = currents.iter_mut().map(|current| &mut current.data).collect();
+    let truncated_from = limit_instances(state, &mut data, req.truncate)?;
+    let usages = zones
+        .iter()
+        .zip(currents)
+        .map(|((req, timezone), current)| zone_usage(state, req, *timezone, current));
+    let usages = future::try_join_all(usages).await?;
+
+    let (mut entries, mut skipped_series) = (Vec::new(), Vec::new());
+    let (mut matched, mut discarded_series) = (false, 0);
+    for usage in usages {
+        matched |= usage.matched;
+        discarded_series += usage.discarded_series;
+        entries.extend(usage.entries);
+        skipped_series.extend(usage.skipped_series);
+    }
+    entries.sort_by(|a, b| a.instance.cmp(&b.instance));
+    prometheus::dedup_skipped(&mut skipped_series);
+    Ok(Usage {
+        entries,
+        truncated_from,
+        matched,
+        discarded_series,
+        skipped_series,
+        fetched_at: Utc::now(),
+        degraded: false,
+        cache: None,
+    })
+}
+
+/// `req` in each zone of `TIMEZONES_FILE`, narrowed to its instances.
+fn zone_requests(state: &AppState, req: &UsageRequest) -> Result<Vec<UsageRequest>, StatusCode> {
+    let groups = state.config.timezones.groups().into_iter();
+    groups.map(|(tz, matchers)| req.in_zone(tz.unwrap_or(req.local_dt.timezone()), &matchers)).collect()
+}
+
+/// Readings are paired per instance on address and phase, and entries
+/// without a previous reading are kept with `prev_kwh: None` so each API
+/// version decides what to show. `timezone` is recorded on every entry when given.
+async fn zone_usage(
+    state: &AppState,
+    req: &UsageRequest,
+    timezone: Option<Tz>,
+    current: Current,
+) -> Result<Usage, Error> {
+    let prometheus = &state.prometheus;
+    let last_week = async {
+        let Some((curr_dt, prev_dt)) = req.last_week else {
+            return Ok(None);
+        };
+        Ok(Some(prometheus.get_pair(&req.selector, curr_dt, prev_dt).await?))
+    };
+    let gauge = async {
+        if !req.power_gauge {
+            return Ok(None);
+        }
+        let power = selector::with_name(&req.selector, "power");
+        let period = (req.curr_dt - req.prev_dt).to_std().unwrap_or_default();
+        Ok(Some(prometheus.get_average(&power, req.curr_dt, period).await?))
+    };
+    let (pair, last_week, gauge) = tokio::try_join!(prometheus.get_previous(current), last_week, gauge)?;
+    let Pair { curr: mut curr_data, prev: mut prev_data, mut skipped } = pair;
+    let last_week = last_week.map(|week: Pair| {
+        skipped.extend(week.skipped);
+        (week.curr, week.prev)
+    });
+    let gauge = gauge.map(|gauge: Parsed| {
+        skipped.extend(gauge.skipped);
+        gauge.data
+    });
+    let matched = !curr_data.is_empty() || !prev_data.is_empty();
+    let tunables = state.config.tunables();
+    // The previous reading follows the job kept for the current one, so a
+    // delta never spans two jobs' counters.
+    let mut duplicates = dedupe_series(&mut curr_data, |_, s| is_preferred_job(&tunables, s));
+    let prev_duplicates = dedupe_series(&mut prev_data, |instance, s| {
+        same_series(curr_data.get(instance), s).is_some_and(|curr| job(curr) == job(s))
+    });
+    for (series, count) in prev_duplicates {
+        let discarded = duplicates.entry(series).or_default();
+        *discarded = (*discarded).max(count);
+    }
+
+    let aliases = state.config.aliases.current();
+    let nominal_hours = hours_between(req.prev_dt, req.curr_dt);
+    let mut entries = Vec::new();
+
+    for (instance, curr_values) in curr_data {
+        let prev_values = prev_data.get(&instance);
+
+        for curr in curr_values {
+            // By address and phase, never by position: a meter missing on
+            // either day must not shift its neighbours' readings.
+            let prev = same_series(prev_values, &curr);
+            let daily = prev.map(|p| curr.value - p.value);
+            let mut flags = Flags::default();
+            flags.set_if(flags::MISSING_PREV, prev.is_none());
+            let duplicate = duplicates.contains_key(&meter_series(&instance, &curr.address, &curr.labels));
+            flags.set_if(flags::DUPLICATE_SERIES, duplicate);
+            let (prev_kwh, meter) = (prev.map(|p| p.value), (instance.as_str(), curr.address.as_str()));
+            let implausible = is_implausible(&tunables, prev_kwh, Some(curr.value));
+            if implausible {
+                note_implausible(state, meter, (req.prev_dt, req.curr_dt), prev_kwh, Some(curr.value));
+            }
+            flags.set_if(flags::IMPLAUSIBLE, implausible);
+
+            let comparison = last_week.as_ref().map(|(week_curr, week_prev)| {
+                let week_curr = same_series(week_curr.get(&instance), &curr);
+                let week_prev = same_series(week_prev.get(&instance), &curr);
+                let last_week_kwh = week_curr.zip(week_prev).map(|(c, p)| c.value - p.value);
+                WeekComparison::new(daily, last_week_kwh)
+            });
+
+            let hours = prev.map(|p| period_hours(p.timestamp, curr.timestamp, nominal_hours));
+            let avg_power = daily.zip(hours).map(|(kwh, hours)| avg_power_watt(kwh, hours));
+            let power_gauge = gauge
+                .as_ref()
+                .and_then(|gauge| same_series(gauge.get(&instance), &curr))
+                .map(|gauge| PowerGauge::new(gauge.value, avg_power));
+            let mismatch_percent = tunables.power_mismatch_percent;
+            let mismatch = power_gauge.as_ref().is_some_and(|g| g.is_mismatch(mismatch_percent));
+            flags.set_if(flags::POWER_MISMATCH, mismatch);
+            let name = aliases.name(&instance, &curr.address).map(str::to_string);
+            let threshold_kwh = tunables
+                .thresholds
+                .for_meter(&instance, &curr.address, name.as_deref())
+                .or(req.threshold_kwh);
+            entries.push(UsageEntry {
+                instance: instance.clone(),
+                address: curr.address,
+                name,
+                timezone,
+                prev_kwh: prev.map(|p| p.value),
+                curr_kwh: curr.value,
+                daily_kwh: daily,
+                avg_power_watt: avg_power,
+                period_hours: hours.map(|h| (h * 1000.0).round() / 1000.0),
+                avg_power_watt_24h: daily.map(|kwh| avg_power_watt(kwh, 24.0)),
+                prev_sample_time: prev.and_then(|p| p.timestamp),
+                curr_sample_time: curr.timestamp,
+                flags,
+                labels: curr.labels,
+                threshold_kwh,
+                comparison,
+                power_gauge,
+                members: None,
+                part_of: None,
+                estimate: None,
+                correction: None,
+                sample_age_seconds: Some(SampleAge {
+                    prev: prev.and_then(|p| sample_age(req.prev_dt, p.timestamp)),
+                    curr: sample_age(req.curr_dt, curr.timestamp),
+                }),
+            });
+        }
+    }
+
+    if let Some(address) = &req.address {
+        entries.retain(|e| &e.address == address);
+    }
+    entries.sort_by(|a, b| a.instance.cmp(&b.instance));
+    let discarded_series = entries
+        .iter()
+        .filter_map(|e| duplicates.get(&meter_series(&e.instance, &e.address, &e.labels)))
+        .sum();
+    observe_sample_ages(state, req, &entries);
+
+    Ok(Usage {
+        entries,
+        truncated_from: None,
+        matched,
+        discarded_series,
+        skipped_series: skipped,
+        fetched_at: Utc::now(),
+        degraded: false,
+        cache: None,
+    })
+}
+
+/// Puts the corrections of the day `req` covers in place of the computed
+/// consumption. Only a request from one local midnight to the next covers
+/// a day, and only whole meters are corrected, never their phases.
+fn correct(state: &AppState, req: &UsageRequest, entries: &mut [UsageEntry]) {
+    if req.naive.time() != chrono::NaiveTime::MIN {
+        return;
+    }
+    let Some(day) = req.naive.date().pred_opt() else {
+        return;
+    };
+    let hours = hours_between(req.prev_dt, req.curr_dt);
+    for entry in entries.iter_mut().filter(|e| !e.labels.contains_key(PHASE_LABEL)) {
+        let Some(correction) = state.config.corrections.find(&entry.instance, &entry.address, day) else {
+            continue;
+        };
+        let kwh = correction.corrected_daily_kwh;
+        entry.correction = Some(AppliedCorrection {
+            correction,
+            computed_daily_kwh: entry.daily_kwh,
+        });
+        entry.daily_kwh = Some(kwh);
+        entry.avg_power_watt = Some(avg_power_watt(kwh, entry.period_hours.unwrap_or(hours)));
+        entry.avg_power_watt_24h = Some(avg_power_watt(kwh, 24.0));
+        entry.comparison = entry.comparison.take().map(|c| WeekComparison::new(Some(kwh), c.last_week_kwh));
+        entry.flags.unset(flags::IMPLAUSIBLE);
+        entry.flags.set(flags::CORRECTED);
+    }
+}
+
+fn sample_age(at: DateTime<Utc>, sample_time: Option<DateTime<Utc>>) -> Option<f64> {
+    sample_time.map(|time| (at - time).num_milliseconds() as f64 / 1000.0)
+}
+
+/// Adds each reading's age to `SAMPLE_AGE_SECONDS` by target, and logs any
+/// older than the lookback, or `FALLBACK_LOOKBACK` when set, which
+/// `last_over_time` should never have returned.
+fn observe_sample_ages(state: &AppState, req: &UsageRequest, entries: &[UsageEntry]) {
+    let window = state.prometheus.fallback_lookback().unwrap_or_else(|| state.prometheus.lookback());
+    let lookback = parse_duration(&window).map(|lookback| lookback.as_secs_f64());
+    let histogram = metrics::histogram!(SAMPLE_AGE_SECONDS, "target" => req.target.clone());
+    for entry in entries {
+        let Some(ages) = &entry.sample_age_seconds else {
+            continue;
+        };
+        for (reading, age) in [("prev", ages.prev), ("curr", ages.curr)] {
+            let Some(age) = age else {
+                continue;
+            };
+            histogram.record(age);
+            if lookback.is_some_and(|lookback| age > lookback) {
+                let (instance, address) = (entry.instance.as_str(), entry.address.as_str());
+                tracing::warn!(instance, address, reading, age, "Sample older than the lookback");
+            }
+        }
+    }
+}
+
+/// Replaces the members of each `ALIASES_FILE` composite found in `entries`
+/// with one entry holding their sums, in place of the first member. With
+/// `expand_composites=true` the members stay, marked `part_of`, and the sum
+/// follows the last. Left alone when `target_name` picked a single address.
+fn compose(state: &AppState, req: &UsageRequest, entries: Vec<UsageEntry>) -> Vec<UsageEntry> {
+    let aliases = state.config.aliases.current();
+    if aliases.composites().is_empty() || req.address.is_some() {
+        return entries;
+    }
+    let mut slots: Vec<Option<UsageEntry>> = entries.into_iter().map(Some).collect();
+    let mut sums: BTreeMap<usize, Vec<UsageEntry>> = BTreeMap::new();
+    for composite in aliases.composites() {
+        let is_member = |e: &UsageEntry| {
+            composite.members.iter().any(|(instance, address)| *instance == e.instance && *address == e.address)
+        };
+        let positions: Vec<usize> =
+            (0..slots.len()).filter(|&i| slots[i].as_ref().is_some_and(is_member)).collect();
+        let (Some(&first), Some(&last)) = (positions.first(), positions.last()) else {
+            continue;
+        };
+        let members: Vec<UsageEntry> = positions
+            .iter()
+            .filter_map(|&i| match req.expand_composites {
+                true => {
+                    let member = slots[i].as_mut()?;
+                    member.part_of = Some(composite.name.clone());
+                    Some(member.clone())
+                }
+                false => slots[i].take(),
+            })
+            .collect();
+        let at = if req.expand_composites { last } else { first };
+        sums.entry(at).or_default().push(composite_entry(state, req, composite, &members));
+    }
+    let mut entries = Vec::new();
+    for (i, slot) in slots.into_iter().enumerate() {
+        entries.extend(slot);
+        entries.extend(sums.remove(&i).into_iter().flatten());
+    }
+    entries
+}
+
+/// The entry of `composite` from those of its `members` found. Each member's
+/// counters were differenced on their own, so one CT resetting only shows in
+/// its own delta; a figure any member lacks is missing from the sum too.
+fn composite_entry(
+    state: &AppState,
+    req: &UsageRequest,
+    composite: &Composite,
+    members: &[UsageEntry],
+) -> UsageEntry {
+    let sum = |figure: fn(&UsageEntry) -> Option<f64>| members.iter().map(figure).sum::<Option<f64>>();
+    let daily_kwh = sum(|e| e.daily_kwh);
+    let avg_power_watt = sum(|e| e.avg_power_watt).map(|watt| (watt * 100.0).round() / 100.0);
+
+    let mut flags = Flags::default();
+    for member in members {
+        flags.union(&member.flags);
+    }
+    let found = |(instance, address): &(String, String)| {
+        members.iter().any(|e| e.instance == *instance && e.address == *address)
+    };
+    flags.set_if(flags::PARTIAL_COMPOSITE, !composite.members.iter().all(found));
+
+    let comparison = members.iter().any(|e| e.comparison.is_some()).then(|| {
+        let last_week_kwh = members
+            .iter()
+            .map(|e| e.comparison.as_ref().and_then(|c| c.last_week_kwh))
+            .sum::<Option<f64>>();
+        WeekComparison::new(daily_kwh, last_week_kwh)
+    });
+    let power_gauge = members
+        .iter()
+        .map(|e| e.power_gauge.as_ref().map(|g| g.avg_power_watt_gauge))
+        .sum::<Option<f64>>()
+        .map(|watt| PowerGauge::new(watt, avg_power_watt));
+    let tunables = state.config.tunables();
+    let mismatch = power_gauge.as_ref().is_some_and(|g| g.is_mismatch(tunables.power_mismatch_percent));
+    flags.set_if(flags::POWER_MISMATCH, mismatch);
+
+    let mut labels = members.first().map(|e| e.labels.clone()).unwrap_or_default();
+    labels.retain(|name, value| members.iter().all(|e| e.labels.get(name) == Some(value)));
+    let name = &composite.name;
+    UsageEntry {
+        instance: name.clone(),
+        address: COMPOSITE_ADDRESS.to_string(),
+        name: Some(name.clone()),
+        timezone: members.first().and_then(|e| e.timezone),
+        prev_kwh: sum(|e| e.prev_kwh),
+        curr_kwh: members.iter().map(|e| e.curr_kwh).sum(),
+        daily_kwh,
+        avg_power_watt,
+        period_hours: members.iter().filter_map(|e| e.period_hours).reduce(f64::max),
+        avg_power_watt_24h: sum(|e| e.avg_power_watt_24h),
+        prev_sample_time: members.iter().filter_map(|e| e.prev_sample_time).min(),
+        curr_sample_time: members.iter().filter_map(|e| e.curr_sample_time).min(),
+        flags,
+        labels,
+        threshold_kwh: tunables
+            .thresholds
+            .for_meter(name, COMPOSITE_ADDRESS, Some(name))
+            .or(req.threshold_kwh),
+        comparison,
+        power_gauge,
+        members: Some(composite.members.iter().map(|(i, a)| format!("{}/{}", i, a)).collect()),
+        part_of: None,
+        estimate: None,
+        sample_age_seconds: None,
+        correction: None,
+    }
+}
+
+/// Sums per-meter deltas, and average powers, by the value of `label`. Raw counter readings are
+/// never added together, only each meter's own daily delta. A composite
+/// whose members are listed too is skipped, so nothing is counted twice.
+/// With `rounding`, each group's `daily_kwh` is the policy's total of its
+/// meters'.
+pub fn group_usage(
+    entries: &[UsageEntry],
+    label: &str,
+    include_implausible: bool,
+    rounding: Option<RoundingPolicy>,
+) -> Vec<GroupUsage> {
+    let expanded: HashSet<&str> = entries.iter().filter_map(|e| e.part_of.as_deref()).collect();
+    let mut groups: BTreeMap<&str, GroupUsage> = BTreeMap::new();
+    for entry in entries {
+        if entry.members.is_some() && expanded.contains(entry.instance.as_str()) {
+            continue;
+        }
+        let key = entry.labels.get(label).map_or(UNLABELLED, String::as_str);
+        let group = groups.entry(key).or_insert_with(|| GroupUsage {
+            group: key.to_string(),
+            daily_kwh: 0.0,
+            avg_power_watt: 0.0,
+            meters: 0,
+            missing_prev: 0,
+            implausible: 0,
+            meter_kwh: Vec::new(),
+        });
+        if entry.is_implausible() && !include_implausible {
+            group.implausible += 1;
+            continue;
+        }
+        match entry.daily_kwh.zip(entry.avg_power_watt) {
+            Some((daily, watt)) => {
+                group.daily_kwh += daily;
+                group.avg_power_watt += watt;
+                group.meter_kwh.push(daily);
+                group.meters += 1;
+            }
+            None => group.missing_prev += 1,
+        }
+    }
+
+    groups
+        .into_values()
+        .map(|mut group| {
+            // Each meter's average already uses its own period, so the
+            // group's is their sum.
+            group.avg_power_watt = (group.avg_power_watt * 100.0).round() / 100.0;
+            if let Some(rounding) = rounding {
+                group.daily_kwh = rounding.total(group.meter_kwh.iter().copied());
+            }
+            group
+        })
+        .collect()
+}
+
+/// Largest deviation of any phase's consumption from the mean of all
+/// phases, as a percentage of that mean.
+pub fn imbalance_percent(daily_kwh: &[f64]) -> Option<f64> {
+    let mean = daily_kwh.iter().sum::<f64>() / daily_kwh.len() as f64;
+    if daily_kwh.len() < 2 || mean == 0.0 {
+        return None;
+    }
+    let max_deviation = daily_kwh
+        .iter()
+        .map(|d| (d - mean).abs())
+        .fold(0.0, f64::max);
+    Some((max_deviation / mean * 10000.0).round() / 100.0)
+}
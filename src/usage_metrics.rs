@@ -0,0 +1,171 @@
+use axum::{extract::State, http::header, response::IntoResponse};
+use chrono::{DateTime, Utc};
+use std::{collections::BTreeMap, fmt::Write, sync::Mutex};
+
+use crate::{
+    period::{local_midnight, previous_day},
+    range::daily_usage,
+    remote_write::DailySample,
+    selector,
+    state::AppState,
+};
+
+/// Each gauge's value from a meter's daily kWh.
+type Gauge = (&'static str, &'static str, fn(f64) -> f64);
+
+const GAUGES: [Gauge; 2] = [
+    (
+        "power_usage_daily_kwh",
+        "Energy used on the most recent completed local day",
+        |kwh| kwh,
+    ),
+    (
+        "power_usage_avg_watt",
+        "Average power over the most recent completed local day",
+        |kwh| kwh / 24.0 * 1000.0,
+    ),
+];
+
+/// Last computed figures for one meter.
+struct Exported {
+    daily_kwh: f64,
+    /// When the meter last had both readings of a completed day.
+    last_seen: DateTime<Utc>,
+    /// Whether `daily_kwh` has reached `REMOTE_WRITE_URL`.
+    pushed: bool,
+}
+
+/// Derived daily usage of `USAGE_METRICS_TARGETS`, exposed on
+/// `/metrics/usage` and recomputed every `USAGE_METRICS_INTERVAL`.
+#[derive(Default)]
+pub struct UsageMetrics {
+    series: Mutex<BTreeMap<(String, String), Exported>>,
+}
+
+impl UsageMetrics {
+    /// Computes the most recent completed local day for every configured
+    /// target, then drops meters unseen for longer than `USAGE_METRICS_STALE`.
+    /// New or changed values are pushed when remote write is configured.
+    pub async fn refresh(&self, state: &AppState) {
+        let config = &state.config;
+        let now = Utc::now();
+        let today = now.with_timezone(&config.timezone).date_naive();
+        let Some(yesterday) = previous_day(today) else {
+            return;
+        };
+
+        let mut fresh = Vec::new();
+        for target in &config.usage_metrics_targets {
+            match daily_usage(state, &selector::energy(target, &[]), yesterday, 1).await {
+                Ok(series) => fresh.extend(series),
+                Err(code) => tracing::warn!(target = %target, "Usage metrics refresh failed: {}", code),
+            }
+        }
+
+        let computed: Vec<(String, String, f64)> = fresh
+            .into_iter()
+            .filter_map(|meter| Some((meter.instance, meter.address, meter.days.first()?.1?)))
+            .collect();
+        let to_push = {
+            let mut series = self.series.lock().unwrap();
+            for (instance, address, daily_kwh) in computed.iter().cloned() {
+                let key = (instance, address);
+                let pushed = series.get(&key).is_some_and(|e| e.pushed && e.daily_kwh == daily_kwh);
+                let exported = Exported {
+                    daily_kwh,
+                    last_seen: now,
+                    pushed,
+                };
+                series.insert(key, exported);
+            }
+            let stale_after = config.usage_metrics_stale;
+            series.retain(|_, e| (now - e.last_seen).to_std().unwrap_or_default() <= stale_after);
+
+            series
+                .iter()
+                .filter(|(_, e)| !e.pushed)
+                .map(|((instance, address), e)| (instance.clone(), address.clone(), e.daily_kwh))
+                .collect::<Vec<_>>()
+        };
+
+        let Some(timestamp) = local_midnight(yesterday, config.timezone) else {
+            return;
+        };
+        if let Some(textfile) = &config.textfile {
+            textfile.write(&computed, timestamp).await;
+        }
+        let Some(remote_write) = &config.remote_write else {
+            return;
+        };
+        let samples: Vec<DailySample> = to_push
+            .into_iter()
+            .map(|(instance, address, daily_kwh)| DailySample {
+                instance,
+                address,
+                daily_kwh,
+                timestamp,
+            })
+            .collect();
+        if remote_write.push(&samples).await {
+            let mut series = self.series.lock().unwrap();
+            for sample in samples {
+                if let Some(e) = series.get_mut(&(sample.instance, sample.address)) {
+                    e.pushed |= e.daily_kwh == sample.daily_kwh;
+                }
+            }
+        }
+    }
+
+    fn render(&self) -> String {
+        let series = self.series.lock().unwrap();
+        let mut body = String::new();
+        write_gauges(&mut body, series.iter().map(|((i, a), e)| (i.as_str(), a.as_str(), e.daily_kwh)));
+        body
+    }
+}
+
+/// `GAUGES` for every `(instance, address, daily_kwh)` of `meters`, in the
+/// text exposition format.
+pub fn write_gauges<'a>(body: &mut String, meters: impl Iterator<Item = (&'a str, &'a str, f64)> + Clone) {
+    for (name, help, value) in GAUGES {
+        writeln!(body, "# HELP {} {}", name, help).ok();
+        writeln!(body, "# TYPE {} gauge", name).ok();
+        for (instance, address, daily_kwh) in meters.clone() {
+            writeln!(
+                body,
+                "{}{{instance=\"{}\",address=\"{}\"}} {}",
+                name,
+                escape_label(instance),
+                escape_label(address),
+                value(daily_kwh)
+            )
+            .ok();
+        }
+    }
+}
+
+pub fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Recomputes the usage metrics forever; does nothing without targets.
+pub async fn refresh_loop(state: AppState) {
+    if state.config.usage_metrics_targets.is_empty() {
+        return;
+    }
+    let mut interval = tokio::time::interval(state.config.usage_metrics_interval);
+    loop {
+        interval.tick().await;
+        state.usage_metrics.refresh(&state).await;
+    }
+}
+
+pub async fn usage_metrics_handler(State(state): State<AppState>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4; charset=utf-8")],
+        state.usage_metrics.render(),
+    )
+}
@@ -0,0 +1,55 @@
+use axum::{
+    extract::Request,
+    http::{HeaderName, HeaderValue},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+/// The short commit the binary was built from, or `unknown` outside a git
+/// checkout; set by `build.rs`.
+pub const GIT_COMMIT: &str = env!("GIT_COMMIT");
+/// Seconds since the epoch when `build.rs` ran, or `SOURCE_DATE_EPOCH`.
+const BUILD_TIMESTAMP: &str = env!("BUILD_TIMESTAMP");
+
+pub const BUILD_INFO: &str = "build_info";
+
+static X_APP_VERSION: HeaderName = HeaderName::from_static("x-app-version");
+
+pub fn built_at() -> Option<DateTime<Utc>> {
+    DateTime::from_timestamp(BUILD_TIMESTAMP.parse().ok()?, 0)
+}
+
+/// `0.1.0+7595b9c`: the version with the commit as build metadata.
+pub fn full_version() -> String {
+    format!("{}+{}", VERSION, GIT_COMMIT)
+}
+
+#[derive(Serialize)]
+struct VersionResponse {
+    version: &'static str,
+    commit: &'static str,
+    built_at: Option<DateTime<Utc>>,
+}
+
+/// `GET /version`: which build is running.
+pub async fn version_handler() -> Response {
+    Json(VersionResponse {
+        version: VERSION,
+        commit: GIT_COMMIT,
+        built_at: built_at(),
+    })
+    .into_response()
+}
+
+/// Adds `X-App-Version` to every response.
+pub async fn version_header_middleware(req: Request, next: Next) -> Response {
+    let mut response = next.run(req).await;
+    if let Ok(version) = HeaderValue::try_from(full_version()) {
+        response.headers_mut().insert(X_APP_VERSION.clone(), version);
+    }
+    response
+}
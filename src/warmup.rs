@@ -0,0 +1,120 @@
+use chrono::{DateTime, NaiveDate, NaiveTime, Utc};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+    time::Duration,
+};
+use tokio::sync::oneshot;
+
+use crate::{
+    error::Error,
+    period::local_midnight,
+    state::AppState,
+    usage::{warm_usage, UsageRequest},
+};
+
+/// How long after local midnight the daily warmup runs, so the day's last
+/// samples have been scraped.
+const RUN_DELAY: Duration = Duration::from_secs(5 * 60);
+/// Attempts per target, with the delay doubling from `RETRY_DELAY` between them.
+const ATTEMPTS: u32 = 4;
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// The last warmup of one `WARM_TARGETS` expression.
+#[derive(Clone, Default, Serialize)]
+pub struct WarmTarget {
+    last_refreshed: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_error: Option<String>,
+}
+
+/// When each of `WARM_TARGETS` was last put into the usage cache.
+#[derive(Default)]
+pub struct Warmup {
+    targets: Mutex<BTreeMap<String, WarmTarget>>,
+}
+
+impl Warmup {
+    /// Every one of `names`, including those not warmed yet.
+    pub fn targets(&self, names: &[String]) -> BTreeMap<String, WarmTarget> {
+        let targets = self.targets.lock().unwrap();
+        names
+            .iter()
+            .map(|name| (name.clone(), targets.get(name).cloned().unwrap_or_default()))
+            .collect()
+    }
+
+    fn record(&self, target: &str, result: Result<(), String>) {
+        let mut targets = self.targets.lock().unwrap();
+        let entry = targets.entry(target.to_string()).or_default();
+        match result {
+            Ok(()) => {
+                entry.last_refreshed = Some(Utc::now());
+                entry.last_error = None;
+            }
+            Err(e) => entry.last_error = Some(e),
+        }
+    }
+}
+
+/// Computes "yesterday" and "today so far" for `target` as a plain
+/// `/api/v1/power-usage` request would.
+async fn warm_once(state: &AppState, target: &str, today: NaiveDate) -> Result<(), Error> {
+    let now = Utc::now().with_timezone(&state.config.timezone).time();
+    for time in [NaiveTime::MIN, now] {
+        let params = HashMap::from([
+            ("target".to_string(), target.to_string()),
+            ("date".to_string(), today.to_string()),
+            ("time".to_string(), time.format("%H:%M").to_string()),
+        ]);
+        warm_usage(state, &UsageRequest::from_params(&params, state)?).await?;
+    }
+    Ok(())
+}
+
+async fn warm_target(state: &AppState, target: &str, today: NaiveDate) -> Result<(), String> {
+    let mut delay = RETRY_DELAY;
+    let mut attempt = 1;
+    loop {
+        match warm_once(state, target, today).await {
+            Ok(()) => return Ok(()),
+            Err(code) if attempt == ATTEMPTS => return Err(code.to_string()),
+            Err(code) => tracing::warn!(target = %target, attempt, "Cache warmup failed: {}", code),
+        }
+        tokio::time::sleep(delay).await;
+        delay *= 2;
+        attempt += 1;
+    }
+}
+
+async fn warm_all(state: &AppState) {
+    let today = Utc::now().with_timezone(&state.config.timezone).date_naive();
+    for target in &state.config.warm_targets {
+        let result = warm_target(state, target, today).await;
+        if let Err(e) = &result {
+            tracing::error!(target = %target, "Cache warmup gave up: {}", e);
+        }
+        state.warmup.record(target, result);
+    }
+}
+
+/// Warms `WARM_TARGETS` now, signalling `ready` after the first pass, then
+/// again shortly after every local midnight. Does nothing without targets.
+pub async fn warm_loop(state: AppState, ready: oneshot::Sender<()>) {
+    if state.config.warm_targets.is_empty() {
+        return;
+    }
+    warm_all(&state).await;
+    ready.send(()).ok();
+    loop {
+        let tz = state.config.timezone;
+        let today = Utc::now().with_timezone(&tz).date_naive();
+        let Some(next) = today.succ_opt().and_then(|date| local_midnight(date, tz)) else {
+            return;
+        };
+        let wait = (next - Utc::now()).to_std().unwrap_or_default() + RUN_DELAY;
+        tokio::time::sleep(wait).await;
+        warm_all(&state).await;
+    }
+}
@@ -0,0 +1,1199 @@
+//! Boots the server with `BACKEND=fixture` on the responses in
+//! `tests/fixtures/` and checks the usage endpoints end to end, then
+//! compares the outputs of `tests/golden/` byte for byte.
+
+use serde_json::{json, Value};
+use std::{
+    net::TcpListener,
+    process::{Child, Command, Stdio},
+    time::{Duration, Instant},
+};
+
+const QUERY: &str = "target=meter-a.*&date=2025-08-01&time=00:00";
+
+/// The server process, killed when the test ends.
+struct Server {
+    child: Child,
+    base: String,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.child.kill().ok();
+        self.child.wait().ok();
+    }
+}
+
+impl Server {
+    /// Stops the server as `SIGTERM` does, so it flushes `CACHE_DIR` first.
+    fn stop(mut self) {
+        Command::new("kill").arg(self.child.id().to_string()).status().unwrap();
+        self.child.wait().unwrap();
+    }
+}
+
+async fn start() -> Server {
+    start_with("tests/fixtures", &[]).await
+}
+
+/// The server on the fixtures in `dir`, relative to the crate, with `env`
+/// set on top of the defaults.
+async fn start_with(dir: &str, env: &[(&str, &str)]) -> Server {
+    spawn(dir, env, Stdio::inherit()).await
+}
+
+/// `start_with` on `tests/fixtures`, with the log readable from the
+/// child's stdout.
+async fn start_logging(env: &[(&str, &str)]) -> Server {
+    spawn("tests/fixtures", env, Stdio::piped()).await
+}
+
+async fn spawn(dir: &str, env: &[(&str, &str)], stdout: Stdio) -> Server {
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let child = Command::new(env!("CARGO_BIN_EXE_power-usage"))
+        .env_clear()
+        .env("BACKEND", "fixture")
+        .env("FIXTURE_DIR", format!("{}/{}", env!("CARGO_MANIFEST_DIR"), dir))
+        .env("FIXTURE_LATENCY", "5ms")
+        .env("TIMEZONE", "Asia/Jakarta")
+        .env("BIND_ADDR", format!("127.0.0.1:{}", port))
+        .env("RUST_LOG", "warn")
+        .envs(env.iter().copied())
+        .stdout(stdout)
+        .spawn()
+        .expect("failed to start the server");
+    let server = Server {
+        child,
+        base: format!("http://127.0.0.1:{}", port),
+    };
+    for _ in 0..100 {
+        if reqwest::get(format!("{}/metrics", server.base)).await.is_ok() {
+            return server;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("the server did not start listening");
+}
+
+async fn get(server: &Server, path: &str) -> (u16, String) {
+    let response = reqwest::get(format!("{}{}", server.base, path)).await.unwrap();
+    (response.status().as_u16(), response.text().await.unwrap())
+}
+
+/// The server with a `KEYS_FILE` holding the key `<name>-key`, limited to
+/// `targets`, in a directory of its own for `test` that the caller removes.
+async fn start_with_keys(test: &str, name: &str, targets: &str) -> (Server, std::path::PathBuf) {
+    let dir = std::env::temp_dir().join(format!("power-usage-keys-{}-{}", test, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let keys_file = dir.join("keys.toml");
+    let keys = format!("[[key]]\nname = \"{0}\"\nkey = \"{0}-key\"\ntargets = \"{1}\"\n", name, targets);
+    std::fs::write(&keys_file, keys).unwrap();
+    let server = start_with("tests/fixtures", &[("KEYS_FILE", keys_file.to_str().unwrap())]).await;
+    (server, dir)
+}
+
+/// `path` asked for with `key` in `X-Api-Key`.
+async fn get_with_key(server: &Server, path: &str, key: &str) -> (u16, String) {
+    let client = reqwest::Client::new();
+    let response = client.get(format!("{}{}", server.base, path)).header("x-api-key", key).send().await.unwrap();
+    (response.status().as_u16(), response.text().await.unwrap())
+}
+
+/// `prometheus_requests_total{outcome="ok"}` so far.
+async fn queries_answered(server: &Server) -> u64 {
+    let (_, metrics) = get(server, "/metrics").await;
+    let prefix = "prometheus_requests_total{outcome=\"ok\"} ";
+    let count = metrics.lines().find_map(|line| line.strip_prefix(prefix));
+    count.map_or(0, |count| count.parse().unwrap())
+}
+
+#[tokio::test]
+async fn v1_json_and_csv() {
+    let server = start().await;
+
+    let (status, body) = get(&server, &format!("/api/v1/power-usage?{}", QUERY)).await;
+    assert_eq!(status, 200);
+    let body: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(
+        body,
+        json!({"meter-a:8899": [
+            {"prev_kwh": 1087.820601851852, "curr_kwh": 1097.820601851852, "daily_kwh": 10.0,
+             "avg_power_watt": 416.67, "period_hours": 24.0, "avg_power_watt_24h": 416.67},
+            {"prev_kwh": 1175.6412037037037, "curr_kwh": 1195.6412037037037, "daily_kwh": 20.0,
+             "avg_power_watt": 833.33, "period_hours": 24.0, "avg_power_watt_24h": 833.33},
+            {"prev_kwh": 1878.2060185185185, "curr_kwh": 1978.2060185185185, "daily_kwh": 100.0,
+             "avg_power_watt": 4166.67, "period_hours": 24.0, "avg_power_watt_24h": 4166.67},
+        ]})
+    );
+
+    let (status, body) = get(&server, &format!("/api/v1/power-usage?{}&csv=true", QUERY)).await;
+    assert_eq!(status, 200);
+    assert_eq!(
+        body,
+        "Target,Address,Prev_kWh,Current_kWh,Daily_KWh,Avg_Power_Watt\n\
+         meter-a:8899,1,1087.820601851852,1097.820601851852,10,416.67\n\
+         meter-a:8899,2,1175.6412037037037,1195.6412037037037,20,833.33\n\
+         meter-a:8899,10,1878.2060185185185,1978.2060185185185,100,4166.67\n"
+    );
+}
+
+#[tokio::test]
+async fn v2_json() {
+    let server = start().await;
+
+    let (status, body) = get(&server, &format!("/api/v2/power-usage?{}", QUERY)).await;
+    assert_eq!(status, 200);
+    let body: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body["meta"]["curr_time"], "2025-07-31T17:00:00Z");
+    assert_eq!(body["meta"]["prev_time"], "2025-07-30T17:00:00Z");
+    let results = body["results"].as_array().unwrap();
+    let daily: Vec<(&str, f64)> = results
+        .iter()
+        .map(|e| (e["address"].as_str().unwrap(), e["daily_kwh"].as_f64().unwrap()))
+        .collect();
+    assert_eq!(daily, [("1", 10.0), ("2", 20.0), ("10", 100.0)]);
+}
+
+/// `golden-a:9100` address 1 is also scraped by a stale `meters-old` job.
+#[tokio::test]
+async fn duplicate_series_keep_one_job() {
+    let query = "/api/v2/power-usage?target=.*&date=2025-08-01&time=00:00";
+    for (env, daily_kwh) in [(&[][..], 12.5), (&[("PREFER_JOB", "meters-old")][..], 4.0)] {
+        let server = start_with("tests/golden/fixtures", env).await;
+
+        let (status, body) = get(&server, query).await;
+        assert_eq!(status, 200);
+        let body: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["meta"]["duplicate_series"], true);
+        assert_eq!(body["meta"]["discarded_series"], 1);
+        let entry = body["results"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|e| e["instance"] == "golden-a:9100" && e["address"] == "1")
+            .unwrap();
+        assert_eq!(entry["daily_kwh"], daily_kwh, "with {:?}", env);
+        assert_eq!(entry["flags"], json!(["duplicate_series"]));
+    }
+}
+
+/// `migrating:9100` address 1 first appears on the current day, from an
+/// `old` and a `new` job at once, so the two days have different series.
+#[tokio::test]
+async fn duplicate_series_on_one_day_pair_by_address() {
+    let query = "/api/v2/power-usage?target=migrating.*&date=2025-08-02&time=00:00";
+    let server = start_with("tests/fixtures", &[]).await;
+    let (status, body) = get(&server, query).await;
+    assert_eq!(status, 200, "{}", body);
+    let body: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body["meta"]["discarded_series"], 1);
+    let results = body["results"].as_array().unwrap().iter();
+    let pairs: Vec<Value> = results.map(|e| json!([e["address"], e["daily_kwh"], e["flags"]])).collect();
+    assert_eq!(
+        pairs,
+        [
+            json!(["1", null, ["missing_prev", "duplicate_series"]]),
+            json!(["2", 20.0, []]),
+            json!(["3", 30.0, []]),
+        ]
+    );
+}
+
+/// `gappy:9100` reads address 1 only on the previous day and address 2
+/// only on the current one.
+#[tokio::test]
+async fn readings_pair_by_address() {
+    let query = "target=gappy.*&date=2025-08-02&time=00:00";
+    let server = start_with("tests/fixtures", &[]).await;
+
+    let (status, body) = get(&server, &format!("/api/v2/power-usage?{}", query)).await;
+    assert_eq!(status, 200, "{}", body);
+    let body: Value = serde_json::from_str(&body).unwrap();
+    let results = body["results"].as_array().unwrap().iter();
+    let pairs: Vec<Value> = results.map(|e| json!([e["address"], e["prev_kwh"], e["daily_kwh"]])).collect();
+    assert_eq!(pairs, [json!(["2", null, null]), json!(["3", 300.0, 30.0]), json!(["4", 400.0, 40.0])]);
+
+    let (_, body) = get(&server, &format!("/api/v1/power-usage?{}", query)).await;
+    let body: Value = serde_json::from_str(&body).unwrap();
+    let daily: Vec<&Value> = body["gappy:9100"].as_array().unwrap().iter().map(|e| &e["daily_kwh"]).collect();
+    assert_eq!(daily, [30.0, 40.0]);
+}
+
+/// `berlin:9100` has no scrape times, so the period is the one requested:
+/// noon to noon across the spring gap is 23 hours, and across the autumn
+/// overlap 25.
+#[tokio::test]
+async fn dst_days_divide_by_their_true_length() {
+    let server = start().await;
+    for (date, hours, watts, offset) in [("2024-03-31", 23.0, 1000.0, "+02:00"), ("2024-10-27", 25.0, 2000.0, "+01:00")] {
+        let query = format!("target=berlin.*&date={}&time=12:00&tz=Europe/Berlin&meta=true", date);
+        let (status, body) = get(&server, &format!("/api/v1/power-usage?{}", query)).await;
+        assert_eq!(status, 200, "{}", body);
+        let body: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["meta"]["utc_offset"], offset);
+        let entry = &body["results"]["berlin:9100"][0];
+        assert_eq!(entry["period_hours"], hours, "{}", date);
+        assert_eq!(entry["avg_power_watt"], watts, "{}", date);
+    }
+}
+
+#[tokio::test]
+async fn entries_filter_on_their_flags() {
+    let query = "target=.*&date=2025-08-01&time=00:00";
+    let server = start_with("tests/golden/fixtures", &[]).await;
+    let flagged = |body: &str| -> Vec<Value> {
+        let body: Value = serde_json::from_str(body).unwrap();
+        let results = body["results"].as_array().unwrap().iter();
+        results.map(|e| json!([e["instance"], e["address"], e["flags"]])).collect()
+    };
+
+    let (_, body) = get(&server, &format!("/api/v2/power-usage?{}&flagged_only=true", query)).await;
+    let expected = [
+        json!(["golden-a:9100", "1", ["duplicate_series"]]),
+        json!(["golden-a:9100", "3", ["implausible"]]),
+        json!(["golden-b:9100", "1", ["missing_prev"]]),
+        json!(["golden-c:9100", "1", ["implausible"]]),
+    ];
+    assert_eq!(flagged(&body), expected);
+    let exclude = "exclude_flags=missing_prev,implausible";
+    let (_, body) = get(&server, &format!("/api/v2/power-usage?{}&{}", query, exclude)).await;
+    let kept = flagged(&body);
+    assert_eq!(kept.len(), 3, "{}", body);
+    assert!(kept.iter().all(|e| e[2] == json!([]) || e[2] == json!(["duplicate_series"])), "{}", body);
+
+    let csv = format!("/api/v1/power-usage?{}&format=csv&columns=Target,Flags&flagged_only=true", query);
+    let (_, body) = get(&server, &csv).await;
+    let expected = "golden-a:9100,duplicate_series\ngolden-a:9100,implausible\ngolden-c:9100,implausible\n";
+    assert_eq!(body, format!("Target,Flags\n{}", expected));
+    let (status, _) = get(&server, &format!("/api/v1/power-usage?{}&exclude_flags=stale", query)).await;
+    assert_eq!(status, 400);
+}
+
+/// `golden-a:9100` address 3 and `golden-c:9100` are implausible on
+/// 2025-07-31, and `golden-c:9100` on 2025-07-30 as well.
+#[tokio::test]
+async fn implausible_days_count_once() {
+    let server = start_with("tests/golden/fixtures", &[]).await;
+    let counted = || async {
+        let (_, metrics) = get(&server, "/metrics").await;
+        let count = metrics.lines().find_map(|line| line.strip_prefix("implausible_readings_total "));
+        count.map_or(0, |count| count.parse::<u64>().unwrap())
+    };
+
+    for _ in 0..2 {
+        let (status, _) = get(&server, "/api/v2/power-usage?target=.*&date=2025-08-01&time=00:00").await;
+        assert_eq!(status, 200);
+    }
+    assert_eq!(counted().await, 2);
+    let range = "/api/v1/power-usage/range?target=.*&start=2025-07-30&end=2025-07-31";
+    let (status, _) = get(&server, range).await;
+    assert_eq!(status, 200);
+    assert_eq!(counted().await, 3);
+}
+
+/// `meter-a:x|.*` matches the key's `targets` as a string but selects every
+/// instance as a regex, so only the matcher added to the query keeps the
+/// others out. The fixtures answer that query with `meter-a:8899` alone.
+#[tokio::test]
+async fn key_targets_narrow_the_query() {
+    let (server, dir) = start_with_keys("narrow", "acme", "meter-a:.*").await;
+
+    let (status, _) = get(&server, &format!("/api/v2/power-usage?{}", QUERY)).await;
+    assert_eq!(status, 401);
+    let everything = "/api/v2/power-usage?target=.*&date=2025-08-01&time=00:00";
+    assert_eq!(get_with_key(&server, everything, "acme-key").await.0, 403);
+    let widened = "/api/v2/power-usage?target=meter-a:x%7C.*&date=2025-08-01&time=00:00";
+    let (status, body) = get_with_key(&server, widened, "acme-key").await;
+    assert_eq!(status, 200, "{}", body);
+    let body: Value = serde_json::from_str(&body).unwrap();
+    let results = body["results"].as_array().unwrap();
+    assert_eq!(results.len(), 3, "{:?}", results);
+    assert!(results.iter().all(|e| e["instance"] == "meter-a:8899"), "{:?}", results);
+    // A `key_targets` of the caller's own does not replace the key's.
+    let (status, _) = get_with_key(&server, &format!("{}&key_targets=.*", widened), "acme-key").await;
+    assert_eq!(status, 200);
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+/// Both `main` and `subs` are held to the key's `targets`, and the
+/// fixtures only answer their queries with the key's matcher added.
+#[tokio::test]
+async fn reconcile_checks_main_and_subs() {
+    let (server, dir) = start_with_keys("reconcile", "acme", "meter-a:.*").await;
+    let reconcile = |main: &str, subs: &str| {
+        format!("/api/v1/power-usage/reconcile?main={}&main_address=10&subs={}&date=2025-07-31", main, subs)
+    };
+
+    let (status, body) = get_with_key(&server, &reconcile("golden-a:9100", "meter-a:.*"), "acme-key").await;
+    assert_eq!(status, 403);
+    assert_eq!(serde_json::from_str::<Value>(&body).unwrap()["target"], "golden-a:9100");
+    let (status, body) = get_with_key(&server, &reconcile("meter-a:8899", ".*"), "acme-key").await;
+    assert_eq!(status, 403);
+    assert_eq!(serde_json::from_str::<Value>(&body).unwrap()["target"], ".*");
+    for subs in ["meter-a:.*", "meter-a:x%7C.*"] {
+        let (status, body) = get_with_key(&server, &reconcile("meter-a:8899", subs), "acme-key").await;
+        assert_eq!(status, 200, "{}", body);
+        let body: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["main"]["daily_kwh"], 40.0);
+        assert_eq!((&body["subs"]["daily_kwh"], &body["subs"]["meters"]), (&json!(35.0), &json!(2)));
+    }
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+/// The target of an annotation is in the body, so a key limited to
+/// `events:.*` may ask for `.*` and gets the events of its own meters.
+#[tokio::test]
+async fn annotations_need_a_key() {
+    let (server, dir) = start_with_keys("annotations", "grafana", "events:.*").await;
+    let request = json!({
+        "range": {"from": "2025-07-31T17:00:00.000Z", "to": "2025-08-07T17:00:00.000Z"},
+        "annotation": {"name": "Meter events", "enable": true, "query": ".*"},
+    });
+    let post = |key: Option<&str>| {
+        let request = reqwest::Client::new().post(format!("{}/annotations", server.base)).json(&request);
+        match key {
+            Some(key) => request.header("x-api-key", key),
+            None => request,
+        }
+        .send()
+    };
+
+    assert_eq!(post(None).await.unwrap().status(), 401);
+    let response = post(Some("grafana-key")).await.unwrap();
+    assert_eq!(response.status(), 200);
+    let body: Value = response.json().await.unwrap();
+    let tags: Vec<&Value> = body.as_array().unwrap().iter().map(|event| &event["tags"]).collect();
+    assert_eq!(tags, [&json!(["anomaly", "events:9100"]), &json!(["counter_reset", "events:9100"])]);
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+/// The fixtures have no day before today, so the scrape itself fails, but
+/// only once the key and its `targets` let it through.
+#[tokio::test]
+async fn probe_needs_a_key() {
+    let (server, dir) = start_with_keys("probe", "scraper", "meter-a:.*").await;
+
+    assert_eq!(get(&server, "/probe?target=meter-a:8899").await.0, 401);
+    assert_eq!(get_with_key(&server, "/probe?target=.*", "scraper-key").await.0, 403);
+    let (status, body) = get_with_key(&server, "/probe?target=meter-a:8899", "scraper-key").await;
+    assert_eq!(status, 200);
+    assert!(body.contains("probe_success 0"), "{}", body);
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[tokio::test]
+async fn missing_fixture_is_an_upstream_error() {
+    let server = start().await;
+
+    let (status, body) = get(&server, "/api/v1/power-usage?target=unrecorded&date=2025-08-01&time=00:00").await;
+    assert_eq!(status, 502);
+    let body: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body["error_kind"], "upstream_error");
+}
+
+/// Both strategies read the same recorded samples for `meter-a:8899`.
+#[tokio::test]
+async fn query_strategies_agree() {
+    let separate = start_with("tests/fixtures", &[("QUERY_STRATEGY", "separate")]).await;
+    let offset = start_with("tests/fixtures", &[("QUERY_STRATEGY", "offset")]).await;
+    let v1 = format!("/api/v1/power-usage?{}", QUERY);
+    for path in [v1.clone(), format!("{}&csv=true", v1)] {
+        let (status, body) = get(&separate, &path).await;
+        assert_eq!(status, 200, "{}", body);
+        assert_eq!(get(&offset, &path).await, (status, body), "{}", path);
+    }
+    let v2 = format!("/api/v2/power-usage?{}", QUERY);
+    let mut bodies = Vec::new();
+    for server in [&separate, &offset] {
+        let (status, body) = get(server, &v2).await;
+        assert_eq!(status, 200, "{}", body);
+        let mut body: Value = serde_json::from_str(&body).unwrap();
+        body["meta"].as_object_mut().unwrap().remove("generated_at");
+        bodies.push(body);
+    }
+    assert_eq!(bodies[0], bodies[1]);
+    let (_, debug) = get(&offset, &format!("/api/v2/power-usage?{}&debug=true", QUERY)).await;
+    let debug: Value = serde_json::from_str(&debug).unwrap();
+    assert_eq!(debug["meta"]["debug"]["queries"].as_array().unwrap().len(), 1);
+}
+
+/// The current reading of `rejected` was answered with `bad_data`.
+#[tokio::test]
+async fn rejected_query_passes_on_what_prometheus_said() {
+    let server = start().await;
+
+    let (status, body) = get(&server, "/api/v1/power-usage?target=rejected&date=2025-08-01&time=00:00").await;
+    assert_eq!(status, 500);
+    let body: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body["error_kind"], "bad_query");
+    let selector = "{__name__=\"energy\",instance=~\"rejected\"}";
+    let expr = format!(
+        "label_replace(last_over_time({0}[10m]), \"power_usage_part\", \"reading\", \"\", \"\") or \
+         label_replace(max_over_time(timestamp({0})[10m:1m]), \"power_usage_part\", \"scraped\", \"\", \"\")",
+        selector
+    );
+    assert_eq!(body["expr"], expr);
+    assert_eq!(body["upstream"], "1:52: parse error: unexpected character inside braces: '~'");
+}
+
+/// The result the fixtures stored in `CACHE_DIR` is moved to the key of an
+/// unreachable Prometheus and aged past `SETTLED_TTL`, so the server no
+/// longer loads it and only `fallback=last_known` reads it back.
+#[tokio::test]
+async fn last_known_reads_cache_dir() {
+    let dir = std::env::temp_dir().join(format!("power-usage-last-known-{}", std::process::id()));
+    let cache_dir = dir.to_str().unwrap();
+    let env = [("USAGE_CACHE_TTL", "1m"), ("CACHE_DIR", cache_dir), ("PROMETHEUS_HOST", "127.0.0.1:1")];
+    let usage = format!("/api/v2/power-usage?{}", QUERY);
+
+    let server = start_with("tests/fixtures", &env).await;
+    assert_eq!(get(&server, &usage).await.0, 200);
+    server.stop();
+    let stored_at = (chrono::Utc::now() - chrono::Duration::hours(30)).to_rfc3339();
+    let fixtures = format!("file://{}/tests/fixtures/", env!("CARGO_MANIFEST_DIR"));
+    for file in std::fs::read_dir(&dir).unwrap() {
+        let path = file.unwrap().path();
+        let mut entry: Value = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        let key = entry["key"].as_str().unwrap().replacen(&fixtures, "http://127.0.0.1:1/", 1);
+        // The file name `CACHE_DIR` gives the key, its FNV-1a hash.
+        let hash = key.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        });
+        entry["key"] = key.into();
+        entry["stored_at"] = stored_at.clone().into();
+        std::fs::remove_file(&path).unwrap();
+        std::fs::write(dir.join(format!("{:016x}.json", hash)), entry.to_string()).unwrap();
+    }
+
+    let server = start_with("tests/fixtures", &[&env[..], &[("BACKEND", "prometheus")]].concat()).await;
+    let (status, body) = get(&server, &usage).await;
+    assert_eq!(status, 502);
+    let body: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body["error_kind"], "unreachable");
+    let (status, body) = get(&server, &format!("{}&fallback=last_known", usage)).await;
+    assert_eq!(status, 200, "{}", body);
+    let body: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body["meta"]["degraded"], true);
+    assert_eq!(body["results"].as_array().unwrap().len(), 3);
+    server.stop();
+
+    // Without the time it was read, a stored result is not served at all.
+    for file in std::fs::read_dir(&dir).unwrap() {
+        let path = file.unwrap().path();
+        let mut entry: Value = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        entry["usage"].as_object_mut().unwrap().remove("fetched_at").unwrap();
+        std::fs::write(&path, entry.to_string()).unwrap();
+    }
+    let server = start_with("tests/fixtures", &[&env[..], &[("BACKEND", "prometheus")]].concat()).await;
+    let (status, body) = get(&server, &format!("{}&fallback=last_known", usage)).await;
+    assert_eq!(status, 502, "{}", body);
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+/// A result stored by this version loads and answers while Prometheus is
+/// down; one from another version or cut short does not, and is deleted.
+#[tokio::test]
+async fn cache_dir_discards_broken_entries() {
+    let dir = std::env::temp_dir().join(format!("power-usage-broken-cache-{}", std::process::id()));
+    let cache_dir = dir.to_str().unwrap();
+    let env = [("USAGE_CACHE_TTL", "1m"), ("CACHE_DIR", cache_dir), ("PROMETHEUS_HOST", "127.0.0.1:1")];
+    let unreachable = [&env[..], &[("BACKEND", "prometheus")]].concat();
+    let usage = format!("/api/v2/power-usage?{}", QUERY);
+
+    let server = start_with("tests/fixtures", &env).await;
+    assert_eq!(get(&server, &usage).await.0, 200);
+    server.stop();
+    let fixtures = format!("file://{}/tests/fixtures/", env!("CARGO_MANIFEST_DIR"));
+    let mut stored = Vec::new();
+    for file in std::fs::read_dir(&dir).unwrap() {
+        let path = file.unwrap().path();
+        let mut entry: Value = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        let key = entry["key"].as_str().unwrap().replacen(&fixtures, "http://127.0.0.1:1/", 1);
+        let hash = key.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        });
+        entry["key"] = key.into();
+        std::fs::remove_file(&path).unwrap();
+        let path = dir.join(format!("{:016x}.json", hash));
+        std::fs::write(&path, entry.to_string()).unwrap();
+        stored.push((path, entry));
+    }
+
+    let server = start_with("tests/fixtures", &unreachable).await;
+    let (status, body) = get(&server, &usage).await;
+    assert_eq!(status, 200, "{}", body);
+    server.stop();
+
+    let truncated = dir.join("0000000000000000.json");
+    for (path, entry) in &stored {
+        let body = entry.to_string();
+        std::fs::write(&truncated, &body[..body.len() / 2]).unwrap();
+        let mut other = entry.clone();
+        other["version"] = "0.0.0".into();
+        std::fs::write(path, other.to_string()).unwrap();
+    }
+    let server = start_with("tests/fixtures", &unreachable).await;
+    let (status, body) = get(&server, &format!("{}&fallback=last_known", usage)).await;
+    assert_eq!(status, 502, "{}", body);
+    server.stop();
+    let left: Vec<_> = std::fs::read_dir(&dir).unwrap().map(|file| file.unwrap().path()).collect();
+    assert!(left.is_empty(), "{:?}", left);
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+/// The server listening on the Unix socket `path` only, with `env` on top.
+fn spawn_on_socket(path: &std::path::Path, env: &[(&str, &str)]) -> Child {
+    Command::new(env!("CARGO_BIN_EXE_power-usage"))
+        .env_clear()
+        .env("BACKEND", "fixture")
+        .env("FIXTURE_DIR", format!("{}/tests/fixtures", env!("CARGO_MANIFEST_DIR")))
+        .env("BIND_ADDR", format!("unix:{}", path.display()))
+        .env("RUST_LOG", "error")
+        .envs(env.iter().copied())
+        .spawn()
+        .expect("failed to start the server")
+}
+
+/// Only a socket left behind is replaced: a regular file at the path fails
+/// the start and is kept. The socket gets `SOCKET_MODE` before it appears.
+#[tokio::test]
+async fn unix_socket_replaces_only_a_socket() {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+    let dir = std::env::temp_dir().join(format!("power-usage-socket-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("power-usage.sock");
+
+    std::fs::write(&path, "keep").unwrap();
+    let mut refused = Server {
+        child: spawn_on_socket(&path, &[]),
+        base: String::new(),
+    };
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let status = loop {
+        if let Some(status) = refused.child.try_wait().unwrap() {
+            break status;
+        }
+        assert!(Instant::now() < deadline, "the server started on a regular file");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    };
+    assert!(!status.success());
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "keep");
+
+    std::fs::remove_file(&path).unwrap();
+    drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+    let server = Server {
+        child: spawn_on_socket(&path, &[("SOCKET_MODE", "0600")]),
+        base: String::new(),
+    };
+    let mut connected = false;
+    for _ in 0..100 {
+        if std::os::unix::net::UnixStream::connect(&path).is_ok() {
+            connected = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert!(connected, "the server did not replace the stale socket");
+    let metadata = std::fs::symlink_metadata(&path).unwrap();
+    assert!(metadata.file_type().is_socket());
+    assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+    let left: Vec<_> = std::fs::read_dir(&dir).unwrap().map(|file| file.unwrap().file_name()).collect();
+    assert_eq!(left, ["power-usage.sock"]);
+    drop(server);
+    std::fs::remove_dir_all(&dir).ok();
+}
+
+#[tokio::test]
+async fn last_known_needs_a_cache() {
+    let server = start().await;
+
+    let (status, body) = get(&server, &format!("/api/v2/power-usage?{}&fallback=last_known", QUERY)).await;
+    assert_eq!(status, 400);
+    let body: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body["error"], "fallback=last_known needs USAGE_CACHE_TTL");
+}
+
+/// `late:9100` was not scraped in the ten minutes before the previous
+/// midnight, only half an hour earlier.
+#[tokio::test]
+async fn fallback_lookback_fills_a_missing_reading() {
+    let query = "/api/v2/power-usage?target=late.*&date=2025-08-01&time=00:00";
+    let server = start().await;
+    let (status, body) = get(&server, query).await;
+    assert_eq!(status, 200, "{}", body);
+    let body: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body["results"][0]["prev_kwh"], Value::Null);
+    assert_eq!(body["results"][0]["flags"], json!(["missing_prev"]));
+    let (_, debug) = get(&server, &format!("{}&debug=true", query)).await;
+    let debug: Value = serde_json::from_str(&debug).unwrap();
+    assert_eq!(debug["meta"]["debug"]["queries"].as_array().unwrap().len(), 2);
+
+    let server = start_with("tests/fixtures", &[("FALLBACK_LOOKBACK", "1h")]).await;
+    let (status, body) = get(&server, query).await;
+    assert_eq!(status, 200, "{}", body);
+    let body: Value = serde_json::from_str(&body).unwrap();
+    let entry = &body["results"][0];
+    assert_eq!((&entry["prev_kwh"], &entry["daily_kwh"]), (&json!(100.0), &json!(10.0)));
+    assert_eq!(entry["prev_sample_time"], "2025-07-30T16:30:00Z");
+    assert_eq!(entry["period_hours"], 24.492);
+    let (_, debug) = get(&server, &format!("{}&debug=true", query)).await;
+    let debug: Value = serde_json::from_str(&debug).unwrap();
+    assert_eq!(debug["meta"]["debug"]["queries"].as_array().unwrap().len(), 3);
+}
+
+/// A panicking handler answers with the usual JSON error, and the server
+/// goes on serving.
+#[cfg(feature = "debug-routes")]
+#[tokio::test]
+async fn handler_panic_is_a_json_500() {
+    let server = start().await;
+
+    let response = reqwest::Client::new()
+        .get(format!("{}/debug/panic", server.base))
+        .header("X-Request-Id", "panic-test")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 500);
+    assert_eq!(response.headers()["x-request-id"], "panic-test");
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body, json!({"error": "Internal server error", "request_id": "panic-test"}));
+    let (status, _) = get(&server, &format!("/api/v1/power-usage?{}", QUERY)).await;
+    assert_eq!(status, 200);
+    let (_, metrics) = get(&server, "/metrics").await;
+    assert!(metrics.lines().any(|line| line == "panics_total 1"), "{}", metrics);
+}
+
+/// A request that waits on an identical one sends Prometheus nothing, so
+/// it logs the id of the request whose queries did.
+#[tokio::test]
+async fn coalesced_request_logs_the_leaders_id() {
+    let env = [("FIXTURE_LATENCY", "300ms"), ("RUST_LOG", "info"), ("NO_COLOR", "1")];
+    let mut server = start_logging(&env).await;
+    let client = reqwest::Client::new();
+    let url = format!("{}/api/v1/power-usage?{}", server.base, QUERY);
+    let request = |id: &'static str| client.get(&url).header("X-Request-Id", id).send();
+
+    let follower = async {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        request("follower").await
+    };
+    let (leader, follower) = tokio::join!(request("leader"), follower);
+    assert_eq!(leader.unwrap().status(), 200);
+    assert_eq!(follower.unwrap().status(), 200);
+    let (_, metrics) = get(&server, "/metrics").await;
+    assert!(metrics.lines().any(|line| line == "usage_requests_coalesced_total 1"), "{}", metrics);
+
+    let stdout = server.child.stdout.take().unwrap();
+    drop(server);
+    let log = std::io::read_to_string(stdout).unwrap();
+    let coalesced: Vec<&str> = log.lines().filter(|line| line.contains("coalesced")).collect();
+    assert_eq!(coalesced.len(), 1, "{}", log);
+    assert!(coalesced[0].contains("request_id=follower"), "{}", coalesced[0]);
+    assert!(coalesced[0].contains("leader_request_id=leader"), "{}", coalesced[0]);
+}
+
+/// `tests/fixtures/timezones.toml` puts `zoned-a:9100` and `zoned-b:9100`
+/// in zones of their own, each within `MAX_INSTANCES` on its own. The
+/// limit trips on the current readings, so no previous one is asked for.
+#[tokio::test]
+async fn instance_limit_covers_every_zone() {
+    let timezones = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/timezones.toml");
+    let env = [("TIMEZONES_FILE", timezones), ("MAX_INSTANCES", "1")];
+    let server = start_with("tests/fixtures", &env).await;
+    let query = "target=zoned.*&date=2025-08-02&time=00:00";
+
+    let before = queries_answered(&server).await;
+    let (status, _) = get(&server, &format!("/api/v2/power-usage?{}", query)).await;
+    assert_eq!(status, 422);
+    assert_eq!(queries_answered(&server).await - before, 2);
+    let (status, body) = get(&server, &format!("/api/v2/power-usage?{}&truncate=true", query)).await;
+    assert_eq!(status, 200, "{}", body);
+    let body: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body["meta"]["truncated"], true);
+    assert_eq!(body["meta"]["total_instances"], 2);
+    let results = body["results"].as_array().unwrap();
+    assert_eq!(results.len(), 1, "{}", body);
+    assert_eq!(results[0]["instance"], "zoned-a:9100");
+    assert_eq!(results[0]["timezone"], "Asia/Makassar");
+}
+
+/// Each zone of `tests/fixtures/timezones.toml` has one row.
+#[tokio::test]
+async fn row_limit_covers_every_zone() {
+    let timezones = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/timezones.toml");
+    let env = [("TIMEZONES_FILE", timezones), ("MAX_RESPONSE_ROWS", "1")];
+    let server = start_with("tests/fixtures", &env).await;
+
+    let (status, body) = get(&server, "/api/v2/power-usage?target=zoned.*&date=2025-08-02&time=00:00").await;
+    assert_eq!(status, 413, "{}", body);
+}
+
+/// `meter-a:8899` has three series, one row each.
+#[tokio::test]
+async fn count_only_sizes_the_rows_the_limit_refuses() {
+    let server = start_with("tests/fixtures", &[("MAX_RESPONSE_ROWS", "2")]).await;
+
+    let response = reqwest::get(format!("{}/api/v1/power-usage?{}&count_only=true", server.base, QUERY))
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 204);
+    let header = |name: &str| response.headers()[name].to_str().unwrap().to_string();
+    assert_eq!((header("x-instance-count"), header("x-row-count")), ("1".to_string(), "3".to_string()));
+
+    let (status, body) = get(&server, &format!("/api/v2/power-usage?{}", QUERY)).await;
+    assert_eq!(status, 413);
+    assert!(body.contains("POST /api/v1/jobs"), "{}", body);
+}
+
+/// `invoice:8899` has one row a day, so three days are over a limit of one,
+/// which a job is not held to.
+#[tokio::test]
+async fn jobs_are_not_held_to_the_row_limit() {
+    let server = start_with("tests/fixtures", &[("MAX_RESPONSE_ROWS", "1")]).await;
+    let params = "target=invoice.*&start=2025-07-29&end=2025-07-31";
+
+    let (status, body) = get(&server, &format!("/api/v1/power-usage/range?{}", params)).await;
+    assert_eq!(status, 413, "{}", body);
+
+    let url = format!("{}/api/v1/jobs?report=range&{}", server.base, params);
+    let response = reqwest::Client::new().post(&url).send().await.unwrap();
+    assert_eq!(response.status(), 202);
+    let mut job: Value = response.json().await.unwrap();
+    let path = format!("/api/v1/jobs/{}", job["id"].as_str().unwrap());
+    for _ in 0..100 {
+        if job["status"] != "queued" && job["status"] != "running" {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        job = serde_json::from_str(&get(&server, &path).await.1).unwrap();
+    }
+    assert_eq!(job["status"], "done", "{}", job);
+    let (status, body) = get(&server, &format!("{}/result", path)).await;
+    assert_eq!(status, 200);
+    let body: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body["results"][0]["days"].as_array().unwrap().len(), 3, "{}", body);
+}
+
+/// A finished job keeps its result in memory until `JOBS_RETENTION`, so it
+/// counts toward `JOBS_PER_KEY` until it is deleted.
+#[tokio::test]
+async fn results_in_memory_count_toward_the_job_limit() {
+    let server = start_with("tests/fixtures", &[("JOBS_PER_KEY", "2")]).await;
+    let client = reqwest::Client::new();
+    let url = format!("{}/api/v1/jobs?report=range&target=invoice.*&start=2025-07-29&end=2025-07-31", server.base);
+    let submit = || client.post(&url).send();
+
+    let mut paths = Vec::new();
+    for _ in 0..2 {
+        let response = submit().await.unwrap();
+        assert_eq!(response.status(), 202);
+        let job: Value = response.json().await.unwrap();
+        paths.push(format!("/api/v1/jobs/{}", job["id"].as_str().unwrap()));
+    }
+    for path in &paths {
+        let mut job = Value::Null;
+        for _ in 0..100 {
+            job = serde_json::from_str(&get(&server, path).await.1).unwrap();
+            if job["status"] == "done" {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        assert_eq!(job["status"], "done", "{}", job);
+    }
+    let response = submit().await.unwrap();
+    assert_eq!(response.status(), 429);
+    let body: Value = response.json().await.unwrap();
+    assert_eq!(body["limit"], 2);
+
+    let deleted = client.delete(format!("{}{}", server.base, paths[0])).send().await.unwrap();
+    assert_eq!(deleted.status(), 204);
+    assert_eq!(submit().await.unwrap().status(), 202);
+}
+
+/// 2025 is long past the lookback, 2099 is yet to come, and debug output
+/// and admin routes are never cached.
+#[tokio::test]
+async fn cache_headers_follow_the_readings() {
+    let server = start().await;
+    let client = reqwest::Client::new();
+    let fetch = |path: String, since: Option<&'static str>| {
+        let mut request = client.get(format!("{}{}", server.base, path));
+        if let Some(since) = since {
+            request = request.header("If-Modified-Since", since);
+        }
+        async move {
+            let response = request.send().await.unwrap();
+            let header = |name: &str| response.headers().get(name).map(|v| v.to_str().unwrap().to_string());
+            (response.status().as_u16(), header("cache-control"), header("last-modified"))
+        }
+    };
+    let historical = Some("public, max-age=86400, immutable".to_string());
+    let last_modified = Some("Thu, 31 Jul 2025 16:59:30 GMT".to_string());
+
+    let usage = format!("/api/v1/power-usage?{}", QUERY);
+    assert_eq!(fetch(usage.clone(), None).await, (200, historical.clone(), last_modified.clone()));
+    let unchanged = fetch(usage.clone(), Some("Thu, 31 Jul 2025 17:00:00 GMT")).await;
+    assert_eq!(unchanged, (304, historical.clone(), last_modified.clone()));
+    let older = fetch(format!("/api/v2/power-usage?{}", QUERY), Some("Thu, 31 Jul 2025 16:00:00 GMT")).await;
+    assert_eq!(older, (200, historical, last_modified));
+
+    let future = "/api/v1/power-usage?target=meter-a.*&date=2099-01-01&time=00:00&empty_ok=true";
+    assert_eq!(fetch(future.to_string(), None).await, (200, Some("public, max-age=30".to_string()), None));
+
+    let no_store = Some("no-store".to_string());
+    let debug = fetch(format!("/api/v2/power-usage?{}&debug=true", QUERY), None).await;
+    assert_eq!(debug, (200, no_store.clone(), None));
+    assert_eq!(fetch("/admin/status".to_string(), None).await.1, no_store);
+}
+
+/// `mixed:9100` answers with its addresses out of order, one of them not
+/// set at all.
+#[tokio::test]
+async fn addresses_sort_naturally() {
+    let query = "/api/v2/power-usage?target=mixed.*&date=2025-08-02&time=00:00";
+    let server = start_with("tests/fixtures", &[]).await;
+    for _ in 0..3 {
+        let (status, body) = get(&server, query).await;
+        assert_eq!(status, 200, "{}", body);
+        let body: Value = serde_json::from_str(&body).unwrap();
+        let addresses: Vec<&str> =
+            body["results"].as_array().unwrap().iter().map(|e| e["address"].as_str().unwrap()).collect();
+        assert_eq!(addresses, ["", "1", "2", "10", "A1", "A10", "B2"]);
+    }
+
+    let csv = query.replace("v2", "v1") + "&format=csv&columns=Address,Daily_KWh";
+    let (_, body) = get(&server, &csv).await;
+    assert_eq!(body, "Address,Daily_KWh\n,4\n1,6\n2,3\n10,1\nA1,2\nA10,7\nB2,5\n");
+}
+
+/// Address 2 of `booting:7070` exports `NaN` for the later reading.
+#[tokio::test]
+async fn nan_readings_are_skipped_and_reported() {
+    let query = "target=booting.*&date=2025-08-02&time=00:00";
+    let server = start_with("tests/fixtures", &[]).await;
+    let (status, body) = get(&server, &format!("/api/v2/power-usage?{}&debug=true", query)).await;
+    assert_eq!(status, 200, "{}", body);
+    let body: Value = serde_json::from_str(&body).unwrap();
+    let results = body["results"].as_array().unwrap();
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0]["daily_kwh"], 10.0);
+    assert_eq!(body["meta"]["skipped_series"], 1);
+    assert_eq!(body["meta"]["skipped_meters"], json!([{"instance": "booting:7070", "address": "2"}]));
+    let skipped = &body["meta"]["debug"]["skipped_series"][0];
+    assert_eq!((&skipped["reason"], &skipped["value"]), (&json!("nan"), &json!("NaN")));
+
+    let (_, body) = get(&server, &format!("/api/v1/power-usage?{}&meta=true&format=csv", query)).await;
+    assert!(body.contains("# skipped_series: 1\n"), "{}", body);
+}
+
+/// Address 1 of `nanfirst:9100`, the first, exports `NaN` for the later
+/// reading.
+#[tokio::test]
+async fn nan_on_the_first_address_leaves_the_others_alone() {
+    let query = "target=nanfirst.*&date=2025-08-02&time=00:00";
+    let server = start_with("tests/fixtures", &[]).await;
+    let (status, body) = get(&server, &format!("/api/v2/power-usage?{}", query)).await;
+    assert_eq!(status, 200, "{}", body);
+    let body: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body["meta"]["skipped_series"], 1);
+    assert_eq!(body["meta"]["skipped_meters"], json!([{"instance": "nanfirst:9100", "address": "1"}]));
+    let results = body["results"].as_array().unwrap().iter();
+    let pairs: Vec<Value> = results.map(|e| json!([e["address"], e["prev_kwh"], e["daily_kwh"]])).collect();
+    assert_eq!(pairs, [json!(["2", 200.0, 20.0]), json!(["3", 300.0, 30.0])]);
+}
+
+#[tokio::test]
+async fn header_lang_translates_only_the_labels() {
+    let query = "/api/v1/power-usage?target=booting.*&date=2025-08-02&time=00:00&format=csv&header_lang=id";
+    let server = start_with("tests/fixtures", &[]).await;
+    let (_, body) = get(&server, query).await;
+    assert!(body.starts_with("Target,Alamat,Sebelumnya_kWh,Saat_Ini_kWh,Pemakaian_Harian_kWh,"), "{}", body);
+    let (_, body) = get(&server, &format!("{}&unit=wh&columns=Address,Daily_KWh", query)).await;
+    assert_eq!(body, "Alamat,Pemakaian_Harian_Wh\n1,10000\n");
+    let (status, _) = get(&server, &query.replace("header_lang=id", "header_lang=xx")).await;
+    assert_eq!(status, 400);
+}
+
+#[tokio::test]
+async fn corrections_replace_the_computed_day() {
+    let server = start_with("tests/fixtures", &[("ADMIN_TOKEN", "secret")]).await;
+    let client = reqwest::Client::new();
+    let correction = json!({"instance": "meter-a:8899", "address": "1", "date": "2025-07-31",
+        "corrected_daily_kwh": 12.0, "reason": "re-read by hand", "author": "ops"});
+    let url = format!("{}/api/v1/corrections", server.base);
+    let response = client.post(&url).json(&correction).send().await.unwrap();
+    assert_eq!(response.status(), 401);
+    let response = client.post(&url).bearer_auth("secret").json(&correction).send().await.unwrap();
+    assert_eq!(response.status(), 201);
+    let id = response.json::<Value>().await.unwrap()["id"].as_str().unwrap().to_string();
+
+    let (status, body) = get(&server, &format!("/api/v2/power-usage?{}", QUERY)).await;
+    assert_eq!(status, 200);
+    let body: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body["results"][0]["daily_kwh"], 12.0);
+    assert_eq!(body["results"][0]["corrected"], true);
+    assert_eq!(body["meta"]["corrections"][0]["computed_daily_kwh"], 10.0);
+    assert_eq!(body["results"][1].get("corrected"), None);
+    let (_, body) = get(&server, &format!("/api/v2/power-usage?{}&corrections=ignore", QUERY)).await;
+    let body: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body["results"][0]["daily_kwh"], 10.0);
+
+    let response = client.delete(format!("{}/{}", url, id)).bearer_auth("secret").send().await.unwrap();
+    assert_eq!(response.status(), 200);
+    let (_, body) = get(&server, &format!("/api/v2/power-usage?{}", QUERY)).await;
+    let body: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body["results"][0]["daily_kwh"], 10.0);
+}
+
+/// With `FIXTURE_LATENCY` each of the four midnight readings of
+/// `invoice:8899` takes 300 ms, one at a time, so its first day reaches the
+/// client while the last reading is still being read.
+#[tokio::test]
+async fn range_csv_streams_each_day_when_read() {
+    let env = [("FIXTURE_LATENCY", "300ms"), ("RANGE_CONCURRENCY", "1")];
+    let server = start_with("tests/fixtures", &env).await;
+    let range = "/api/v1/power-usage/range?target=invoice.*&start=2025-07-29&end=2025-07-31&format=csv";
+
+    let started = Instant::now();
+    let mut response = reqwest::get(format!("{}{}", server.base, range)).await.unwrap();
+    assert_eq!(response.status(), 200);
+    let mut body = String::new();
+    let mut first_day = None;
+    while let Some(chunk) = response.chunk().await.unwrap() {
+        body.push_str(std::str::from_utf8(&chunk).unwrap());
+        if first_day.is_none() && body.contains("2025-07-29") {
+            first_day = Some(started.elapsed());
+        }
+    }
+    let finished = started.elapsed();
+    assert!(body.ends_with("invoice:8899,1,2025-07-31,10.400000000000091,\n"), "{}", body);
+    let first_day = first_day.unwrap();
+    assert!(first_day + Duration::from_millis(300) <= finished, "{:?} of {:?}", first_day, finished);
+}
+
+/// `invoice:8899` read 1000, 1012.5, 1021 and 1031.4 kWh at the local
+/// midnights from 29 July to 1 August. Its invoice rounds each day half up
+/// to whole kWh before summing: 13 + 9 + 10 = 32 kWh for the 31.4 used.
+#[tokio::test]
+async fn utility_rounding_follows_the_invoice() {
+    let range = "/api/v1/power-usage/range?target=invoice.*&start=2025-07-29&end=2025-07-31";
+    let report = |body: &str| {
+        let body: Value = serde_json::from_str(body).unwrap();
+        let meter = &body["results"][0];
+        let days = meter["days"].as_array().unwrap().iter();
+        let days: Vec<f64> = days.map(|day| day["daily_kwh"].as_f64().unwrap()).collect();
+        (days, meter["total_kwh"].as_f64().unwrap())
+    };
+
+    let server = start_with("tests/fixtures", &[("TARIFF_PER_KWH", "1444.70")]).await;
+    let (_, body) = get(&server, range).await;
+    assert_eq!(report(&body).0[..2], [12.5, 8.5]);
+    let (status, body) = get(&server, &format!("{}&rounding=utility", range)).await;
+    assert_eq!(status, 200);
+    assert_eq!(report(&body), (vec![13.0, 9.0, 10.0], 32.0));
+    let (_, body) = get(&server, &format!("{}&rounding=utility&format=csv", range)).await;
+    assert!(body.ends_with("invoice:8899,1,2025-07-31,10,\n"), "{}", body);
+    // The last day's 10.4 kWh is billed as 10, at 1444.70 a kWh.
+    let day = "/api/v1/power-usage?target=invoice.*&date=2025-08-01&time=00:00&format=markdown&summary=true";
+    let (_, body) = get(&server, &format!("{}&rounding=utility", day)).await;
+    assert!(body.contains("| **Total** |  |  |  | 10.00 | 433.33 | 14447.00 |"), "{}", body);
+    let (status, _) = get(&server, &format!("{}&rounding=bankers", range)).await;
+    assert_eq!(status, 400);
+
+    let server = start_with("tests/fixtures", &[("ROUNDING_STAGE", "total")]).await;
+    let (_, body) = get(&server, &format!("{}&rounding=utility", range)).await;
+    let (days, total) = report(&body);
+    assert_eq!((days[..2].to_vec(), total), (vec![12.5, 8.5], 31.0));
+
+    let server = start_with("tests/fixtures", &[("ROUNDING_TIES", "half_even")]).await;
+    let (_, body) = get(&server, &format!("{}&rounding=utility", range)).await;
+    assert_eq!(report(&body), (vec![12.0, 8.0, 10.0], 30.0));
+}
+
+/// At 0.10004 a kWh the days of 10, 20 and 100 kWh cost 1.0004, 2.0008
+/// and 10.004, which round one by one to 13.00 against a total of 13.01.
+#[tokio::test]
+async fn costs_add_up_to_the_total() {
+    let env = [("TARIFF_PER_KWH", "0.10004"), ("TARIFF_CURRENCY", "EUR")];
+    let server = start_with("tests/fixtures", &env).await;
+    let (status, body) = get(&server, &format!("/api/v1/power-usage?{}&csv=true&summary=true", QUERY)).await;
+    assert_eq!(status, 200, "{}", body);
+    let mut lines = body.lines();
+    assert_eq!(lines.next(), Some("# currency: EUR"));
+    let headers: Vec<&str> = lines.next().unwrap().split(',').collect();
+    assert_eq!(headers[headers.len() - 2..], ["Avg_Power_Watt", "Cost"]);
+    let costs: Vec<&str> = lines.clone().map(|line| line.rsplit(',').next().unwrap()).collect();
+    assert_eq!(costs, ["1", "2", "10.01", "13.01"]);
+    assert_eq!(lines.last(), Some("Total,,,,130,5416.67,13.01"));
+
+    let markdown = format!("/api/v1/power-usage?{}&format=markdown&summary=true", QUERY);
+    let (_, body) = get(&server, &markdown).await;
+    assert!(body.contains("| 1.00 |\n"), "{}", body);
+    assert!(body.contains("| 2.00 |\n"), "{}", body);
+    assert!(body.contains("| 10.01 |\n"), "{}", body);
+    assert!(body.contains("| **Total** |  |  |  | 130.00 | 5416.67 | 13.01 |"), "{}", body);
+}
+
+/// The golden fixtures have no reading at the midnight ending 2025-08-01.
+#[tokio::test]
+async fn range_leaves_failed_days_empty() {
+    let server = start_with("tests/golden/fixtures", &[]).await;
+    let query = "/api/v1/power-usage/range?target=.*&start=2025-07-30&end=2025-08-01";
+
+    let (status, body) = get(&server, query).await;
+    assert_eq!(status, 200);
+    let body: Value = serde_json::from_str(&body).unwrap();
+    let days = &body["results"][0]["days"];
+    assert_eq!(days[1]["daily_kwh"], 7.125);
+    assert_eq!(days[2]["daily_kwh"], Value::Null);
+    assert_eq!(days[2]["error"], "upstream_error");
+    assert_eq!(days[2]["flags"], json!(["failed"]));
+
+    let (status, _) = get(&server, &format!("{}&on_error=fail_fast", query)).await;
+    assert_eq!(status, 502);
+    // Three of five days failing is more than RANGE_MAX_FAILED_FRACTION.
+    let longer = "/api/v1/power-usage/range?target=.*&start=2025-07-30&end=2025-08-03";
+    let (status, _) = get(&server, longer).await;
+    assert_eq!(status, 502);
+}
+
+/// `phased:9100` address 1 has a series per phase, and its `L3` is not
+/// scraped before the last midnight.
+#[tokio::test]
+async fn range_sums_the_phases_of_a_meter() {
+    let server = start().await;
+    let query = "/api/v1/power-usage/range?target=phased.*&start=2025-08-01&end=2025-08-03";
+
+    let (status, body) = get(&server, query).await;
+    assert_eq!(status, 200, "{}", body);
+    let body: Value = serde_json::from_str(&body).unwrap();
+    let results = body["results"].as_array().unwrap();
+    assert_eq!(results.len(), 1, "{:?}", results);
+    let days: Vec<&Value> = results[0]["days"].as_array().unwrap().iter().map(|d| &d["daily_kwh"]).collect();
+    assert_eq!(days, [&json!(17.0), &json!(29.0), &Value::Null]);
+}
+
+/// `tests/golden/changeovers.toml` replaces two meters on 2025-07-31.
+#[tokio::test]
+async fn range_stitches_changeovers() {
+    let changeovers = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/golden/changeovers.toml");
+    let server = start_with("tests/golden/fixtures", &[("CHANGEOVERS_FILE", changeovers)]).await;
+    let meter = |body: &Value, instance: &str, address: &str| {
+        let results = body["results"].as_array().unwrap();
+        results.iter().find(|m| m["instance"] == instance && m["address"] == address).cloned()
+    };
+
+    let query = "/api/v1/power-usage/range?target=.*&start=2025-07-30&end=2025-07-31";
+    let (status, body) = get(&server, query).await;
+    assert_eq!(status, 200);
+    let body: Value = serde_json::from_str(&body).unwrap();
+    // The day before is the old meter's, the day itself (510 - 500) + (3.25 - 0).
+    let reset = meter(&body, "golden-a:9100", "3").unwrap();
+    assert_eq!(reset["changeover"], true);
+    assert_eq!(reset["days"][0]["daily_kwh"], 20.0);
+    assert_eq!(reset["days"][1]["daily_kwh"], 13.25);
+    assert_eq!(reset["days"][1]["flags"], json!(["changeover"]));
+    // The old meter is reported as the new one: (51 - 50) + (42 - 40).
+    assert!(meter(&body, "golden-a:9100", "2").is_none());
+    let replaced = meter(&body, "golden-b:9100", "1").unwrap();
+    assert_eq!(replaced["days"][0]["daily_kwh"], 0.0);
+    assert_eq!(replaced["days"][1]["daily_kwh"], 3.0);
+    assert_eq!(replaced["total_kwh"], 3.0);
+
+    // Starting on the changeover day, and streamed.
+    let query = "/api/v1/power-usage/range?target=.*&start=2025-07-31&end=2025-07-31&format=csv";
+    let (status, body) = get(&server, query).await;
+    assert_eq!(status, 200);
+    assert!(body.contains("golden-a:9100,3,2025-07-31,13.25,changeover\n"), "{}", body);
+    assert!(body.contains("golden-b:9100,1,2025-07-31,3,changeover\n"), "{}", body);
+
+    // Ending the day before, the old meter is still itself.
+    let (_, body) = get(&server, "/api/v1/power-usage/range?target=.*&start=2025-07-30&end=2025-07-30").await;
+    let body: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(meter(&body, "golden-a:9100", "2").unwrap().get("changeover"), None);
+    assert!(meter(&body, "golden-b:9100", "1").is_none());
+}
+
+/// `events:9100` uses about 10 kWh a day, 40 on 2025-08-04, and its
+/// counter goes back from 1082 to 2 on 2025-08-06.
+#[tokio::test]
+async fn annotations_mark_resets_and_anomalies() {
+    let server = start().await;
+    let request = json!({
+        "range": {"from": "2025-07-31T17:00:00.000Z", "to": "2025-08-07T17:00:00.000Z"},
+        "annotation": {"name": "Meter events", "enable": true, "query": "events.*"},
+    });
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/annotations", server.base))
+        .json(&request)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let body: Value = response.json().await.unwrap();
+    let events: Vec<(&Value, &Value, &Value)> = body
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|event| (&event["time"], &event["title"], &event["tags"]))
+        .collect();
+    assert_eq!(
+        events,
+        [
+            (
+                &json!(1754240400000i64),
+                &json!("Unusually high consumption on events:9100/1"),
+                &json!(["anomaly", "events:9100"]),
+            ),
+            (
+                &json!(1754413200000i64),
+                &json!("Counter reset on events:9100/1"),
+                &json!(["counter_reset", "events:9100"]),
+            ),
+        ]
+    );
+    assert_eq!(body[1]["text"], "The counter went back by 1080.00 kWh on 2025-08-06");
+    assert_eq!(body[1]["annotation"], request["annotation"]);
+}
+
+/// Every instance of an `anonymize=true` answer is a pseudonym that
+/// `/admin/anonymize/reveal` turns back into the instance.
+#[tokio::test]
+async fn anonymized_instances_are_revealed() {
+    let env = [("ADMIN_TOKEN", "secret"), ("ANONYMIZE_KEY", "0123456789abcdef")];
+    let server = start_with("tests/fixtures", &env).await;
+    let (status, body) = get(&server, &format!("/api/v2/power-usage?{}&anonymize=true", QUERY)).await;
+    assert_eq!(status, 200, "{}", body);
+    let body: Value = serde_json::from_str(&body).unwrap();
+    let pseudonym = body["results"][0]["instance"].as_str().unwrap().to_string();
+    assert!(pseudonym.starts_with("anon-"), "{}", pseudonym);
+
+    let client = reqwest::Client::new();
+    let reveal = |pseudonym: &str| {
+        let url = format!("{}/admin/anonymize/reveal?pseudonym={}", server.base, pseudonym);
+        client.get(url).bearer_auth("secret").send()
+    };
+    let response = reveal(&pseudonym).await.unwrap();
+    assert_eq!(response.status(), 200);
+    let revealed: Value = response.json().await.unwrap();
+    assert_eq!(revealed, json!({"pseudonym": pseudonym, "name": "meter-a:8899"}));
+    assert_eq!(reveal("anon-000000000000").await.unwrap().status(), 404);
+}
+
+#[test]
+fn golden_outputs_match() {
+    let output = Command::new(env!("CARGO_BIN_EXE_power-usage"))
+        .args(["verify", "--fixtures", concat!(env!("CARGO_MANIFEST_DIR"), "/tests/golden")])
+        .env_clear()
+        .output()
+        .expect("failed to run verify");
+    assert!(
+        output.status.success(),
+        "golden outputs differ; if intended, run `verify --fixtures tests/golden --update`:\n{}{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+}